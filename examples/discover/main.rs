@@ -0,0 +1,127 @@
+use std::{env, process::exit, time::Duration};
+
+use testeq_rs::protocol::{
+    DiscoveryOptions, PortmapClient, ResourceTransport, SerialConfig, discover_with,
+    probe_serial_ports, serial_ports, vxi11_programs,
+};
+use tokio::time::Instant;
+
+fn usage() -> ! {
+    println!("Usage: ... [options]");
+    println!("  Options:");
+    println!("    --portmap <addr>:<port>: Portmapper to query, instead of broadcasting");
+    println!("    --mdns <addr>:<port>: mDNS responder to query, instead of multicasting");
+    println!("    --timeout <ms>: Time to wait for responses, default 1000");
+    println!("    --programs: List the VXI-11 programs registered by each instrument");
+    println!("    --serial: List serial ports instead, probing each at 9600 baud");
+    exit(1);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let mut options = DiscoveryOptions::default();
+    let mut portmap_targets = vec![];
+    let mut mdns_targets = vec![];
+    let mut programs = false;
+    let mut serial = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--programs" => programs = true,
+            "--serial" => serial = true,
+            "--portmap" | "--mdns" | "--timeout" => {
+                let Some(value) = args.next() else {
+                    usage();
+                };
+                match arg.as_str() {
+                    "--portmap" => portmap_targets.push(value.parse()?),
+                    "--mdns" => mdns_targets.push(value.parse()?),
+                    _ => options.timeout = Duration::from_millis(value.parse()?),
+                }
+            }
+            _ => usage(),
+        }
+    }
+    if !portmap_targets.is_empty() || !mdns_targets.is_empty() {
+        options.portmap_targets = portmap_targets;
+        options.mdns_targets = mdns_targets;
+    }
+
+    if serial {
+        return list_serial_ports(options.timeout).await;
+    }
+
+    let found = discover_with(&options).await?;
+    if found.is_empty() {
+        println!("No instruments found");
+    }
+    for instrument in found {
+        let model = &instrument.model;
+        println!(
+            "{}: {} {} {}",
+            instrument.uri(),
+            model.manufacturer,
+            model.model,
+            model.serial.as_deref().unwrap_or("")
+        );
+
+        if !programs
+            || !matches!(
+                instrument.address.transport,
+                ResourceTransport::Vxi11 { .. }
+            )
+        {
+            continue;
+        }
+        let portmapper = instrument.address.resolve_socket()?;
+
+        let mut client = PortmapClient::connect_any(portmapper).await?;
+        let start = Instant::now();
+        client.null().await?;
+        println!(
+            "  Portmapper over {:?}, {:.1} ms round trip",
+            client.protocol(),
+            start.elapsed().as_secs_f64() * 1000.
+        );
+        client.disconnect().await?;
+
+        for program in vxi11_programs(portmapper).await? {
+            println!(
+                "  {:?}: program {} version {} over {:?} on port {}",
+                program.channel, program.prog, program.vers, program.prot, program.port
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn list_serial_ports(timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let ports = serial_ports()?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+    for port in &ports {
+        match &port.usb {
+            Some(usb) => println!(
+                "{}: USB {:04x}:{:04x} {} {} {}",
+                port.port,
+                usb.vid,
+                usb.pid,
+                usb.manufacturer.as_deref().unwrap_or(""),
+                usb.product.as_deref().unwrap_or(""),
+                usb.serial.as_deref().unwrap_or("")
+            ),
+            None => println!("{}", port.port),
+        }
+    }
+
+    for (port, model) in probe_serial_ports(ports, &SerialConfig::default(), timeout).await {
+        println!("{}: {} {}", port.port, model.manufacturer, model.model);
+    }
+
+    Ok(())
+}
@@ -26,8 +26,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("Usage: ... <uri>");
         println!("  <uri>:");
         println!("    tcp://<host>:<port>: SCPI over raw TCP");
-        println!("    vxi11://<host>[:<port>]: SCPI over raw VXI11");
-        println!("    serial:<port>[?baud=<baud>]: SCPI over serial");
+        println!("    vxi11://<host>[:<port>][/<device>]: SCPI over raw VXI11");
+        println!("    hislip://<host>[:<port>][/<sub-address>]: SCPI over HiSLIP");
+        println!("    serial:<port>: SCPI over serial");
+        println!(
+            "    serial-usb:vid=<vid>,pid=<pid>[,serial=<serial>]: SCPI over serial of a USB device"
+        );
+        println!("    usbtmc://<vid>:<pid>[:<serial>]: SCPI over USBTMC");
+        println!("    prologix://<host>[:<port>]/<gpib address>: SCPI over Prologix GPIB-ETHERNET");
+        println!("    prologix-serial:<port>/<gpib address>: SCPI over Prologix GPIB-USB");
+        println!("    mock://<path>: Replay of a script or recorded session");
+        println!("    sim://psu|dmm|scope|sa|ac[/<model>[/<name>]]: Simulated instrument");
+        println!("    TCPIP0::<host>::inst0::INSTR and other VISA resource strings");
+        println!("  IPv6 hosts with a port are bracketed, e.g. [fe80::1%eth0]:5025");
+        println!("  Options (appended as ?<key>=<value>&...):");
+        println!("    timeout=<duration>: Default I/O timeout, e.g. 500ms or 5s");
+        println!("    error_check=never|always|<n>: Check error queue after every <n> commands");
+        println!("    termination=lf|crlf|cr|eoi: Message terminator, eoi for VXI11/HiSLIP only");
+        println!("    record=<path>: Record the session to a JSON lines file");
+        println!(
+            "    reconnect=<retries>: Reconnect up to <retries> times if the connection is lost"
+        );
+        println!("    net=<instrument>:<net>: Net probed by a simulated multimeter, e.g. psu:CH1");
+        println!("    baud=<baud>: Serial baud rate");
+        println!("    parity=none|odd|even, data_bits=5..8, stop_bits=1|2: Serial framing");
+        println!("    flow_control=none|software|hardware: Serial flow control");
+        println!("    dtr=on|off, rts=on|off: Serial line levels on connect");
+        println!("    command_delay=<duration>: Minimum delay between serial commands");
         exit(1);
     }
 
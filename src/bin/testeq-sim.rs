@@ -0,0 +1,90 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr},
+    process::exit,
+};
+
+use testeq_rs::{
+    protocol::{PORTMAP_PORT, ResourceAddress, ResourceTransport},
+    sim::{self, server::SimServer},
+};
+
+/// First raw TCP port, as used by most instruments
+const RAW_PORT: u16 = 5025;
+
+fn usage() -> ! {
+    println!("Usage: ... [options] <instrument>...");
+    println!(
+        "  <instrument>: [sim://]psu|dmm|scope|sa|ac[/<model>[/<name>]][?net=<instrument>:<net>]"
+    );
+    println!("  Options:");
+    println!("    --bind <ip>: Address to listen on, default 127.0.0.1");
+    println!("    --raw-port <port>: Raw TCP port of the first instrument, default {RAW_PORT}");
+    println!("    --portmap-port <port>: VXI-11 portmapper port, default {PORTMAP_PORT}");
+    println!("  Each instrument has a raw TCP port, consecutive from the first, and is");
+    println!("  available via VXI-11 as inst<n> or by its name. Port 0 picks free ports.");
+    exit(1);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let mut ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut raw_port = RAW_PORT;
+    let mut portmap_port = PORTMAP_PORT;
+    let mut devices = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" | "--raw-port" | "--portmap-port" => {
+                let Some(value) = args.next() else {
+                    usage();
+                };
+                match arg.as_str() {
+                    "--bind" => ip = value.parse()?,
+                    "--raw-port" => raw_port = value.parse()?,
+                    _ => portmap_port = value.parse()?,
+                }
+            }
+            arg if arg.starts_with("--") => usage(),
+            arg => {
+                let uri = if arg.contains("://") {
+                    arg.to_string()
+                } else {
+                    format!("sim://{arg}")
+                };
+                let addr: ResourceAddress = uri.parse()?;
+                let ResourceTransport::Sim { class, model, name } = addr.transport else {
+                    return Err(format!("{uri} is not a simulated instrument").into());
+                };
+
+                let device = sim::open(class, model.as_deref(), name.as_deref())?;
+                if let Some(net) = &addr.options.net {
+                    device.lock().unwrap().instrument().set_probe(net)?;
+                }
+                devices.push(device);
+            }
+        }
+    }
+    if devices.is_empty() {
+        usage();
+    }
+
+    let server = SimServer::start(ip, raw_port, portmap_port, devices.clone()).await?;
+
+    let vxi11_addr = server.vxi11_addr();
+    for (idx, (device, raw_addr)) in devices.iter().zip(server.raw_addrs()).enumerate() {
+        let mut device = device.lock().unwrap();
+        let idn = device.instrument().idn();
+        println!("{} ({idn}):", device.name());
+        println!("  tcp://{raw_addr}");
+        println!("  vxi11://{vxi11_addr}/inst{idx}");
+    }
+
+    /* Serve until killed */
+    std::future::pending::<()>().await;
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use tokio::sync::Mutex;
@@ -12,22 +12,22 @@ use crate::{
         },
     },
     error::{Error, Result},
-    protocol::ScpiProtocol,
+    protocol::ScpiSession,
 };
 
 pub struct KeysightAcSource {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     channels: Vec<Arc<Mutex<KeysightAcSourceChannel>>>,
 }
 impl KeysightAcSource {
-    pub fn new(proto: Box<dyn ScpiProtocol>) -> Result<Self> {
-        let proto_arc = Arc::new(Mutex::new(proto));
+    pub fn new(proto: impl Into<ScpiSession>) -> Result<Self> {
+        let proto: ScpiSession = proto.into();
 
         Ok(Self {
             channels: vec![Arc::new(Mutex::new(KeysightAcSourceChannel::new(
-                proto_arc.clone(),
+                proto.clone(),
             )))],
-            proto: proto_arc,
+            proto,
         })
     }
 }
@@ -37,6 +37,10 @@ impl BaseEquipment for KeysightAcSource {
         /* TODO */
         Ok(())
     }
+
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        self.proto.wait_complete(timeout).await
+    }
 }
 #[async_trait]
 impl AcSourceEquipment for KeysightAcSource {
@@ -68,10 +72,10 @@ impl AcSourceEquipment for KeysightAcSource {
 }
 
 struct KeysightAcSourceChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
 }
 impl KeysightAcSourceChannel {
-    fn new(proto: Arc<Mutex<Box<dyn ScpiProtocol>>>) -> Self {
+    fn new(proto: ScpiSession) -> Self {
         Self { proto }
     }
 }
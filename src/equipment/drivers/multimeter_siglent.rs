@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use tokio::sync::{Mutex, RwLock};
 
@@ -13,26 +13,26 @@ use crate::{
     },
     error::{Error, Result},
     model::ModelInfo,
-    protocol::ScpiProtocol,
+    protocol::ScpiSession,
 };
 
 #[allow(unused)]
 pub struct SiglentMultimeter {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     model: Option<ModelInfo>,
     channels: Vec<Arc<Mutex<SiglentMultimeterChannel>>>,
 }
 impl SiglentMultimeter {
-    pub fn new(proto: Box<dyn ScpiProtocol>) -> Result<Self> {
-        let proto_arc = Arc::new(Mutex::new(proto));
+    pub fn new(proto: impl Into<ScpiSession>) -> Result<Self> {
+        let proto: ScpiSession = proto.into();
 
         Ok(Self {
             /* TODO: Support scanner cards */
             channels: vec![Arc::new(Mutex::new(SiglentMultimeterChannel::new(
-                proto_arc.clone(),
+                proto.clone(),
                 0,
             )))],
-            proto: proto_arc,
+            proto,
             model: None,
         })
     }
@@ -43,6 +43,10 @@ impl BaseEquipment for SiglentMultimeter {
         /* TODO */
         Ok(())
     }
+
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        self.proto.wait_complete(timeout).await
+    }
 }
 #[async_trait::async_trait]
 impl MultimeterEquipment for SiglentMultimeter {
@@ -103,13 +107,13 @@ impl MultimeterEquipment for SiglentMultimeter {
 }
 
 struct SiglentMultimeterChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     idx: u8,
     /// Cached multimeter mode
     mode: Arc<RwLock<Option<MultimeterMode>>>,
 }
 impl SiglentMultimeterChannel {
-    fn new(proto: Arc<Mutex<Box<dyn ScpiProtocol>>>, idx: u8) -> Self {
+    fn new(proto: ScpiSession, idx: u8) -> Self {
         Self {
             proto,
             idx,
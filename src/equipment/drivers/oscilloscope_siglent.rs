@@ -14,24 +14,22 @@ use crate::{
     },
     error::{Error, Result},
     model::{Manufacturer, ModelInfo, SiglentFamily},
-    protocol::ScpiProtocol,
+    protocol::{ScpiSession, ScpiTransaction},
 };
 
 #[allow(unused)]
 pub struct SiglentOscilloscope {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     model: Option<ModelInfo>,
     analog_channels: Vec<Arc<Mutex<SiglentOscilloscopeChannel>>>,
     digital_channels: Vec<Arc<Mutex<SiglentOscilloscopeDigitalChannel>>>,
 }
 impl SiglentOscilloscope {
-    pub fn new(proto: Box<dyn ScpiProtocol>) -> Result<Self> {
-        let proto_arc = Arc::new(Mutex::new(proto));
-
+    pub fn new(proto: impl Into<ScpiSession>) -> Result<Self> {
         Ok(Self {
             analog_channels: vec![],
             digital_channels: vec![],
-            proto: proto_arc,
+            proto: proto.into(),
             model: None,
         })
     }
@@ -80,6 +78,10 @@ impl BaseEquipment for SiglentOscilloscope {
         }
         Ok(())
     }
+
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        self.proto.wait_complete(timeout).await
+    }
 }
 #[async_trait::async_trait]
 impl OscilloscopeEquipment for SiglentOscilloscope {
@@ -247,18 +249,18 @@ impl OscilloscopeEquipment for SiglentOscilloscope {
 
             capture.analog.insert(name, waveform);
 
-            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.proto.lock().await.opc_query().await?;
         }
         Ok(capture)
     }
 }
 
 struct SiglentOscilloscopeChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     idx: u8,
 }
 impl SiglentOscilloscopeChannel {
-    fn new(proto: Arc<Mutex<Box<dyn ScpiProtocol>>>, idx: u8) -> Self {
+    fn new(proto: ScpiSession, idx: u8) -> Self {
         Self { proto, idx }
     }
 
@@ -276,50 +278,35 @@ impl SiglentOscilloscopeChannel {
             .to_string())
     }
 
-    async fn read_header(&self, proto: &mut dyn ScpiProtocol) -> Result<String> {
-        let res = proto
-            .recv_raw(Some(2), Some(Duration::from_secs(3)))
-            .await?;
-        let length = (res[1] - b'0').into();
-        let value = proto
-            .recv_raw(Some(length), Some(Duration::from_secs(1)))
-            .await?;
-        Ok(String::from_utf8_lossy(&value).to_string())
-    }
-
-    async fn read_preamble(&self) -> Result<WaveformPreable> {
-        let mut proto = self.proto.lock().await;
-
-        proto.send(":WAV:PRE?").await?;
-        let pre = self.read_header(proto.as_mut()).await?;
-        let desc = proto
-            .recv_raw(Some(346), Some(Duration::from_secs(1)))
-            .await?;
+    async fn read_preamble(proto: &mut ScpiTransaction) -> Result<WaveformPreable> {
+        let desc = proto.query_block(":WAV:PRE?").await?;
 
         Ok(WaveformPreable {
-            header: pre,
             wavedesc: WaveDescData::from_bytes(&desc)?,
         })
     }
 
     async fn read_samples(
-        &self,
+        proto: &mut ScpiTransaction,
         count: usize,
         wavedesc: &WaveDescData,
         dest: &mut Vec<f64>,
     ) -> Result<()> {
-        let mut proto = self.proto.lock().await;
-
         let bytes = if wavedesc.comm_type == 0 {
             count
         } else {
             count * 2
         };
 
-        self.read_header(proto.as_mut()).await?;
-        let raw = proto
-            .recv_raw(Some(bytes), Some(Duration::from_secs(3)))
-            .await?;
+        let mut raw = proto.recv_block().await?;
+        if raw.len() < bytes {
+            return Err(Error::BadResponse(format!(
+                "Expected {} bytes of waveform data, got {}",
+                bytes,
+                raw.len()
+            )));
+        }
+        raw.truncate(bytes);
 
         let scale = (wavedesc.attenuation as f64 * wavedesc.vert_gain as f64)
             / wavedesc.code_per_div as f64;
@@ -349,19 +336,23 @@ impl OscilloscopeChannel for SiglentOscilloscopeChannel {
     }
 
     async fn read_waveform(&self) -> Result<AnalogWaveform> {
-        self.send(format!(":WAV:SOUR C{}", self.idx + 1)).await?;
+        /* Held throughout, so other tasks cannot change the waveform settings
+         * or take the data */
+        let mut proto = self.proto.lock().await;
+
+        proto.send(format!(":WAV:SOUR C{}", self.idx + 1)).await?;
         /* Start at point 0 */
-        self.send(":WAV:STAR 0").await?;
+        proto.send(":WAV:STAR 0").await?;
         /* 20000 points per read */
         /* TODO: Use :WAV:MAXP? to get max size */
-        self.send(":WAV:POIN 20000").await?;
+        proto.send(":WAV:POIN 20000").await?;
         /* Retrieve every data point */
-        self.send(":WAV:INT 1").await?;
+        proto.send(":WAV:INT 1").await?;
         /* Set data width */
         /* TODO: Support byte width */
-        self.send(":WAV:WIDT WORD").await?;
+        proto.send(":WAV:WIDT WORD").await?;
 
-        let pre = self.read_preamble().await?;
+        let pre = Self::read_preamble(&mut proto).await?;
 
         let mut waveform = AnalogWaveform {
             time_per_pt: pre.wavedesc.horiz_interval.into(),
@@ -379,8 +370,9 @@ impl OscilloscopeChannel for SiglentOscilloscopeChannel {
                 samples_to_read = 20000;
             }
 
-            self.send(":WAV:DATA?").await?;
-            self.read_samples(
+            proto.send(":WAV:DATA?").await?;
+            Self::read_samples(
+                &mut proto,
                 samples_to_read,
                 &pre.wavedesc,
                 &mut waveform.readings.values,
@@ -390,9 +382,6 @@ impl OscilloscopeChannel for SiglentOscilloscopeChannel {
             sample += samples_to_read;
         }
 
-        /* Consume final newline */
-        self.proto.lock().await.recv().await?;
-
         Ok(waveform)
     }
 
@@ -421,12 +410,12 @@ impl OscilloscopeChannel for SiglentOscilloscopeChannel {
 
 #[allow(unused)]
 struct SiglentOscilloscopeDigitalChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     idx: u8,
 }
 #[allow(unused)]
 impl SiglentOscilloscopeDigitalChannel {
-    fn new(proto: Arc<Mutex<Box<dyn ScpiProtocol>>>, idx: u8) -> Self {
+    fn new(proto: ScpiSession, idx: u8) -> Self {
         Self { proto, idx }
     }
 
@@ -506,6 +495,5 @@ impl WaveDescData {
 #[allow(unused)]
 #[derive(Debug)]
 struct WaveformPreable {
-    header: String,
     wavedesc: WaveDescData,
 }
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use tokio::sync::Mutex;
 
@@ -9,18 +9,18 @@ use crate::{
     },
     error::{Error, Result},
     model::{Manufacturer, ModelInfo},
-    protocol::ScpiProtocol,
+    protocol::ScpiSession,
 };
 
 pub struct GenericScpiPsu {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     model: Option<ModelInfo>,
     channels: Vec<Arc<Mutex<GenericScpiPsuChannel>>>,
 }
 impl GenericScpiPsu {
-    pub fn new(proto: Box<dyn ScpiProtocol>) -> Result<Self> {
+    pub fn new(proto: impl Into<ScpiSession>) -> Result<Self> {
         Ok(Self {
-            proto: Arc::new(Mutex::new(proto)),
+            proto: proto.into(),
             model: None,
             channels: vec![],
         })
@@ -54,6 +54,10 @@ impl BaseEquipment for GenericScpiPsu {
 
         Ok(())
     }
+
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        self.proto.wait_complete(timeout).await
+    }
 }
 #[async_trait::async_trait]
 impl PowerSupplyEquipment for GenericScpiPsu {
@@ -75,14 +79,14 @@ impl PowerSupplyEquipment for GenericScpiPsu {
 }
 
 struct GenericScpiPsuChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     idx: u8,
     details: PowerSupplyChannelDetails,
     protocol: ScpiPsuProto,
 }
 impl GenericScpiPsuChannel {
     fn new(
-        proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+        proto: ScpiSession,
         idx: u8,
         details: PowerSupplyChannelDetails,
         protocol: ScpiPsuProto,
@@ -189,7 +193,7 @@ impl PowerSupplyChannel for GenericScpiPsuChannel {
     }
 }
 
-enum ScpiPsuModel {
+pub(crate) enum ScpiPsuModel {
     /* Rigol DP700 series */
     RigolDP711,
     RigolDP712,
@@ -216,7 +220,7 @@ enum ScpiPsuModel {
     SiglentSPD4323X,
 }
 impl ScpiPsuModel {
-    fn channel_details(&self) -> Vec<PowerSupplyChannelDetails> {
+    pub(crate) fn channel_details(&self) -> Vec<PowerSupplyChannelDetails> {
         match self {
             Self::RigolDP711 => vec![PowerSupplyChannelDetails::new(0.0, 30.0, 5.0)],
             Self::RigolDP712 => vec![PowerSupplyChannelDetails::new(0.0, 50.0, 5.0)],
@@ -284,7 +288,7 @@ impl ScpiPsuModel {
         }
     }
 
-    fn from_model(model: &ModelInfo) -> Result<Self> {
+    pub(crate) fn from_model(model: &ModelInfo) -> Result<Self> {
         let res = match &model.man_family {
             Manufacturer::Rigol(_) => {
                 let mdl = &model.model;
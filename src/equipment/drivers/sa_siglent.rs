@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use tokio::sync::Mutex;
@@ -14,21 +14,19 @@ use crate::{
     },
     error::{Error, Result},
     model::ModelInfo,
-    protocol::ScpiProtocol,
+    protocol::ScpiSession,
 };
 
 pub struct SiglentSpectrumAnalyzer {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
     model: Option<ModelInfo>,
     channels: Vec<Arc<Mutex<SiglentSaChannel>>>,
 }
 impl SiglentSpectrumAnalyzer {
-    pub fn new(proto: Box<dyn ScpiProtocol>) -> Result<Self> {
-        let proto_arc = Arc::new(Mutex::new(proto));
-
+    pub fn new(proto: impl Into<ScpiSession>) -> Result<Self> {
         Ok(Self {
             channels: vec![],
-            proto: proto_arc,
+            proto: proto.into(),
             model: None,
         })
     }
@@ -50,6 +48,10 @@ impl BaseEquipment for SiglentSpectrumAnalyzer {
 
         Ok(())
     }
+
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        self.proto.wait_complete(timeout).await
+    }
 }
 #[async_trait]
 impl SpectrumAnalyzerEquipment for SiglentSpectrumAnalyzer {
@@ -71,10 +73,10 @@ impl SpectrumAnalyzerEquipment for SiglentSpectrumAnalyzer {
 }
 
 struct SiglentSaChannel {
-    proto: Arc<Mutex<Box<dyn ScpiProtocol>>>,
+    proto: ScpiSession,
 }
 impl SiglentSaChannel {
-    fn new(proto: Arc<Mutex<Box<dyn ScpiProtocol>>>) -> Self {
+    fn new(proto: ScpiSession) -> Self {
         Self { proto }
     }
 }
@@ -132,16 +134,18 @@ impl SpectrumAnalyzerChannel for SiglentSaChannel {
 
         /* Use 64-bit floating-point numbers */
         proto.send(":FORM REAL,64").await?;
-        /* Request data */
-        proto.send(format!(":TRAC? {}", idx + 1)).await?;
-        let data = proto.recv().await?;
-
-        /* Data is a block of 64-bit floating-point values, followed by a newline */
-        for chunk in data.chunks(8) {
-            if chunk.len() == 8 {
-                let value = f64::from_le_bytes(chunk.try_into().unwrap());
-                trace.readings.values.push(unit.normalize(value));
-            }
+        /* Data is a block of 64-bit floating-point values */
+        let data = proto.query_block(format!(":TRAC? {}", idx + 1)).await?;
+        if data.len() % 8 != 0 {
+            return Err(Error::BadResponse(format!(
+                "Trace of {} bytes is not a whole number of values",
+                data.len()
+            )));
+        }
+
+        for chunk in data.chunks_exact(8) {
+            let value = f64::from_le_bytes(chunk.try_into().unwrap());
+            trace.readings.values.push(unit.normalize(value));
         }
 
         trace.freq_step = trace.span.span() / (trace.readings.values.len() as f32);
@@ -5,6 +5,8 @@ pub mod oscilloscope;
 pub mod psu;
 pub mod spectrum_analyzer;
 
+use std::time::Duration;
+
 use async_trait::async_trait;
 
 use ac_source::AcSourceEquipment;
@@ -16,7 +18,7 @@ use spectrum_analyzer::SpectrumAnalyzerEquipment;
 use crate::{
     error::{Error, Result},
     model::{KeysightFamily, Manufacturer, RigolFamily, SiglentFamily},
-    protocol::{ScpiProtocol, scpi_from_uri},
+    protocol::{ScpiProtocol, ScpiSession, scpi_from_uri},
 };
 
 use self::drivers::{
@@ -33,8 +35,14 @@ pub enum Equipment {
     SpectrumAnalyzer(Box<dyn SpectrumAnalyzerEquipment>),
 }
 
-pub async fn equipment_from_scpi(mut proto: Box<dyn ScpiProtocol>) -> Result<Equipment> {
-    let model = proto.idn_model().await?;
+pub async fn equipment_from_scpi(proto: Box<dyn ScpiProtocol>) -> Result<Equipment> {
+    equipment_from_session(ScpiSession::new(proto)).await
+}
+
+/// Driver for the instrument of a session, which other tasks may keep using
+/// through clones of the session
+pub async fn equipment_from_session(proto: ScpiSession) -> Result<Equipment> {
+    let model = proto.lock().await.idn_model().await?;
 
     #[allow(clippy::collapsible_match)]
     match &model.man_family {
@@ -87,6 +95,16 @@ pub async fn equipment_from_uri(uri: impl AsRef<str>) -> Result<Equipment> {
 }
 
 #[async_trait]
+/* Don't warn about unused arguments for default implementations */
+#[allow(unused_variables)]
 pub trait BaseEquipment: Sync + Send {
     async fn connect(&mut self) -> Result<()>;
+
+    /// Wait up to timeout for all pending operations to complete, such that
+    /// the equipment has settled after previous commands
+    async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        Err(Error::NotSupported(
+            "Equipment cannot wait for pending operations".into(),
+        ))
+    }
 }
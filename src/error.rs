@@ -16,6 +16,54 @@ pub enum Error {
     Timeout(String),
     /// Invalid argument passed to library
     InvalidArgument(String),
+    /// Device reported an error in its error queue
+    DeviceError {
+        /// SCPI error code, negative values are defined by the standard
+        code: i32,
+        message: String,
+        /// Command after which the error was detected, if known
+        command: Option<String>,
+    },
+}
+impl Error {
+    /// Whether the error indicates the connection to the device was lost,
+    /// such that reconnecting may recover from it
+    pub fn is_connection_lost(&self) -> bool {
+        let io_error = match self {
+            Error::IoError(e) => e,
+            Error::Unhandled(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(e) => e,
+                None => return false,
+            },
+            _ => return false,
+        };
+
+        /* Serial devices that have been unplugged fail with EIO or ENODEV */
+        if cfg!(unix) && matches!(io_error.raw_os_error(), Some(5 | 19)) {
+            return true;
+        }
+
+        matches!(
+            io_error.kind(),
+            std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::HostUnreachable
+                | std::io::ErrorKind::NetworkUnreachable
+                | std::io::ErrorKind::NetworkDown
+        )
+    }
+
+    /// Error for a connection closed by the device
+    pub(crate) fn connection_closed() -> Self {
+        Error::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Connection closed",
+        ))
+    }
 }
 impl std::error::Error for Error {}
 impl Display for Error {
@@ -29,6 +77,17 @@ impl Display for Error {
             Error::NotSupported(e) => write!(f, "Not supported: {e}"),
             Error::Timeout(e) => write!(f, "Timed out: {e}"),
             Error::InvalidArgument(e) => write!(f, "Invalid argument: {e}"),
+            Error::DeviceError {
+                code,
+                message,
+                command,
+            } => {
+                write!(f, "Device error {code}, \"{message}\"")?;
+                if let Some(command) = command {
+                    write!(f, " after `{command}`")?;
+                }
+                Ok(())
+            }
         }
     }
 }
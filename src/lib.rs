@@ -3,6 +3,7 @@ pub mod equipment;
 pub mod error;
 pub mod model;
 pub mod protocol;
+pub mod sim;
 
 pub fn version() -> &'static str {
     env!("CARGO_PKG_VERSION")
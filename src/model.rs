@@ -30,6 +30,14 @@ impl ModelInfo {
             version: idn_sep.get(3).map(|s| s.to_string()),
         })
     }
+
+    /// Whether both identify the same device, which may have had its firmware
+    /// updated in between
+    pub fn same_device(&self, other: &Self) -> bool {
+        self.manufacturer == other.manufacturer
+            && self.model == other.model
+            && self.serial == other.serial
+    }
 }
 impl Display for ModelInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
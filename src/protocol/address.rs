@@ -0,0 +1,1252 @@
+//! Instrument resource addresses, parsed from either our URI form (e.g.
+//! `vxi11://10.0.0.5?timeout=5s`) or a VISA resource string (e.g.
+//! `TCPIP0::10.0.0.5::inst0::INSTR`)
+
+use std::{
+    fmt::Display,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    time::Duration,
+};
+
+use strum_macros::{AsRefStr, EnumString};
+
+use crate::{
+    error::{Error, Result},
+    sim::SimClass,
+};
+
+use super::{
+    ErrorCheck, GPIB_MAX_ADDR, HISLIP_PORT, PORTMAP_PORT, PROLOGIX_PORT, SerialConfig, Terminator,
+    UsbSerialMatch, vxi11::VXI11_DEFAULT_DEVICE,
+};
+
+/// HiSLIP sub-address used if none is given
+const HISLIP_DEFAULT_SUB_ADDRESS: &str = "hislip0";
+
+/// Transport used to reach an instrument, and its location
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceTransport {
+    /// SCPI over a raw TCP socket
+    Tcp { host: String, port: u16 },
+    /// VXI-11, port being that of the portmapper
+    Vxi11 {
+        host: String,
+        port: Option<u16>,
+        device: String,
+    },
+    /// HiSLIP
+    Hislip {
+        host: String,
+        port: Option<u16>,
+        sub_address: String,
+    },
+    /// Serial port, by device path or name
+    Serial { port: String },
+    /// Serial port of a USB device, looked up on connect
+    SerialUsb { usb: UsbSerialMatch },
+    /// USBTMC device, by vendor and product IDs and serial number
+    Usbtmc {
+        vid: u16,
+        pid: u16,
+        serial: Option<String>,
+    },
+    /// Device on the bus of a Prologix GPIB-ETHERNET adapter
+    Prologix {
+        host: String,
+        port: Option<u16>,
+        gpib_addr: u8,
+    },
+    /// Device on the bus of a Prologix GPIB-USB adapter
+    PrologixSerial { port: String, gpib_addr: u8 },
+    /// Replay of a script or recorded session file
+    Mock { path: String },
+    /// Simulated instrument, shared with other connections by name
+    Sim {
+        class: SimClass,
+        model: Option<String>,
+        name: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+pub enum StopBits {
+    #[strum(serialize = "1")]
+    One,
+    #[strum(serialize = "2")]
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+/// Typed options given with a resource address. Options that are not given
+/// leave the transport's defaults in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceOptions {
+    /// Default I/O timeout
+    pub timeout: Option<Duration>,
+    /// Automatic error queue checking
+    pub error_check: Option<ErrorCheck>,
+    /// Message terminator, for both sending and receiving
+    pub termination: Option<Terminator>,
+    /// Serial baud rate
+    pub baud: Option<u32>,
+    /// Serial parity
+    pub parity: Option<Parity>,
+    /// Serial data bits, 5 to 8
+    pub data_bits: Option<u8>,
+    /// Serial stop bits
+    pub stop_bits: Option<StopBits>,
+    /// Serial flow control
+    pub flow_control: Option<FlowControl>,
+    /// Serial DTR line level
+    pub dtr: Option<bool>,
+    /// Serial RTS line level
+    pub rts: Option<bool>,
+    /// Minimum delay between serial commands
+    pub command_delay: Option<Duration>,
+    /// File to record the session to
+    pub record: Option<String>,
+    /// Number of attempts to reconnect after the connection is lost, with
+    /// the rest of the default [`ReconnectPolicy`](super::ReconnectPolicy)
+    pub reconnect: Option<u32>,
+    /// Net probed by a simulated instrument, as `<instrument>:<net>`
+    pub net: Option<String>,
+}
+impl ResourceOptions {
+    fn parse(args: &str) -> Result<Self> {
+        let mut options = Self::default();
+
+        for arg in args.split('&').filter(|arg| !arg.is_empty()) {
+            let Some((key, value)) = arg.split_once('=') else {
+                return Err(Error::InvalidArgument(format!(
+                    "Improperly formatted URI argument '{arg}'"
+                )));
+            };
+            let invalid = || Error::InvalidArgument(format!("Invalid value for {key}: {value}"));
+
+            match key {
+                "timeout" => options.timeout = Some(parse_duration(value)?),
+                "error_check" => {
+                    options.error_check = Some(match value {
+                        "never" => ErrorCheck::Never,
+                        "always" => ErrorCheck::EveryCommand,
+                        _ => match value.parse() {
+                            Ok(0) | Err(_) => return Err(invalid()),
+                            Ok(n) => ErrorCheck::Interval(n),
+                        },
+                    })
+                }
+                "termination" => options.termination = Some(value.parse().map_err(|_| invalid())?),
+                "baud" => options.baud = Some(value.parse().map_err(|_| invalid())?),
+                "parity" => options.parity = Some(value.parse().map_err(|_| invalid())?),
+                "data_bits" => {
+                    let bits = value.parse().map_err(|_| invalid())?;
+                    if !(5..=8).contains(&bits) {
+                        return Err(invalid());
+                    }
+                    options.data_bits = Some(bits);
+                }
+                "stop_bits" => options.stop_bits = Some(value.parse().map_err(|_| invalid())?),
+                "flow_control" => {
+                    options.flow_control = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "dtr" => options.dtr = Some(parse_bool(value).ok_or_else(invalid)?),
+                "rts" => options.rts = Some(parse_bool(value).ok_or_else(invalid)?),
+                "command_delay" => options.command_delay = Some(parse_duration(value)?),
+                "record" => options.record = Some(value.into()),
+                "reconnect" => options.reconnect = Some(value.parse().map_err(|_| invalid())?),
+                "net" => options.net = Some(value.into()),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Unsupported argument '{key}' in URI"
+                    )));
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Serial line configuration, using defaults for options not given
+    pub fn serial_config(&self) -> SerialConfig {
+        let mut config = SerialConfig::default();
+        if let Some(baud) = self.baud {
+            config.baud = baud;
+        }
+        if let Some(parity) = self.parity {
+            config.parity = parity;
+        }
+        if let Some(data_bits) = self.data_bits {
+            config.data_bits = data_bits;
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            config.stop_bits = stop_bits;
+        }
+        if let Some(flow_control) = self.flow_control {
+            config.flow_control = flow_control;
+        }
+        if let Some(command_delay) = self.command_delay {
+            config.command_delay = command_delay;
+        }
+        config.dtr = self.dtr;
+        config.rts = self.rts;
+
+        config
+    }
+
+    /// Whether any serial line options are given
+    pub fn has_serial(&self) -> bool {
+        self.baud.is_some()
+            || self.parity.is_some()
+            || self.data_bits.is_some()
+            || self.stop_bits.is_some()
+            || self.flow_control.is_some()
+            || self.dtr.is_some()
+            || self.rts.is_some()
+            || self.command_delay.is_some()
+    }
+}
+impl Display for ResourceOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut args = vec![];
+
+        if let Some(timeout) = self.timeout {
+            args.push(format!("timeout={}", format_duration(timeout)));
+        }
+        if let Some(error_check) = self.error_check {
+            args.push(match error_check {
+                ErrorCheck::Never => "error_check=never".into(),
+                ErrorCheck::EveryCommand => "error_check=always".into(),
+                ErrorCheck::Interval(n) => format!("error_check={n}"),
+            });
+        }
+        if let Some(termination) = self.termination {
+            args.push(format!("termination={}", termination.as_ref()));
+        }
+        if let Some(baud) = self.baud {
+            args.push(format!("baud={baud}"));
+        }
+        if let Some(parity) = self.parity {
+            args.push(format!("parity={}", parity.as_ref()));
+        }
+        if let Some(data_bits) = self.data_bits {
+            args.push(format!("data_bits={data_bits}"));
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            args.push(format!("stop_bits={}", stop_bits.as_ref()));
+        }
+        if let Some(flow_control) = self.flow_control {
+            args.push(format!("flow_control={}", flow_control.as_ref()));
+        }
+
+        if let Some(dtr) = self.dtr {
+            args.push(format!("dtr={}", if dtr { "on" } else { "off" }));
+        }
+        if let Some(rts) = self.rts {
+            args.push(format!("rts={}", if rts { "on" } else { "off" }));
+        }
+        if let Some(command_delay) = self.command_delay {
+            args.push(format!("command_delay={}", format_duration(command_delay)));
+        }
+
+        if let Some(record) = &self.record {
+            args.push(format!("record={record}"));
+        }
+        if let Some(reconnect) = self.reconnect {
+            args.push(format!("reconnect={reconnect}"));
+        }
+        if let Some(net) = &self.net {
+            args.push(format!("net={net}"));
+        }
+
+        if !args.is_empty() {
+            write!(f, "?{}", args.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Address of an instrument resource, along with connection options
+///
+/// Supported URI forms, each optionally followed by `?<key>=<value>&...`
+/// options:
+/// * `tcp://<host>:<port>`
+/// * `vxi11://<host>[:<port>][/<device>]`
+/// * `hislip://<host>[:<port>][/<sub-address>]`
+/// * `serial:<port>`
+/// * `serial-usb:vid=<vid>,pid=<pid>[,serial=<serial>]`, the serial port of a
+///   USB device, IDs being in hex
+/// * `usbtmc://<vid>:<pid>[:<serial>]`, IDs being in hex
+/// * `prologix://<host>[:<port>]/<gpib address>`
+/// * `prologix-serial:<port>/<gpib address>`
+/// * `mock://<path>`, replaying a script or recorded session
+/// * `sim://<class>[/<model>[/<name>]]`, a simulated instrument of class
+///   `psu`, `dmm`, `scope`, `sa` or `ac`
+///
+/// Supported VISA resource strings:
+/// * `TCPIP[board]::<host>[::<device>][::INSTR]`, using HiSLIP if the
+///   device is `hislip<n>[,<port>]` and VXI-11 otherwise
+/// * `TCPIP[board]::<host>::<port>::SOCKET`
+/// * `USB[board]::<vid>::<pid>[::<serial>][::INSTR]`
+/// * `ASRL<n>[::INSTR]` or `ASRL<path>[::INSTR]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceAddress {
+    pub transport: ResourceTransport,
+    pub options: ResourceOptions,
+}
+impl ResourceAddress {
+    /// Resolve the host of a network resource to a socket address, using the
+    /// default port for the transport if none was given
+    pub fn resolve_socket(&self) -> Result<SocketAddr> {
+        let (host, port) = match &self.transport {
+            ResourceTransport::Tcp { host, port } => (host, *port),
+            ResourceTransport::Vxi11 { host, port, .. } => (host, port.unwrap_or(PORTMAP_PORT)),
+            ResourceTransport::Hislip { host, port, .. } => (host, port.unwrap_or(HISLIP_PORT)),
+            ResourceTransport::Prologix { host, port, .. } => (host, port.unwrap_or(PROLOGIX_PORT)),
+            ResourceTransport::Serial { .. }
+            | ResourceTransport::SerialUsb { .. }
+            | ResourceTransport::Usbtmc { .. }
+            | ResourceTransport::PrologixSerial { .. }
+            | ResourceTransport::Mock { .. }
+            | ResourceTransport::Sim { .. } => {
+                return Err(Error::InvalidArgument(
+                    "Resource has no socket address".into(),
+                ));
+            }
+        };
+
+        /* Zones are handled by the system resolver */
+        let Some(socket) = (host.as_str(), port).to_socket_addrs()?.next() else {
+            return Err(Error::Unspecified(format!("Could not resolve '{host}'")));
+        };
+
+        Ok(socket)
+    }
+
+    fn parse_uri(uri: &str) -> Result<ResourceTransport> {
+        if let Some(addr) = uri.strip_prefix("tcp://") {
+            let (host, port) = split_host_port(addr)?;
+            let Some(port) = port else {
+                return Err(Error::InvalidArgument(format!("No port given in '{addr}'")));
+            };
+            Ok(ResourceTransport::Tcp { host, port })
+        } else if let Some(addr) = uri.strip_prefix("vxi11://") {
+            let (addr, device) = split_path(addr, VXI11_DEFAULT_DEVICE);
+            let (host, port) = split_host_port(addr)?;
+            Ok(ResourceTransport::Vxi11 { host, port, device })
+        } else if let Some(addr) = uri.strip_prefix("hislip://") {
+            let (addr, sub_address) = split_path(addr, HISLIP_DEFAULT_SUB_ADDRESS);
+            let (host, port) = split_host_port(addr)?;
+            Ok(ResourceTransport::Hislip {
+                host,
+                port,
+                sub_address,
+            })
+        } else if let Some(port) = uri.strip_prefix("serial:") {
+            Ok(ResourceTransport::Serial { port: port.into() })
+        } else if let Some(usb) = uri.strip_prefix("serial-usb:") {
+            Ok(ResourceTransport::SerialUsb { usb: usb.parse()? })
+        } else if let Some(addr) = uri.strip_prefix("usbtmc://") {
+            let invalid = || Error::InvalidArgument(format!("Invalid USBTMC device '{addr}'"));
+            let mut parts = addr.splitn(3, ':');
+            let (Some(vid), Some(pid)) = (parts.next(), parts.next()) else {
+                return Err(invalid());
+            };
+            Ok(ResourceTransport::Usbtmc {
+                vid: parse_usb_id(vid, 16).ok_or_else(invalid)?,
+                pid: parse_usb_id(pid, 16).ok_or_else(invalid)?,
+                serial: parts.next().map(String::from),
+            })
+        } else if let Some(addr) = uri.strip_prefix("prologix://") {
+            let Some((addr, gpib_addr)) = addr.split_once('/') else {
+                return Err(Error::InvalidArgument(format!(
+                    "No GPIB address given in '{uri}'"
+                )));
+            };
+            let (host, port) = split_host_port(addr)?;
+            Ok(ResourceTransport::Prologix {
+                host,
+                port,
+                gpib_addr: parse_gpib_addr(gpib_addr)?,
+            })
+        } else if let Some(path) = uri.strip_prefix("mock://") {
+            Ok(ResourceTransport::Mock { path: path.into() })
+        } else if let Some(path) = uri.strip_prefix("sim://") {
+            let mut parts = path.splitn(3, '/').filter(|part| !part.is_empty());
+            let class = parts.next().unwrap_or_default();
+            let class = class.parse().map_err(|_| {
+                Error::InvalidArgument(format!("Unknown simulated instrument class '{class}'"))
+            })?;
+            Ok(ResourceTransport::Sim {
+                class,
+                model: parts.next().map(String::from),
+                name: parts.next().map(String::from),
+            })
+        } else if let Some(addr) = uri.strip_prefix("prologix-serial:") {
+            let Some((port, gpib_addr)) = addr.rsplit_once('/') else {
+                return Err(Error::InvalidArgument(format!(
+                    "No GPIB address given in '{uri}'"
+                )));
+            };
+            Ok(ResourceTransport::PrologixSerial {
+                port: port.into(),
+                gpib_addr: parse_gpib_addr(gpib_addr)?,
+            })
+        } else {
+            Err(Error::InvalidArgument(format!("Unknown scheme in '{uri}'")))
+        }
+    }
+
+    fn parse_visa(visa: &str) -> Result<ResourceTransport> {
+        let invalid = || Error::InvalidArgument(format!("Invalid VISA resource '{visa}'"));
+
+        let mut parts = split_visa(visa);
+        /* Resource class is optional for INSTR resources */
+        let class = match parts.last() {
+            Some(class) if class.eq_ignore_ascii_case("INSTR") => {
+                parts.pop();
+                "INSTR"
+            }
+            Some(class) if class.eq_ignore_ascii_case("SOCKET") => {
+                parts.pop();
+                "SOCKET"
+            }
+            _ => "INSTR",
+        };
+
+        let Some(&interface) = parts.first() else {
+            return Err(invalid());
+        };
+        if let Some(board) = strip_prefix_ignore_case(interface, "TCPIP") {
+            if !board.chars().all(|c| c.is_ascii_digit()) {
+                return Err(invalid());
+            }
+
+            match (class, &parts[1..]) {
+                ("SOCKET", [host, port]) => Ok(ResourceTransport::Tcp {
+                    host: unbracket(host).into(),
+                    port: port.parse().map_err(|_| invalid())?,
+                }),
+                ("INSTR", [host]) => Ok(ResourceTransport::Vxi11 {
+                    host: unbracket(host).into(),
+                    port: None,
+                    device: VXI11_DEFAULT_DEVICE.into(),
+                }),
+                ("INSTR", [host, device]) => {
+                    if device.to_ascii_lowercase().starts_with("hislip") {
+                        /* Port may follow the sub-address, e.g. hislip0,4880 */
+                        let (sub_address, port) = match device.split_once(',') {
+                            Some((sub_address, port)) => {
+                                (sub_address, Some(port.parse().map_err(|_| invalid())?))
+                            }
+                            None => (*device, None),
+                        };
+                        Ok(ResourceTransport::Hislip {
+                            host: unbracket(host).into(),
+                            port,
+                            sub_address: sub_address.into(),
+                        })
+                    } else {
+                        Ok(ResourceTransport::Vxi11 {
+                            host: unbracket(host).into(),
+                            port: None,
+                            device: device.to_string(),
+                        })
+                    }
+                }
+                _ => Err(invalid()),
+            }
+        } else if let Some(board) = strip_prefix_ignore_case(interface, "USB") {
+            if !board.chars().all(|c| c.is_ascii_digit()) || class != "INSTR" {
+                return Err(invalid());
+            }
+
+            /* Interface number may follow the serial number, the USBTMC
+             * interface being found regardless */
+            match &parts[1..] {
+                [vid, pid, rest @ ..] if rest.len() <= 2 => Ok(ResourceTransport::Usbtmc {
+                    vid: parse_usb_id(vid, 10).ok_or_else(invalid)?,
+                    pid: parse_usb_id(pid, 10).ok_or_else(invalid)?,
+                    serial: rest.first().map(|serial| serial.to_string()),
+                }),
+                _ => Err(invalid()),
+            }
+        } else if let Some(port) = strip_prefix_ignore_case(interface, "ASRL") {
+            if class != "INSTR" || parts.len() != 1 || port.is_empty() {
+                return Err(invalid());
+            }
+
+            /* Numbered ports follow the VISA convention of ASRL1 being the
+             * first port */
+            let port = match port.parse::<u32>() {
+                Ok(0) => return Err(invalid()),
+                Ok(n) if cfg!(windows) => format!("COM{n}"),
+                Ok(n) => format!("/dev/ttyS{}", n - 1),
+                Err(_) => port.into(),
+            };
+            Ok(ResourceTransport::Serial { port })
+        } else {
+            Err(Error::NotSupported(format!(
+                "Unsupported VISA interface in '{visa}'"
+            )))
+        }
+    }
+}
+impl FromStr for ResourceAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, options) = match s.split_once('?') {
+            Some((addr, args)) => (addr, ResourceOptions::parse(args)?),
+            None => (s, ResourceOptions::default()),
+        };
+
+        let transport = if addr.contains("://")
+            || addr.starts_with("serial:")
+            || addr.starts_with("serial-usb:")
+            || addr.starts_with("prologix-serial:")
+        {
+            Self::parse_uri(addr)?
+        } else {
+            Self::parse_visa(addr)?
+        };
+
+        Ok(Self { transport, options })
+    }
+}
+impl Display for ResourceAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.transport {
+            ResourceTransport::Tcp { host, port } => {
+                write!(f, "tcp://{}:{port}", bracket(host))?;
+            }
+            ResourceTransport::Vxi11 { host, port, device } => {
+                write!(f, "vxi11://{}", bracket(host))?;
+                if let Some(port) = port {
+                    write!(f, ":{port}")?;
+                }
+                if device != VXI11_DEFAULT_DEVICE {
+                    write!(f, "/{device}")?;
+                }
+            }
+            ResourceTransport::Hislip {
+                host,
+                port,
+                sub_address,
+            } => {
+                write!(f, "hislip://{}", bracket(host))?;
+                if let Some(port) = port {
+                    write!(f, ":{port}")?;
+                }
+                if sub_address != HISLIP_DEFAULT_SUB_ADDRESS {
+                    write!(f, "/{sub_address}")?;
+                }
+            }
+            ResourceTransport::Serial { port } => write!(f, "serial:{port}")?,
+            ResourceTransport::SerialUsb { usb } => write!(f, "serial-usb:{usb}")?,
+            ResourceTransport::Usbtmc { vid, pid, serial } => {
+                write!(f, "usbtmc://{vid:04x}:{pid:04x}")?;
+                if let Some(serial) = serial {
+                    write!(f, ":{serial}")?;
+                }
+            }
+            ResourceTransport::Prologix {
+                host,
+                port,
+                gpib_addr,
+            } => {
+                write!(f, "prologix://{}", bracket(host))?;
+                if let Some(port) = port {
+                    write!(f, ":{port}")?;
+                }
+                write!(f, "/{gpib_addr}")?;
+            }
+            ResourceTransport::PrologixSerial { port, gpib_addr } => {
+                write!(f, "prologix-serial:{port}/{gpib_addr}")?
+            }
+            ResourceTransport::Mock { path } => write!(f, "mock://{path}")?,
+            ResourceTransport::Sim { class, model, name } => {
+                write!(f, "sim://{}", class.as_ref())?;
+                match (model, name) {
+                    (Some(model), Some(name)) => write!(f, "/{model}/{name}")?,
+                    (Some(model), None) => write!(f, "/{model}")?,
+                    (None, Some(name)) => write!(f, "/{}/{name}", class.default_model())?,
+                    (None, None) => {}
+                }
+            }
+        }
+
+        write!(f, "{}", self.options)
+    }
+}
+
+/// Parse a duration with an optional unit suffix (ms, s, m), defaulting to
+/// milliseconds if none is given
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| Error::InvalidArgument(format!("Invalid duration '{value}'")))?;
+    let secs = match unit {
+        "" | "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid unit '{unit}' in duration '{value}'"
+            )));
+        }
+    };
+
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| Error::InvalidArgument(format!("Duration '{value}' out of range")))
+}
+
+/// Format a duration in milliseconds, as parsed by parse_duration(), with a
+/// fractional part only if needed
+fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_millis();
+    match duration.subsec_nanos() % 1_000_000 {
+        0 => format!("{ms}ms"),
+        nanos => format!("{ms}.{}ms", format!("{nanos:06}").trim_end_matches('0')),
+    }
+}
+
+fn parse_gpib_addr(value: &str) -> Result<u8> {
+    match value.parse() {
+        Ok(addr) if addr <= GPIB_MAX_ADDR => Ok(addr),
+        _ => Err(Error::InvalidArgument(format!(
+            "Invalid GPIB address '{value}'"
+        ))),
+    }
+}
+
+/// Parse a USB vendor or product ID, in the given radix unless prefixed with
+/// `0x`
+fn parse_usb_id(value: &str, radix: u32) -> Option<u16> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => u16::from_str_radix(value, radix).ok(),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Split a host with optional port. IPv6 addresses with a port must be
+/// bracketed, e.g. `[fe80::1%eth0]:5025`, and may include a zone (interface
+/// name or index) for link-local addresses.
+fn split_host_port(addr: &str) -> Result<(String, Option<u16>)> {
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let Some((host, rest)) = rest.split_once(']') else {
+            return Err(Error::InvalidArgument(format!(
+                "Unterminated '[' in address '{addr}'"
+            )));
+        };
+        match rest {
+            "" => (host, None),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Unexpected '{rest}' after address '{host}'"
+                    )));
+                }
+            },
+        }
+    } else if addr.matches(':').count() > 1 {
+        /* Bare IPv6 address, cannot include a port */
+        (addr, None)
+    } else {
+        match addr.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(Error::InvalidArgument(format!("No host given in '{addr}'")));
+    }
+
+    let port = match port {
+        Some(port) => Some(
+            port.parse()
+                .map_err(|_| Error::InvalidArgument(format!("Invalid port '{port}'")))?,
+        ),
+        None => None,
+    };
+
+    Ok((host.into(), port))
+}
+
+/// Split an optional path following the host, e.g. a VXI-11 device name
+fn split_path<'a>(addr: &'a str, default: &str) -> (&'a str, String) {
+    /* Bracketed IPv6 addresses cannot contain '/' */
+    match addr.split_once('/') {
+        Some((addr, path)) if !path.is_empty() => (addr, path.into()),
+        Some((addr, _)) => (addr, default.into()),
+        None => (addr, default.into()),
+    }
+}
+
+/// Split a VISA resource string on `::`, except within a bracketed IPv6
+/// address
+fn split_visa(visa: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut bracketed = false;
+
+    let bytes = visa.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'[' => bracketed = true,
+            b']' => bracketed = false,
+            b':' if !bracketed && bytes.get(i + 1) == Some(&b':') => {
+                parts.push(&visa[start..i]);
+                start = i + 2;
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.push(&visa[start..]);
+
+    parts
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    match s.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Bracket IPv6 addresses so a port may follow
+fn bracket(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.into()
+    }
+}
+
+fn unbracket(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn rejects_visa_without_interface() {
+        for visa in ["INSTR", "SOCKET", "instr"] {
+            assert!(
+                matches!(
+                    visa.parse::<ResourceAddress>(),
+                    Err(Error::InvalidArgument(_))
+                ),
+                "{visa:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_timeout() {
+        assert!(matches!(
+            "tcp://10.0.0.5:5025?timeout=1000000000000000000000s".parse::<ResourceAddress>(),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let addr: ResourceAddress = "tcp://10.0.0.5:5025?timeout=1.5s".parse().unwrap();
+        assert_eq!(addr.options.timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(addr.to_string(), "tcp://10.0.0.5:5025?timeout=1500ms");
+
+        /* Fractions of a millisecond are kept */
+        for (uri, displayed) in [
+            (
+                "tcp://10.0.0.5:5025?timeout=0.5",
+                "tcp://10.0.0.5:5025?timeout=0.5ms",
+            ),
+            (
+                "tcp://10.0.0.5:5025?timeout=1.0005s",
+                "tcp://10.0.0.5:5025?timeout=1000.5ms",
+            ),
+            (
+                "serial:COM3?command_delay=2.5ms",
+                "serial:COM3?command_delay=2.5ms",
+            ),
+            (
+                "serial:COM3?command_delay=0.000001s",
+                "serial:COM3?command_delay=0.001ms",
+            ),
+        ] {
+            let addr: ResourceAddress = uri.parse().unwrap();
+            assert_eq!(addr.to_string(), displayed);
+            assert_eq!(displayed.parse::<ResourceAddress>().unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        for (addr, host, port) in [
+            ("10.0.0.5", "10.0.0.5", None),
+            ("10.0.0.5:5025", "10.0.0.5", Some(5025)),
+            ("scope.lab", "scope.lab", None),
+            ("[::1]", "::1", None),
+            ("[::1]:5025", "::1", Some(5025)),
+            ("fe80::1", "fe80::1", None),
+            ("fe80::1%eth0", "fe80::1%eth0", None),
+            ("[fe80::1%eth0]:4880", "fe80::1%eth0", Some(4880)),
+        ] {
+            assert_eq!(
+                split_host_port(addr).unwrap(),
+                (host.to_string(), port),
+                "{addr:?}"
+            );
+        }
+
+        for addr in ["", ":5025", "[::1", "[::1]5025", "[]:5025", "10.0.0.5:port"] {
+            assert!(
+                matches!(split_host_port(addr), Err(Error::InvalidArgument(_))),
+                "{addr:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_ipv6_sockets() {
+        for (uri, port) in [
+            ("tcp://[::1]:5025", 5025),
+            ("vxi11://[::1]", PORTMAP_PORT),
+            ("vxi11://[::1]:1111/gpib0,5", 1111),
+            ("hislip://[::1]", HISLIP_PORT),
+            ("prologix://[::1]/5", PROLOGIX_PORT),
+        ] {
+            let addr: ResourceAddress = uri.parse().unwrap();
+            assert_eq!(
+                addr.resolve_socket().unwrap(),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+                "{uri:?}"
+            );
+        }
+
+        /* Zone is resolved to the interface index */
+        let addr: ResourceAddress = "tcp://[fe80::1%lo]:5025".parse().unwrap();
+        let SocketAddr::V6(socket) = addr.resolve_socket().unwrap() else {
+            panic!("Expected an IPv6 socket address");
+        };
+        assert_eq!(*socket.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(socket.port(), 5025);
+        assert_ne!(socket.scope_id(), 0);
+
+        let addr: ResourceAddress = "serial:/dev/ttyUSB0".parse().unwrap();
+        assert!(matches!(
+            addr.resolve_socket(),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    fn tcp(host: &str, port: u16) -> ResourceTransport {
+        ResourceTransport::Tcp {
+            host: host.into(),
+            port,
+        }
+    }
+
+    fn vxi11(host: &str, port: Option<u16>, device: &str) -> ResourceTransport {
+        ResourceTransport::Vxi11 {
+            host: host.into(),
+            port,
+            device: device.into(),
+        }
+    }
+
+    fn hislip(host: &str, port: Option<u16>, sub_address: &str) -> ResourceTransport {
+        ResourceTransport::Hislip {
+            host: host.into(),
+            port,
+            sub_address: sub_address.into(),
+        }
+    }
+
+    fn usbtmc(vid: u16, pid: u16, serial: Option<&str>) -> ResourceTransport {
+        ResourceTransport::Usbtmc {
+            vid,
+            pid,
+            serial: serial.map(String::from),
+        }
+    }
+
+    fn sim(class: SimClass, model: Option<&str>, name: Option<&str>) -> ResourceTransport {
+        ResourceTransport::Sim {
+            class,
+            model: model.map(String::from),
+            name: name.map(String::from),
+        }
+    }
+
+    /// Check an address parses to the transport given, and displays as the
+    /// URI given, which parses back to the same address
+    fn check(addr: &str, transport: ResourceTransport, uri: &str) {
+        let parsed: ResourceAddress = addr.parse().unwrap();
+        assert_eq!(parsed.transport, transport, "{addr:?}");
+        assert_eq!(parsed.options, ResourceOptions::default(), "{addr:?}");
+        assert_eq!(parsed.to_string(), uri, "{addr:?}");
+        assert_eq!(uri.parse::<ResourceAddress>().unwrap(), parsed, "{uri:?}");
+    }
+
+    #[test]
+    fn parses_uris() {
+        for (uri, transport) in [
+            ("tcp://10.0.0.5:5025", tcp("10.0.0.5", 5025)),
+            ("tcp://scope.lab:5025", tcp("scope.lab", 5025)),
+            ("tcp://[fe80::1%eth0]:5025", tcp("fe80::1%eth0", 5025)),
+            ("vxi11://10.0.0.5", vxi11("10.0.0.5", None, "inst0")),
+            (
+                "vxi11://10.0.0.5:1111/gpib0,5",
+                vxi11("10.0.0.5", Some(1111), "gpib0,5"),
+            ),
+            ("vxi11://[::1]/inst1", vxi11("::1", None, "inst1")),
+            ("hislip://10.0.0.5", hislip("10.0.0.5", None, "hislip0")),
+            (
+                "hislip://[::1]:4881/hislip1",
+                hislip("::1", Some(4881), "hislip1"),
+            ),
+            (
+                "serial:/dev/ttyUSB0",
+                ResourceTransport::Serial {
+                    port: "/dev/ttyUSB0".into(),
+                },
+            ),
+            (
+                "serial:COM3",
+                ResourceTransport::Serial {
+                    port: "COM3".into(),
+                },
+            ),
+            (
+                "serial-usb:vid=1ab1,pid=0e11",
+                ResourceTransport::SerialUsb {
+                    usb: UsbSerialMatch {
+                        vid: 0x1ab1,
+                        pid: 0x0e11,
+                        serial: None,
+                    },
+                },
+            ),
+            (
+                "serial-usb:vid=1ab1,pid=0e11,serial=DP8C1234",
+                ResourceTransport::SerialUsb {
+                    usb: UsbSerialMatch {
+                        vid: 0x1ab1,
+                        pid: 0x0e11,
+                        serial: Some("DP8C1234".into()),
+                    },
+                },
+            ),
+            ("usbtmc://1ab1:0588", usbtmc(0x1ab1, 0x0588, None)),
+            (
+                "usbtmc://1ab1:0588:DS1ZA1234",
+                usbtmc(0x1ab1, 0x0588, Some("DS1ZA1234")),
+            ),
+            (
+                "prologix://10.0.0.9/5",
+                ResourceTransport::Prologix {
+                    host: "10.0.0.9".into(),
+                    port: None,
+                    gpib_addr: 5,
+                },
+            ),
+            (
+                "prologix://[::1]:1234/30",
+                ResourceTransport::Prologix {
+                    host: "::1".into(),
+                    port: Some(1234),
+                    gpib_addr: 30,
+                },
+            ),
+            (
+                "prologix-serial:/dev/ttyACM0/12",
+                ResourceTransport::PrologixSerial {
+                    port: "/dev/ttyACM0".into(),
+                    gpib_addr: 12,
+                },
+            ),
+            (
+                "mock://tests/data/dp832.txt",
+                ResourceTransport::Mock {
+                    path: "tests/data/dp832.txt".into(),
+                },
+            ),
+            ("sim://psu", sim(SimClass::Psu, None, None)),
+            (
+                "sim://dmm/SDM4065A",
+                sim(SimClass::Dmm, Some("SDM4065A"), None),
+            ),
+            (
+                "sim://sa/SSA3075X Plus/sa1",
+                sim(SimClass::Sa, Some("SSA3075X Plus"), Some("sa1")),
+            ),
+        ] {
+            check(uri, transport, uri);
+        }
+
+        /* Defaults and alternative spellings are displayed canonically */
+        for (uri, transport, canonical) in [
+            (
+                "vxi11://10.0.0.5/inst0",
+                vxi11("10.0.0.5", None, "inst0"),
+                "vxi11://10.0.0.5",
+            ),
+            (
+                "hislip://10.0.0.5/",
+                hislip("10.0.0.5", None, "hislip0"),
+                "hislip://10.0.0.5",
+            ),
+            (
+                "usbtmc://0x1AB1:588",
+                usbtmc(0x1ab1, 0x0588, None),
+                "usbtmc://1ab1:0588",
+            ),
+            (
+                "serial-usb:vid=0x1AB1,pid=0x0E11",
+                ResourceTransport::SerialUsb {
+                    usb: UsbSerialMatch {
+                        vid: 0x1ab1,
+                        pid: 0x0e11,
+                        serial: None,
+                    },
+                },
+                "serial-usb:vid=1ab1,pid=0e11",
+            ),
+            ("sim://PSU", sim(SimClass::Psu, None, None), "sim://psu"),
+        ] {
+            check(uri, transport, canonical);
+        }
+    }
+
+    #[test]
+    fn parses_visa_resources() {
+        let (asrl3, asrl3_uri) = if cfg!(windows) {
+            ("COM3", "serial:COM3")
+        } else {
+            ("/dev/ttyS2", "serial:/dev/ttyS2")
+        };
+
+        for (visa, transport, uri) in [
+            (
+                "TCPIP0::10.0.0.5::INSTR",
+                vxi11("10.0.0.5", None, "inst0"),
+                "vxi11://10.0.0.5",
+            ),
+            (
+                "TCPIP::10.0.0.5",
+                vxi11("10.0.0.5", None, "inst0"),
+                "vxi11://10.0.0.5",
+            ),
+            (
+                "TCPIP0::10.0.0.5::gpib0,5::INSTR",
+                vxi11("10.0.0.5", None, "gpib0,5"),
+                "vxi11://10.0.0.5/gpib0,5",
+            ),
+            (
+                "TCPIP0::[fe80::1%eth0]::inst1::INSTR",
+                vxi11("fe80::1%eth0", None, "inst1"),
+                "vxi11://[fe80::1%eth0]/inst1",
+            ),
+            (
+                "TCPIP0::10.0.0.5::hislip0::INSTR",
+                hislip("10.0.0.5", None, "hislip0"),
+                "hislip://10.0.0.5",
+            ),
+            (
+                "TCPIP1::10.0.0.5::hislip1,4881::INSTR",
+                hislip("10.0.0.5", Some(4881), "hislip1"),
+                "hislip://10.0.0.5:4881/hislip1",
+            ),
+            (
+                "TCPIP0::10.0.0.5::5025::SOCKET",
+                tcp("10.0.0.5", 5025),
+                "tcp://10.0.0.5:5025",
+            ),
+            (
+                "tcpip0::[::1]::5025::socket",
+                tcp("::1", 5025),
+                "tcp://[::1]:5025",
+            ),
+            (
+                "USB0::6833::1416::INSTR",
+                usbtmc(0x1ab1, 0x0588, None),
+                "usbtmc://1ab1:0588",
+            ),
+            (
+                "USB::0x1AB1::0x0588::DS1ZA1234::0::INSTR",
+                usbtmc(0x1ab1, 0x0588, Some("DS1ZA1234")),
+                "usbtmc://1ab1:0588:DS1ZA1234",
+            ),
+            (
+                "ASRL3::INSTR",
+                ResourceTransport::Serial { port: asrl3.into() },
+                asrl3_uri,
+            ),
+            (
+                "ASRL/dev/ttyUSB0::INSTR",
+                ResourceTransport::Serial {
+                    port: "/dev/ttyUSB0".into(),
+                },
+                "serial:/dev/ttyUSB0",
+            ),
+        ] {
+            check(visa, transport, uri);
+        }
+
+        /* No GPIB interface of our own, only through a Prologix adapter */
+        assert!(matches!(
+            "GPIB0::5::INSTR".parse::<ResourceAddress>(),
+            Err(Error::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn parses_options() {
+        let addr: ResourceAddress =
+            "tcp://10.0.0.5:5025?timeout=2s&record=session.log&reconnect=3&error_check=always&termination=crlf"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            addr.options,
+            ResourceOptions {
+                timeout: Some(Duration::from_secs(2)),
+                error_check: Some(ErrorCheck::EveryCommand),
+                termination: Some(Terminator::CrLf),
+                record: Some("session.log".into()),
+                reconnect: Some(3),
+                ..Default::default()
+            }
+        );
+        /* Options are displayed in a fixed order */
+        let uri = "tcp://10.0.0.5:5025?timeout=2000ms&error_check=always&termination=crlf&record=session.log&reconnect=3";
+        assert_eq!(addr.to_string(), uri);
+        assert_eq!(uri.parse::<ResourceAddress>().unwrap(), addr);
+
+        for (arg, options) in [
+            (
+                "timeout=250",
+                ResourceOptions {
+                    timeout: Some(Duration::from_millis(250)),
+                    ..Default::default()
+                },
+            ),
+            (
+                "timeout=1m",
+                ResourceOptions {
+                    timeout: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                },
+            ),
+            (
+                "error_check=never",
+                ResourceOptions {
+                    error_check: Some(ErrorCheck::Never),
+                    ..Default::default()
+                },
+            ),
+            (
+                "error_check=10",
+                ResourceOptions {
+                    error_check: Some(ErrorCheck::Interval(10)),
+                    ..Default::default()
+                },
+            ),
+            (
+                "termination=lf",
+                ResourceOptions {
+                    termination: Some(Terminator::Lf),
+                    ..Default::default()
+                },
+            ),
+            (
+                "termination=CR",
+                ResourceOptions {
+                    termination: Some(Terminator::Cr),
+                    ..Default::default()
+                },
+            ),
+            (
+                "termination=eoi",
+                ResourceOptions {
+                    termination: Some(Terminator::Eoi),
+                    ..Default::default()
+                },
+            ),
+            (
+                "reconnect=0",
+                ResourceOptions {
+                    reconnect: Some(0),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let addr: ResourceAddress = format!("TCPIP0::10.0.0.5::INSTR?{arg}").parse().unwrap();
+            assert_eq!(addr.transport, vxi11("10.0.0.5", None, "inst0"), "{arg:?}");
+            assert_eq!(addr.options, options, "{arg:?}");
+            assert_eq!(
+                addr.to_string().parse::<ResourceAddress>().unwrap(),
+                addr,
+                "{arg:?}"
+            );
+        }
+
+        for arg in [
+            "timeout=soon",
+            "error_check=sometimes",
+            "error_check=0",
+            "termination=nul",
+            "reconnect=-1",
+            "record",
+            "colour=blue",
+        ] {
+            assert!(
+                matches!(
+                    format!("tcp://10.0.0.5:5025?{arg}").parse::<ResourceAddress>(),
+                    Err(Error::InvalidArgument(_))
+                ),
+                "{arg:?}"
+            );
+        }
+    }
+}
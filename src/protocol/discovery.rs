@@ -0,0 +1,220 @@
+//! Discovery of instruments on the local network, by broadcasting VXI-11
+//! portmap queries and browsing mDNS/DNS-SD services
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use log::{debug, warn};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{
+    PORTMAP_PORT, ResourceAddress, ResourceOptions, ResourceTransport,
+    mdns::{self, MdnsService},
+    scpi_from_address,
+    vxi11::{
+        VXI_CORE_PROG, VXI_CORE_VERS, VXI11_DEFAULT_DEVICE,
+        portmap::{self, RpcIpProto},
+    },
+};
+
+/// DNS-SD service types of instruments
+const SERVICE_LXI: &str = "_lxi._tcp.local";
+const SERVICE_VXI11: &str = "_vxi-11._tcp.local";
+const SERVICE_SCPI_RAW: &str = "_scpi-raw._tcp.local";
+
+/// Where to look for instruments, and how long to wait for them
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// Time to wait for responses to the portmap and mDNS queries
+    pub timeout: Duration,
+    /// Time to wait for each responder to connect and answer *IDN?
+    pub identify_timeout: Duration,
+    /// Portmappers to query for the VXI-11 core program, broadcast addresses
+    /// included
+    pub portmap_targets: Vec<SocketAddr>,
+    /// Addresses to send mDNS queries to
+    pub mdns_targets: Vec<SocketAddr>,
+}
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            identify_timeout: Duration::from_secs(3),
+            portmap_targets: vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::BROADCAST),
+                PORTMAP_PORT,
+            )],
+            mdns_targets: vec![mdns::MDNS_ADDR],
+        }
+    }
+}
+
+/// Instrument found on the network
+#[derive(Debug, Clone)]
+pub struct DiscoveredInstrument {
+    /// Identity of the instrument, from its *IDN? response
+    pub model: ModelInfo,
+    /// Address the instrument answered at, see [`Self::uri`]
+    pub address: ResourceAddress,
+}
+impl DiscoveredInstrument {
+    /// URI to connect to the instrument with, e.g. using [`super::scpi_from_uri`]
+    pub fn uri(&self) -> String {
+        self.address.to_string()
+    }
+}
+
+/// Find instruments on the local network with the default options
+pub async fn discover() -> Result<Vec<DiscoveredInstrument>> {
+    discover_with(&DiscoveryOptions::default()).await
+}
+
+/// Find instruments answering VXI-11 portmap queries or advertising LXI,
+/// VXI-11 or raw SCPI services over mDNS, identifying each with *IDN?.
+///
+/// An instrument reachable in several ways is only reported once, VXI-11
+/// being preferred over raw TCP.
+pub async fn discover_with(options: &DiscoveryOptions) -> Result<Vec<DiscoveredInstrument>> {
+    let (portmappers, services) = tokio::join!(
+        portmap::broadcast_request_port(
+            &options.portmap_targets,
+            VXI_CORE_PROG,
+            VXI_CORE_VERS,
+            RpcIpProto::Tcp,
+            options.timeout,
+        ),
+        mdns::browse(
+            &options.mdns_targets,
+            &[SERVICE_LXI, SERVICE_VXI11, SERVICE_SCPI_RAW],
+            options.timeout,
+        ),
+    );
+
+    let mut candidates = vec![];
+    match (&portmappers, &services) {
+        (Err(portmap_err), Err(mdns_err)) => {
+            return Err(Error::Unspecified(format!(
+                "Discovery failed, portmap: {portmap_err}, mDNS: {mdns_err}"
+            )));
+        }
+        (Err(e), _) => warn!("Portmap discovery failed: {e}"),
+        (_, Err(e)) => warn!("mDNS discovery failed: {e}"),
+        _ => {}
+    }
+    for (portmapper, _) in portmappers.unwrap_or_default() {
+        debug!("VXI-11 portmapper at {portmapper}");
+        candidates.push(vxi11_address(portmapper.ip(), portmapper.port()));
+    }
+    /* Raw TCP is only tried once all VXI-11 candidates have been */
+    let mut raw = vec![];
+    for service in services.unwrap_or_default() {
+        debug!("{} at {}:{}", service.instance, service.addr, service.port);
+        match service_address(&service) {
+            Some(addr) if matches!(addr.transport, ResourceTransport::Tcp { .. }) => raw.push(addr),
+            Some(addr) => candidates.push(addr),
+            None => {}
+        }
+    }
+    candidates.append(&mut raw);
+    /* Keeps the first of any duplicates */
+    let mut unique: Vec<ResourceAddress> = vec![];
+    for addr in candidates {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+
+    let models = join_all(
+        unique
+            .iter()
+            .map(|addr| identify(addr, options.identify_timeout)),
+    )
+    .await;
+
+    let mut found: Vec<DiscoveredInstrument> = vec![];
+    for (address, model) in unique.into_iter().zip(models) {
+        let model = match model {
+            Ok(model) => model,
+            Err(e) => {
+                debug!("Failed to identify {address}: {e}");
+                continue;
+            }
+        };
+
+        let host = address_host(&address);
+        if found
+            .iter()
+            .any(|f| address_host(&f.address) == host && f.model.same_device(&model))
+        {
+            continue;
+        }
+
+        found.push(DiscoveredInstrument { model, address });
+    }
+
+    Ok(found)
+}
+
+fn vxi11_address(ip: IpAddr, port: u16) -> ResourceAddress {
+    ResourceAddress {
+        transport: ResourceTransport::Vxi11 {
+            host: ip.to_string(),
+            port: (port != PORTMAP_PORT).then_some(port),
+            device: VXI11_DEFAULT_DEVICE.into(),
+        },
+        options: ResourceOptions::default(),
+    }
+}
+
+/// Address to reach an advertised service at, if it is one SCPI can be used
+/// over
+fn service_address(service: &MdnsService) -> Option<ResourceAddress> {
+    match service.service.as_str() {
+        /* The port of the LXI service is that of its web interface, VXI-11
+         * being required on the standard portmapper port */
+        SERVICE_LXI => Some(vxi11_address(service.addr, PORTMAP_PORT)),
+        SERVICE_VXI11 => Some(vxi11_address(service.addr, service.port)),
+        SERVICE_SCPI_RAW => Some(ResourceAddress {
+            transport: ResourceTransport::Tcp {
+                host: service.addr.to_string(),
+                port: service.port,
+            },
+            options: ResourceOptions::default(),
+        }),
+        _ => None,
+    }
+}
+
+fn address_host(address: &ResourceAddress) -> Option<&str> {
+    match &address.transport {
+        ResourceTransport::Tcp { host, .. } | ResourceTransport::Vxi11 { host, .. } => Some(host),
+        _ => None,
+    }
+}
+
+async fn identify(address: &ResourceAddress, timeout: Duration) -> Result<ModelInfo> {
+    let mut address = address.clone();
+    address.options.timeout = Some(timeout);
+
+    /* Connecting has timeouts of its own, which may be longer */
+    let connect = tokio::time::timeout(timeout, scpi_from_address(&address)).await;
+    let Ok(client) = connect else {
+        return Err(Error::Timeout(format!(
+            "Timed out connecting for {} ms",
+            timeout.as_millis()
+        )));
+    };
+    let mut client = client?;
+
+    let model = client.idn_model().await;
+    let _ = client.disconnect().await;
+
+    model
+}
@@ -0,0 +1,1194 @@
+//! High-Speed LAN Instrument Protocol (HiSLIP), referencing IVI-6.1 revision
+//! 2.0 specification
+
+use std::{net::SocketAddr, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings, Terminator};
+
+/// Default TCP port for HiSLIP servers
+pub const HISLIP_PORT: u16 = 4880;
+
+/// Protocol version we implement (major, minor)
+const PROTOCOL_VERSION: u16 = 0x0100;
+/// Arbitrary two-character vendor ID sent on initialization
+const CLIENT_VENDOR_ID: [u8; 2] = *b"TQ";
+/// Size of the header preceding every message
+const HEADER_SIZE: usize = 16;
+/// Initial message ID after initialization or device clear
+const INITIAL_MESSAGE_ID: u32 = 0xffff_ff00;
+/// Maximum message size we advertise to the server
+const MAX_MESSAGE_SIZE: u64 = 1 << 24;
+/// Space to make available in the receive buffer for each read from a channel
+const READ_CHUNK_SIZE: usize = 65536;
+/// AsyncRemoteLocalControl request placing the device in local mode
+const REMOTE_LOCAL_GO_TO_LOCAL: u8 = 6;
+
+pub struct ScpiHislipProtocol {
+    socket: SocketAddr,
+    /// Sub-address (e.g. hislip0) identifying the device on the server
+    sub_address: String,
+    /// Overlap mode to request from the server, if any
+    request_overlapped: Option<bool>,
+    sync_chan: Option<HislipChannel>,
+    async_chan: Option<HislipChannel>,
+    session_id: u16,
+    /// Whether the session is operating in overlapped mode
+    overlapped: bool,
+    /// Maximum message size accepted by the server
+    max_msg_size: u64,
+    /// ID to use for the next message sent on the synchronous channel
+    message_id: u32,
+    /// ID of the last message of the most recent command, which responses
+    /// to it carry
+    response_id: u32,
+    /// Whether the response to the last message was completely read
+    rmt_delivered: bool,
+    /// Data received but not yet consumed
+    rx_buf: Vec<u8>,
+    /// Whether rx_buf contains the end of a message
+    rx_end: bool,
+    settings: ScpiSettings,
+}
+impl ScpiHislipProtocol {
+    pub fn new(socket: SocketAddr, sub_address: &str) -> Self {
+        /* Messages are ended by the END indicator by default */
+        let mut settings = ScpiSettings::default();
+        settings.read_terminator = Terminator::Eoi;
+
+        Self {
+            socket,
+            sub_address: sub_address.to_string(),
+            request_overlapped: None,
+            sync_chan: None,
+            async_chan: None,
+            session_id: 0,
+            overlapped: false,
+            max_msg_size: MAX_MESSAGE_SIZE,
+            message_id: INITIAL_MESSAGE_ID,
+            response_id: INITIAL_MESSAGE_ID,
+            rmt_delivered: false,
+            rx_buf: vec![],
+            rx_end: false,
+            settings,
+        }
+    }
+
+    /// Request overlapped (true) or synchronized (false) mode. If the server
+    /// prefers a different mode, it is negotiated via a device clear after
+    /// connecting. Without a request the server's preference is used.
+    pub fn set_overlapped(&mut self, overlapped: bool) {
+        self.request_overlapped = Some(overlapped);
+    }
+
+    /// Whether the session is currently operating in overlapped mode
+    pub fn overlapped(&self) -> bool {
+        self.overlapped
+    }
+
+    /// Acquire a shared lock with the given name, waiting up to timeout for it
+    /// to become available. Clients holding a shared lock of the same name may
+    /// access the device concurrently.
+    pub async fn lock_shared(&mut self, timeout: Duration, name: &str) -> Result<()> {
+        self.request_lock(timeout, name).await
+    }
+
+    /// Request a lock, an empty name requests an exclusive lock
+    async fn request_lock(&mut self, timeout: Duration, name: &str) -> Result<()> {
+        let Some(async_chan) = &mut self.async_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!("request_lock({timeout:?}, {name:?})");
+
+        /* Saturated rather than truncated, u32::MAX ms being over 49 days */
+        let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        let mut req = HislipMessage::new(MessageType::AsyncLock, 1, timeout_ms);
+        req.payload = name.as_bytes().to_vec();
+        async_chan.send(req).await?;
+
+        /* Server will wait up to the lock timeout before responding */
+        let resp = async_chan
+            .recv_type(
+                MessageType::AsyncLockResponse,
+                timeout.saturating_add(self.settings.timeout),
+            )
+            .await?;
+
+        match resp.control {
+            1 => Ok(()),
+            0 => Err(Error::Timeout(format!(
+                "Timed out waiting for lock for {} ms",
+                timeout.as_millis()
+            ))),
+            _ => Err(Error::Unspecified("Device reported error on lock".into())),
+        }
+    }
+
+    /// ID of the most recently sent message
+    fn last_message_id(&self) -> u32 {
+        self.message_id.wrapping_sub(2)
+    }
+
+    /// Receive data on the synchronous channel until done returns true, or the
+    /// end of a message is reached
+    async fn fill_rx(&mut self, timeout: Duration, done: impl Fn(&[u8]) -> bool) -> Result<()> {
+        let Some(sync_chan) = &mut self.sync_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        let end = Instant::now() + timeout;
+        while !self.rx_end && !done(&self.rx_buf) {
+            let msg = match tokio::time::timeout_at(end, sync_chan.recv()).await {
+                Err(_) => {
+                    return Err(Error::Timeout(format!(
+                        "Timed out waiting for data for {} ms",
+                        timeout.as_millis()
+                    )));
+                }
+                Ok(msg) => msg?,
+            };
+
+            /* In synchronized mode only the response to the most recent
+             * command is wanted, anything else is left over from an earlier
+             * one. Overlapped mode may have several responses queued. */
+            if matches!(msg.msg_type, MessageType::Data | MessageType::DataEnd)
+                && !self.overlapped
+                && msg.param != self.response_id
+            {
+                debug!(
+                    "Discarding stale HiSLIP {:?} for message {:#010x}, expecting {:#010x}",
+                    msg.msg_type, msg.param, self.response_id
+                );
+                continue;
+            }
+
+            match msg.msg_type {
+                MessageType::Data => self.rx_buf.extend(msg.payload),
+                MessageType::DataEnd => {
+                    self.rx_buf.extend(msg.payload);
+                    self.rx_end = true;
+                }
+                MessageType::Interrupted => {
+                    /* Response to a previous query was interrupted by a
+                     * newer message, discard what we have of it */
+                    self.rx_buf.clear();
+                }
+                t => warn!("Ignoring unexpected HiSLIP message {t:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take count bytes from the receive buffer, marking the response as
+    /// delivered once the end of the message has been consumed
+    fn take_rx(&mut self, count: usize) -> Vec<u8> {
+        let count = count.min(self.rx_buf.len());
+        let data: Vec<u8> = self.rx_buf.drain(0..count).collect();
+
+        if self.rx_end && self.rx_buf.is_empty() {
+            self.rx_end = false;
+            self.rmt_delivered = true;
+        }
+
+        debug!(
+            "recv: {}",
+            String::from_utf8_lossy(&data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        data
+    }
+}
+#[async_trait]
+impl Protocol for ScpiHislipProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.sync_chan.is_some() {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        let mut sync_chan = HislipChannel::connect(self.socket).await?;
+
+        let mut req = HislipMessage::new(
+            MessageType::Initialize,
+            0,
+            ((PROTOCOL_VERSION as u32) << 16) | u16::from_be_bytes(CLIENT_VENDOR_ID) as u32,
+        );
+        req.payload = self.sub_address.as_bytes().to_vec();
+        sync_chan.send(req).await?;
+
+        let resp = sync_chan
+            .recv_type(MessageType::InitializeResponse, self.settings.timeout)
+            .await?;
+        self.overlapped = resp.control & 1 != 0;
+        self.session_id = (resp.param & 0xffff) as u16;
+
+        debug!(
+            "HiSLIP server version {:#06x}, session {}, overlapped: {}",
+            resp.param >> 16,
+            self.session_id,
+            self.overlapped
+        );
+
+        let mut async_chan = HislipChannel::connect(self.socket).await?;
+        async_chan
+            .send(HislipMessage::new(
+                MessageType::AsyncInitialize,
+                0,
+                self.session_id as u32,
+            ))
+            .await?;
+        async_chan
+            .recv_type(MessageType::AsyncInitializeResponse, self.settings.timeout)
+            .await?;
+
+        let mut req = HislipMessage::new(MessageType::AsyncMaximumMessageSize, 0, 0);
+        req.payload = MAX_MESSAGE_SIZE.to_be_bytes().to_vec();
+        async_chan.send(req).await?;
+        let resp = async_chan
+            .recv_type(
+                MessageType::AsyncMaximumMessageSizeResponse,
+                self.settings.timeout,
+            )
+            .await?;
+        let Ok(max_size) = resp.payload[..].try_into() else {
+            return Err(Error::BadResponse(format!(
+                "Invalid maximum message size payload length {}",
+                resp.payload.len()
+            )));
+        };
+        self.max_msg_size = u64::from_be_bytes(max_size);
+
+        self.sync_chan = Some(sync_chan);
+        self.async_chan = Some(async_chan);
+        self.message_id = INITIAL_MESSAGE_ID;
+        self.response_id = INITIAL_MESSAGE_ID;
+        self.rmt_delivered = false;
+        self.rx_buf.clear();
+        self.rx_end = false;
+
+        if self
+            .request_overlapped
+            .is_some_and(|overlapped| overlapped != self.overlapped)
+        {
+            /* Overlap mode can only be changed as part of a device clear */
+            Ieee488Control::device_clear(self).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if let Some(mut chan) = self.async_chan.take() {
+            chan.shutdown().await;
+        }
+        if let Some(mut chan) = self.sync_chan.take() {
+            chan.shutdown().await;
+        }
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ScpiHislipProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        let Some(sync_chan) = &mut self.sync_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!(
+            "int_send(): {}",
+            String::from_utf8_lossy(data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        let chunk_size = (self.max_msg_size as usize)
+            .saturating_sub(HEADER_SIZE)
+            .max(1);
+        let n_chunks = data.len().div_ceil(chunk_size).max(1);
+        let mut chunks = data.chunks(chunk_size);
+
+        for index in 0..n_chunks {
+            let msg_type = if index == (n_chunks - 1) {
+                MessageType::DataEnd
+            } else {
+                MessageType::Data
+            };
+
+            let mut msg = HislipMessage::new(msg_type, self.rmt_delivered as u8, self.message_id);
+            msg.payload = chunks.next().unwrap_or_default().to_vec();
+            sync_chan.send(msg).await?;
+
+            self.response_id = self.message_id;
+            self.rmt_delivered = false;
+            self.message_id = self.message_id.wrapping_add(2);
+        }
+
+        Ok(())
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        self.fill_rx(self.settings.timeout, |_| false).await?;
+        Ok(self.take_rx(self.rx_buf.len()))
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        debug!("recv_raw({length:?}, {timeout:?})");
+
+        let timeout = timeout.unwrap_or(self.settings.timeout);
+        match length {
+            Some(length) => {
+                self.fill_rx(timeout, |buf| buf.len() >= length).await?;
+                Ok(self.take_rx(length))
+            }
+            None => {
+                self.fill_rx(timeout, |_| false).await?;
+                Ok(self.take_rx(self.rx_buf.len()))
+            }
+        }
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        debug!("recv_until({byte}, {timeout:?})");
+
+        self.fill_rx(timeout, |buf| buf.contains(&byte)).await?;
+
+        match self.rx_buf.iter().position(|b| *b == byte) {
+            Some(pos) => Ok(self.take_rx(pos + 1)),
+            /* The whole message has been received, so waiting longer would
+             * not help */
+            None => Err(Error::BadResponse(format!(
+                "Message ended before receiving {byte}"
+            ))),
+        }
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        debug!("flush_rx({timeout:?})");
+
+        loop {
+            self.rx_buf.clear();
+            if self.rx_end {
+                self.rx_end = false;
+                self.rmt_delivered = true;
+            }
+
+            match self.fill_rx(timeout, |_| false).await {
+                Ok(()) => {}
+                Err(Error::Timeout(_)) => {
+                    self.rx_buf.clear();
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        true
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        Some(self)
+    }
+}
+#[async_trait]
+impl Ieee488Control for ScpiHislipProtocol {
+    /// The overlap mode requested with set_overlapped() is renegotiated as
+    /// part of the clear
+    async fn device_clear(&mut self) -> Result<()> {
+        let (Some(sync_chan), Some(async_chan)) = (&mut self.sync_chan, &mut self.async_chan)
+        else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!("device_clear()");
+
+        async_chan
+            .send(HislipMessage::new(MessageType::AsyncDeviceClear, 0, 0))
+            .await?;
+        let ack = async_chan
+            .recv_type(
+                MessageType::AsyncDeviceClearAcknowledge,
+                self.settings.timeout,
+            )
+            .await?;
+
+        let preference = ack.control & 1 != 0;
+        let request = self.request_overlapped.unwrap_or(preference);
+
+        sync_chan
+            .send(HislipMessage::new(
+                MessageType::DeviceClearComplete,
+                request as u8,
+                0,
+            ))
+            .await?;
+        /* Any data still in flight on the synchronous channel is discarded
+         * by recv_type() */
+        let ack = sync_chan
+            .recv_type(MessageType::DeviceClearAcknowledge, self.settings.timeout)
+            .await?;
+
+        self.overlapped = ack.control & 1 != 0;
+        self.message_id = INITIAL_MESSAGE_ID;
+        self.response_id = INITIAL_MESSAGE_ID;
+        self.rmt_delivered = false;
+        self.rx_buf.clear();
+        self.rx_end = false;
+
+        Ok(())
+    }
+
+    async fn device_trigger(&mut self) -> Result<()> {
+        let Some(sync_chan) = &mut self.sync_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!("device_trigger()");
+
+        sync_chan
+            .send(HislipMessage::new(
+                MessageType::Trigger,
+                self.rmt_delivered as u8,
+                self.message_id,
+            ))
+            .await?;
+
+        self.rmt_delivered = false;
+        self.message_id = self.message_id.wrapping_add(2);
+
+        Ok(())
+    }
+
+    async fn read_stb(&mut self) -> Result<u8> {
+        let last_message_id = self.last_message_id();
+        let Some(async_chan) = &mut self.async_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        async_chan
+            .send(HislipMessage::new(
+                MessageType::AsyncStatusQuery,
+                self.rmt_delivered as u8,
+                last_message_id,
+            ))
+            .await?;
+        self.rmt_delivered = false;
+
+        let resp = async_chan
+            .recv_type(MessageType::AsyncStatusResponse, self.settings.timeout)
+            .await?;
+
+        debug!("read_stb(): {:#04x}", resp.control);
+
+        Ok(resp.control)
+    }
+
+    async fn device_local(&mut self) -> Result<()> {
+        let last_message_id = self.last_message_id();
+        let Some(async_chan) = &mut self.async_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!("device_local()");
+
+        async_chan
+            .send(HislipMessage::new(
+                MessageType::AsyncRemoteLocalControl,
+                REMOTE_LOCAL_GO_TO_LOCAL,
+                last_message_id,
+            ))
+            .await?;
+        async_chan
+            .recv_type(MessageType::AsyncRemoteLocalResponse, self.settings.timeout)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn lock(&mut self, timeout: Duration) -> Result<()> {
+        self.request_lock(timeout, "").await
+    }
+
+    async fn unlock(&mut self) -> Result<()> {
+        let last_message_id = self.last_message_id();
+        let Some(async_chan) = &mut self.async_chan else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        debug!("unlock()");
+
+        async_chan
+            .send(HislipMessage::new(
+                MessageType::AsyncLock,
+                0,
+                last_message_id,
+            ))
+            .await?;
+        let resp = async_chan
+            .recv_type(MessageType::AsyncLockResponse, self.settings.timeout)
+            .await?;
+
+        match resp.control {
+            1 | 2 => Ok(()),
+            _ => Err(Error::Unspecified(
+                "Device reported error on unlock, no lock held?".into(),
+            )),
+        }
+    }
+}
+
+/// A single HiSLIP TCP connection, either the synchronous or asynchronous
+/// channel
+struct HislipChannel {
+    stream: TcpStream,
+    /// Received bytes not yet parsed into a message
+    buf: Vec<u8>,
+}
+impl HislipChannel {
+    async fn connect(socket: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(socket)
+            .await
+            .map_err(|e| Error::Unhandled(e.into()))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            buf: vec![],
+        })
+    }
+
+    async fn send(&mut self, msg: HislipMessage) -> Result<()> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + msg.payload.len());
+        msg.pack(&mut packet);
+
+        self.stream
+            .write_all(&packet)
+            .await
+            .map_err(|e| Error::Unhandled(e.into()))
+    }
+
+    /// Receive a single message. Partially received messages are kept across
+    /// calls, so this may be safely cancelled.
+    async fn recv(&mut self) -> Result<HislipMessage> {
+        loop {
+            if let Some(msg) = HislipMessage::unpack(&mut self.buf)? {
+                return Ok(msg);
+            }
+
+            /* read_buf() is cancel-safe, reading into the spare capacity */
+            self.buf.reserve(READ_CHUNK_SIZE);
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(Error::connection_closed());
+            }
+        }
+    }
+
+    /// Receive messages until one of the given type arrives, discarding any
+    /// others
+    async fn recv_type(
+        &mut self,
+        msg_type: MessageType,
+        timeout: Duration,
+    ) -> Result<HislipMessage> {
+        let end = Instant::now() + timeout;
+
+        loop {
+            let msg = match tokio::time::timeout_at(end, self.recv()).await {
+                Err(_) => {
+                    return Err(Error::Timeout(format!(
+                        "Timed out waiting for {msg_type:?} for {} ms",
+                        timeout.as_millis()
+                    )));
+                }
+                Ok(msg) => msg?,
+            };
+
+            if msg.msg_type == msg_type {
+                return Ok(msg);
+            }
+
+            match msg.msg_type {
+                MessageType::Error | MessageType::FatalError => {
+                    return Err(Error::Unspecified(format!(
+                        "HiSLIP {:?} {}: {}",
+                        msg.msg_type,
+                        msg.control,
+                        String::from_utf8_lossy(&msg.payload)
+                    )));
+                }
+                t => debug!("Discarding HiSLIP message {t:?} while waiting for {msg_type:?}"),
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        if let Err(e) = self.stream.shutdown().await {
+            debug!("Failed to shut down HiSLIP channel: {e}");
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HislipMessage {
+    msg_type: MessageType,
+    control: u8,
+    param: u32,
+    payload: Vec<u8>,
+}
+impl HislipMessage {
+    fn new(msg_type: MessageType, control: u8, param: u32) -> Self {
+        Self {
+            msg_type,
+            control,
+            param,
+            payload: vec![],
+        }
+    }
+
+    fn pack(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"HS");
+        out.push(self.msg_type as u8);
+        out.push(self.control);
+        out.extend(self.param.to_be_bytes());
+        out.extend((self.payload.len() as u64).to_be_bytes());
+        out.extend(self.payload);
+    }
+
+    /// Unpack a message from the start of src if it is complete, removing it
+    /// from src
+    fn unpack(src: &mut Vec<u8>) -> Result<Option<Self>> {
+        if src.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        if &src[0..2] != b"HS" {
+            return Err(Error::BadResponse(format!(
+                "Invalid HiSLIP prologue {:02x?}",
+                &src[0..2]
+            )));
+        }
+
+        let len = u64::from_be_bytes(src[8..16].try_into().unwrap());
+        if len > MAX_MESSAGE_SIZE {
+            return Err(Error::BadResponse(format!(
+                "HiSLIP message length {len} exceeds maximum of {MAX_MESSAGE_SIZE}"
+            )));
+        }
+        let len = len as usize;
+        if src.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let msg_type = MessageType::from_u8(src[2])?;
+        let control = src[3];
+        let param = u32::from_be_bytes(src[4..8].try_into().unwrap());
+        let payload = src[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        src.drain(0..HEADER_SIZE + len);
+
+        Ok(Some(Self {
+            msg_type,
+            control,
+            param,
+            payload,
+        }))
+    }
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum MessageType {
+    Initialize = 0,
+    InitializeResponse = 1,
+    FatalError = 2,
+    Error = 3,
+    AsyncLock = 4,
+    AsyncLockResponse = 5,
+    Data = 6,
+    DataEnd = 7,
+    DeviceClearComplete = 8,
+    DeviceClearAcknowledge = 9,
+    AsyncRemoteLocalControl = 10,
+    AsyncRemoteLocalResponse = 11,
+    Trigger = 12,
+    Interrupted = 13,
+    AsyncInterrupted = 14,
+    AsyncMaximumMessageSize = 15,
+    AsyncMaximumMessageSizeResponse = 16,
+    AsyncInitialize = 17,
+    AsyncInitializeResponse = 18,
+    AsyncDeviceClear = 19,
+    AsyncServiceRequest = 20,
+    AsyncStatusQuery = 21,
+    AsyncStatusResponse = 22,
+    AsyncDeviceClearAcknowledge = 23,
+    AsyncLockInfo = 24,
+    AsyncLockInfoResponse = 25,
+}
+impl MessageType {
+    fn from_u8(value: u8) -> Result<Self> {
+        Ok(match value {
+            0 => Self::Initialize,
+            1 => Self::InitializeResponse,
+            2 => Self::FatalError,
+            3 => Self::Error,
+            4 => Self::AsyncLock,
+            5 => Self::AsyncLockResponse,
+            6 => Self::Data,
+            7 => Self::DataEnd,
+            8 => Self::DeviceClearComplete,
+            9 => Self::DeviceClearAcknowledge,
+            10 => Self::AsyncRemoteLocalControl,
+            11 => Self::AsyncRemoteLocalResponse,
+            12 => Self::Trigger,
+            13 => Self::Interrupted,
+            14 => Self::AsyncInterrupted,
+            15 => Self::AsyncMaximumMessageSize,
+            16 => Self::AsyncMaximumMessageSizeResponse,
+            17 => Self::AsyncInitialize,
+            18 => Self::AsyncInitializeResponse,
+            19 => Self::AsyncDeviceClear,
+            20 => Self::AsyncServiceRequest,
+            21 => Self::AsyncStatusQuery,
+            22 => Self::AsyncStatusResponse,
+            23 => Self::AsyncDeviceClearAcknowledge,
+            24 => Self::AsyncLockInfo,
+            25 => Self::AsyncLockInfoResponse,
+            i => {
+                return Err(Error::BadResponse(format!(
+                    "Unknown HiSLIP message type {i}"
+                )));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+
+    const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+    async fn accept(listener: &TcpListener) -> Result<HislipChannel> {
+        let (stream, _) = listener.accept().await?;
+        Ok(HislipChannel {
+            stream,
+            buf: vec![],
+        })
+    }
+
+    /// Accept a session on both channels, the server preferring overlapped
+    /// mode if given
+    async fn accept_session(
+        listener: TcpListener,
+        overlapped: bool,
+    ) -> Result<(HislipChannel, HislipChannel)> {
+        let mut sync_chan = accept(&listener).await?;
+        let init = sync_chan
+            .recv_type(MessageType::Initialize, IO_TIMEOUT)
+            .await?;
+        assert_eq!(init.payload, b"hislip0");
+        sync_chan
+            .send(HislipMessage::new(
+                MessageType::InitializeResponse,
+                overlapped as u8,
+                ((PROTOCOL_VERSION as u32) << 16) | 7,
+            ))
+            .await?;
+
+        let mut async_chan = accept(&listener).await?;
+        let init = async_chan
+            .recv_type(MessageType::AsyncInitialize, IO_TIMEOUT)
+            .await?;
+        assert_eq!(init.param, 7);
+        async_chan
+            .send(HislipMessage::new(
+                MessageType::AsyncInitializeResponse,
+                0,
+                0,
+            ))
+            .await?;
+        async_chan
+            .recv_type(MessageType::AsyncMaximumMessageSize, IO_TIMEOUT)
+            .await?;
+        let mut resp = HislipMessage::new(MessageType::AsyncMaximumMessageSizeResponse, 0, 0);
+        resp.payload = 1024u64.to_be_bytes().to_vec();
+        async_chan.send(resp).await?;
+
+        Ok((sync_chan, async_chan))
+    }
+
+    /// Start a stand-in server running the given session on a loopback port
+    async fn serve<F, Fut>(overlapped: bool, session: F) -> (SocketAddr, JoinHandle<Result<()>>)
+    where
+        F: FnOnce(HislipChannel, HislipChannel) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (sync_chan, async_chan) = accept_session(listener, overlapped).await?;
+            session(sync_chan, async_chan).await
+        });
+
+        (addr, server)
+    }
+
+    fn data_end(param: u32, payload: &[u8]) -> HislipMessage {
+        let mut msg = HislipMessage::new(MessageType::DataEnd, 0, param);
+        msg.payload = payload.to_vec();
+        msg
+    }
+
+    #[tokio::test]
+    async fn query_discards_stale_responses() {
+        /* Echoes every command back, preceded by a response carrying the ID
+         * of an earlier message. Ends when the client disconnects. */
+        let (addr, server) = serve(false, |mut sync_chan, _async_chan| async move {
+            while let Ok(req) = sync_chan.recv().await {
+                assert_eq!(req.msg_type, MessageType::DataEnd);
+                sync_chan
+                    .send(data_end(req.param.wrapping_sub(2), b"stale\n"))
+                    .await?;
+                sync_chan.send(data_end(req.param, &req.payload)).await?;
+            }
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        assert!(!hislip.overlapped());
+        assert_eq!(hislip.max_msg_size, 1024);
+
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "*IDN?");
+        assert_eq!(proto.query_str("MEAS:VOLT?").await.unwrap(), "MEAS:VOLT?");
+
+        hislip.disconnect().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn numbers_messages() {
+        let (addr, server) = serve(false, |mut sync_chan, _async_chan| async move {
+            /* First query, response not yet delivered */
+            let req = sync_chan.recv().await?;
+            assert_eq!(
+                (req.msg_type, req.control, req.param),
+                (MessageType::DataEnd, 0, INITIAL_MESSAGE_ID)
+            );
+            sync_chan.send(data_end(req.param, b"1\n")).await?;
+
+            /* Message split to fit the maximum size, after the response to
+             * the previous one was delivered */
+            let first = sync_chan.recv().await?;
+            assert_eq!(
+                (first.msg_type, first.control, first.param),
+                (MessageType::Data, 1, INITIAL_MESSAGE_ID + 2)
+            );
+            assert_eq!(first.payload.len(), 1024 - HEADER_SIZE);
+            let last = sync_chan.recv().await?;
+            assert_eq!(
+                (last.msg_type, last.control, last.param),
+                (MessageType::DataEnd, 0, INITIAL_MESSAGE_ID + 4)
+            );
+            assert_eq!(first.payload.len() + last.payload.len(), 2002);
+
+            let trigger = sync_chan.recv().await?;
+            assert_eq!(
+                (trigger.msg_type, trigger.param),
+                (MessageType::Trigger, INITIAL_MESSAGE_ID + 6)
+            );
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        assert_eq!(proto.query_str("*OPC?").await.unwrap(), "1");
+        proto.send(vec![b'0'; 2000]).await.unwrap();
+        hislip.device_trigger().await.unwrap();
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn recv_until_message_end() {
+        let (addr, server) = serve(false, |mut sync_chan, _async_chan| async move {
+            let req = sync_chan.recv().await?;
+            sync_chan.send(data_end(req.param, b"1.0")).await?;
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        proto.send("MEAS?").await.unwrap();
+        let res = proto.recv_until(b'\n', IO_TIMEOUT).await;
+        assert!(matches!(res, Err(Error::BadResponse(_))), "{res:?}");
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiates_overlap_mode() {
+        let (addr, server) = serve(false, |mut sync_chan, mut async_chan| async move {
+            async_chan
+                .recv_type(MessageType::AsyncDeviceClear, IO_TIMEOUT)
+                .await?;
+            /* Server prefers synchronized mode */
+            async_chan
+                .send(HislipMessage::new(
+                    MessageType::AsyncDeviceClearAcknowledge,
+                    0,
+                    0,
+                ))
+                .await?;
+            let complete = sync_chan
+                .recv_type(MessageType::DeviceClearComplete, IO_TIMEOUT)
+                .await?;
+            assert_eq!(complete.control, 1);
+            sync_chan
+                .send(HislipMessage::new(
+                    MessageType::DeviceClearAcknowledge,
+                    1,
+                    0,
+                ))
+                .await?;
+
+            /* Message IDs start over after a clear */
+            let req = sync_chan.recv().await?;
+            assert_eq!(req.param, INITIAL_MESSAGE_ID);
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.set_overlapped(true);
+        hislip.connect().await.unwrap();
+        assert!(hislip.overlapped());
+        (&mut hislip as &mut dyn ScpiProtocol)
+            .send("*RST")
+            .await
+            .unwrap();
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn device_clear_discards_pending_data() {
+        let (addr, server) = serve(false, |mut sync_chan, mut async_chan| async move {
+            let req = sync_chan.recv().await?;
+            sync_chan.send(data_end(req.param, b"pending\n")).await?;
+
+            async_chan
+                .recv_type(MessageType::AsyncDeviceClear, IO_TIMEOUT)
+                .await?;
+            async_chan
+                .send(HislipMessage::new(
+                    MessageType::AsyncDeviceClearAcknowledge,
+                    0,
+                    0,
+                ))
+                .await?;
+            /* Without a request the server's preference is kept */
+            let complete = sync_chan
+                .recv_type(MessageType::DeviceClearComplete, IO_TIMEOUT)
+                .await?;
+            assert_eq!(complete.control, 0);
+            sync_chan
+                .send(HislipMessage::new(
+                    MessageType::DeviceClearAcknowledge,
+                    0,
+                    0,
+                ))
+                .await?;
+
+            let req = sync_chan.recv().await?;
+            sync_chan.send(data_end(req.param, b"fresh\n")).await?;
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        proto.send("*IDN?").await.unwrap();
+        proto.device_clear().await.unwrap();
+        assert!(!hislip.overlapped());
+        assert!(hislip.rx_buf.is_empty());
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "fresh");
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn locks_device() {
+        let (addr, server) = serve(false, |_sync_chan, mut async_chan| async move {
+            /* Exclusive lock, granted */
+            let req = async_chan
+                .recv_type(MessageType::AsyncLock, IO_TIMEOUT)
+                .await?;
+            assert_eq!((req.control, req.param), (1, 250));
+            assert!(req.payload.is_empty());
+            async_chan
+                .send(HislipMessage::new(MessageType::AsyncLockResponse, 1, 0))
+                .await?;
+
+            /* Shared lock, timed out, with an out of range timeout saturated */
+            let req = async_chan
+                .recv_type(MessageType::AsyncLock, IO_TIMEOUT)
+                .await?;
+            assert_eq!((req.control, req.payload.as_slice()), (1, &b"bench"[..]));
+            assert_eq!(req.param, u32::MAX);
+            async_chan
+                .send(HislipMessage::new(MessageType::AsyncLockResponse, 0, 0))
+                .await?;
+
+            /* Release, carrying the ID of the last message sent */
+            for control in [1, 3] {
+                let req = async_chan
+                    .recv_type(MessageType::AsyncLock, IO_TIMEOUT)
+                    .await?;
+                assert_eq!((req.control, req.param), (0, INITIAL_MESSAGE_ID - 2));
+                async_chan
+                    .send(HislipMessage::new(
+                        MessageType::AsyncLockResponse,
+                        control,
+                        0,
+                    ))
+                    .await?;
+            }
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        hislip.lock(Duration::from_millis(250)).await.unwrap();
+        assert!(matches!(
+            hislip
+                .lock_shared(Duration::from_secs(u32::MAX as u64), "bench")
+                .await,
+            Err(Error::Timeout(_))
+        ));
+        hislip.unlock().await.unwrap();
+        assert!(hislip.unlock().await.is_err());
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_channel_uses_connection_timeout() {
+        let (addr, _server) = serve(false, |_sync_chan, mut async_chan| async move {
+            /* Never answered */
+            async_chan
+                .recv_type(MessageType::AsyncStatusQuery, IO_TIMEOUT)
+                .await?;
+            tokio::time::sleep(IO_TIMEOUT).await;
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        let proto = &mut hislip as &mut dyn ScpiProtocol;
+        proto.set_timeout(Duration::from_millis(50));
+
+        let start = Instant::now();
+        assert!(matches!(proto.read_stb().await, Err(Error::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn reads_status_byte() {
+        let (addr, server) = serve(false, |mut sync_chan, mut async_chan| async move {
+            let req = sync_chan.recv().await?;
+            sync_chan.send(data_end(req.param, b"1\n")).await?;
+
+            let query = async_chan
+                .recv_type(MessageType::AsyncStatusQuery, IO_TIMEOUT)
+                .await?;
+            assert_eq!((query.control, query.param), (1, INITIAL_MESSAGE_ID));
+            async_chan
+                .send(HislipMessage::new(
+                    MessageType::AsyncStatusResponse,
+                    0x50,
+                    0,
+                ))
+                .await?;
+            Ok(())
+        })
+        .await;
+
+        let mut hislip = ScpiHislipProtocol::new(addr, "hislip0");
+        hislip.connect().await.unwrap();
+        let proto: &mut dyn ScpiProtocol = &mut hislip;
+        proto.query("*OPC?").await.unwrap();
+        assert_eq!(hislip.read_stb().await.unwrap(), 0x50);
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_oversized_message() {
+        let mut buf = b"HS\x06\x00\x00\x00\x00\x00".to_vec();
+        buf.extend((MAX_MESSAGE_SIZE + 1).to_be_bytes());
+        assert!(matches!(
+            HislipMessage::unpack(&mut buf),
+            Err(Error::BadResponse(_))
+        ));
+
+        let mut buf = vec![];
+        let mut msg = HislipMessage::new(MessageType::DataEnd, 1, 0xffff_ff00);
+        msg.payload = b"data".to_vec();
+        msg.pack(&mut buf);
+        let msg = HislipMessage::unpack(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type, MessageType::DataEnd);
+        assert_eq!(msg.payload, b"data");
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::error::{Error, Result};
+
+/// Bus-level IEEE 488 control operations, for transports that can perform
+/// them out-of-band from the SCPI message stream
+#[async_trait]
+/* Don't warn about unused arguments for default implementations */
+#[allow(unused_variables)]
+pub trait Ieee488Control: Send + Sync {
+    /// Clear the device, aborting pending operations and discarding its input
+    /// and output buffers
+    async fn device_clear(&mut self) -> Result<()>;
+
+    /// Send a group execute trigger to the device
+    async fn device_trigger(&mut self) -> Result<()>;
+
+    /// Read the device's status byte
+    async fn read_stb(&mut self) -> Result<u8>;
+
+    /// Return the device to local (front panel) control
+    async fn device_local(&mut self) -> Result<()>;
+
+    /// Acquire an exclusive lock on the device, waiting up to timeout for
+    /// another client to release it
+    async fn lock(&mut self, timeout: Duration) -> Result<()> {
+        Err(Error::NotSupported(
+            "Transport does not support locking".into(),
+        ))
+    }
+
+    /// Release a lock acquired with lock()
+    async fn unlock(&mut self) -> Result<()> {
+        Err(Error::NotSupported(
+            "Transport does not support locking".into(),
+        ))
+    }
+
+    /// Get a receiver notified on each service request from the device,
+    /// enabling service requests if necessary
+    async fn subscribe_srq(&mut self) -> Result<broadcast::Receiver<()>> {
+        Err(Error::NotSupported(
+            "Transport does not support service requests".into(),
+        ))
+    }
+}
@@ -0,0 +1,364 @@
+//! Minimal DNS-SD browsing over multicast DNS, RFC6762 and RFC6763
+//!
+//! Queries are sent as one-shot queries from an ephemeral port, which
+//! responders answer by unicast to that port, so no multicast group needs to
+//! be joined.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::error::{Error, Result};
+
+/// Multicast address and port of mDNS over IPv4
+pub const MDNS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Top bit of the question class, asking for a unicast response
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+/// Service instance found by browsing
+#[derive(Debug, Clone)]
+pub struct MdnsService {
+    /// Service type browsed for, e.g. `_lxi._tcp.local`
+    pub service: String,
+    /// Instance name, e.g. `Keysight 34465A._lxi._tcp.local`
+    pub instance: String,
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Debug)]
+enum Record {
+    Ptr {
+        name: String,
+        target: String,
+    },
+    Srv {
+        name: String,
+        target: String,
+        port: u16,
+    },
+    Addr {
+        name: String,
+        addr: IpAddr,
+    },
+}
+
+/// Browse for instances of the given service types, e.g. `_lxi._tcp.local`,
+/// sending the query to each target and collecting responses received within
+/// timeout
+pub async fn browse(
+    targets: &[SocketAddr],
+    services: &[&str],
+    timeout: Duration,
+) -> Result<Vec<MdnsService>> {
+    let local = if targets.iter().all(SocketAddr::is_ipv6) {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+
+    let query = pack_query(services);
+    for target in targets {
+        if let Err(e) = socket.send_to(&query, target).await {
+            warn!("Failed to send mDNS query to {target}: {e}");
+        }
+    }
+
+    /* Records are collected from all responses first, as the SRV and address
+     * records for an instance may come in separate responses */
+    let mut records = vec![];
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 9000];
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, peer) = res?;
+        match parse_response(&buf[..len]) {
+            Ok(parsed) => records.extend(parsed.into_iter().map(|record| (peer, record))),
+            Err(e) => debug!("Ignoring invalid mDNS response from {peer}: {e}"),
+        }
+    }
+
+    let mut found: Vec<MdnsService> = vec![];
+    for (peer, record) in &records {
+        let Record::Ptr { name, target } = record else {
+            continue;
+        };
+        let Some(service) = services.iter().find(|s| s.eq_ignore_ascii_case(name)) else {
+            continue;
+        };
+        if found
+            .iter()
+            .any(|f| f.instance.eq_ignore_ascii_case(target))
+        {
+            continue;
+        }
+
+        let Some((host, port)) = records.iter().find_map(|(_, record)| match record {
+            Record::Srv {
+                name,
+                target: host,
+                port,
+            } if name.eq_ignore_ascii_case(target) => Some((host, *port)),
+            _ => None,
+        }) else {
+            debug!("No SRV record for {target}");
+            continue;
+        };
+
+        /* IPv4 is preferred, as IPv6 link-local addresses would need a zone,
+         * falling back to the address the response came from rather than
+         * using one of those */
+        let addrs = records.iter().filter_map(|(_, record)| match record {
+            Record::Addr { name, addr } if name.eq_ignore_ascii_case(host) => Some(*addr),
+            _ => None,
+        });
+        let addr = addrs
+            .clone()
+            .find(IpAddr::is_ipv4)
+            .or_else(|| addrs.clone().find(|addr| !needs_zone(addr)))
+            .unwrap_or(peer.ip());
+
+        found.push(MdnsService {
+            service: service.to_string(),
+            instance: target.clone(),
+            addr,
+            port,
+        });
+    }
+
+    Ok(found)
+}
+
+/// Whether the address is only usable along with the zone it is in
+fn needs_zone(addr: &IpAddr) -> bool {
+    matches!(addr, IpAddr::V6(addr) if addr.is_unicast_link_local())
+}
+
+/// Pack a query for the PTR records of each service type
+fn pack_query(services: &[&str]) -> Vec<u8> {
+    let mut packet = vec![];
+    /* ID, flags, and counts of questions, answers, authority and additional
+     * records */
+    for field in [0, 0, services.len() as u16, 0, 0, 0] {
+        packet.extend(field.to_be_bytes());
+    }
+
+    for service in services {
+        for label in service.split('.').filter(|label| !label.is_empty()) {
+            packet.push(label.len() as u8);
+            packet.extend(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend(TYPE_PTR.to_be_bytes());
+        packet.extend((CLASS_IN | CLASS_UNICAST_RESPONSE).to_be_bytes());
+    }
+
+    packet
+}
+
+/// Parse the PTR, SRV and address records from all sections of a response
+fn parse_response(packet: &[u8]) -> Result<Vec<Record>> {
+    let mut reader = DnsReader { packet, pos: 0 };
+
+    let _id = reader.u16()?;
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(Error::BadResponse("Not a response".into()));
+    }
+    let questions = reader.u16()?;
+    let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+
+    for _ in 0..questions {
+        reader.name()?;
+        /* Type and class */
+        reader.bytes(4)?;
+    }
+
+    let mut parsed = vec![];
+    for _ in 0..records {
+        let name = reader.name()?;
+        let rtype = reader.u16()?;
+        /* Class, with the cache flush bit, and TTL */
+        reader.bytes(6)?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+
+        match rtype {
+            TYPE_PTR => parsed.push(Record::Ptr {
+                name,
+                target: reader.name()?,
+            }),
+            TYPE_SRV => {
+                /* Priority and weight */
+                reader.bytes(4)?;
+                let port = reader.u16()?;
+                parsed.push(Record::Srv {
+                    name,
+                    target: reader.name()?,
+                    port,
+                });
+            }
+            TYPE_A if len == 4 => {
+                let octets: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+                parsed.push(Record::Addr {
+                    name,
+                    addr: IpAddr::from(octets),
+                });
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = reader.bytes(16)?.try_into().unwrap();
+                parsed.push(Record::Addr {
+                    name,
+                    addr: IpAddr::from(octets),
+                });
+            }
+            _ => {}
+        }
+
+        reader.pos = end;
+    }
+
+    Ok(parsed)
+}
+
+struct DnsReader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+impl<'a> DnsReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.packet.get(self.pos..self.pos + len) else {
+            return Err(Error::BadResponse("Truncated DNS message".into()));
+        };
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a possibly compressed name, as dot-separated labels
+    fn name(&mut self) -> Result<String> {
+        let mut labels = vec![];
+        let mut pos = self.pos;
+        /* Position to continue at after the name, once a pointer is followed */
+        let mut resume = None;
+
+        /* Limits pointer loops */
+        for _ in 0..128 {
+            let Some(&len) = self.packet.get(pos) else {
+                break;
+            };
+
+            if len & 0xc0 == 0xc0 {
+                let Some(&low) = self.packet.get(pos + 1) else {
+                    break;
+                };
+                resume.get_or_insert(pos + 2);
+                pos = ((len as usize & 0x3f) << 8) | low as usize;
+            } else if len == 0 {
+                self.pos = resume.unwrap_or(pos + 1);
+                return Ok(labels.join("."));
+            } else {
+                let Some(label) = self.packet.get(pos + 1..pos + 1 + len as usize) else {
+                    break;
+                };
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+
+        Err(Error::BadResponse("Invalid DNS name".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_name(packet: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend(label.as_bytes());
+        }
+        packet.push(0);
+    }
+
+    fn push_record(packet: &mut Vec<u8>, name: &str, rtype: u16, data: &[u8]) {
+        push_name(packet, name);
+        packet.extend(rtype.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+        packet.extend(120u32.to_be_bytes());
+        packet.extend((data.len() as u16).to_be_bytes());
+        packet.extend(data);
+    }
+
+    /// Response advertising a raw SCPI service on a host with only the given
+    /// IPv6 address
+    fn scpi_raw_response(addr: Ipv6Addr) -> Vec<u8> {
+        let instance = "DP832._scpi-raw._tcp.local";
+        let host = "dp832.local";
+
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+        let mut target = vec![];
+        push_name(&mut target, instance);
+        push_record(&mut packet, "_scpi-raw._tcp.local", TYPE_PTR, &target);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend(5025u16.to_be_bytes());
+        push_name(&mut srv, host);
+        push_record(&mut packet, instance, TYPE_SRV, &srv);
+        push_record(&mut packet, host, TYPE_AAAA, &addr.octets());
+
+        packet
+    }
+
+    async fn browse_responder(response: Vec<u8>) -> Vec<MdnsService> {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            let (_, peer) = responder.recv_from(&mut buf).await.unwrap();
+            responder.send_to(&response, peer).await.unwrap();
+        });
+
+        browse(
+            &[target],
+            &["_scpi-raw._tcp.local"],
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn browse_uses_advertised_address() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let found = browse_responder(scpi_raw_response(addr)).await;
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].instance, "DP832._scpi-raw._tcp.local");
+        assert_eq!(found[0].addr, IpAddr::V6(addr));
+        assert_eq!(found[0].port, 5025);
+    }
+
+    #[tokio::test]
+    async fn browse_avoids_link_local_address() {
+        let found = browse_responder(scpi_raw_response("fe80::1".parse().unwrap())).await;
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -1,15 +1,55 @@
 use async_trait::async_trait;
 
+mod address;
+mod discovery;
+mod hislip;
+mod ieee488;
+mod mdns;
+mod prologix;
+mod reconnect;
+mod recording;
+mod replay;
 mod scpi;
 mod scpi_serial;
+mod scpi_sim;
 mod scpi_tcp;
+mod serial_ports;
+mod session;
+mod stream;
+mod usbtmc;
 mod vxi11;
 
-pub use scpi::{ScpiProtocol, scpi_from_uri};
-pub use scpi_serial::ScpiSerialProtocol;
+pub use address::{
+    FlowControl, Parity, ResourceAddress, ResourceOptions, ResourceTransport, StopBits,
+};
+pub use discovery::{DiscoveredInstrument, DiscoveryOptions, discover, discover_with};
+pub use hislip::{HISLIP_PORT, ScpiHislipProtocol};
+pub use ieee488::Ieee488Control;
+pub use prologix::{GPIB_MAX_ADDR, PROLOGIX_PORT, PrologixAdapter, ScpiPrologixProtocol};
+pub use reconnect::{ReconnectEvent, ReconnectPolicy, ReconnectingProtocol};
+pub use recording::{RecordCall, RecordDirection, RecordEntry, RecordError, RecordingProtocol};
+pub use replay::ReplayProtocol;
+pub use scpi::{
+    DEFAULT_TIMEOUT, ErrorCheck, ScpiProtocol, ScpiSettings, Terminator, scpi_from_address,
+    scpi_from_uri,
+};
+pub use scpi_serial::{ScpiSerialProtocol, SerialConfig};
+pub use scpi_sim::ScpiSimProtocol;
 pub use scpi_tcp::ScpiTcpProtocol;
-pub use vxi11::ScpiVxiProtocol;
-pub use vxi11::portmap::PORTMAP_PORT;
+pub use serial_ports::{
+    SerialPortDetails, UsbSerialInfo, UsbSerialMatch, find_serial_instrument, find_usb_serial_port,
+    probe_serial_ports, serial_ports,
+};
+pub use session::{ScpiSession, ScpiTransaction, SessionPriority};
+#[cfg(target_os = "linux")]
+pub use usbtmc::UsbfsDevice;
+pub use usbtmc::{
+    ScpiUsbtmcProtocol, UsbtmcCapabilities, UsbtmcEndpoint, UsbtmcIo, UsbtmcRecipient,
+};
+pub use vxi11::portmap::{PORTMAP_PORT, PortmapClient, RpcIpProto, RpcMapping};
+pub use vxi11::{
+    ScpiVxiProtocol, VxiAbortHandle, VxiPortType, VxiProgramInfo, VxiServer, vxi11_programs,
+};
 
 use crate::{error::Result, model::ModelInfo};
 
@@ -0,0 +1,856 @@
+//! Prologix GPIB-ETHERNET and GPIB-USB adapters, referencing the Prologix
+//! user manuals
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpSocket,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings, SerialConfig, stream};
+
+/// TCP port of the GPIB-ETHERNET adapter
+pub const PROLOGIX_PORT: u16 = 1234;
+/// Highest valid GPIB primary address
+pub const GPIB_MAX_ADDR: u8 = 30;
+
+/// Inter-character timeout given to the adapter for reads. Reads are reissued
+/// if the device has not started responding by then.
+const ADAPTER_READ_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time to wait for the adapter to respond to its own commands
+const ADAPTER_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time to wait for the rest of a response left unread before discarding it
+const DISCARD_TIMEOUT: Duration = Duration::from_millis(50);
+/// Escape character, preceding data bytes that the adapter would otherwise
+/// interpret
+const ESC: u8 = 0x1b;
+
+/// Adapters shared by connections opened by URI, keyed by location
+static SHARED: LazyLock<std::sync::Mutex<HashMap<String, Weak<Mutex<PrologixConnection>>>>> =
+    LazyLock::new(Default::default);
+
+trait AdapterStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> AdapterStream for T {}
+
+#[derive(Debug, Clone)]
+enum AdapterLocation {
+    Tcp(SocketAddr),
+    Serial(String, SerialConfig),
+}
+
+/// Connection to an adapter, shared by all devices on its bus
+struct PrologixConnection {
+    location: AdapterLocation,
+    stream: Option<BufReader<Box<dyn AdapterStream>>>,
+    /// Number of devices connected through the adapter
+    users: usize,
+    /// Device currently addressed
+    addr: Option<u8>,
+    /// Whether a read from the device is in progress
+    read_active: bool,
+    /// Minimum time between writes, for serial adapters configured with one
+    command_delay: Duration,
+    /// Time the last write finished, for pacing
+    last_write: Option<Instant>,
+}
+impl PrologixConnection {
+    async fn open(&mut self, timeout: Duration) -> Result<()> {
+        let stream: Box<dyn AdapterStream> = match &self.location {
+            AdapterLocation::Tcp(socket) => {
+                let tcp = if socket.is_ipv6() {
+                    TcpSocket::new_v6()
+                } else {
+                    TcpSocket::new_v4()
+                }
+                .map_err(|e| Error::Unhandled(e.into()))?;
+
+                match tokio::time::timeout(timeout, tcp.connect(*socket)).await {
+                    Ok(res) => Box::new(res.map_err(|e| Error::Unhandled(e.into()))?),
+                    Err(_) => {
+                        return Err(Error::Timeout(format!(
+                            "Timed out connecting to {} for {} ms",
+                            socket,
+                            timeout.as_millis()
+                        )));
+                    }
+                }
+            }
+            AdapterLocation::Serial(port, config) => Box::new(config.open(port)?),
+        };
+        self.stream = Some(BufReader::new(stream));
+        self.addr = None;
+        self.read_active = false;
+        self.last_write = None;
+
+        /* Controller mode, only reading when asked so devices sharing the
+         * adapter do not have their responses read by each other. Data is sent
+         * with EOI asserted on the last byte and no terminator appended, as
+         * the terminator is included in the escaped data. */
+        for cmd in [
+            "++mode 1",
+            "++auto 0",
+            "++eoi 1",
+            "++eos 3",
+            "++eot_enable 0",
+        ] {
+            self.command(cmd).await?;
+        }
+        self.command(&format!(
+            "++read_tmo_ms {}",
+            ADAPTER_READ_TIMEOUT.as_millis()
+        ))
+        .await?;
+
+        let version = self.command_query("++ver").await?;
+        debug!("Prologix adapter: {version}");
+
+        Ok(())
+    }
+
+    fn stream(&mut self) -> Result<&mut BufReader<Box<dyn AdapterStream>>> {
+        /* Only dropped while devices are connected if the connection was lost,
+         * until one of them connects again */
+        let Some(stream) = &mut self.stream else {
+            return Err(Error::connection_closed());
+        };
+
+        Ok(stream)
+    }
+
+    /// Drop the stream if the error shows the connection was lost, so that
+    /// the next connect() opens it again rather than reusing a dead stream
+    fn check_lost(&mut self, e: Error) -> Error {
+        if e.is_connection_lost() && self.stream.is_some() {
+            debug!("Adapter connection lost: {e}");
+            self.stream = None;
+        }
+
+        e
+    }
+
+    async fn write(&mut self, data: &[u8], timeout: Duration) -> Result<()> {
+        if let Some(last_write) = self.last_write {
+            tokio::time::sleep_until(last_write + self.command_delay).await;
+        }
+
+        let stream = self.stream()?;
+        match tokio::time::timeout(timeout, stream.write_all(data)).await {
+            Ok(res) => res.map_err(|e| self.check_lost(Error::Unhandled(e.into())))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out sending for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        }
+        self.last_write = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Stop using the connection for a device, closing it once the last
+    /// device is done with it
+    fn release(&mut self) {
+        self.users -= 1;
+        if self.users == 0 {
+            self.stream = None;
+        }
+    }
+
+    /// Send a command to the adapter itself
+    async fn command(&mut self, cmd: &str) -> Result<()> {
+        debug!("command(): {cmd}");
+
+        /* Any command ends a read in progress */
+        self.read_active = false;
+        self.write(format!("{cmd}\n").as_bytes(), ADAPTER_TIMEOUT)
+            .await
+    }
+
+    /// Discard any response left unread, so that it is not taken as the
+    /// response of another device or of the adapter itself
+    async fn discard_unread(&mut self) -> Result<()> {
+        let read_active = self.read_active;
+        let stream = self.stream()?;
+        if !read_active && stream.buffer().is_empty() {
+            return Ok(());
+        }
+
+        match stream::recv_until_idle(stream, &mut Vec::new(), None, DISCARD_TIMEOUT).await {
+            Ok(data) => debug!("Discarded unread response: {}", data.escape_ascii()),
+            Err(Error::Timeout(_)) => {}
+            Err(e) => return Err(self.check_lost(e)),
+        }
+        self.read_active = false;
+
+        Ok(())
+    }
+
+    /// Send a command to the adapter and read its single line response
+    async fn command_query(&mut self, cmd: &str) -> Result<String> {
+        self.discard_unread().await?;
+        self.command(cmd).await?;
+
+        let stream = self.stream()?;
+        let mut resp = vec![];
+        match tokio::time::timeout(ADAPTER_TIMEOUT, stream.read_until(b'\n', &mut resp)).await {
+            Ok(Ok(0)) => return Err(self.check_lost(Error::connection_closed())),
+            Ok(res) => res.map_err(|e| self.check_lost(Error::Unhandled(e.into())))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for adapter response to `{cmd}`"
+                )));
+            }
+        };
+
+        Ok(String::from_utf8_lossy(&resp).trim().into())
+    }
+
+    /// Address a device, if not already addressed
+    async fn select(&mut self, addr: u8) -> Result<()> {
+        if self.addr == Some(addr) {
+            return Ok(());
+        }
+
+        self.discard_unread().await?;
+        self.command(&format!("++addr {addr}")).await?;
+        self.addr = Some(addr);
+
+        Ok(())
+    }
+
+    /// Send data to a device, escaping characters the adapter would
+    /// otherwise interpret
+    async fn send(&mut self, addr: u8, data: &[u8], timeout: Duration) -> Result<()> {
+        self.select(addr).await?;
+
+        let mut escaped = Vec::with_capacity(data.len() + 1);
+        for &c in data {
+            if matches!(c, b'\r' | b'\n' | ESC | b'+') {
+                escaped.push(ESC);
+            }
+            escaped.push(c);
+        }
+        escaped.push(b'\n');
+
+        self.discard_unread().await?;
+        self.write(&escaped, timeout).await
+    }
+
+    /// Wait for data from a device to be available, starting a read if one
+    /// is not in progress. Reads are reissued if the adapter times out before
+    /// the device starts responding.
+    async fn wait_data(&mut self, addr: u8, timeout: Duration) -> Result<()> {
+        self.select(addr).await?;
+
+        let end = Instant::now() + timeout;
+        loop {
+            if !self.read_active {
+                self.command("++read eoi").await?;
+                self.read_active = true;
+            }
+
+            let now = Instant::now();
+            if now >= end {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for response for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+            /* Allow for the adapter's own latency */
+            let wait = (end - now).min(ADAPTER_READ_TIMEOUT * 2);
+
+            let stream = self.stream()?;
+            match tokio::time::timeout(wait, stream.fill_buf()).await {
+                Ok(res) => {
+                    let res = match res {
+                        Ok([]) => Err(Error::connection_closed()),
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(e.into()),
+                    };
+                    return res.map_err(|e| self.check_lost(e));
+                }
+                Err(_) => self.read_active = false,
+            }
+        }
+    }
+}
+
+/// Prologix GPIB adapter, which may be shared by devices at several GPIB
+/// addresses
+#[derive(Clone)]
+pub struct PrologixAdapter {
+    conn: Arc<Mutex<PrologixConnection>>,
+}
+impl PrologixAdapter {
+    /// GPIB-ETHERNET adapter at the given socket
+    pub fn new_tcp(socket: SocketAddr) -> Self {
+        Self::new(AdapterLocation::Tcp(socket))
+    }
+
+    /// GPIB-USB adapter at the given serial port
+    pub fn new_serial(port: &str, config: SerialConfig) -> Self {
+        Self::new(AdapterLocation::Serial(port.into(), config))
+    }
+
+    fn new(location: AdapterLocation) -> Self {
+        let command_delay = match &location {
+            AdapterLocation::Tcp(_) => Duration::ZERO,
+            AdapterLocation::Serial(_, config) => config.command_delay,
+        };
+
+        Self {
+            conn: Arc::new(Mutex::new(PrologixConnection {
+                location,
+                stream: None,
+                users: 0,
+                addr: None,
+                read_active: false,
+                command_delay,
+                last_write: None,
+            })),
+        }
+    }
+
+    /// Get the adapter at a location, shared with any other devices already
+    /// using it
+    fn shared(location: AdapterLocation) -> Self {
+        let key = match &location {
+            AdapterLocation::Tcp(socket) => format!("tcp:{socket}"),
+            AdapterLocation::Serial(port, _) => format!("serial:{port}"),
+        };
+
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(conn) = shared.get(&key).and_then(Weak::upgrade) {
+            return Self { conn };
+        }
+
+        let adapter = Self::new(location);
+        shared.retain(|_, conn| conn.strong_count() > 0);
+        shared.insert(key, Arc::downgrade(&adapter.conn));
+
+        adapter
+    }
+
+    /// GPIB-ETHERNET adapter at the given socket, shared with other devices
+    /// opened with this function
+    pub fn shared_tcp(socket: SocketAddr) -> Self {
+        Self::shared(AdapterLocation::Tcp(socket))
+    }
+
+    /// GPIB-USB adapter at the given serial port, shared with other devices
+    /// opened with this function. The configuration is ignored if the adapter
+    /// is already in use.
+    pub fn shared_serial(port: &str, config: SerialConfig) -> Self {
+        Self::shared(AdapterLocation::Serial(port.into(), config))
+    }
+
+    /// Device at the given GPIB primary address on the adapter's bus
+    pub fn device(&self, addr: u8) -> Result<ScpiPrologixProtocol> {
+        ScpiPrologixProtocol::new(self.clone(), addr)
+    }
+}
+
+pub struct ScpiPrologixProtocol {
+    adapter: PrologixAdapter,
+    /// GPIB primary address
+    addr: u8,
+    connected: bool,
+    settings: ScpiSettings,
+}
+impl ScpiPrologixProtocol {
+    pub fn new(adapter: PrologixAdapter, addr: u8) -> Result<Self> {
+        if addr > GPIB_MAX_ADDR {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid GPIB address {addr}"
+            )));
+        }
+
+        Ok(Self {
+            adapter,
+            addr,
+            connected: false,
+            settings: ScpiSettings::default(),
+        })
+    }
+
+    /// GPIB primary address of the device
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    async fn conn(&self) -> Result<tokio::sync::MutexGuard<'_, PrologixConnection>> {
+        if !self.connected {
+            return Err(Error::Unspecified("Not connected".into()));
+        }
+
+        Ok(self.adapter.conn.lock().await)
+    }
+}
+#[async_trait]
+impl Protocol for ScpiPrologixProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.connected {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        let mut conn = self.adapter.conn.lock().await;
+        if conn.stream.is_none()
+            && let Err(e) = conn.open(self.settings.timeout).await
+        {
+            conn.stream = None;
+            return Err(e);
+        }
+        conn.users += 1;
+        self.connected = true;
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        self.connected = false;
+
+        self.adapter.conn.lock().await.release();
+
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+impl Drop for ScpiPrologixProtocol {
+    fn drop(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        /* Waiting here for another device to finish with the adapter could
+         * block the runtime, so leave it to a task instead */
+        match self.adapter.conn.try_lock() {
+            Ok(mut conn) => conn.release(),
+            Err(_) => {
+                let conn = self.adapter.conn.clone();
+                match tokio::runtime::Handle::try_current() {
+                    Ok(handle) => {
+                        handle.spawn(async move { conn.lock().await.release() });
+                    }
+                    Err(_) => debug!("Adapter busy and no runtime, leaving connection open"),
+                }
+            }
+        }
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ScpiPrologixProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        debug!(
+            "int_send(): {}",
+            String::from_utf8_lossy(data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        let timeout = self.settings.timeout;
+        self.conn().await?.send(self.addr, data, timeout).await
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        let timeout = self.settings.timeout;
+        let end_byte = self.settings.read_terminator.end_byte();
+        let mut conn = self.conn().await?;
+
+        let end = Instant::now() + timeout;
+        conn.wait_data(self.addr, timeout).await?;
+        let remaining = end.saturating_duration_since(Instant::now());
+
+        let stream = conn.stream()?;
+        let resp = match end_byte {
+            Some(end_byte) => {
+                let mut resp = vec![];
+                match tokio::time::timeout(remaining, stream.read_until(end_byte, &mut resp)).await
+                {
+                    Ok(res) => res.map_err(|e| conn.check_lost(Error::Unhandled(e.into())))?,
+                    Err(_) => {
+                        return Err(Error::Timeout(format!(
+                            "Timed out waiting for response for {} ms",
+                            timeout.as_millis()
+                        )));
+                    }
+                };
+                resp
+            }
+            /* The adapter does not mark EOI, so wait for the device to go
+             * idle */
+            None => stream::recv_until_idle(stream, &mut Vec::new(), None, remaining)
+                .await
+                .map_err(|e| conn.check_lost(e))?,
+        };
+        conn.read_active = false;
+
+        debug!(
+            "int_recv: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        debug!("recv_raw({length:?}, {timeout:?})");
+
+        let timeout = timeout.unwrap_or(self.settings.timeout);
+        let end_byte = self.settings.read_terminator.end_byte();
+        let mut conn = self.conn().await?;
+
+        let end = Instant::now() + timeout;
+        conn.wait_data(self.addr, timeout).await?;
+        let remaining = end.saturating_duration_since(Instant::now());
+
+        let stream = conn.stream()?;
+        let resp = if let Some(length) = length {
+            let mut resp = vec![0; length];
+            match tokio::time::timeout(remaining, stream.read_exact(&mut resp)).await {
+                Ok(res) => {
+                    res.map_err(|e| conn.check_lost(e.into()))?;
+                }
+                Err(_) => {
+                    return Err(Error::Timeout(format!(
+                        "Timed out reading {} bytes for {} ms",
+                        length,
+                        timeout.as_millis()
+                    )));
+                }
+            }
+            /* The rest of the response may still be read */
+            resp
+        } else {
+            let resp = stream::recv_until_idle(stream, &mut Vec::new(), end_byte, remaining)
+                .await
+                .map_err(|e| conn.check_lost(e))?;
+            conn.read_active = false;
+            resp
+        };
+
+        debug!(
+            "recv_raw: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        debug!("recv_until({byte}, {timeout:?})");
+
+        let mut conn = self.conn().await?;
+
+        let end = Instant::now() + timeout;
+        conn.wait_data(self.addr, timeout).await?;
+        let remaining = end.saturating_duration_since(Instant::now());
+
+        let stream = conn.stream()?;
+        let mut data = vec![];
+        match tokio::time::timeout(remaining, stream.read_until(byte, &mut data)).await {
+            Ok(res) => res.map_err(|e| conn.check_lost(Error::Unhandled(e.into())))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for {} for {} ms",
+                    byte,
+                    timeout.as_millis()
+                )));
+            }
+        };
+
+        debug!(
+            "recv_until: {}",
+            String::from_utf8_lossy(&data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(data)
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        debug!("flush_rx({timeout:?})");
+
+        let mut conn = self.conn().await?;
+        /* Only discard data already sent by the adapter, rather than starting
+         * a new read */
+        if !conn.read_active {
+            return Ok(());
+        }
+
+        let stream = conn.stream()?;
+        match stream::recv_until_idle(stream, &mut Vec::new(), None, timeout).await {
+            Ok(_) | Err(Error::Timeout(_)) => {}
+            Err(e) => return Err(conn.check_lost(e)),
+        }
+        conn.read_active = false;
+
+        Ok(())
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        /* EOI is asserted on the last byte sent, but is not indicated on
+         * reads, which instead wait for the device to go idle */
+        true
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        Some(self)
+    }
+}
+#[async_trait]
+impl Ieee488Control for ScpiPrologixProtocol {
+    async fn device_clear(&mut self) -> Result<()> {
+        let mut conn = self.conn().await?;
+        conn.select(self.addr).await?;
+        conn.command("++clr").await
+    }
+
+    async fn device_trigger(&mut self) -> Result<()> {
+        let mut conn = self.conn().await?;
+        conn.select(self.addr).await?;
+        conn.command("++trg").await
+    }
+
+    async fn read_stb(&mut self) -> Result<u8> {
+        let mut conn = self.conn().await?;
+        let resp = conn
+            .command_query(&format!("++spoll {}", self.addr))
+            .await?;
+
+        resp.parse()
+            .map_err(|e| Error::BadResponse(format!("Invalid status byte `{resp}`: {e}")))
+    }
+
+    async fn device_local(&mut self) -> Result<()> {
+        let mut conn = self.conn().await?;
+        conn.select(self.addr).await?;
+        conn.command("++loc").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Stand-in GPIB-ETHERNET adapter, with devices answering `*IDN?` with
+    /// their address
+    async fn mock_adapter(stream: TcpStream) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut addr = 0;
+        let mut pending: HashMap<u8, Vec<u8>> = HashMap::new();
+
+        loop {
+            /* Line ends at the first unescaped newline */
+            let mut data = vec![];
+            loop {
+                let c = match stream.read_u8().await {
+                    Ok(c) => c,
+                    Err(_) => return Ok(()),
+                };
+                match c {
+                    b'\n' => break,
+                    ESC => data.push(stream.read_u8().await?),
+                    c => data.push(c),
+                }
+            }
+
+            let cmd = String::from_utf8_lossy(&data).into_owned();
+            if cmd == "++ver" {
+                stream.write_all(b"Mock GPIB-ETHERNET\n").await?;
+            } else if let Some(value) = cmd.strip_prefix("++addr ") {
+                addr = value.parse().unwrap();
+            } else if let Some(value) = cmd.strip_prefix("++spoll ") {
+                /* Status byte is the device's address */
+                stream.write_all(format!("{value}\n").as_bytes()).await?;
+            } else if cmd == "++read eoi" {
+                if let Some(resp) = pending.remove(&addr) {
+                    stream.write_all(&resp).await?;
+                }
+            } else if cmd.starts_with("++") {
+                /* Other adapter configuration */
+            } else if cmd.trim_end() == "*RST" {
+                /* Connection dropped, as if the adapter were power cycled */
+                return Ok(());
+            } else if cmd.trim_end() == "*IDN?" {
+                pending.insert(addr, format!("MOCK,DEV{addr},0,1\n").into_bytes());
+            } else {
+                panic!("Unexpected command `{cmd}`");
+            }
+        }
+    }
+
+    async fn start_mock_adapter() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                mock_adapter(stream).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn devices_share_adapter() {
+        let adapter = PrologixAdapter::new_tcp(start_mock_adapter().await);
+        let mut dev5 = adapter.device(5).unwrap();
+        let mut dev7 = adapter.device(7).unwrap();
+        dev5.connect().await.unwrap();
+        dev7.connect().await.unwrap();
+
+        for _ in 0..2 {
+            for (dev, addr) in [(&mut dev5, 5), (&mut dev7, 7)] {
+                let proto: &mut dyn ScpiProtocol = dev;
+                assert_eq!(
+                    proto.query_str("*IDN?").await.unwrap(),
+                    format!("MOCK,DEV{addr},0,1")
+                );
+            }
+        }
+
+        dev5.disconnect().await.unwrap();
+        assert!(adapter.conn.lock().await.stream.is_some());
+        dev7.disconnect().await.unwrap();
+        assert!(adapter.conn.lock().await.stream.is_none());
+    }
+
+    #[tokio::test]
+    async fn discards_response_left_unread() {
+        let adapter = PrologixAdapter::new_tcp(start_mock_adapter().await);
+        let mut dev5 = adapter.device(5).unwrap();
+        let mut dev7 = adapter.device(7).unwrap();
+        dev5.connect().await.unwrap();
+        dev7.connect().await.unwrap();
+        let dev5: &mut dyn ScpiProtocol = &mut dev5;
+        let dev7: &mut dyn ScpiProtocol = &mut dev7;
+
+        /* Rest of device 5's response is left with the adapter */
+        dev5.send("*IDN?").await.unwrap();
+        assert_eq!(dev5.recv_raw(Some(4), None).await.unwrap(), b"MOCK");
+        assert_eq!(dev7.query_str("*IDN?").await.unwrap(), "MOCK,DEV7,0,1");
+
+        dev5.send("*IDN?").await.unwrap();
+        assert_eq!(dev5.recv_raw(Some(4), None).await.unwrap(), b"MOCK");
+        assert_eq!(dev7.ieee488().unwrap().read_stb().await.unwrap(), 7);
+
+        /* Device 5 can still be queried afterwards */
+        dev5.send("*IDN?").await.unwrap();
+        assert_eq!(dev5.recv_raw(Some(4), None).await.unwrap(), b"MOCK");
+        assert_eq!(dev5.query_str("*IDN?").await.unwrap(), "MOCK,DEV5,0,1");
+    }
+
+    #[tokio::test]
+    async fn reopens_lost_connection() {
+        let adapter = PrologixAdapter::new_tcp(start_mock_adapter().await);
+        let mut dev5 = adapter.device(5).unwrap();
+        let mut dev7 = adapter.device(7).unwrap();
+        dev5.connect().await.unwrap();
+        dev7.connect().await.unwrap();
+
+        (&mut dev5 as &mut dyn ScpiProtocol)
+            .send("*RST")
+            .await
+            .unwrap();
+        let err = (&mut dev5 as &mut dyn ScpiProtocol)
+            .query("*IDN?")
+            .await
+            .unwrap_err();
+        assert!(err.is_connection_lost(), "{err}");
+        assert!(adapter.conn.lock().await.stream.is_none());
+
+        /* Reconnecting opens the adapter again, although device 7 still has
+         * it open */
+        dev5.disconnect().await.unwrap();
+        dev5.connect().await.unwrap();
+        for (dev, addr) in [(&mut dev5, 5), (&mut dev7, 7)] {
+            let proto: &mut dyn ScpiProtocol = dev;
+            assert_eq!(
+                proto.query_str("*IDN?").await.unwrap(),
+                format!("MOCK,DEV{addr},0,1")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_while_adapter_busy() {
+        let adapter = PrologixAdapter::new_tcp(start_mock_adapter().await);
+        let mut dev = adapter.device(5).unwrap();
+        dev.connect().await.unwrap();
+
+        /* Must not wait for the adapter to be free */
+        let conn = adapter.conn.lock().await;
+        drop(dev);
+        assert_eq!(conn.users, 1);
+        drop(conn);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while adapter.conn.lock().await.users != 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(adapter.conn.lock().await.stream.is_none());
+    }
+
+    #[tokio::test]
+    async fn writes_paced_by_command_delay() {
+        let adapter = PrologixAdapter::new_tcp(start_mock_adapter().await);
+        let mut dev = adapter.device(5).unwrap();
+        dev.connect().await.unwrap();
+        adapter.conn.lock().await.command_delay = Duration::from_millis(50);
+
+        let start = Instant::now();
+        let proto: &mut dyn ScpiProtocol = &mut dev;
+        proto.send("*IDN?").await.unwrap();
+        proto.recv().await.unwrap();
+        /* Address is already selected, so a send and a read command */
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
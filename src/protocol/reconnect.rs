@@ -0,0 +1,318 @@
+//! Automatic reconnection to a device after the connection to it is lost
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings};
+
+/// How to reconnect after the connection to a device is lost
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Number of attempts to make before giving up
+    pub retries: u32,
+    /// Delay before the first attempt, doubling with each further attempt
+    pub backoff: Duration,
+    /// Upper limit of the delay between attempts
+    pub max_backoff: Duration,
+    /// Check with *IDN? that the same device is reached after reconnecting
+    pub verify_identity: bool,
+    /// Send a command or query that failed as the connection was lost again
+    /// once reconnected. The device may already have acted on it, so only
+    /// enable this if repeating every command is harmless.
+    pub retry_commands: bool,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            verify_identity: true,
+            retry_commands: false,
+        }
+    }
+}
+
+/// Event reported to the callback of a [`ReconnectingProtocol`]
+#[derive(Debug)]
+pub enum ReconnectEvent<'a> {
+    /// The connection was lost, and reconnection is starting
+    ConnectionLost(&'a Error),
+    /// A reconnection attempt failed
+    AttemptFailed { attempt: u32, error: &'a Error },
+    /// Reconnected after the given number of attempts
+    Reconnected { attempts: u32 },
+    /// A different device was reached after reconnecting, and has been
+    /// disconnected from. The next call will start reconnecting again.
+    IdentityMismatch {
+        expected: &'a ModelInfo,
+        found: &'a ModelInfo,
+    },
+    /// All attempts failed. The next call will start reconnecting again.
+    GaveUp { attempts: u32 },
+}
+
+type ReconnectCallback = Box<dyn Fn(&ReconnectEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    Disconnected,
+    Connected,
+    /// Connection was lost and could not be re-established yet
+    Lost,
+}
+
+/// Transport wrapper reconnecting to the device when the connection to it is
+/// lost, such as when it reboots or the network drops out.
+///
+/// The call that failed as the connection was lost still fails once
+/// reconnected, unless the policy retries commands, but later calls use the
+/// new connection. Data that was being received is lost with the connection,
+/// as is any state of it on the device side, such as a lock on the device or
+/// enabled service requests, which have to be set up again. Bus-level
+/// control operations are passed through without reconnecting.
+pub struct ReconnectingProtocol {
+    inner: Box<dyn ScpiProtocol>,
+    policy: ReconnectPolicy,
+    callback: Option<ReconnectCallback>,
+    /// Identity of the device when first connected, if it is verified
+    identity: Option<ModelInfo>,
+    state: ConnectionState,
+}
+impl ReconnectingProtocol {
+    pub fn new(inner: Box<dyn ScpiProtocol>, policy: ReconnectPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            callback: None,
+            identity: None,
+            state: ConnectionState::Disconnected,
+        }
+    }
+
+    /// Set a callback to be notified of each reconnection event
+    pub fn set_callback(&mut self, callback: impl Fn(&ReconnectEvent) + Send + Sync + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    pub fn into_inner(self) -> Box<dyn ScpiProtocol> {
+        self.inner
+    }
+
+    fn notify(&self, event: ReconnectEvent) {
+        if let Some(callback) = &self.callback {
+            callback(&event);
+        }
+    }
+
+    /// Connect the inner transport, identifying the device if its identity
+    /// is to be verified
+    async fn connect_inner(&mut self) -> Result<Option<ModelInfo>> {
+        self.inner.connect().await?;
+        if !self.policy.verify_identity {
+            return Ok(None);
+        }
+
+        match self.inner.idn_model().await {
+            Ok(model) => Ok(Some(model)),
+            Err(e) => {
+                let _ = self.inner.disconnect().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Reconnect after the connection was lost with the given error
+    async fn recover(&mut self, error: &Error) -> Result<()> {
+        warn!("Connection lost: {error}, reconnecting");
+        self.notify(ReconnectEvent::ConnectionLost(error));
+        /* Release whatever is left of the old connection */
+        let _ = self.inner.disconnect().await;
+
+        let mut backoff = self.policy.backoff;
+        for attempt in 1..=self.policy.retries {
+            tokio::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(self.policy.max_backoff);
+
+            let found = match self.connect_inner().await {
+                Ok(found) => found,
+                Err(e) => {
+                    warn!("Reconnect attempt {attempt} failed: {e}");
+                    self.notify(ReconnectEvent::AttemptFailed { attempt, error: &e });
+                    continue;
+                }
+            };
+
+            if let (Some(expected), Some(found)) = (&self.identity, &found)
+                && !expected.same_device(found)
+            {
+                let _ = self.inner.disconnect().await;
+                /* Try again on the next call, as the right device may be back
+                 * by then */
+                self.state = ConnectionState::Lost;
+                self.notify(ReconnectEvent::IdentityMismatch { expected, found });
+                return Err(Error::Unspecified(format!(
+                    "Reconnected to a different device {found}, expected {expected}"
+                )));
+            }
+
+            info!("Reconnected after {attempt} attempts");
+            self.state = ConnectionState::Connected;
+            self.notify(ReconnectEvent::Reconnected { attempts: attempt });
+            return Ok(());
+        }
+
+        self.state = ConnectionState::Lost;
+        self.notify(ReconnectEvent::GaveUp {
+            attempts: self.policy.retries,
+        });
+        Err(Error::Unspecified(format!(
+            "Failed to reconnect after {} attempts: {error}",
+            self.policy.retries
+        )))
+    }
+
+    /// Reconnect first if a previous reconnection gave up
+    async fn ensure_connected(&mut self) -> Result<()> {
+        if self.state != ConnectionState::Lost {
+            return Ok(());
+        }
+
+        self.recover(&Error::Unspecified("Connection lost earlier".into()))
+            .await
+    }
+
+    /// Whether the error is one that reconnecting may recover from
+    fn is_lost(&self, error: &Error) -> bool {
+        self.state == ConnectionState::Connected && error.is_connection_lost()
+    }
+}
+#[async_trait]
+impl Protocol for ReconnectingProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.state != ConnectionState::Disconnected {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        self.identity = self.connect_inner().await?;
+        self.state = ConnectionState::Connected;
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.state = ConnectionState::Disconnected;
+        self.inner.disconnect().await
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ReconnectingProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        self.ensure_connected().await?;
+
+        match self.inner.int_send(data).await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                if !self.policy.retry_commands {
+                    return Err(e);
+                }
+                self.inner.int_send(data).await
+            }
+            res => res,
+        }
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        self.ensure_connected().await?;
+
+        match self.inner.int_recv().await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                Err(e)
+            }
+            res => res,
+        }
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.ensure_connected().await?;
+
+        match self.inner.int_query(data).await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                if !self.policy.retry_commands {
+                    return Err(e);
+                }
+                self.inner.int_query(data).await
+            }
+            res => res,
+        }
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.ensure_connected().await?;
+
+        match self.inner.recv_raw(length, timeout).await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                Err(e)
+            }
+            res => res,
+        }
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        self.ensure_connected().await?;
+
+        match self.inner.recv_until(byte, timeout).await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                Err(e)
+            }
+            res => res,
+        }
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        self.ensure_connected().await?;
+
+        match self.inner.flush_rx(timeout).await {
+            Err(e) if self.is_lost(&e) => {
+                self.recover(&e).await?;
+                Err(e)
+            }
+            res => res,
+        }
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        self.inner.settings()
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        self.inner.settings_mut()
+    }
+
+    fn supports_eoi(&self) -> bool {
+        self.inner.supports_eoi()
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        self.inner.ieee488()
+    }
+}
@@ -0,0 +1,419 @@
+//! Recording of the exchange with a device, as JSON lines
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings};
+
+/// Direction of the data in a recorded call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordDirection {
+    /// Sent to the device
+    Tx,
+    /// Received from the device
+    Rx,
+}
+
+/// Transport call that was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordCall {
+    IntSend,
+    IntRecv,
+    RecvRaw,
+    RecvUntil,
+    FlushRx,
+}
+
+/// Error returned by a recorded call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordError {
+    Timeout(String),
+    Other(String),
+}
+impl From<&Error> for RecordError {
+    fn from(value: &Error) -> Self {
+        match value {
+            Error::Timeout(e) => Self::Timeout(e.clone()),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+impl From<RecordError> for Error {
+    fn from(value: RecordError) -> Self {
+        match value {
+            RecordError::Timeout(e) => Error::Timeout(e),
+            RecordError::Other(e) => Error::Unspecified(e),
+        }
+    }
+}
+
+/// Single recorded call, written as one line of JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordEntry {
+    /// Seconds from the start of the recording to the start of the call
+    pub time: f64,
+    /// Seconds the call took to complete
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    pub dir: RecordDirection,
+    pub call: RecordCall,
+    /// Length requested by recv_raw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Byte waited for by recv_until
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byte: Option<u8>,
+    /// Timeout given to the call, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Data sent or received, with non-printable bytes escaped
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "escaped_bytes"
+    )]
+    pub data: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RecordError>,
+}
+impl RecordEntry {
+    pub(crate) fn new(call: RecordCall, dir: RecordDirection) -> Self {
+        Self {
+            time: 0.0,
+            duration: None,
+            dir,
+            call,
+            length: None,
+            byte: None,
+            timeout_ms: None,
+            data: None,
+            error: None,
+        }
+    }
+
+    fn with_result(mut self, result: &Result<Vec<u8>>) -> Self {
+        match result {
+            Ok(data) => self.data = Some(data.clone()),
+            Err(e) => self.error = Some(e.into()),
+        }
+        self
+    }
+
+    /// Parse entries from JSON lines, skipping empty lines
+    pub fn parse_lines(lines: &str) -> Result<Vec<Self>> {
+        lines
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(num, line)| {
+                serde_json::from_str(line).map_err(|e| {
+                    Error::InvalidArgument(format!("Invalid record on line {}: {e}", num + 1))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Transport wrapper recording every call to the inner transport. Bus-level
+/// control operations are passed through without being recorded.
+pub struct RecordingProtocol {
+    inner: Box<dyn ScpiProtocol>,
+    writer: Box<dyn AsyncWrite + Send + Sync + Unpin>,
+    start: Instant,
+}
+impl RecordingProtocol {
+    pub fn new(
+        inner: Box<dyn ScpiProtocol>,
+        writer: impl AsyncWrite + Send + Sync + Unpin + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            writer: Box::new(writer),
+            start: Instant::now(),
+        }
+    }
+
+    /// Record to a file, replacing any existing file
+    pub async fn to_file(inner: Box<dyn ScpiProtocol>, path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::create(path).await?;
+
+        Ok(Self::new(inner, file))
+    }
+
+    pub fn into_inner(self) -> Box<dyn ScpiProtocol> {
+        self.inner
+    }
+
+    /// Write an entry for a call that began at `started`
+    async fn record(&mut self, started: Instant, mut entry: RecordEntry) {
+        entry.time = started.duration_since(self.start).as_secs_f64();
+        entry.duration = Some(started.elapsed().as_secs_f64());
+
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize recording: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        /* Flush each entry, so the recording survives a crash */
+        let res = async {
+            self.writer.write_all(&line).await?;
+            self.writer.flush().await
+        }
+        .await;
+        /* Failing to record should not disturb the session being recorded */
+        if let Err(e) = res {
+            error!("Failed to write recording: {e}");
+        }
+    }
+}
+#[async_trait]
+impl Protocol for RecordingProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        self.inner.connect().await
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.inner.disconnect().await
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for RecordingProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        let res = self.inner.int_send(data).await;
+
+        let mut entry = RecordEntry::new(RecordCall::IntSend, RecordDirection::Tx);
+        entry.data = Some(data.to_vec());
+        if let Err(e) = &res {
+            entry.error = Some(e.into());
+        }
+        self.record(started, entry).await;
+
+        res
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let res = self.inner.int_recv().await;
+        self.record(
+            started,
+            RecordEntry::new(RecordCall::IntRecv, RecordDirection::Rx).with_result(&res),
+        )
+        .await;
+
+        res
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        /* Sent and received separately, so an error is recorded against the
+         * step that failed */
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let res = self.inner.recv_raw(length, timeout).await;
+
+        let mut entry = RecordEntry::new(RecordCall::RecvRaw, RecordDirection::Rx);
+        entry.length = length;
+        entry.timeout_ms = timeout.map(timeout_ms);
+        self.record(started, entry.with_result(&res)).await;
+
+        res
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let res = self.inner.recv_until(byte, timeout).await;
+
+        let mut entry = RecordEntry::new(RecordCall::RecvUntil, RecordDirection::Rx);
+        entry.byte = Some(byte);
+        entry.timeout_ms = Some(timeout_ms(timeout));
+        self.record(started, entry.with_result(&res)).await;
+
+        res
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        let res = self.inner.flush_rx(timeout).await;
+
+        let mut entry = RecordEntry::new(RecordCall::FlushRx, RecordDirection::Rx);
+        entry.timeout_ms = Some(timeout_ms(timeout));
+        if let Err(e) = &res {
+            entry.error = Some(e.into());
+        }
+        self.record(started, entry).await;
+
+        res
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        self.inner.settings()
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        self.inner.settings_mut()
+    }
+
+    fn supports_eoi(&self) -> bool {
+        self.inner.supports_eoi()
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        self.inner.ieee488()
+    }
+}
+
+/// Timeout in ms as recorded, saturating if out of range
+fn timeout_ms(timeout: Duration) -> u64 {
+    u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Reverse the escaping of `escape_ascii()`
+pub(crate) fn unescape(text: &str) -> std::result::Result<Vec<u8>, String> {
+    let mut data = vec![];
+    let mut bytes = text.bytes();
+    while let Some(c) = bytes.next() {
+        if c != b'\\' {
+            data.push(c);
+            continue;
+        }
+
+        data.push(match bytes.next() {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(c @ (b'\\' | b'\'' | b'"')) => c,
+            Some(b'x') => {
+                let (Some(hi), Some(lo)) = (bytes.next(), bytes.next()) else {
+                    return Err("Truncated \\x escape".into());
+                };
+                let hex = [hi, lo];
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid \\x escape in `{text}`"))?
+            }
+            _ => return Err(format!("Invalid escape in `{text}`")),
+        });
+    }
+
+    Ok(data)
+}
+
+/// Serialize bytes as a string, escaped as by `escape_ascii()`
+mod escaped_bytes {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(data: &Option<Vec<u8>>, ser: S) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(data) => ser.serialize_some(&data.escape_ascii().to_string()),
+            None => ser.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(de)? {
+            Some(text) => super::unescape(&text).map(Some).map_err(de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ReplayProtocol;
+
+    #[tokio::test]
+    async fn records_query_errors_against_step() {
+        let path = std::env::temp_dir().join(format!("testeq-record-{}.jsonl", std::process::id()));
+
+        let replay = ReplayProtocol::from_script(
+            r"
+            > *IDN?
+            < MAKE,MODEL,0,1
+            > MEAS?
+            ! Response times out
+            > *RST
+            ",
+        )
+        .unwrap();
+        let mut recording = RecordingProtocol::to_file(Box::new(replay), &path)
+            .await
+            .unwrap();
+        recording.connect().await.unwrap();
+
+        let proto: &mut dyn ScpiProtocol = &mut recording;
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "MAKE,MODEL,0,1");
+        assert!(matches!(proto.query("MEAS?").await, Err(Error::Timeout(_))));
+        /* Fails to send, as the replay expects another command */
+        assert!(proto.query("*TST?").await.is_err());
+        drop(recording);
+
+        let entries = RecordEntry::parse_lines(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.call, entry.data.as_deref(), entry.error.is_some()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (RecordCall::IntSend, Some(&b"*IDN?\r\n"[..]), false),
+                (RecordCall::IntRecv, Some(&b"MAKE,MODEL,0,1\n"[..]), false),
+                (RecordCall::IntSend, Some(&b"MEAS?\r\n"[..]), false),
+                (RecordCall::IntRecv, None, true),
+                (RecordCall::IntSend, Some(&b"*TST?\r\n"[..]), true),
+            ]
+        );
+
+        /* Each call is timestamped when it starts, before the next call */
+        for pair in entries.windows(2) {
+            let end = pair[0].time + pair[0].duration.unwrap();
+            assert!(pair[0].time <= end && end <= pair[1].time);
+        }
+
+        /* The recording replays the same session */
+        let mut replay = ReplayProtocol::new(entries);
+        replay.connect().await.unwrap();
+        let proto: &mut dyn ScpiProtocol = &mut replay;
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "MAKE,MODEL,0,1");
+        assert!(matches!(proto.query("MEAS?").await, Err(Error::Timeout(_))));
+        assert!(proto.query("*TST?").await.is_err());
+        replay.check_complete().unwrap();
+    }
+}
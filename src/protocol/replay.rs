@@ -0,0 +1,434 @@
+//! Replay of canned responses, from a script or a recorded session, checking
+//! that the expected commands are sent
+
+use std::{collections::VecDeque, path::Path, time::Duration};
+
+use async_trait::async_trait;
+use log::debug;
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{
+    Protocol, RecordCall, RecordDirection, RecordEntry, RecordError, ScpiProtocol, ScpiSettings,
+    recording,
+};
+
+/// Transport serving responses from a list of expected exchanges, failing if
+/// a command is sent that does not match the next one expected
+///
+/// Scripts list commands and responses in order, one per line, with bytes
+/// escaped as by `escape_ascii()`:
+/// ```text
+/// # Comment
+/// > *IDN?
+/// < SIGLENT,SDM4065A,0123456789,1.0
+/// > MEAS:VOLT:DC?
+/// ! Response times out
+/// ```
+/// Commands are compared ignoring trailing whitespace, and responses have the
+/// read terminator appended. `!` lines time out with the given message in
+/// place of a response. Each response must be read before the next command is
+/// sent.
+pub struct ReplayProtocol {
+    entries: VecDeque<RecordEntry>,
+    /// Response data not yet consumed
+    rx_buf: Vec<u8>,
+    /// Whether to append the read terminator to responses, which are stored
+    /// without one in scripts
+    append_terminator: bool,
+    connected: bool,
+    settings: ScpiSettings,
+}
+impl ReplayProtocol {
+    pub fn new(entries: Vec<RecordEntry>) -> Self {
+        Self {
+            entries: entries.into(),
+            rx_buf: vec![],
+            append_terminator: false,
+            connected: false,
+            settings: ScpiSettings::default(),
+        }
+    }
+
+    /// Replay a script
+    pub fn from_script(script: &str) -> Result<Self> {
+        let mut entries = vec![];
+
+        for (num, line) in script.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some(kind) = line.chars().next() else {
+                continue;
+            };
+            let text = line[kind.len_utf8()..].trim();
+
+            let mut entry = match kind {
+                '>' => RecordEntry::new(RecordCall::IntSend, RecordDirection::Tx),
+                '<' | '!' => RecordEntry::new(RecordCall::IntRecv, RecordDirection::Rx),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Invalid script line {}: `{line}`",
+                        num + 1
+                    )));
+                }
+            };
+            if kind == '!' {
+                /* Text describes the error, rather than being data */
+                let message = if text.is_empty() {
+                    "Replayed timeout"
+                } else {
+                    text
+                };
+                entry.error = Some(RecordError::Timeout(message.into()));
+            } else {
+                entry.data = Some(recording::unescape(text).map_err(|e| {
+                    Error::InvalidArgument(format!("Invalid script line {}: {e}", num + 1))
+                })?);
+            }
+            entries.push(entry);
+        }
+
+        let mut replay = Self::new(entries);
+        /* Terminators depend on the settings at the time the response is read */
+        replay.append_terminator = true;
+
+        Ok(replay)
+    }
+
+    /// Replay a script or recorded session from a file, detecting the format
+    /// from its content
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+
+        if text.trim_start().starts_with('{') {
+            Ok(Self::new(RecordEntry::parse_lines(&text)?))
+        } else {
+            Self::from_script(&text)
+        }
+    }
+
+    /// Check that all expected exchanges took place
+    pub fn check_complete(&self) -> Result<()> {
+        let remaining = self
+            .entries
+            .iter()
+            .filter(|entry| entry.call != RecordCall::FlushRx)
+            .count();
+        if remaining > 0 {
+            return Err(Error::Unspecified(format!(
+                "Replay ended with {remaining} exchanges remaining"
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if !self.connected {
+            return Err(Error::Unspecified("Not connected".into()));
+        }
+
+        Ok(())
+    }
+
+    /// Take the next entry if it is in the given direction, skipping flushes
+    /// that were not replayed
+    fn next_entry(&mut self, dir: RecordDirection) -> Option<RecordEntry> {
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.call == RecordCall::FlushRx)
+        {
+            self.entries.pop_front();
+        }
+
+        match self.entries.front() {
+            Some(entry) if entry.dir == dir => self.entries.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Load the next response into the receive buffer
+    fn load_response(&mut self) -> Result<()> {
+        /* A command left unmatched is reported by the next send */
+        let Some(entry) = self.next_entry(RecordDirection::Rx) else {
+            return Err(Error::Timeout(
+                "No response expected at this point in the replay".into(),
+            ));
+        };
+
+        if let Some(error) = entry.error {
+            return Err(error.into());
+        }
+        if let Some(data) = entry.data {
+            self.rx_buf.extend_from_slice(&data);
+            if self.append_terminator {
+                self.rx_buf
+                    .extend_from_slice(self.settings.read_terminator.suffix());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take data from the receive buffer up to and including byte, or all of
+    /// it if byte is not found
+    fn take_until(&mut self, byte: Option<u8>) -> Vec<u8> {
+        let len = byte
+            .and_then(|byte| self.rx_buf.iter().position(|&c| c == byte))
+            .map(|pos| pos + 1)
+            .unwrap_or(self.rx_buf.len());
+
+        self.rx_buf.drain(..len).collect()
+    }
+}
+#[async_trait]
+impl Protocol for ReplayProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.connected {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        self.connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.connected = false;
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ReplayProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        self.check_connected()?;
+
+        debug!(
+            "int_send(): {}",
+            String::from_utf8_lossy(data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        /* A driver that no longer reads a response is a regression */
+        if let Some(entry) = self.next_entry(RecordDirection::Rx) {
+            let expected = match (entry.data, entry.error) {
+                (_, Some(error)) => format!("{error:?}"),
+                (Some(data), None) => format!("`{}`", data.escape_ascii()),
+                (None, None) => "``".into(),
+            };
+            return Err(Error::Unspecified(format!(
+                "Replay expected response {expected} to be read before sending `{}`",
+                data.escape_ascii()
+            )));
+        }
+
+        let Some(entry) = self.next_entry(RecordDirection::Tx) else {
+            return Err(Error::Unspecified(format!(
+                "Replay ended, but sent `{}`",
+                data.escape_ascii()
+            )));
+        };
+
+        let expected = entry.data.unwrap_or_default();
+        if expected.trim_ascii_end() != data.trim_ascii_end() {
+            return Err(Error::Unspecified(format!(
+                "Replay expected `{}`, but sent `{}`",
+                expected.escape_ascii(),
+                data.escape_ascii()
+            )));
+        }
+        if let Some(error) = entry.error {
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        self.check_connected()?;
+
+        if self.rx_buf.is_empty() {
+            self.load_response()?;
+        }
+
+        let resp = self.take_until(self.settings.read_terminator.end_byte());
+
+        debug!(
+            "int_recv: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.check_connected()?;
+
+        debug!("recv_raw({length:?}, {timeout:?})");
+
+        if self.rx_buf.is_empty() {
+            self.load_response()?;
+        }
+
+        let resp = match length {
+            Some(length) => {
+                /* Recorded responses may have been received in parts */
+                while self.rx_buf.len() < length {
+                    self.load_response()?;
+                }
+                self.rx_buf.drain(..length).collect()
+            }
+            None => self.take_until(None),
+        };
+
+        debug!(
+            "recv_raw: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        self.check_connected()?;
+
+        debug!("recv_until({byte}, {timeout:?})");
+
+        if self.rx_buf.is_empty() {
+            self.load_response()?;
+        }
+        while !self.rx_buf.contains(&byte) {
+            self.load_response()?;
+        }
+
+        let resp = self.take_until(Some(byte));
+
+        debug!(
+            "recv_until: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        self.check_connected()?;
+
+        debug!("flush_rx({timeout:?})");
+
+        self.rx_buf.clear();
+        if self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.call == RecordCall::FlushRx)
+        {
+            self.entries.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_script_lines() {
+        for script in ["é *IDN?", "> *IDN?\n→ 1", "? *IDN?"] {
+            assert!(
+                matches!(
+                    ReplayProtocol::from_script(script),
+                    Err(Error::InvalidArgument(_))
+                ),
+                "{script:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn error_lines_are_not_unescaped() {
+        let replay = ReplayProtocol::from_script(r"! C:\Data timed out").unwrap();
+        assert!(matches!(
+            &replay.entries[0].error,
+            Some(RecordError::Timeout(message)) if message == r"C:\Data timed out"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unread_response_is_an_error() {
+        let mut replay = ReplayProtocol::from_script(
+            r"
+            > MEAS?
+            < 1.0
+            > MEAS?
+            < 2.0
+            ",
+        )
+        .unwrap();
+        replay.connect().await.unwrap();
+
+        let proto: &mut dyn ScpiProtocol = &mut replay;
+        proto.send("MEAS?").await.unwrap();
+        let err = proto.query("MEAS?").await.unwrap_err();
+        assert!(err.to_string().contains("expected response `1.0`"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn replays_script() {
+        let mut replay = ReplayProtocol::from_script(
+            r"
+            # Comment
+            > *IDN?
+            < MAKE,MODEL,0,1
+            > MEAS?
+            ! Response times out
+            ",
+        )
+        .unwrap();
+        replay.connect().await.unwrap();
+
+        let proto: &mut dyn ScpiProtocol = &mut replay;
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "MAKE,MODEL,0,1");
+        assert!(matches!(proto.query("MEAS?").await, Err(Error::Timeout(_))));
+        replay.check_complete().unwrap();
+    }
+}
@@ -1,6 +1,9 @@
-use std::{net::ToSocketAddrs, time::Duration};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use log::warn;
+use strum_macros::{AsRefStr, EnumString};
+use tokio::{sync::broadcast, time::Instant};
 
 use crate::{
     error::{Error, Result},
@@ -8,7 +11,100 @@ use crate::{
     protocol,
 };
 
-use super::Protocol;
+use super::{Ieee488Control, Protocol, ResourceAddress, ResourceTransport};
+
+/// Default timeout for I/O operations
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of entries to read when draining the error queue, in case
+/// the device never reports the end of the queue
+const MAX_ERROR_QUEUE: usize = 32;
+
+/// Operation complete bit in the standard event status register
+pub(crate) const ESR_OPC: u8 = 0x01;
+/// Event status bit in the status byte, set when an enabled standard event
+/// occurs
+const STB_ESB: u8 = 0x20;
+/// Interval at which to poll for operation completion when service requests
+/// are not available
+pub(crate) const OPC_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Time to wait for the terminator following a definite length block, which
+/// some devices do not send
+const BLOCK_TERMINATOR_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// When to automatically check the device's error queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorCheck {
+    /// Only check when explicitly requested
+    #[default]
+    Never,
+    /// Check after every command
+    EveryCommand,
+    /// Check after every given number of commands
+    Interval(u32),
+}
+
+/// Message terminator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Terminator {
+    /// Line feed
+    Lf,
+    /// Carriage return followed by line feed
+    CrLf,
+    /// Carriage return
+    Cr,
+    /// End of message signalled by the transport, e.g. the GPIB EOI line
+    Eoi,
+}
+impl Terminator {
+    /// Bytes to append to each message sent
+    pub fn suffix(&self) -> &'static [u8] {
+        match self {
+            Self::Lf => b"\n",
+            Self::CrLf => b"\r\n",
+            Self::Cr => b"\r",
+            Self::Eoi => b"",
+        }
+    }
+
+    /// Byte ending each message received, or None if the end of the message
+    /// is signalled by the transport
+    pub fn end_byte(&self) -> Option<u8> {
+        match self {
+            Self::Lf | Self::CrLf => Some(b'\n'),
+            Self::Cr => Some(b'\r'),
+            Self::Eoi => None,
+        }
+    }
+}
+
+/// Per-connection settings common to all SCPI transports
+#[derive(Debug, Clone)]
+pub struct ScpiSettings {
+    /// Timeout for I/O operations that do not specify their own
+    pub timeout: Duration,
+    /// Automatic error queue checking
+    pub error_check: ErrorCheck,
+    /// Terminator appended to messages sent
+    pub write_terminator: Terminator,
+    /// Terminator ending messages received. HiSLIP always ends messages on
+    /// the END indicator, regardless of this setting.
+    pub read_terminator: Terminator,
+    /// Commands sent since the error queue was last checked
+    unchecked_commands: u32,
+}
+impl Default for ScpiSettings {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            error_check: ErrorCheck::Never,
+            write_terminator: Terminator::CrLf,
+            read_terminator: Terminator::Lf,
+            unchecked_commands: 0,
+        }
+    }
+}
 
 #[async_trait]
 pub trait ScpiProtocol: Protocol + Send + Sync {
@@ -27,20 +123,44 @@ pub trait ScpiProtocol: Protocol + Send + Sync {
     async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>>;
 
     async fn flush_rx(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Connection settings
+    fn settings(&self) -> &ScpiSettings;
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings;
+
+    /// Whether the transport signals the end of each message itself, allowing
+    /// EOI termination
+    fn supports_eoi(&self) -> bool {
+        false
+    }
+
+    /// Bus-level control operations, if supported by the transport
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        None
+    }
 }
 impl dyn ScpiProtocol {
     pub async fn send(&mut self, data: impl AsRef<[u8]>) -> Result<()> {
-        let mut to_send = vec![];
-        to_send.extend_from_slice(data.as_ref());
-        to_send.extend_from_slice("\r\n".as_bytes());
-        self.int_send(&to_send).await
+        let to_send = self.terminate(data.as_ref())?;
+        self.int_send(&to_send).await?;
+
+        /* A response is pending for queries, so the error queue cannot be
+         * checked until it has been read */
+        if !is_query(data.as_ref()) {
+            self.command_complete(data.as_ref()).await?;
+        }
+
+        Ok(())
     }
 
     pub async fn query(&mut self, data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
-        let mut to_send = vec![];
-        to_send.extend_from_slice(data.as_ref());
-        to_send.extend_from_slice("\r\n".as_bytes());
-        self.int_query(&to_send).await
+        let to_send = self.terminate(data.as_ref())?;
+        let resp = self.int_query(&to_send).await?;
+
+        self.command_complete(data.as_ref()).await?;
+
+        Ok(resp)
     }
 
     pub async fn query_str(&mut self, data: impl AsRef<[u8]>) -> Result<String> {
@@ -61,6 +181,239 @@ impl dyn ScpiProtocol {
         self.int_recv().await
     }
 
+    /// Receive an IEEE 488.2 arbitrary block, returning its payload. Both
+    /// definite (`#<n><length><data>`) and indefinite (`#0<data>`) length
+    /// blocks are supported. The response terminator following the block is
+    /// consumed.
+    pub async fn recv_block(&mut self) -> Result<Vec<u8>> {
+        let timeout = self.settings().timeout;
+
+        /* Header starts with '#' and the number of length digits, skipping any
+         * whitespace left over from a previous response */
+        let mut header = vec![];
+        loop {
+            let mut data = self.recv_raw(Some(2 - header.len()), Some(timeout)).await?;
+            if data.is_empty() {
+                return Err(Error::BadResponse(
+                    "Response ended before block header".into(),
+                ));
+            }
+            header.append(&mut data);
+            while header.first().is_some_and(u8::is_ascii_whitespace) {
+                header.remove(0);
+            }
+            if header.len() == 2 {
+                break;
+            }
+        }
+
+        if header[0] != b'#' {
+            return Err(Error::BadResponse(format!(
+                "Expected block header, got '{}'",
+                header[0].escape_ascii()
+            )));
+        }
+        let digits = header[1];
+        if !digits.is_ascii_digit() {
+            return Err(Error::BadResponse(format!(
+                "Invalid block header length digit '{}'",
+                digits.escape_ascii()
+            )));
+        }
+
+        if digits == b'0' {
+            /* Indefinite length, data continues until the terminator */
+            let mut data = self.recv_raw(None, Some(timeout)).await?;
+            match self.settings().read_terminator {
+                Terminator::Lf | Terminator::CrLf => {
+                    if data.last() == Some(&b'\n') {
+                        data.pop();
+                        if data.last() == Some(&b'\r') {
+                            data.pop();
+                        }
+                    }
+                }
+                Terminator::Cr => {
+                    if data.last() == Some(&b'\r') {
+                        data.pop();
+                    }
+                }
+                Terminator::Eoi => {}
+            }
+            return Ok(data);
+        }
+
+        let length = self
+            .recv_raw(Some((digits - b'0') as usize), Some(timeout))
+            .await?;
+        let length = String::from_utf8_lossy(&length);
+        let length: usize = length
+            .parse()
+            .map_err(|e| Error::BadResponse(format!("Invalid block length `{length}`: {e}")))?;
+
+        /* Transports stop reading at the end of a message, so a block that is
+         * shorter than its header claims is returned short */
+        let data = self.recv_raw(Some(length), Some(timeout)).await?;
+        if data.len() != length {
+            return Err(Error::BadResponse(format!(
+                "Block of {length} bytes ended after {} bytes",
+                data.len()
+            )));
+        }
+
+        /* Consume the terminator, some devices may not send one */
+        match self.recv_raw(None, Some(BLOCK_TERMINATOR_TIMEOUT)).await {
+            Ok(rest) if rest.iter().all(|c| c.is_ascii_whitespace()) => {}
+            Ok(rest) => {
+                return Err(Error::BadResponse(format!(
+                    "Unexpected data after block: `{}`",
+                    rest.escape_ascii()
+                )));
+            }
+            Err(Error::Timeout(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(data)
+    }
+
+    /// Send a query and receive an IEEE 488.2 arbitrary block response,
+    /// returning its payload
+    pub async fn query_block(&mut self, data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+        self.send(&data).await?;
+        let block = self.recv_block().await?;
+
+        self.command_complete(data.as_ref()).await?;
+
+        Ok(block)
+    }
+
+    /// Drain the device's error queue, returning the first error reported
+    pub async fn check_errors(&mut self) -> Result<()> {
+        self.settings_mut().unchecked_commands = 0;
+        self.drain_errors(None).await
+    }
+
+    /// Check the error queue if required by the error check setting, after a
+    /// command has completed
+    async fn command_complete(&mut self, command: &[u8]) -> Result<()> {
+        let settings = self.settings_mut();
+        settings.unchecked_commands += 1;
+        let check = match settings.error_check {
+            ErrorCheck::Never => false,
+            ErrorCheck::EveryCommand => true,
+            ErrorCheck::Interval(n) => settings.unchecked_commands >= n,
+        };
+        if !check {
+            return Ok(());
+        }
+        settings.unchecked_commands = 0;
+
+        let command = String::from_utf8_lossy(command).to_string();
+        self.drain_errors(Some(command)).await
+    }
+
+    async fn drain_errors(&mut self, command: Option<String>) -> Result<()> {
+        let mut first = None;
+
+        for _ in 0..MAX_ERROR_QUEUE {
+            /* Bypass query() to avoid checking errors recursively */
+            let query = self.terminate(b"SYST:ERR?")?;
+            let resp = self.int_query(&query).await?;
+            let resp = String::from_utf8_lossy(&resp);
+            let resp = resp.trim();
+
+            let Some((code, message)) = resp.split_once(',') else {
+                return Err(Error::BadResponse(format!(
+                    "Could not parse error queue entry `{resp}`"
+                )));
+            };
+            let code: i32 = code.trim().parse().map_err(|e| {
+                Error::BadResponse(format!("Could not parse error code `{code}`: {e}"))
+            })?;
+            if code == 0 {
+                break;
+            }
+
+            let message = message.trim().trim_matches('"').to_string();
+            if first.is_none() {
+                first = Some(Error::DeviceError {
+                    code,
+                    message,
+                    command: command.clone(),
+                });
+            } else {
+                warn!("Additional device error {code}, \"{message}\"");
+            }
+        }
+
+        match first {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Append the write terminator to a message
+    fn terminate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let terminator = self.settings().write_terminator;
+        if terminator == Terminator::Eoi && !self.supports_eoi() {
+            return Err(Error::NotSupported(
+                "Transport does not support EOI termination".into(),
+            ));
+        }
+
+        let mut msg = data.to_vec();
+        msg.extend_from_slice(terminator.suffix());
+        Ok(msg)
+    }
+
+    /// Set the terminators for messages sent and received
+    pub fn set_terminators(&mut self, write: Terminator, read: Terminator) -> Result<()> {
+        if (write == Terminator::Eoi || read == Terminator::Eoi) && !self.supports_eoi() {
+            return Err(Error::NotSupported(
+                "Transport does not support EOI termination".into(),
+            ));
+        }
+
+        let settings = self.settings_mut();
+        settings.write_terminator = write;
+        settings.read_terminator = read;
+
+        Ok(())
+    }
+
+    /// Set the default timeout for I/O operations
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.settings_mut().timeout = timeout;
+    }
+
+    /// Set when the device's error queue is automatically checked
+    pub fn set_error_check(&mut self, error_check: ErrorCheck) {
+        let settings = self.settings_mut();
+        settings.error_check = error_check;
+        settings.unchecked_commands = 0;
+    }
+
+    /// Receive a response, overriding the default timeout
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let default = std::mem::replace(&mut self.settings_mut().timeout, timeout);
+        let res = self.int_recv().await;
+        self.settings_mut().timeout = default;
+        res
+    }
+
+    /// Send a query and receive the response, overriding the default timeout
+    pub async fn query_timeout(
+        &mut self,
+        data: impl AsRef<[u8]>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let default = std::mem::replace(&mut self.settings_mut().timeout, timeout);
+        let res = self.query(data).await;
+        self.settings_mut().timeout = default;
+        res
+    }
+
     pub async fn identify(&mut self) -> Result<String> {
         self.query_str("*IDN?").await
     }
@@ -70,68 +423,739 @@ impl dyn ScpiProtocol {
 
         ModelInfo::from_idn(&idn)
     }
+
+    /// Clear the device, falling back to *CLS if the transport cannot perform
+    /// a device clear
+    pub async fn device_clear(&mut self) -> Result<()> {
+        match self.ieee488() {
+            Some(ctrl) => ctrl.device_clear().await,
+            None => self.send("*CLS").await,
+        }
+    }
+
+    /// Trigger the device, falling back to *TRG if the transport cannot send
+    /// a group execute trigger
+    pub async fn device_trigger(&mut self) -> Result<()> {
+        match self.ieee488() {
+            Some(ctrl) => ctrl.device_trigger().await,
+            None => self.send("*TRG").await,
+        }
+    }
+
+    /// Read the status byte, falling back to *STB? if the transport cannot
+    /// read it directly
+    pub async fn read_stb(&mut self) -> Result<u8> {
+        if let Some(ctrl) = self.ieee488() {
+            return ctrl.read_stb().await;
+        }
+
+        self.query_u8("*STB?").await
+    }
+
+    /// Wait for all pending operations to complete, using *OPC?
+    pub async fn opc_query(&mut self) -> Result<()> {
+        let resp = self.query_str("*OPC?").await?;
+        if resp != "1" {
+            return Err(Error::BadResponse(format!(
+                "Unexpected *OPC? response `{resp}`"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to timeout for all pending operations to complete. Unlike
+    /// opc_query(), the device is not blocked from handling other commands
+    /// while waiting. A service request is used to signal completion if the
+    /// transport supports it, otherwise the event status register is polled.
+    pub async fn wait_complete(&mut self, timeout: Duration) -> Result<()> {
+        if let Some(ctrl) = self.ieee488() {
+            match ctrl.subscribe_srq().await {
+                Ok(srq) => return self.wait_complete_srq(srq, timeout).await,
+                Err(Error::NotSupported(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.start_opc_poll().await?;
+
+        let end = Instant::now() + timeout;
+        loop {
+            if self.poll_opc().await? {
+                return Ok(());
+            }
+            if Instant::now() >= end {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for operation complete for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+            tokio::time::sleep(OPC_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Have the device set the operation complete event once all pending
+    /// operations complete, to be polled for with poll_opc(). Returns the
+    /// event status bits cleared in doing so.
+    pub(crate) async fn start_opc_poll(&mut self) -> Result<u8> {
+        /* Clear any operation complete event left over from earlier */
+        let stale = self.read_esr().await?;
+        self.send("*OPC").await?;
+        Ok(stale)
+    }
+
+    /// Whether the operations pending at start_opc_poll() have completed
+    pub(crate) async fn poll_opc(&mut self) -> Result<bool> {
+        Ok(self.read_esr().await? & ESR_OPC != 0)
+    }
+
+    /// Read and clear the standard event status register
+    pub(crate) async fn read_esr(&mut self) -> Result<u8> {
+        self.query_u8("*ESR?").await
+    }
+
+    async fn wait_complete_srq(
+        &mut self,
+        mut srq: broadcast::Receiver<()>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let (enables, _) = self.start_opc_srq().await?;
+        let res = recv_srq(&mut srq, Instant::now() + timeout, timeout).await;
+        let finished = self.finish_opc_srq(enables).await;
+
+        res.and(finished.map(|_| ()))
+    }
+
+    /// Have the device request service on operation complete once all
+    /// pending operations complete. Returns the enables to restore with
+    /// finish_opc_srq(), and the event status bits cleared in doing so.
+    pub(crate) async fn start_opc_srq(&mut self) -> Result<(SrqEnables, u8)> {
+        let enables = SrqEnables {
+            ese: self.query_u8("*ESE?").await?,
+            sre: self.query_u8("*SRE?").await?,
+        };
+
+        let res = async {
+            let stale = self.read_esr().await?;
+            self.send(format!("*ESE {ESR_OPC}")).await?;
+            self.send(format!("*SRE {STB_ESB}")).await?;
+            self.send("*OPC").await?;
+            Ok(stale)
+        }
+        .await;
+
+        match res {
+            Ok(stale) => Ok((enables, stale)),
+            Err(e) => {
+                /* Already failing, so the original error is reported */
+                let _ = self.restore_srq_enables(enables).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Clear the operation complete event and service request left by
+    /// start_opc_srq(), restoring the previous enables even if clearing
+    /// fails. Returns the event status bits cleared.
+    pub(crate) async fn finish_opc_srq(&mut self, enables: SrqEnables) -> Result<u8> {
+        let res = async {
+            let esr = self.read_esr().await?;
+            self.read_stb().await?;
+            Ok(esr)
+        }
+        .await;
+        let restored = self.restore_srq_enables(enables).await;
+
+        res.and_then(|esr| restored.map(|()| esr))
+    }
+
+    async fn restore_srq_enables(&mut self, enables: SrqEnables) -> Result<()> {
+        self.send(format!("*ESE {}", enables.ese)).await?;
+        self.send(format!("*SRE {}", enables.sre)).await
+    }
+
+    async fn query_u8(&mut self, data: impl AsRef<[u8]>) -> Result<u8> {
+        let resp = self.query_str(data).await?;
+        resp.parse()
+            .map_err(|e| Error::BadResponse(format!("Could not parse response `{resp}`: {e}")))
+    }
+
+    /// Return the device to local control
+    pub async fn device_local(&mut self) -> Result<()> {
+        match self.ieee488() {
+            Some(ctrl) => ctrl.device_local().await,
+            None => Err(Error::NotSupported(
+                "Transport cannot return device to local control".into(),
+            )),
+        }
+    }
+
+    /// Acquire an exclusive lock on the device, waiting up to timeout for
+    /// another client to release it
+    pub async fn lock(&mut self, timeout: Duration) -> Result<()> {
+        match self.ieee488() {
+            Some(ctrl) => ctrl.lock(timeout).await,
+            None => Err(Error::NotSupported(
+                "Transport does not support locking".into(),
+            )),
+        }
+    }
+
+    /// Release a lock acquired with lock()
+    pub async fn unlock(&mut self) -> Result<()> {
+        match self.ieee488() {
+            Some(ctrl) => ctrl.unlock().await,
+            None => Err(Error::NotSupported(
+                "Transport does not support locking".into(),
+            )),
+        }
+    }
+}
+
+/// Event status and service request enables in effect before waiting for
+/// operation complete by service request
+pub(crate) struct SrqEnables {
+    ese: u8,
+    sre: u8,
+}
+
+/// Wait until deadline for a service request, timeout being the total time
+/// waited as reported in the error
+pub(crate) async fn recv_srq(
+    srq: &mut broadcast::Receiver<()>,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<()> {
+    match tokio::time::timeout_at(deadline, srq.recv()).await {
+        Ok(Ok(())) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => Ok(()),
+        Ok(Err(broadcast::error::RecvError::Closed)) => {
+            Err(Error::Unspecified("Service request channel closed".into()))
+        }
+        Err(_) => Err(Error::Timeout(format!(
+            "Timed out waiting for operation complete for {} ms",
+            timeout.as_millis()
+        ))),
+    }
 }
 
+/// Connect to an instrument by URI or VISA resource string, see
+/// [`ResourceAddress`] for the supported forms
 pub async fn scpi_from_uri(uri: impl AsRef<str>) -> Result<Box<dyn ScpiProtocol>> {
-    /* TODO: Centralize URI parsing */
-
-    let uri = uri.as_ref();
-    if let Some(socket) = uri.strip_prefix("vxi11://") {
-        let socket = if socket.contains(':') {
-            socket.to_string()
-        } else {
-            format!("{}:{}", socket, protocol::PORTMAP_PORT)
-        };
-        let Some(socket) = socket.to_socket_addrs()?.next() else {
-            return Err(Error::Unspecified(format!("Could not resolve '{socket}'")));
-        };
+    let addr: ResourceAddress = uri.as_ref().parse()?;
 
-        let mut client = protocol::ScpiVxiProtocol::new(socket);
-        client.connect().await?;
+    scpi_from_address(&addr).await
+}
 
-        Ok(Box::new(client))
-    } else if let Some(socket) = uri.strip_prefix("tcp://") {
-        let Some(socket) = socket.to_socket_addrs()?.next() else {
-            return Err(Error::Unspecified(format!("Could not resolve '{socket}'")));
-        };
+/// Connect to an instrument at a parsed resource address
+pub async fn scpi_from_address(addr: &ResourceAddress) -> Result<Box<dyn ScpiProtocol>> {
+    let options = &addr.options;
 
-        let mut scpi = protocol::ScpiTcpProtocol::new(socket)?;
-        scpi.connect().await?;
+    if !matches!(
+        addr.transport,
+        ResourceTransport::Serial { .. }
+            | ResourceTransport::SerialUsb { .. }
+            | ResourceTransport::PrologixSerial { .. }
+    ) && options.has_serial()
+    {
+        return Err(Error::InvalidArgument(
+            "Serial options given for non-serial resource".into(),
+        ));
+    }
+    if !matches!(addr.transport, ResourceTransport::Sim { .. }) && options.net.is_some() {
+        return Err(Error::InvalidArgument(
+            "Net given for non-simulated resource".into(),
+        ));
+    }
+
+    let mut client: Box<dyn ScpiProtocol> = match &addr.transport {
+        ResourceTransport::Vxi11 { device, .. } => {
+            let mut client = protocol::ScpiVxiProtocol::new(addr.resolve_socket()?);
+            client.set_device_name(device.as_str());
+            Box::new(client)
+        }
+        ResourceTransport::Tcp { .. } => {
+            Box::new(protocol::ScpiTcpProtocol::new(addr.resolve_socket()?)?)
+        }
+        ResourceTransport::Hislip { sub_address, .. } => Box::new(
+            protocol::ScpiHislipProtocol::new(addr.resolve_socket()?, sub_address),
+        ),
+        ResourceTransport::Serial { port } => Box::new(
+            protocol::ScpiSerialProtocol::new_with_config(port, options.serial_config()),
+        ),
+        ResourceTransport::SerialUsb { usb } => Box::new(protocol::ScpiSerialProtocol::new_usb(
+            usb.clone(),
+            options.serial_config(),
+        )),
+        ResourceTransport::Usbtmc { vid, pid, serial } => Box::new(
+            protocol::ScpiUsbtmcProtocol::new(*vid, *pid, serial.as_deref()),
+        ),
+        ResourceTransport::Prologix { gpib_addr, .. } => Box::new(
+            protocol::PrologixAdapter::shared_tcp(addr.resolve_socket()?).device(*gpib_addr)?,
+        ),
+        ResourceTransport::Mock { path } => Box::new(protocol::ReplayProtocol::from_file(path)?),
+        ResourceTransport::Sim { class, model, name } => {
+            let client =
+                protocol::ScpiSimProtocol::open(*class, model.as_deref(), name.as_deref())?;
+            if let Some(net) = &options.net {
+                client
+                    .device()
+                    .lock()
+                    .unwrap()
+                    .instrument()
+                    .set_probe(net)?;
+            }
+            Box::new(client)
+        }
+        ResourceTransport::PrologixSerial { port, gpib_addr } => Box::new(
+            protocol::PrologixAdapter::shared_serial(port, options.serial_config())
+                .device(*gpib_addr)?,
+        ),
+    };
 
-        Ok(Box::new(scpi))
-    } else if let Some(path) = uri.strip_prefix("serial:") {
-        let (path, args) = match path.split_once('?') {
-            Some((path, args)) => (path, args.split('&').collect()),
-            None => (path, vec![]),
+    if let Some(path) = &options.record {
+        client = Box::new(protocol::RecordingProtocol::to_file(client, path).await?);
+    }
+    if let Some(retries) = options.reconnect {
+        let policy = protocol::ReconnectPolicy {
+            retries,
+            ..Default::default()
         };
-        let mut baud = 9600;
+        client = Box::new(protocol::ReconnectingProtocol::new(client, policy));
+    }
 
-        for arg in args {
-            let Some((key, value)) = arg.split_once('=') else {
-                return Err(Error::InvalidArgument(format!(
-                    "Improperly formatted URI argument '{arg}'"
-                )));
-            };
+    if let Some(timeout) = options.timeout {
+        client.set_timeout(timeout);
+    }
+    if let Some(error_check) = options.error_check {
+        client.set_error_check(error_check);
+    }
+    if let Some(termination) = options.termination {
+        client.set_terminators(termination, termination)?;
+    }
+    client.connect().await?;
 
-            match key {
-                "baud" => {
-                    baud = value.parse().map_err(|_| {
-                        Error::InvalidArgument(format!("Invalid value for baud rate: {value}"))
-                    })?
-                }
-                _ => {
-                    return Err(Error::InvalidArgument(format!(
-                        "Unsupported argument '{key}' in URI"
-                    )));
+    Ok(client)
+}
+
+/// Whether a program message includes a query, judged by the header of each
+/// message unit so that a `?` within string or block data is ignored
+fn is_query(message: &[u8]) -> bool {
+    let mut i = 0;
+    while i < message.len() {
+        while message.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        let start = i;
+        while i < message.len() && !message[i].is_ascii_whitespace() && message[i] != b';' {
+            i += 1;
+        }
+        if message[start..i].ends_with(b"?") {
+            return true;
+        }
+
+        /* Skip the parameters of the message unit */
+        while i < message.len() && message[i] != b';' {
+            match message[i] {
+                quote @ (b'"' | b'\'') => {
+                    /* Doubled quotes within the string are skipped as two
+                     * adjacent strings */
+                    i += 1;
+                    while i < message.len() && message[i] != quote {
+                        i += 1;
+                    }
+                    i += 1;
                 }
+                b'#' => match message.get(i + 1) {
+                    Some(digits @ b'1'..=b'9') => {
+                        let digits = (digits - b'0') as usize;
+                        let length = message
+                            .get(i + 2..i + 2 + digits)
+                            .and_then(|length| std::str::from_utf8(length).ok())
+                            .and_then(|length| length.parse::<usize>().ok());
+                        match length {
+                            Some(length) => i += 2 + digits + length,
+                            None => i += 1,
+                        }
+                    }
+                    /* Indefinite length block runs to the end of the message */
+                    Some(b'0') => return false,
+                    /* Non-decimal numeric, e.g. #H1F */
+                    _ => i += 1,
+                },
+                _ => i += 1,
+            }
+        }
+        i += 1;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ReplayProtocol;
+
+    /// Replay with service requests, one being sent as soon as subscribed if
+    /// fire is set
+    struct SrqReplay {
+        replay: ReplayProtocol,
+        srq: broadcast::Sender<()>,
+        fire: bool,
+    }
+    #[async_trait]
+    impl Protocol for SrqReplay {
+        async fn connect(&mut self) -> Result<()> {
+            self.replay.connect().await
+        }
+
+        async fn disconnect(&mut self) -> Result<()> {
+            self.replay.disconnect().await
+        }
+
+        async fn model(&mut self) -> Result<ModelInfo> {
+            self.replay.model().await
+        }
+    }
+    #[async_trait]
+    impl ScpiProtocol for SrqReplay {
+        async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+            self.replay.int_send(data).await
+        }
+
+        async fn int_recv(&mut self) -> Result<Vec<u8>> {
+            self.replay.int_recv().await
+        }
+
+        async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+            self.replay.int_query(data).await
+        }
+
+        async fn recv_raw(
+            &mut self,
+            length: Option<usize>,
+            timeout: Option<Duration>,
+        ) -> Result<Vec<u8>> {
+            self.replay.recv_raw(length, timeout).await
+        }
+
+        async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+            self.replay.recv_until(byte, timeout).await
+        }
+
+        async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+            self.replay.flush_rx(timeout).await
+        }
+
+        fn settings(&self) -> &ScpiSettings {
+            self.replay.settings()
+        }
+
+        fn settings_mut(&mut self) -> &mut ScpiSettings {
+            self.replay.settings_mut()
+        }
+
+        fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+            Some(self)
+        }
+    }
+    #[async_trait]
+    impl Ieee488Control for SrqReplay {
+        async fn device_clear(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn device_trigger(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn read_stb(&mut self) -> Result<u8> {
+            Ok(0)
+        }
+
+        async fn device_local(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn subscribe_srq(&mut self) -> Result<broadcast::Receiver<()>> {
+            let srq = self.srq.subscribe();
+            if self.fire {
+                self.srq.send(()).unwrap();
             }
+            Ok(srq)
+        }
+    }
+
+    async fn srq_replay(script: &str, fire: bool) -> SrqReplay {
+        let mut proto = SrqReplay {
+            replay: ReplayProtocol::from_script(script).unwrap(),
+            srq: broadcast::channel(1).0,
+            fire,
+        };
+        proto.connect().await.unwrap();
+        proto
+    }
+
+    async fn replay(script: &str) -> Box<dyn ScpiProtocol> {
+        let mut proto: Box<dyn ScpiProtocol> =
+            Box::new(ReplayProtocol::from_script(script).unwrap());
+        proto.connect().await.unwrap();
+        proto
+    }
+
+    #[tokio::test]
+    async fn recv_block_definite_length() {
+        let mut proto = replay(
+            r"
+            > :DATA?
+            < #15a\nb\x00c
+            > *OPC?
+            < 1
+            ",
+        )
+        .await;
+
+        /* Block contains the terminator, which must not end it */
+        assert_eq!(proto.query_block(":DATA?").await.unwrap(), b"a\nb\0c");
+        assert_eq!(proto.query_str("*OPC?").await.unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn recv_block_skips_leading_whitespace() {
+        let mut proto = replay(
+            r"
+            > :DATA?
+            < \r\n
+            < #210abcdefghij
+            ",
+        )
+        .await;
+
+        assert_eq!(proto.query_block(":DATA?").await.unwrap(), b"abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn recv_block_indefinite_length() {
+        let mut proto = replay(
+            r"
+            > :DATA?
+            < #0a\nbc
+            ",
+        )
+        .await;
+
+        /* Data runs to the end of the message, terminator excluded */
+        assert_eq!(proto.query_block(":DATA?").await.unwrap(), b"a\nbc");
+    }
+
+    #[tokio::test]
+    async fn recv_block_rejects_invalid_header() {
+        let mut proto = replay(
+            r"
+            > :DATA?
+            < 1.0,2.0
+            > :DATA?
+            < #x
+            ",
+        )
+        .await;
+
+        for _ in 0..2 {
+            assert!(matches!(
+                proto.query_block(":DATA?").await,
+                Err(Error::BadResponse(_))
+            ));
         }
+    }
+
+    #[tokio::test]
+    async fn wait_complete_polling_clears_stale_event() {
+        let mut proto = replay(
+            r"
+            > *ESR?
+            < 1
+            > *OPC
+            > *ESR?
+            < 0
+            > *ESR?
+            < 1
+            ",
+        )
+        .await;
+
+        proto.wait_complete(Duration::from_secs(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_complete_srq_restores_enables() {
+        let script = r"
+            > *ESE?
+            < 4
+            > *SRE?
+            < 16
+            > *ESR?
+            < 0
+            > *ESE 1
+            > *SRE 32
+            > *OPC
+            > *ESR?
+            < 1
+            > *ESE 4
+            > *SRE 16
+            ";
+        let mut srq = srq_replay(script, true).await;
+        let proto: &mut dyn ScpiProtocol = &mut srq;
+        proto.wait_complete(Duration::from_secs(1)).await.unwrap();
+        srq.replay.check_complete().unwrap();
+
+        /* Enables are restored even if waiting fails */
+        let mut srq = srq_replay(script, false).await;
+        let proto: &mut dyn ScpiProtocol = &mut srq;
+        assert!(matches!(
+            proto.wait_complete(Duration::from_millis(10)).await,
+            Err(Error::Timeout(_))
+        ));
+        srq.replay.check_complete().unwrap();
+    }
+
+    #[test]
+    fn detects_queries_by_header() {
+        for message in [
+            &b"*IDN?"[..],
+            b":MEAS:VOLT? CH1",
+            b"  :sour1:volt?",
+            b"VOLT 1;:MEAS:CURR?",
+            b":DISP:TEXT \"a;b\";*OPC?",
+            b":DATA #13;?;;*ESR?",
+        ] {
+            assert!(is_query(message), "{}", message.escape_ascii());
+        }
+
+        for message in [
+            &b"*RST"[..],
+            b":DISP:TEXT \"Ready?\"",
+            b":DISP:TEXT 'it''s?'",
+            b":DATA #15ab?de",
+            b":DATA #0a?b",
+            b"VOLT 1;CURR 2",
+            b":STAT:PRES #H1F",
+            b"",
+        ] {
+            assert!(!is_query(message), "{}", message.escape_ascii());
+        }
+    }
+
+    #[tokio::test]
+    async fn send_checks_errors_after_data_containing_question_mark() {
+        let mut replay = ReplayProtocol::from_script(
+            r#"
+            > :DISP:TEXT "Ready?"
+            > SYST:ERR?
+            < 0,"No error"
+            > :DATA #13a?c
+            > SYST:ERR?
+            < 0,"No error"
+            "#,
+        )
+        .unwrap();
+        replay.connect().await.unwrap();
+        let proto = &mut replay as &mut dyn ScpiProtocol;
+        proto.settings_mut().error_check = ErrorCheck::EveryCommand;
+
+        proto.send(":DISP:TEXT \"Ready?\"").await.unwrap();
+        proto.send(":DATA #13a?c").await.unwrap();
+        replay.check_complete().unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_errors_after_every_command() {
+        let mut replay = ReplayProtocol::from_script(
+            r#"
+            > *RST
+            > SYST:ERR?
+            < 0,"No error"
+            > :MEAS:VOLT?
+            < 1.5
+            > SYST:ERR?
+            < 0,"No error"
+            > :SOUR:VOLT 100
+            > SYST:ERR?
+            < -222,"Data out of range"
+            > SYST:ERR?
+            < -113,"Undefined header"
+            > SYST:ERR?
+            < +0,"No error"
+            "#,
+        )
+        .unwrap();
+        replay.connect().await.unwrap();
+        let proto = &mut replay as &mut dyn ScpiProtocol;
+        proto.settings_mut().error_check = ErrorCheck::EveryCommand;
+
+        proto.send("*RST").await.unwrap();
+        assert_eq!(proto.query_f32(":MEAS:VOLT?").await.unwrap(), 1.5);
+
+        /* First error is reported, the rest of the queue drained */
+        let Err(Error::DeviceError {
+            code,
+            message,
+            command,
+        }) = proto.send(":SOUR:VOLT 100").await
+        else {
+            panic!("Expected a device error");
+        };
+        assert_eq!(code, -222);
+        assert_eq!(message, "Data out of range");
+        assert_eq!(command.as_deref(), Some(":SOUR:VOLT 100"));
+        replay.check_complete().unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_errors_at_interval() {
+        let mut replay = ReplayProtocol::from_script(
+            r#"
+            > OUTP ON
+            > :MEAS:VOLT?
+            < 1.5
+            > *RST
+            > SYST:ERR?
+            < 0,"No error"
+            > OUTP OFF
+            > SYST:ERR?
+            < 0,"No error"
+            > *CLS
+            "#,
+        )
+        .unwrap();
+        replay.connect().await.unwrap();
+        let proto = &mut replay as &mut dyn ScpiProtocol;
+        proto.settings_mut().error_check = ErrorCheck::Interval(3);
+
+        proto.send("OUTP ON").await.unwrap();
+        proto.query(":MEAS:VOLT?").await.unwrap();
+        proto.send("*RST").await.unwrap();
+
+        /* Explicit check restarts the count */
+        proto.send("OUTP OFF").await.unwrap();
+        proto.check_errors().await.unwrap();
+        proto.send("*CLS").await.unwrap();
+        replay.check_complete().unwrap();
+    }
 
-        let mut client = protocol::ScpiSerialProtocol::new(path, baud);
-        client.connect().await?;
+    #[tokio::test]
+    async fn error_queue_drain_is_limited() {
+        let mut script = "> SYST:ERR?\n< -100,\"Command error\"\n".repeat(MAX_ERROR_QUEUE);
+        script.push_str("> *RST\n");
+        let mut replay = ReplayProtocol::from_script(&script).unwrap();
+        replay.connect().await.unwrap();
+        let proto = &mut replay as &mut dyn ScpiProtocol;
 
-        Ok(Box::new(client))
-    } else {
-        Err(Error::InvalidArgument(format!("Unknown scheme in '{uri}'")))
+        /* Device never reports the end of its queue */
+        assert!(matches!(
+            proto.check_errors().await,
+            Err(Error::DeviceError { code: -100, .. })
+        ));
+        proto.send("*RST").await.unwrap();
+        replay.check_complete().unwrap();
     }
 }
@@ -3,30 +3,188 @@ use std::time::Duration;
 use async_trait::async_trait;
 use log::debug;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
     time::Instant,
 };
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
 
 use crate::{
     error::{Error, Result},
     model::ModelInfo,
-    protocol::{Protocol, ScpiProtocol},
+    protocol::{
+        FlowControl, Parity, Protocol, ScpiProtocol, ScpiSettings, StopBits, UsbSerialMatch,
+        serial_ports, stream,
+    },
 };
 
+/// Serial line configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub parity: Parity,
+    /// Data bits, 5 to 8
+    pub data_bits: u8,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// Level to drive DTR to on connect, or left as opened if None
+    pub dtr: Option<bool>,
+    /// Level to drive RTS to on connect, or left as opened if None. Should not
+    /// be set with hardware flow control.
+    pub rts: Option<bool>,
+    /// Minimum time between the end of one command and the start of the
+    /// next, for devices that cannot keep up otherwise
+    pub command_delay: Duration,
+}
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud: 9600,
+            parity: Parity::None,
+            data_bits: 8,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+            command_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Open a serial port with this configuration
+    pub(crate) fn open(&self, port: &str) -> Result<SerialStream> {
+        let data_bits = match self.data_bits {
+            5 => tokio_serial::DataBits::Five,
+            6 => tokio_serial::DataBits::Six,
+            7 => tokio_serial::DataBits::Seven,
+            8 => tokio_serial::DataBits::Eight,
+            bits => {
+                return Err(Error::InvalidArgument(format!(
+                    "Unsupported number of data bits: {bits}"
+                )));
+            }
+        };
+        let parity = match self.parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Odd => tokio_serial::Parity::Odd,
+            Parity::Even => tokio_serial::Parity::Even,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => tokio_serial::StopBits::One,
+            StopBits::Two => tokio_serial::StopBits::Two,
+        };
+        let flow_control = match self.flow_control {
+            FlowControl::None => tokio_serial::FlowControl::None,
+            FlowControl::Software => tokio_serial::FlowControl::Software,
+            FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
+        };
+
+        let mut serial = tokio_serial::new(port, self.baud)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .flow_control(flow_control)
+            .open_native_async()
+            .map_err(|e| Error::Unhandled(e.into()))?;
+
+        if let Some(level) = self.dtr {
+            serial
+                .write_data_terminal_ready(level)
+                .map_err(|e| Error::Unhandled(e.into()))?;
+        }
+        if let Some(level) = self.rts {
+            serial
+                .write_request_to_send(level)
+                .map_err(|e| Error::Unhandled(e.into()))?;
+        }
+
+        Ok(serial)
+    }
+}
+
 pub struct ScpiSerialProtocol {
     port: String,
-    baud: u32,
-    serial: Option<SerialStream>,
+    /// USB device to look the port up by on each connect, if given
+    usb: Option<UsbSerialMatch>,
+    config: SerialConfig,
+    /// Buffered so data received beyond the end of a response is retained
+    serial: Option<BufReader<SerialStream>>,
+    /// Start of a response that timed out, to resume from on the next read
+    partial: Vec<u8>,
+    /// Time the last command finished sending, for pacing
+    last_send: Option<Instant>,
+    settings: ScpiSettings,
 }
 impl ScpiSerialProtocol {
     pub fn new(port: &str, baud: u32) -> Self {
+        Self::new_with_config(
+            port,
+            SerialConfig {
+                baud,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn new_with_config(port: &str, config: SerialConfig) -> Self {
         Self {
             port: port.to_string(),
-            baud,
+            usb: None,
+            config,
             serial: None,
+            partial: vec![],
+            last_send: None,
+            settings: ScpiSettings::default(),
+        }
+    }
+
+    /// Use the serial port of a USB device, looked up on each connect as its
+    /// name may change when it is plugged in again
+    pub fn new_usb(usb: UsbSerialMatch, config: SerialConfig) -> Self {
+        Self {
+            usb: Some(usb),
+            ..Self::new_with_config("", config)
         }
     }
+
+    /// Device path or name of the port, found on connect for a USB device
+    pub fn port(&self) -> &str {
+        &self.port
+    }
+
+    pub fn config(&self) -> &SerialConfig {
+        &self.config
+    }
+
+    /// Change the serial line configuration. Line settings take effect on the
+    /// next connect, while the command delay applies immediately.
+    pub fn set_config(&mut self, config: SerialConfig) {
+        self.config = config;
+    }
+
+    /// Drive the DTR line
+    pub fn set_dtr(&mut self, level: bool) -> Result<()> {
+        let Some(serial) = &mut self.serial else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        serial
+            .get_mut()
+            .write_data_terminal_ready(level)
+            .map_err(|e| Error::Unhandled(e.into()))
+    }
+
+    /// Drive the RTS line
+    pub fn set_rts(&mut self, level: bool) -> Result<()> {
+        let Some(serial) = &mut self.serial else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        serial
+            .get_mut()
+            .write_request_to_send(level)
+            .map_err(|e| Error::Unhandled(e.into()))
+    }
 }
 #[async_trait]
 impl Protocol for ScpiSerialProtocol {
@@ -35,10 +193,13 @@ impl Protocol for ScpiSerialProtocol {
             return Err(Error::Unspecified("Already connected".into()));
         }
 
-        let serial = tokio_serial::new(&self.port, self.baud)
-            .open_native_async()
-            .map_err(|e| Error::Unhandled(e.into()))?;
-        self.serial = Some(serial);
+        if let Some(usb) = &self.usb {
+            self.port = serial_ports::find_usb_serial_port(usb)?;
+        }
+        let serial = self.config.open(&self.port)?;
+        self.serial = Some(BufReader::new(serial));
+        self.partial.clear();
+        self.last_send = None;
 
         Ok(())
     }
@@ -66,10 +227,21 @@ impl ScpiProtocol for ScpiSerialProtocol {
                 .replace('\r', "␊")
         );
 
-        serial
-            .write_all(data)
-            .await
-            .map_err(|e| Error::Unhandled(e.into()))?;
+        if let Some(last_send) = self.last_send {
+            tokio::time::sleep_until(last_send + self.config.command_delay).await;
+        }
+
+        let timeout = self.settings.timeout;
+        match tokio::time::timeout(timeout, serial.write_all(data)).await {
+            Ok(res) => res.map_err(|e| Error::Unhandled(e.into()))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out sending for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        };
+        self.last_send = Some(Instant::now());
 
         Ok(())
     }
@@ -79,13 +251,27 @@ impl ScpiProtocol for ScpiSerialProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        let mut resp = vec![];
-        let mut stream = BufReader::new(serial);
-        /* TODO: Timeout */
-        stream
-            .read_until(b'\n', &mut resp)
-            .await
-            .map_err(|e| Error::Unhandled(e.into()))?;
+        let Some(end) = self.settings.read_terminator.end_byte() else {
+            return Err(Error::NotSupported(
+                "Transport does not support EOI termination".into(),
+            ));
+        };
+
+        let timeout = self.settings.timeout;
+        let resp = match tokio::time::timeout(
+            timeout,
+            stream::recv_until_byte(serial, &mut self.partial, end),
+        )
+        .await
+        {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for response for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        };
 
         debug!(
             "int_recv: {}",
@@ -113,23 +299,23 @@ impl ScpiProtocol for ScpiSerialProtocol {
 
         debug!("recv_raw({length:?}, {timeout:?})");
 
+        let timeout = timeout.unwrap_or(self.settings.timeout);
         if let Some(length) = length {
-            let mut resp = vec![0; length];
-
-            if let Some(timeout) = timeout {
-                if tokio::time::timeout(timeout, serial.read_exact(&mut resp))
-                    .await
-                    .is_err()
-                {
+            let resp = match tokio::time::timeout(
+                timeout,
+                stream::recv_exact(serial, &mut self.partial, length),
+            )
+            .await
+            {
+                Ok(res) => res?,
+                Err(_) => {
                     return Err(Error::Timeout(format!(
                         "Timed out reading {} bytes for {} ms",
                         length,
                         timeout.as_millis()
                     )));
                 }
-            } else {
-                serial.read_exact(&mut resp).await?;
-            }
+            };
 
             debug!(
                 "recv_raw: {}",
@@ -140,7 +326,22 @@ impl ScpiProtocol for ScpiSerialProtocol {
 
             Ok(resp)
         } else {
-            Err(Error::Unimplemented("TODO".into()))
+            let resp = stream::recv_until_idle(
+                serial,
+                &mut self.partial,
+                self.settings.read_terminator.end_byte(),
+                timeout,
+            )
+            .await?;
+
+            debug!(
+                "recv_raw: {}",
+                String::from_utf8_lossy(&resp)
+                    .replace('\n', "␤")
+                    .replace('\r', "␊")
+            );
+
+            Ok(resp)
         }
     }
 
@@ -151,43 +352,30 @@ impl ScpiProtocol for ScpiSerialProtocol {
 
         debug!("recv_until({byte}, {timeout:?})");
 
-        let mut data = vec![];
-        let end = Instant::now() + timeout;
-
-        loop {
-            let now = Instant::now();
-            if now >= end {
+        let data = match tokio::time::timeout(
+            timeout,
+            stream::recv_until_byte(serial, &mut self.partial, byte),
+        )
+        .await
+        {
+            Ok(res) => res?,
+            Err(_) => {
                 return Err(Error::Timeout(format!(
                     "Timed out waiting for {} for {} ms",
                     byte,
                     timeout.as_millis()
                 )));
             }
-            let remaining = end - now;
+        };
 
-            match tokio::time::timeout(remaining, serial.read_u8()).await {
-                Err(_) => {
-                    return Err(Error::Timeout(format!(
-                        "Timed out waiting for {} for {} ms",
-                        byte,
-                        timeout.as_millis()
-                    )));
-                }
-                Ok(res) => {
-                    let res = res?;
-                    data.push(res);
-                    if res == byte {
-                        debug!(
-                            "recv_until: {}",
-                            String::from_utf8_lossy(&data)
-                                .replace('\n', "␤")
-                                .replace('\r', "␊")
-                        );
-                        return Ok(data);
-                    }
-                }
-            }
-        }
+        debug!(
+            "recv_until: {}",
+            String::from_utf8_lossy(&data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(data)
     }
 
     async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
@@ -197,6 +385,7 @@ impl ScpiProtocol for ScpiSerialProtocol {
 
         debug!("flush_rx({timeout:?})");
 
+        self.partial.clear();
         let end = Instant::now() + timeout;
         loop {
             let now = Instant::now();
@@ -218,4 +407,98 @@ impl ScpiProtocol for ScpiSerialProtocol {
 
         Ok(())
     }
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ResourceAddress;
+
+    fn config(uri: &str) -> Result<SerialConfig> {
+        Ok(uri.parse::<ResourceAddress>()?.options.serial_config())
+    }
+
+    #[test]
+    fn parses_line_options() {
+        let uri = "serial:/dev/ttyS0?baud=19200&parity=even&data_bits=7&stop_bits=1";
+        assert_eq!(
+            config(uri).unwrap(),
+            SerialConfig {
+                baud: 19200,
+                parity: Parity::Even,
+                data_bits: 7,
+                ..Default::default()
+            }
+        );
+        assert_eq!(uri.parse::<ResourceAddress>().unwrap().to_string(), uri);
+
+        assert_eq!(
+            config("serial:COM3?stop_bits=2&flow_control=hardware").unwrap(),
+            SerialConfig {
+                stop_bits: StopBits::Two,
+                flow_control: FlowControl::Hardware,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            config("serial:COM3?parity=ODD&flow_control=software").unwrap(),
+            SerialConfig {
+                parity: Parity::Odd,
+                flow_control: FlowControl::Software,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_line_control_and_pacing() {
+        let uri = "serial:/dev/ttyUSB0?dtr=on&rts=off&command_delay=50ms";
+        assert_eq!(
+            config(uri).unwrap(),
+            SerialConfig {
+                dtr: Some(true),
+                rts: Some(false),
+                command_delay: Duration::from_millis(50),
+                ..Default::default()
+            }
+        );
+        assert_eq!(uri.parse::<ResourceAddress>().unwrap().to_string(), uri);
+
+        assert_eq!(
+            config("serial:/dev/ttyUSB0?command_delay=0.2s")
+                .unwrap()
+                .command_delay,
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            config("serial:/dev/ttyUSB0").unwrap(),
+            SerialConfig::default()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_line_options() {
+        for uri in [
+            "serial:COM3?data_bits=9",
+            "serial:COM3?data_bits=4",
+            "serial:COM3?stop_bits=3",
+            "serial:COM3?parity=mark",
+            "serial:COM3?flow_control=dsr",
+            "serial:COM3?baud=fast",
+            "serial:COM3?dtr=high",
+            "serial:COM3?command_delay=5h",
+        ] {
+            assert!(
+                matches!(config(uri), Err(Error::InvalidArgument(_))),
+                "{uri:?}"
+            );
+        }
+    }
 }
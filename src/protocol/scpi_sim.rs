@@ -0,0 +1,201 @@
+//! Transport to a simulated instrument, see [`crate::sim`]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::debug;
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+    sim::{self, SimClass, SimDevice},
+};
+
+use super::{Protocol, ScpiProtocol, ScpiSettings};
+
+/// Transport to a simulated instrument. Responses are available as soon as a
+/// query is sent, and reads with no response pending time out immediately.
+pub struct ScpiSimProtocol {
+    device: Arc<Mutex<SimDevice>>,
+    /// Response data not yet consumed
+    rx_buf: Vec<u8>,
+    connected: bool,
+    settings: ScpiSettings,
+}
+impl ScpiSimProtocol {
+    pub fn new(device: Arc<Mutex<SimDevice>>) -> Self {
+        Self {
+            device,
+            rx_buf: vec![],
+            connected: false,
+            settings: ScpiSettings::default(),
+        }
+    }
+
+    /// Open a simulated instrument, see [`sim::open`]
+    pub fn open(class: SimClass, model: Option<&str>, name: Option<&str>) -> Result<Self> {
+        Ok(Self::new(sim::open(class, model, name)?))
+    }
+
+    /// Simulated instrument, for control of its simulated environment
+    pub fn device(&self) -> &Arc<Mutex<SimDevice>> {
+        &self.device
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if !self.connected {
+            return Err(Error::Unspecified("Not connected".into()));
+        }
+
+        Ok(())
+    }
+
+    fn check_pending(&self) -> Result<()> {
+        if self.rx_buf.is_empty() {
+            return Err(Error::Timeout(
+                "No response from simulated instrument".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Take data from the receive buffer up to and including byte, or all of
+    /// it if byte is not found
+    fn take_until(&mut self, byte: Option<u8>) -> Vec<u8> {
+        let len = byte
+            .and_then(|byte| self.rx_buf.iter().position(|&c| c == byte))
+            .map(|pos| pos + 1)
+            .unwrap_or(self.rx_buf.len());
+
+        self.rx_buf.drain(..len).collect()
+    }
+}
+#[async_trait]
+impl Protocol for ScpiSimProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.connected {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        self.connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.connected = false;
+        self.rx_buf.clear();
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ScpiSimProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        self.check_connected()?;
+
+        debug!(
+            "int_send(): {}",
+            String::from_utf8_lossy(data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        let message = data.trim_ascii_end();
+        let resp = self.device.lock().unwrap().write(message);
+        if let Some(resp) = resp {
+            self.rx_buf.extend_from_slice(&resp);
+            self.rx_buf
+                .extend_from_slice(self.settings.read_terminator.suffix());
+        }
+
+        Ok(())
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        self.check_connected()?;
+        self.check_pending()?;
+
+        let resp = self.take_until(self.settings.read_terminator.end_byte());
+
+        debug!(
+            "int_recv: {}",
+            String::from_utf8_lossy(&resp)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(resp)
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.check_connected()?;
+
+        debug!("recv_raw({length:?}, {timeout:?})");
+
+        self.check_pending()?;
+        let resp = match length {
+            Some(length) if length > self.rx_buf.len() => {
+                return Err(Error::Timeout(format!(
+                    "Expected {length} bytes from simulated instrument, {} pending",
+                    self.rx_buf.len()
+                )));
+            }
+            Some(length) => self.rx_buf.drain(..length).collect(),
+            None => self.take_until(None),
+        };
+
+        Ok(resp)
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        self.check_connected()?;
+
+        debug!("recv_until({byte}, {timeout:?})");
+
+        if !self.rx_buf.contains(&byte) {
+            return Err(Error::Timeout(format!(
+                "No {} in response from simulated instrument",
+                byte.escape_ascii()
+            )));
+        }
+
+        Ok(self.take_until(Some(byte)))
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        self.check_connected()?;
+
+        debug!("flush_rx({timeout:?})");
+
+        self.rx_buf.clear();
+        Ok(())
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        true
+    }
+}
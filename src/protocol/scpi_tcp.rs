@@ -3,7 +3,7 @@ use std::{net::SocketAddr, time::Duration};
 use async_trait::async_trait;
 use log::debug;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpSocket, TcpStream},
     time::Instant,
 };
@@ -13,17 +13,23 @@ use crate::{
     model::ModelInfo,
 };
 
-use super::{Protocol, ScpiProtocol};
+use super::{Protocol, ScpiProtocol, ScpiSettings, stream};
 
 pub struct ScpiTcpProtocol {
     socket: SocketAddr,
-    stream: Option<TcpStream>,
+    /// Buffered so data received beyond the end of a response is retained
+    stream: Option<BufReader<TcpStream>>,
+    /// Start of a response that timed out, to resume from on the next read
+    partial: Vec<u8>,
+    settings: ScpiSettings,
 }
 impl ScpiTcpProtocol {
     pub fn new(socket: SocketAddr) -> Result<Self> {
         Ok(Self {
             socket,
             stream: None,
+            partial: vec![],
+            settings: ScpiSettings::default(),
         })
     }
 }
@@ -34,14 +40,27 @@ impl Protocol for ScpiTcpProtocol {
             return Err(Error::Unspecified("Already connected".into()));
         }
 
-        /* TODO: Support IPv6 */
-        let socket = TcpSocket::new_v4().map_err(|e| Error::Unhandled(e.into()))?;
-        self.stream = Some(
-            socket
-                .connect(self.socket)
-                .await
-                .map_err(|e| Error::Unhandled(e.into()))?,
-        );
+        let socket = if self.socket.is_ipv6() {
+            TcpSocket::new_v6()
+        } else {
+            TcpSocket::new_v4()
+        }
+        .map_err(|e| Error::Unhandled(e.into()))?;
+        let timeout = self.settings.timeout;
+        match tokio::time::timeout(timeout, socket.connect(self.socket)).await {
+            Ok(res) => {
+                let stream = res.map_err(|e| Error::Unhandled(e.into()))?;
+                self.stream = Some(BufReader::new(stream));
+                self.partial.clear();
+            }
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out connecting to {} for {} ms",
+                    self.socket,
+                    timeout.as_millis()
+                )));
+            }
+        }
         Ok(())
     }
 
@@ -68,10 +87,16 @@ impl ScpiProtocol for ScpiTcpProtocol {
                 .replace('\r', "␊")
         );
 
-        stream
-            .write_all(data)
-            .await
-            .map_err(|e| Error::Unhandled(e.into()))?;
+        let timeout = self.settings.timeout;
+        match tokio::time::timeout(timeout, stream.write_all(data)).await {
+            Ok(res) => res.map_err(|e| Error::Unhandled(e.into()))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out sending for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        };
 
         Ok(())
     }
@@ -81,13 +106,27 @@ impl ScpiProtocol for ScpiTcpProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        let mut resp = vec![];
-        let mut stream = BufReader::new(stream);
-        /* TODO: Timeout */
-        stream
-            .read_until(b'\n', &mut resp)
-            .await
-            .map_err(|e| Error::Unhandled(e.into()))?;
+        let Some(end) = self.settings.read_terminator.end_byte() else {
+            return Err(Error::NotSupported(
+                "Transport does not support EOI termination".into(),
+            ));
+        };
+
+        let timeout = self.settings.timeout;
+        let resp = match tokio::time::timeout(
+            timeout,
+            stream::recv_until_byte(stream, &mut self.partial, end),
+        )
+        .await
+        {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for response for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        };
 
         debug!(
             "int_recv: {}",
@@ -115,23 +154,23 @@ impl ScpiProtocol for ScpiTcpProtocol {
 
         debug!("recv_raw({length:?}, {timeout:?})");
 
+        let timeout = timeout.unwrap_or(self.settings.timeout);
         if let Some(length) = length {
-            let mut resp = vec![0; length];
-
-            if let Some(timeout) = timeout {
-                if tokio::time::timeout(timeout, stream.read_exact(&mut resp))
-                    .await
-                    .is_err()
-                {
+            let resp = match tokio::time::timeout(
+                timeout,
+                stream::recv_exact(stream, &mut self.partial, length),
+            )
+            .await
+            {
+                Ok(res) => res?,
+                Err(_) => {
                     return Err(Error::Timeout(format!(
                         "Timed out reading {} bytes for {} ms",
                         length,
                         timeout.as_millis()
                     )));
                 }
-            } else {
-                stream.read_exact(&mut resp).await?;
-            }
+            };
 
             debug!(
                 "recv_raw: {}",
@@ -142,7 +181,22 @@ impl ScpiProtocol for ScpiTcpProtocol {
 
             Ok(resp)
         } else {
-            Err(Error::Unimplemented("TODO".into()))
+            let resp = stream::recv_until_idle(
+                stream,
+                &mut self.partial,
+                self.settings.read_terminator.end_byte(),
+                timeout,
+            )
+            .await?;
+
+            debug!(
+                "recv_raw: {}",
+                String::from_utf8_lossy(&resp)
+                    .replace('\n', "␤")
+                    .replace('\r', "␊")
+            );
+
+            Ok(resp)
         }
     }
 
@@ -153,43 +207,30 @@ impl ScpiProtocol for ScpiTcpProtocol {
 
         debug!("recv_until({byte}, {timeout:?})");
 
-        let mut data = vec![];
-        let end = Instant::now() + timeout;
-
-        loop {
-            let now = Instant::now();
-            if now >= end {
+        let data = match tokio::time::timeout(
+            timeout,
+            stream::recv_until_byte(stream, &mut self.partial, byte),
+        )
+        .await
+        {
+            Ok(res) => res?,
+            Err(_) => {
                 return Err(Error::Timeout(format!(
                     "Timed out waiting for {} for {} ms",
                     byte,
                     timeout.as_millis()
                 )));
             }
-            let remaining = end - now;
+        };
 
-            match tokio::time::timeout(remaining, stream.read_u8()).await {
-                Err(_) => {
-                    return Err(Error::Timeout(format!(
-                        "Timed out waiting for {} for {} ms",
-                        byte,
-                        timeout.as_millis()
-                    )));
-                }
-                Ok(res) => {
-                    let res = res?;
-                    data.push(res);
-                    if res == byte {
-                        debug!(
-                            "recv_until: {}",
-                            String::from_utf8_lossy(&data)
-                                .replace('\n', "␤")
-                                .replace('\r', "␊")
-                        );
-                        return Ok(data);
-                    }
-                }
-            }
-        }
+        debug!(
+            "recv_until: {}",
+            String::from_utf8_lossy(&data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(data)
     }
 
     async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
@@ -199,6 +240,7 @@ impl ScpiProtocol for ScpiTcpProtocol {
 
         debug!("flush_rx({timeout:?})");
 
+        self.partial.clear();
         let end = Instant::now() + timeout;
         loop {
             let now = Instant::now();
@@ -220,4 +262,179 @@ impl ScpiProtocol for ScpiTcpProtocol {
 
         Ok(())
     }
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::protocol::{Terminator, scpi_from_uri};
+
+    /// Connect to a loopback listener, returning the accepted server end
+    async fn connect() -> (ScpiTcpProtocol, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proto = ScpiTcpProtocol::new(listener.local_addr().unwrap()).unwrap();
+        proto.connect().await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (proto, server)
+    }
+
+    #[tokio::test]
+    async fn receive_times_out() {
+        let (mut tcp, mut server) = connect().await;
+        let proto = &mut tcp as &mut dyn ScpiProtocol;
+        proto.set_timeout(Duration::from_millis(50));
+
+        assert!(matches!(proto.query("*IDN?").await, Err(Error::Timeout(_))));
+
+        /* Partial response is not returned, but is kept for the next read */
+        server.write_all(b"ACME,PSU").await.unwrap();
+        assert!(matches!(proto.recv().await, Err(Error::Timeout(_))));
+        assert!(matches!(
+            proto.recv_raw(Some(16), None).await,
+            Err(Error::Timeout(_))
+        ));
+
+        /* Late reply is delivered whole */
+        server.write_all(b",1234,1.0\r\n").await.unwrap();
+        assert_eq!(proto.recv().await.unwrap(), b"ACME,PSU,1234,1.0\r\n");
+
+        server.write_all(b"#14AB").await.unwrap();
+        assert!(matches!(
+            proto.recv_raw(Some(7), None).await,
+            Err(Error::Timeout(_))
+        ));
+        server.write_all(b"CD").await.unwrap();
+        assert_eq!(proto.recv_raw(Some(7), None).await.unwrap(), b"#14ABCD");
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_lost() {
+        let (mut tcp, server) = connect().await;
+        let proto = &mut tcp as &mut dyn ScpiProtocol;
+        drop(server);
+
+        let err = proto.recv().await.unwrap_err();
+        assert!(err.is_connection_lost(), "{err}");
+    }
+
+    #[tokio::test]
+    async fn call_timeout_overrides_default() {
+        let (mut tcp, _server) = connect().await;
+        let proto = &mut tcp as &mut dyn ScpiProtocol;
+        proto.set_timeout(Duration::from_secs(60));
+
+        let start = Instant::now();
+        assert!(matches!(
+            proto
+                .recv_raw(Some(1), Some(Duration::from_millis(50)))
+                .await,
+            Err(Error::Timeout(_))
+        ));
+        assert!(matches!(
+            proto.recv_until(b'\n', Duration::from_millis(50)).await,
+            Err(Error::Timeout(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn uri_sets_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut proto = scpi_from_uri(format!("tcp://127.0.0.1:{port}?timeout=50ms"))
+            .await
+            .unwrap();
+        let _server = listener.accept().await.unwrap();
+        assert_eq!(proto.settings().timeout, Duration::from_millis(50));
+        assert!(matches!(proto.query("*IDN?").await, Err(Error::Timeout(_))));
+    }
+
+    /// Read from the server end until the given bytes have been received
+    async fn expect(server: &mut TcpStream, expected: &[u8]) {
+        let mut data = vec![0; expected.len()];
+        server.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[tokio::test]
+    async fn uses_connection_terminators() {
+        let (mut tcp, mut server) = connect().await;
+        let proto = &mut tcp as &mut dyn ScpiProtocol;
+
+        /* Defaults */
+        server.write_all(b"1\n").await.unwrap();
+        assert_eq!(proto.query("A?").await.unwrap(), b"1\n");
+        expect(&mut server, b"A?\r\n").await;
+
+        /* Carriage return does not end a line feed terminated response */
+        proto
+            .set_terminators(Terminator::Lf, Terminator::Lf)
+            .unwrap();
+        server.write_all(b"2\r3\n").await.unwrap();
+        assert_eq!(proto.query_str("B?").await.unwrap(), "2\r3");
+        expect(&mut server, b"B?\n").await;
+
+        /* Data following the terminator is left for the next response */
+        proto
+            .set_terminators(Terminator::Cr, Terminator::Cr)
+            .unwrap();
+        server.write_all(b"4\r5\r").await.unwrap();
+        assert_eq!(proto.query("C?").await.unwrap(), b"4\r");
+        assert_eq!(proto.recv().await.unwrap(), b"5\r");
+        expect(&mut server, b"C?\r").await;
+
+        /* Raw TCP has no end of message indicator */
+        assert!(matches!(
+            proto.set_terminators(Terminator::Eoi, Terminator::Lf),
+            Err(Error::NotSupported(_))
+        ));
+        assert_eq!(proto.settings().write_terminator, Terminator::Cr);
+    }
+
+    #[tokio::test]
+    async fn terminators_are_per_connection() {
+        let (mut cr_tcp, mut cr_server) = connect().await;
+        let (mut lf_tcp, mut lf_server) = connect().await;
+        let cr = &mut cr_tcp as &mut dyn ScpiProtocol;
+        let lf = &mut lf_tcp as &mut dyn ScpiProtocol;
+        cr.set_terminators(Terminator::Cr, Terminator::Cr).unwrap();
+        lf.set_terminators(Terminator::Lf, Terminator::Lf).unwrap();
+
+        cr.send("OUTP ON").await.unwrap();
+        lf.send("OUTP ON").await.unwrap();
+        expect(&mut cr_server, b"OUTP ON\r").await;
+        expect(&mut lf_server, b"OUTP ON\n").await;
+    }
+
+    #[tokio::test]
+    async fn uri_sets_terminators() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut proto = scpi_from_uri(format!("tcp://127.0.0.1:{port}?termination=cr"))
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert_eq!(proto.settings().read_terminator, Terminator::Cr);
+
+        server.write_all(b"ACME\r").await.unwrap();
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "ACME");
+        expect(&mut server, b"*IDN?\r").await;
+
+        assert!(
+            scpi_from_uri(format!("tcp://127.0.0.1:{port}?termination=eoi"))
+                .await
+                .is_err()
+        );
+    }
 }
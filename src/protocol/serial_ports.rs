@@ -0,0 +1,305 @@
+//! Enumeration of serial ports, finding them by the USB device they belong to
+//! or by probing them for an instrument
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use futures::future::join_all;
+use log::debug;
+use tokio_serial::SerialPortType;
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Protocol, ScpiProtocol, ScpiSerialProtocol, SerialConfig};
+
+/// Serial port present on the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialPortDetails {
+    /// Device path or name, e.g. `/dev/ttyUSB0` or `COM3`
+    pub port: String,
+    /// USB device the port belongs to, if any
+    pub usb: Option<UsbSerialInfo>,
+}
+
+/// USB device providing a serial port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbSerialInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// USB device to find a serial port by, parsed from e.g.
+/// `vid=1ab1,pid=0e11,serial=XYZ`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbSerialMatch {
+    pub vid: u16,
+    pub pid: u16,
+    /// Serial number, needed to tell apart several devices of the same type
+    pub serial: Option<String>,
+}
+impl UsbSerialMatch {
+    pub fn matches(&self, usb: &UsbSerialInfo) -> bool {
+        self.vid == usb.vid
+            && self.pid == usb.pid
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|serial| usb.serial.as_ref() == Some(serial))
+    }
+}
+impl FromStr for UsbSerialMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument(format!("Invalid USB serial device '{s}'"));
+        let parse_id = |value: &str| {
+            let value = value.strip_prefix("0x").unwrap_or(value);
+            u16::from_str_radix(value, 16).map_err(|_| invalid())
+        };
+
+        let (mut vid, mut pid, mut serial) = (None, None, None);
+        for arg in s.split(',') {
+            let Some((key, value)) = arg.split_once('=') else {
+                return Err(invalid());
+            };
+            match key {
+                "vid" => vid = Some(parse_id(value)?),
+                "pid" => pid = Some(parse_id(value)?),
+                "serial" => serial = Some(value.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+
+        let (Some(vid), Some(pid)) = (vid, pid) else {
+            return Err(Error::InvalidArgument(format!(
+                "USB vendor and product IDs are both needed in '{s}'"
+            )));
+        };
+
+        Ok(Self { vid, pid, serial })
+    }
+}
+impl Display for UsbSerialMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "vid={:04x},pid={:04x}", self.vid, self.pid)?;
+        if let Some(serial) = &self.serial {
+            write!(f, ",serial={serial}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// List the serial ports present on the system
+pub fn serial_ports() -> Result<Vec<SerialPortDetails>> {
+    let ports = tokio_serial::available_ports().map_err(|e| Error::Unhandled(e.into()))?;
+
+    Ok(ports
+        .into_iter()
+        .map(|info| SerialPortDetails {
+            port: info.port_name,
+            usb: match info.port_type {
+                SerialPortType::UsbPort(usb) => Some(UsbSerialInfo {
+                    vid: usb.vid,
+                    pid: usb.pid,
+                    serial: usb.serial_number,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                }),
+                _ => None,
+            },
+        })
+        .collect())
+}
+
+/// Find the serial port of a USB device. Fails if several ports match, in
+/// which case a serial number should be given.
+pub fn find_usb_serial_port(usb: &UsbSerialMatch) -> Result<String> {
+    select_usb_serial_port(serial_ports()?, usb)
+}
+
+/// Select the port of a USB device from those given
+fn select_usb_serial_port(ports: Vec<SerialPortDetails>, usb: &UsbSerialMatch) -> Result<String> {
+    let mut matching = ports
+        .into_iter()
+        .filter(|port| port.usb.as_ref().is_some_and(|info| usb.matches(info)));
+
+    let Some(port) = matching.next() else {
+        return Err(Error::Unspecified(format!(
+            "No serial port found for USB device {usb}"
+        )));
+    };
+    if let Some(other) = matching.next() {
+        return Err(Error::InvalidArgument(format!(
+            "Several serial ports found for USB device {usb}: {}, {}",
+            port.port, other.port
+        )));
+    }
+
+    debug!("USB device {usb} is at {}", port.port);
+
+    Ok(port.port)
+}
+
+/// Identify the instrument on each of the given ports with *IDN?, returning
+/// the ports something answered on. Anything attached to the ports is sent
+/// the query, so only ports instruments may be on should be probed.
+pub async fn probe_serial_ports(
+    ports: Vec<SerialPortDetails>,
+    config: &SerialConfig,
+    timeout: Duration,
+) -> Vec<(SerialPortDetails, ModelInfo)> {
+    let models = join_all(
+        ports
+            .iter()
+            .map(|port| probe_serial_port(&port.port, config, timeout)),
+    )
+    .await;
+
+    ports
+        .into_iter()
+        .zip(models)
+        .filter_map(|(port, model)| match model {
+            Ok(model) => Some((port, model)),
+            Err(e) => {
+                debug!("Nothing identified on {}: {e}", port.port);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Find the serial port an instrument of the given model is on, by probing
+/// all serial ports with *IDN?
+pub async fn find_serial_instrument(
+    model: &str,
+    config: &SerialConfig,
+    timeout: Duration,
+) -> Result<SerialPortDetails> {
+    probe_serial_ports(serial_ports()?, config, timeout)
+        .await
+        .into_iter()
+        .find(|(_, found)| found.model.eq_ignore_ascii_case(model))
+        .map(|(port, _)| port)
+        .ok_or_else(|| Error::Unspecified(format!("No {model} found on any serial port")))
+}
+
+async fn probe_serial_port(
+    port: &str,
+    config: &SerialConfig,
+    timeout: Duration,
+) -> Result<ModelInfo> {
+    let mut client = ScpiSerialProtocol::new_with_config(port, config.clone());
+    client.connect().await?;
+
+    let client: &mut dyn ScpiProtocol = &mut client;
+    client.set_timeout(timeout);
+    /* Discard anything left over from whoever used the port last */
+    client.flush_rx(Duration::from_millis(50)).await?;
+
+    client.idn_model().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(port: &str, vid: u16, pid: u16, serial: Option<&str>) -> SerialPortDetails {
+        SerialPortDetails {
+            port: port.into(),
+            usb: Some(UsbSerialInfo {
+                vid,
+                pid,
+                serial: serial.map(Into::into),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn ports() -> Vec<SerialPortDetails> {
+        vec![
+            SerialPortDetails {
+                port: "/dev/ttyS0".into(),
+                usb: None,
+            },
+            port("/dev/ttyUSB0", 0x0403, 0x6001, Some("A1")),
+            port("/dev/ttyUSB1", 0x0403, 0x6001, Some("B2")),
+            port("/dev/ttyACM0", 0x1ab1, 0x0e11, None),
+        ]
+    }
+
+    #[test]
+    fn parses_usb_match() {
+        assert_eq!(
+            "vid=1ab1,pid=0x0E11".parse::<UsbSerialMatch>().unwrap(),
+            UsbSerialMatch {
+                vid: 0x1ab1,
+                pid: 0x0e11,
+                serial: None,
+            }
+        );
+        let usb: UsbSerialMatch = "pid=6001,vid=0403,serial=A1".parse().unwrap();
+        assert_eq!(usb.serial.as_deref(), Some("A1"));
+        assert_eq!(usb.to_string(), "vid=0403,pid=6001,serial=A1");
+
+        for invalid in [
+            "",
+            "vid=1ab1",
+            "pid=0e11",
+            "vid=xyz,pid=0e11",
+            "vid=1ab1,pid=10000",
+            "vid=1ab1,pid=0e11,bus=1",
+            "vid=1ab1,pid",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<UsbSerialMatch>(),
+                    Err(Error::InvalidArgument(_))
+                ),
+                "{invalid:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_ids_and_serial() {
+        let usb = |s: &str| s.parse::<UsbSerialMatch>().unwrap();
+        let info = ports()[1].usb.clone().unwrap();
+
+        assert!(usb("vid=0403,pid=6001").matches(&info));
+        assert!(usb("vid=0403,pid=6001,serial=A1").matches(&info));
+        assert!(!usb("vid=0403,pid=6001,serial=B2").matches(&info));
+        assert!(!usb("vid=0403,pid=6010").matches(&info));
+        assert!(!usb("vid=0404,pid=6001").matches(&info));
+
+        /* A serial number is needed, but the device does not report one */
+        let info = ports()[3].usb.clone().unwrap();
+        assert!(!usb("vid=1ab1,pid=0e11,serial=A1").matches(&info));
+    }
+
+    #[test]
+    fn selects_single_matching_port() {
+        let select = |s: &str| select_usb_serial_port(ports(), &s.parse().unwrap());
+
+        assert_eq!(select("vid=1ab1,pid=0e11").unwrap(), "/dev/ttyACM0");
+        assert_eq!(
+            select("vid=0403,pid=6001,serial=B2").unwrap(),
+            "/dev/ttyUSB1"
+        );
+        assert!(matches!(
+            select("vid=0403,pid=6001,serial=C3"),
+            Err(Error::Unspecified(_))
+        ));
+
+        /* Devices of the same type are told apart by serial number */
+        let err = select("vid=0403,pid=6001").unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err}");
+        assert!(err.to_string().contains("/dev/ttyUSB0, /dev/ttyUSB1"));
+    }
+}
@@ -0,0 +1,592 @@
+//! Connection to an instrument shared between tasks, each command sequence
+//! run as a transaction without interleaving with those of other tasks
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::error::{Error, Result};
+
+use super::{
+    ScpiProtocol,
+    scpi::{ESR_OPC, OPC_POLL_INTERVAL, recv_srq},
+};
+
+/// Number of times a waiting transaction may be passed over for ones of
+/// higher priority, queued after it, before it is run regardless
+const MAX_BYPASSED: u32 = 8;
+
+/// Priority of the transactions of a session handle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SessionPriority {
+    /// Background work such as polling for a UI
+    Low,
+    #[default]
+    Normal,
+    /// Work that should not wait behind others, such as a test sequence
+    High,
+}
+
+struct Waiter {
+    priority: SessionPriority,
+    seq: u64,
+    /// Number of times passed over for higher priority transactions
+    bypassed: u32,
+    tx: oneshot::Sender<Box<dyn ScpiProtocol>>,
+}
+
+struct SessionState {
+    /// Connection, while no transaction holds it
+    proto: Option<Box<dyn ScpiProtocol>>,
+    /// Transactions waiting to start, in the order they were queued
+    waiters: Vec<Waiter>,
+    next_seq: u64,
+    /// Standard event status bits read and cleared from the device by one
+    /// task, kept for others
+    events: u8,
+}
+impl SessionState {
+    /// Hand the connection to the next waiting transaction, if any
+    fn release(&mut self, mut proto: Box<dyn ScpiProtocol>) {
+        loop {
+            let Some(index) = self.next_waiter() else {
+                self.proto = Some(proto);
+                return;
+            };
+
+            let waiter = self.waiters.remove(index);
+            for other in &mut self.waiters {
+                if other.seq < waiter.seq && other.priority < waiter.priority {
+                    other.bypassed += 1;
+                }
+            }
+
+            /* Waiter may have given up since, in which case try the next */
+            match waiter.tx.send(proto) {
+                Ok(()) => return,
+                Err(returned) => proto = returned,
+            }
+        }
+    }
+
+    /// Index of the waiter to run next, the earliest of those of the highest
+    /// priority unless one has been passed over too often
+    fn next_waiter(&self) -> Option<usize> {
+        if let Some(index) = self
+            .waiters
+            .iter()
+            .position(|waiter| waiter.bypassed >= MAX_BYPASSED)
+        {
+            return Some(index);
+        }
+
+        /* Reversed, as the last of equal maximums is returned */
+        self.waiters
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, waiter)| waiter.priority)
+            .map(|(index, _)| index)
+    }
+}
+
+/// Cloneable handle to a connection shared between tasks.
+///
+/// Access is through transactions, each holding the connection exclusively
+/// until dropped, so a query and the reading of its response cannot be
+/// split by another task. Waiting transactions run in the order they were
+/// started, those of higher priority handles first.
+#[derive(Clone)]
+pub struct ScpiSession {
+    state: Arc<Mutex<SessionState>>,
+    priority: SessionPriority,
+    /// Held by the wait for operation complete in progress
+    opc_wait: Arc<tokio::sync::Mutex<()>>,
+}
+impl ScpiSession {
+    pub fn new(proto: Box<dyn ScpiProtocol>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SessionState {
+                proto: Some(proto),
+                waiters: vec![],
+                next_seq: 0,
+                events: 0,
+            })),
+            priority: SessionPriority::Normal,
+            opc_wait: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Handle to the same connection, whose transactions have the given
+    /// priority
+    pub fn with_priority(&self, priority: SessionPriority) -> Self {
+        Self {
+            state: self.state.clone(),
+            priority,
+            opc_wait: self.opc_wait.clone(),
+        }
+    }
+
+    pub fn priority(&self) -> SessionPriority {
+        self.priority
+    }
+
+    /// Start a transaction, waiting for those started before it, and of
+    /// higher priority, to end
+    pub async fn lock(&self) -> ScpiTransaction {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.waiters.is_empty()
+                && let Some(proto) = state.proto.take()
+            {
+                return ScpiTransaction {
+                    state: self.state.clone(),
+                    proto: Some(proto),
+                };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiters.push(Waiter {
+                priority: self.priority,
+                seq,
+                bypassed: 0,
+                tx,
+            });
+            rx
+        };
+
+        let mut pending = PendingTransaction {
+            state: self.state.clone(),
+            rx,
+        };
+        let Ok(proto) = (&mut pending.rx).await else {
+            unreachable!("Waiters are only removed to be handed the connection");
+        };
+
+        ScpiTransaction {
+            state: self.state.clone(),
+            proto: Some(proto),
+        }
+    }
+
+    /// Send a command in a transaction of its own
+    pub async fn send(&self, data: impl AsRef<[u8]>) -> Result<()> {
+        self.lock().await.send(data).await
+    }
+
+    /// Query in a transaction of its own
+    pub async fn query(&self, data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+        self.lock().await.query(data).await
+    }
+
+    pub async fn query_str(&self, data: impl AsRef<[u8]>) -> Result<String> {
+        self.lock().await.query_str(data).await
+    }
+
+    pub async fn query_f32(&self, data: impl AsRef<[u8]>) -> Result<f32> {
+        self.lock().await.query_f32(data).await
+    }
+
+    /// Wait up to timeout for all pending operations to complete, in short
+    /// transactions so that other tasks may use the connection meanwhile. A
+    /// service request is used to signal completion if the transport supports
+    /// it, otherwise the event status register is polled.
+    ///
+    /// The device has a single operation complete event, so waits from
+    /// several tasks take turns, each only starting once the one before has
+    /// ended. While waiting for a service request, the device's `*ESE` and
+    /// `*SRE` enables are changed to only request service on operation
+    /// complete, and are restored afterwards.
+    ///
+    /// Either way, `*ESR?` is read, which clears the whole event status
+    /// register. Other bits read meanwhile are kept for event_status(), which
+    /// other tasks should use rather than querying `*ESR?` themselves, as that
+    /// would also clear the operation complete event this waits for. Errors
+    /// in the device's error queue, as reported by check_errors(), are
+    /// unaffected.
+    pub async fn wait_complete(&self, timeout: Duration) -> Result<()> {
+        let end = Instant::now() + timeout;
+        let Ok(_turn) = tokio::time::timeout_at(end, self.opc_wait.lock()).await else {
+            return Err(opc_timeout(timeout));
+        };
+
+        let srq = {
+            let mut transaction = self.lock().await;
+            /* Only an event set after this point completes the wait */
+            self.state.lock().unwrap().events &= !ESR_OPC;
+
+            let srq = match transaction.ieee488() {
+                Some(ctrl) => match ctrl.subscribe_srq().await {
+                    Ok(srq) => Some(srq),
+                    Err(Error::NotSupported(_)) => None,
+                    Err(e) => return Err(e),
+                },
+                None => None,
+            };
+
+            match srq {
+                Some(srq) => {
+                    let (enables, stale) = transaction.start_opc_srq().await?;
+                    self.state.lock().unwrap().events |= stale & !ESR_OPC;
+                    Some((srq, enables))
+                }
+                None => {
+                    let stale = transaction.start_opc_poll().await?;
+                    self.state.lock().unwrap().events |= stale & !ESR_OPC;
+                    None
+                }
+            }
+        };
+
+        match srq {
+            Some((mut srq, enables)) => {
+                let res = recv_srq(&mut srq, end, timeout).await;
+                let esr = self.lock().await.finish_opc_srq(enables).await?;
+                /* Also complete if the event was set just as the wait ended */
+                if self.take_opc(esr) {
+                    return Ok(());
+                }
+                res
+            }
+            None => loop {
+                let esr = self.lock().await.read_esr().await?;
+                if self.take_opc(esr) {
+                    return Ok(());
+                }
+
+                if Instant::now() >= end {
+                    return Err(opc_timeout(timeout));
+                }
+                tokio::time::sleep(OPC_POLL_INTERVAL).await;
+            },
+        }
+    }
+
+    /// Record event status bits read from the device, taking the operation
+    /// complete bit if set
+    fn take_opc(&self, esr: u8) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.events |= esr;
+        let opc = state.events & ESR_OPC != 0;
+        state.events &= !ESR_OPC;
+        opc
+    }
+
+    /// Read and clear the standard event status register in a transaction of
+    /// its own, including bits already read by wait_complete(). The operation
+    /// complete bit is still seen by a wait in progress.
+    pub async fn event_status(&self) -> Result<u8> {
+        let esr = self.lock().await.read_esr().await?;
+
+        let mut state = self.state.lock().unwrap();
+        let events = state.events | esr;
+        state.events = events & ESR_OPC;
+
+        Ok(events)
+    }
+}
+impl From<Box<dyn ScpiProtocol>> for ScpiSession {
+    fn from(proto: Box<dyn ScpiProtocol>) -> Self {
+        Self::new(proto)
+    }
+}
+
+fn opc_timeout(timeout: Duration) -> Error {
+    Error::Timeout(format!(
+        "Timed out waiting for operation complete for {} ms",
+        timeout.as_millis()
+    ))
+}
+
+/// Transaction waiting to start, giving up its place if dropped
+struct PendingTransaction {
+    state: Arc<Mutex<SessionState>>,
+    rx: oneshot::Receiver<Box<dyn ScpiProtocol>>,
+}
+impl Drop for PendingTransaction {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+
+        /* Connection may have been handed over just before giving up */
+        self.rx.close();
+        if let Ok(proto) = self.rx.try_recv() {
+            state.release(proto);
+        }
+        state.waiters.retain(|waiter| !waiter.tx.is_closed());
+    }
+}
+
+/// Exclusive access to the connection of a session, until dropped
+pub struct ScpiTransaction {
+    state: Arc<Mutex<SessionState>>,
+    /// Only None once dropped
+    proto: Option<Box<dyn ScpiProtocol>>,
+}
+impl Deref for ScpiTransaction {
+    type Target = dyn ScpiProtocol;
+
+    fn deref(&self) -> &Self::Target {
+        self.proto.as_deref().unwrap()
+    }
+}
+impl DerefMut for ScpiTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.proto.as_deref_mut().unwrap()
+    }
+}
+impl Drop for ScpiTransaction {
+    fn drop(&mut self) {
+        if let Some(proto) = self.proto.take() {
+            self.state.lock().unwrap().release(proto);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::protocol::ReplayProtocol;
+
+    async fn session(script: &str) -> ScpiSession {
+        let mut proto: Box<dyn ScpiProtocol> =
+            Box::new(ReplayProtocol::from_script(script).unwrap());
+        proto.connect().await.unwrap();
+        ScpiSession::new(proto)
+    }
+
+    /// Start a transaction in a task of its own, recording when it starts
+    fn spawn_lock(
+        session: &ScpiSession,
+        priority: SessionPriority,
+        id: u32,
+        order: &Arc<Mutex<Vec<u32>>>,
+    ) -> JoinHandle<()> {
+        let session = session.with_priority(priority);
+        let order = order.clone();
+        tokio::spawn(async move {
+            let _transaction = session.lock().await;
+            order.lock().unwrap().push(id);
+        })
+    }
+
+    /// Wait for the given number of transactions to be waiting
+    async fn queued(session: &ScpiSession, waiters: usize) {
+        while session.state.lock().unwrap().waiters.len() < waiters {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Order in which transactions queued behind a held one start, each
+    /// given its priority
+    async fn start_order(priorities: &[SessionPriority]) -> Vec<u32> {
+        let session = session("").await;
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let held = session.lock().await;
+        let mut tasks = vec![];
+        for (id, &priority) in priorities.iter().enumerate() {
+            tasks.push(spawn_lock(&session, priority, id as u32, &order));
+            queued(&session, id + 1).await;
+        }
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_in_order_started() {
+        let order = start_order(&[SessionPriority::Normal; 4]).await;
+        assert_eq!(order, [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn runs_higher_priority_first() {
+        use SessionPriority::*;
+
+        let order = start_order(&[Low, Normal, High, Normal, High]).await;
+        assert_eq!(order, [2, 4, 1, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn limits_times_bypassed() {
+        let mut priorities = vec![SessionPriority::Low];
+        priorities.extend([SessionPriority::High; 10]);
+
+        let order = start_order(&priorities).await;
+        assert_eq!(order, [1, 2, 3, 4, 5, 6, 7, 8, 0, 9, 10]);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_gives_up_place() {
+        let session = session("").await;
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let held = session.lock().await;
+        let dropped = spawn_lock(&session, SessionPriority::Normal, 0, &order);
+        queued(&session, 1).await;
+        let next = spawn_lock(&session, SessionPriority::Normal, 1, &order);
+        queued(&session, 2).await;
+
+        dropped.abort();
+        assert!(dropped.await.unwrap_err().is_cancelled());
+        assert_eq!(session.state.lock().unwrap().waiters.len(), 1);
+
+        drop(held);
+        next.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), [1]);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_hands_on_connection() {
+        let session = session("").await;
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let held = session.lock().await;
+        let dropped = spawn_lock(&session, SessionPriority::Normal, 0, &order);
+        queued(&session, 1).await;
+        let next = spawn_lock(&session, SessionPriority::Normal, 1, &order);
+        queued(&session, 2).await;
+
+        /* Connection is handed to the first waiter, which gives up before
+         * running to take it */
+        drop(held);
+        dropped.abort();
+        assert!(dropped.await.unwrap_err().is_cancelled());
+
+        tokio::time::timeout(Duration::from_secs(1), next)
+            .await
+            .expect("Connection not handed on")
+            .unwrap();
+        assert_eq!(*order.lock().unwrap(), [1]);
+        assert!(session.state.lock().unwrap().proto.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_complete_lets_others_run() {
+        let session = session(
+            r"
+            > *ESR?
+            < 0
+            > *OPC
+            > *ESR?
+            < 0
+            > SYST:BEEP
+            > *ESR?
+            < 1
+            ",
+        )
+        .await;
+
+        let waiting = session.clone();
+        let wait = tokio::spawn(async move { waiting.wait_complete(Duration::from_secs(1)).await });
+        /* Between the first and second poll */
+        tokio::time::sleep(OPC_POLL_INTERVAL / 2).await;
+        session.send("SYST:BEEP").await.unwrap();
+
+        wait.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_complete_keeps_other_events() {
+        let session = session(
+            r"
+            > *ESR?
+            < 32
+            > *OPC
+            > *ESR?
+            < 16
+            > *ESR?
+            < 1
+            > *ESR?
+            < 0
+            ",
+        )
+        .await;
+
+        /* Command and execution errors read while waiting */
+        session.wait_complete(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(session.event_status().await.unwrap(), 0x30);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_complete_sees_event_read_by_others() {
+        let session = session(
+            r"
+            > *ESR?
+            < 0
+            > *OPC
+            > *ESR?
+            < 0
+            > *ESR?
+            < 1
+            > *ESR?
+            < 0
+            ",
+        )
+        .await;
+
+        let waiting = session.clone();
+        let wait = tokio::spawn(async move { waiting.wait_complete(Duration::from_secs(1)).await });
+        /* Between the first and second poll */
+        tokio::time::sleep(OPC_POLL_INTERVAL / 2).await;
+        assert_eq!(session.event_status().await.unwrap(), ESR_OPC);
+
+        wait.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_waits_take_turns() {
+        let session = session(
+            r"
+            > *ESR?
+            < 0
+            > *OPC
+            > *ESR?
+            < 0
+            > *ESR?
+            < 1
+            > *ESR?
+            < 0
+            > *OPC
+            > *ESR?
+            < 1
+            ",
+        )
+        .await;
+
+        let waits: Vec<_> = (0..2)
+            .map(|_| {
+                let session = session.clone();
+                tokio::spawn(async move { session.wait_complete(Duration::from_secs(1)).await })
+            })
+            .collect();
+        for wait in waits {
+            wait.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_complete_times_out() {
+        let mut script = String::from("> *ESR?\n< 0\n> *OPC\n");
+        for _ in 0..=5 {
+            script.push_str("> *ESR?\n< 0\n");
+        }
+
+        let res = session(&script)
+            .await
+            .wait_complete(OPC_POLL_INTERVAL * 5)
+            .await;
+        assert!(matches!(res, Err(Error::Timeout(_))), "{res:?}");
+    }
+}
@@ -0,0 +1,199 @@
+//! Helpers shared by byte stream transports
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    time::Instant,
+};
+
+use crate::error::{Error, Result};
+
+/// Time to wait for further data after receiving the message terminator,
+/// before considering the message complete
+const TERMINATED_IDLE_TIMEOUT: Duration = Duration::from_millis(20);
+/// Time to wait for further data when the message terminator has not been
+/// received, before considering the message complete
+const IDLE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Receive a message of unknown length, waiting up to timeout for it to start.
+/// As binary data may contain the terminator, the message is only considered
+/// complete once the stream has been idle for a short time after receiving the
+/// terminator, or for a longer time otherwise or if there is no terminator.
+/// Data left in partial by an earlier timed out read starts the message. If data
+/// is still arriving once timeout has passed, as from a device that never goes
+/// idle, the read times out with the data received left in partial.
+pub(crate) async fn recv_until_idle(
+    stream: &mut (impl AsyncBufRead + Unpin),
+    partial: &mut Vec<u8>,
+    terminator: Option<u8>,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut data = std::mem::take(partial);
+    let mut wait = if data.is_empty() {
+        timeout
+    } else {
+        IDLE_TIMEOUT
+    };
+
+    loop {
+        /* fill_buf() is cancel-safe, so no data is lost on timeout */
+        let buf = match tokio::time::timeout(wait, stream.fill_buf()).await {
+            Ok(res) => res?,
+            Err(_) if data.is_empty() => {
+                return Err(Error::Timeout(format!(
+                    "Timed out waiting for data for {} ms",
+                    timeout.as_millis()
+                )));
+            }
+            Err(_) => return Ok(data),
+        };
+
+        if buf.is_empty() {
+            if data.is_empty() {
+                return Err(Error::connection_closed());
+            }
+            return Ok(data);
+        }
+
+        let len = buf.len();
+        data.extend_from_slice(buf);
+        stream.consume(len);
+
+        if Instant::now() >= deadline {
+            *partial = data;
+            return Err(Error::Timeout(format!(
+                "Timed out waiting for data to end for {} ms",
+                timeout.as_millis()
+            )));
+        }
+
+        wait = if terminator.is_some() && data.last() == terminator.as_ref() {
+            TERMINATED_IDLE_TIMEOUT
+        } else {
+            IDLE_TIMEOUT
+        };
+    }
+}
+
+/// Receive up to and including the given byte. Data is accumulated in
+/// partial, which is kept by the caller across calls, so if this is cancelled
+/// on timeout the next call resumes where it left off rather than returning
+/// the remainder of the message as a new one.
+pub(crate) async fn recv_until_byte(
+    stream: &mut (impl AsyncBufRead + Unpin),
+    partial: &mut Vec<u8>,
+    byte: u8,
+) -> Result<Vec<u8>> {
+    if let Some(pos) = partial.iter().position(|b| *b == byte) {
+        let rest = partial.split_off(pos + 1);
+        return Ok(std::mem::replace(partial, rest));
+    }
+
+    loop {
+        let buf = stream.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(Error::connection_closed());
+        }
+
+        if let Some(pos) = buf.iter().position(|b| *b == byte) {
+            partial.extend_from_slice(&buf[..=pos]);
+            stream.consume(pos + 1);
+            return Ok(std::mem::take(partial));
+        }
+
+        let len = buf.len();
+        partial.extend_from_slice(buf);
+        stream.consume(len);
+    }
+}
+
+/// Receive exactly length bytes, resuming from partial as for
+/// [`recv_until_byte`]
+pub(crate) async fn recv_exact(
+    stream: &mut (impl AsyncBufRead + Unpin),
+    partial: &mut Vec<u8>,
+    length: usize,
+) -> Result<Vec<u8>> {
+    while partial.len() < length {
+        let buf = stream.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(Error::connection_closed());
+        }
+
+        let len = buf.len().min(length - partial.len());
+        partial.extend_from_slice(&buf[..len]);
+        stream.consume(len);
+    }
+
+    let rest = partial.split_off(length);
+    Ok(std::mem::replace(partial, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resumes_from_partial_data() {
+        let mut partial = b"ACME,".to_vec();
+        let mut stream: &[u8] = b"PSU\nAB";
+        assert_eq!(
+            recv_until_byte(&mut stream, &mut partial, b'\n')
+                .await
+                .unwrap(),
+            b"ACME,PSU\n"
+        );
+        assert!(partial.is_empty());
+
+        /* Data beyond the requested length is left for the next read */
+        let mut partial = b"#14".to_vec();
+        assert_eq!(
+            recv_exact(&mut stream, &mut partial, 2).await.unwrap(),
+            b"#1"
+        );
+        assert_eq!(
+            recv_exact(&mut stream, &mut partial, 3).await.unwrap(),
+            b"4AB"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_never_idle_times_out() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            while tx.write_all(b"1.0,").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let mut stream = tokio::io::BufReader::new(rx);
+        let mut partial = vec![];
+        let err = recv_until_idle(&mut stream, &mut partial, None, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{err}");
+        assert!(partial.starts_with(b"1.0,1.0,"));
+
+        drop(stream);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn end_of_stream_is_connection_lost() {
+        /* As when a USB serial adapter is unplugged */
+        let mut partial = vec![];
+        let mut stream: &[u8] = b"1.0";
+        let err = recv_until_byte(&mut stream, &mut partial, b'\n')
+            .await
+            .unwrap_err();
+        assert!(err.is_connection_lost(), "{err}");
+        assert_eq!(partial, b"1.0");
+
+        let err = recv_exact(&mut stream, &mut partial, 4).await.unwrap_err();
+        assert!(err.is_connection_lost(), "{err}");
+    }
+}
@@ -0,0 +1,1002 @@
+//! USBTMC protocol with the USB488 subclass, referencing USBTMC 1.0 and
+//! USBTMC-USB488 1.0 specifications
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+
+use crate::{
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings, Terminator};
+
+#[cfg(target_os = "linux")]
+mod usbfs;
+
+#[cfg(target_os = "linux")]
+pub use usbfs::UsbfsDevice;
+
+/// Bulk message IDs (USBTMC 3.2.1.1, USB488 3.2.1.1)
+const DEV_DEP_MSG_OUT: u8 = 1;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const DEV_DEP_MSG_IN: u8 = 2;
+const USB488_TRIGGER: u8 = 128;
+
+/// Class-specific requests (USBTMC 4.2.1, USB488 4.3)
+const INITIATE_ABORT_BULK_OUT: u8 = 1;
+const CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const INITIATE_ABORT_BULK_IN: u8 = 3;
+const CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const INITIATE_CLEAR: u8 = 5;
+const CHECK_CLEAR_STATUS: u8 = 6;
+const GET_CAPABILITIES: u8 = 7;
+const INDICATOR_PULSE: u8 = 64;
+const READ_STATUS_BYTE: u8 = 128;
+const GO_TO_LOCAL: u8 = 161;
+
+/// Request status values (USBTMC table 16)
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_PENDING: u8 = 0x02;
+const STATUS_TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+
+/// Size of the header of each bulk message
+const HEADER_SIZE: usize = 12;
+/// Max amount to transfer in a single bulk message
+const TRANSFER_SIZE: usize = 65536;
+/// Time to wait for each control request
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the rest of an aborted transfer
+const ABORT_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time between polls of the status of a clear or abort
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Bulk endpoint of a USBTMC interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbtmcEndpoint {
+    BulkIn,
+    BulkOut,
+}
+
+/// Recipient of a class-specific control request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbtmcRecipient {
+    Interface,
+    Endpoint(UsbtmcEndpoint),
+}
+
+/// Access to the endpoints of a USBTMC interface, implemented by
+/// [`UsbfsDevice`] for real devices. Message framing is done on top of this,
+/// so a fake device may be used in its place.
+#[async_trait]
+pub trait UsbtmcIo: Send + Sync {
+    /// Write a transfer to the bulk-out endpoint
+    async fn bulk_out(&mut self, data: &[u8], timeout: Duration) -> Result<()>;
+
+    /// Read a transfer from the bulk-in endpoint, ending at len bytes or a
+    /// short packet
+    async fn bulk_in(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Read a transfer from the interrupt-in endpoint
+    async fn interrupt_in(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Perform a class-specific control-in request, returning up to len bytes
+    async fn control_in(
+        &mut self,
+        recipient: UsbtmcRecipient,
+        request: u8,
+        value: u16,
+        len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>>;
+
+    /// Clear a halt condition on a bulk endpoint
+    async fn clear_halt(&mut self, endpoint: UsbtmcEndpoint) -> Result<()>;
+
+    /// Whether the interface has an interrupt-in endpoint
+    fn has_interrupt_in(&self) -> bool;
+
+    /// Max packet size of the bulk-in endpoint
+    fn max_packet_size(&self) -> usize;
+}
+
+/// Capabilities reported by a device (USBTMC table 37, USB488 table 8)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsbtmcCapabilities {
+    /// USBTMC version, in BCD
+    pub bcd_usbtmc: u16,
+    pub indicator_pulse: bool,
+    pub talk_only: bool,
+    pub listen_only: bool,
+    /// Whether reads may end on a termination character
+    pub term_char: bool,
+    /// USB488 version in BCD, 0 if the device is not USB488
+    pub bcd_usb488: u16,
+    /// Whether REN_CONTROL, GO_TO_LOCAL and LOCAL_LOCKOUT are supported
+    pub ren_control: bool,
+    /// Whether the TRIGGER message is supported
+    pub trigger: bool,
+}
+impl UsbtmcCapabilities {
+    fn unpack(resp: &[u8]) -> Result<Self> {
+        if resp.len() < 16 {
+            return Err(Error::BadResponse(format!(
+                "Capabilities response of {} bytes is too short",
+                resp.len()
+            )));
+        }
+
+        Ok(Self {
+            bcd_usbtmc: u16::from_le_bytes([resp[2], resp[3]]),
+            indicator_pulse: resp[4] & 0x04 != 0,
+            talk_only: resp[4] & 0x02 != 0,
+            listen_only: resp[4] & 0x01 != 0,
+            term_char: resp[5] & 0x01 != 0,
+            bcd_usb488: u16::from_le_bytes([resp[12], resp[13]]),
+            ren_control: resp[14] & 0x02 != 0,
+            trigger: resp[14] & 0x01 != 0,
+        })
+    }
+}
+
+/// Device to open over usbfs on connect
+#[derive(Debug, Clone)]
+struct UsbtmcDeviceId {
+    vid: u16,
+    pid: u16,
+    serial: Option<String>,
+}
+
+pub struct ScpiUsbtmcProtocol {
+    /// Device to open on connect, or None if I/O was given
+    device: Option<UsbtmcDeviceId>,
+    io: Option<Box<dyn UsbtmcIo>>,
+    connected: bool,
+    capabilities: UsbtmcCapabilities,
+    /// Tag of the last bulk message, never 0
+    last_tag: u8,
+    /// Tag of the last READ_STATUS_BYTE request, 2 to 127
+    last_stb_tag: u8,
+    settings: ScpiSettings,
+}
+impl ScpiUsbtmcProtocol {
+    /// Device with the given vendor and product IDs, and serial number if
+    /// several are attached, opened via usbfs on connect
+    pub fn new(vid: u16, pid: u16, serial: Option<&str>) -> Self {
+        Self {
+            device: Some(UsbtmcDeviceId {
+                vid,
+                pid,
+                serial: serial.map(String::from),
+            }),
+            ..Self::with_io(None)
+        }
+    }
+
+    /// Use the given device I/O, kept across reconnects
+    pub fn from_io(io: Box<dyn UsbtmcIo>) -> Self {
+        Self::with_io(Some(io))
+    }
+
+    fn with_io(io: Option<Box<dyn UsbtmcIo>>) -> Self {
+        /* Messages are ended by EOM by default */
+        let mut settings = ScpiSettings::default();
+        settings.read_terminator = Terminator::Eoi;
+
+        Self {
+            device: None,
+            io,
+            connected: false,
+            capabilities: UsbtmcCapabilities::default(),
+            last_tag: 0,
+            last_stb_tag: 1,
+            settings,
+        }
+    }
+
+    /// Capabilities the device reported on connect
+    pub fn capabilities(&self) -> &UsbtmcCapabilities {
+        &self.capabilities
+    }
+
+    /// Flash the device's activity indicator, to identify it among others
+    pub async fn indicator_pulse(&mut self) -> Result<()> {
+        let io = self.io()?;
+
+        let resp = io
+            .control_in(
+                UsbtmcRecipient::Interface,
+                INDICATOR_PULSE,
+                0,
+                1,
+                CONTROL_TIMEOUT,
+            )
+            .await?;
+        check_status(&resp, "INDICATOR_PULSE")
+    }
+
+    fn io(&mut self) -> Result<&mut dyn UsbtmcIo> {
+        match &mut self.io {
+            Some(io) if self.connected => Ok(io.as_mut()),
+            _ => Err(Error::Unspecified("Not connected".into())),
+        }
+    }
+
+    fn next_tag(&mut self) -> u8 {
+        self.last_tag = self.last_tag.checked_add(1).unwrap_or(1);
+        self.last_tag
+    }
+
+    /// Write a message, split into several transfers if large, EOM being set
+    /// on the last
+    async fn write(&mut self, data: &[u8], timeout: Duration) -> Result<()> {
+        debug!(
+            "write(): {}",
+            String::from_utf8_lossy(data)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        /* An empty message is still sent, as a single transfer with EOM */
+        let mut chunks: Vec<&[u8]> = data.chunks(TRANSFER_SIZE).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let n_chunks = chunks.len();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let last = index == n_chunks - 1;
+
+            let tag = self.next_tag();
+            let mut packet = header(DEV_DEP_MSG_OUT, tag);
+            packet.extend((chunk.len() as u32).to_le_bytes());
+            packet.extend([last as u8, 0, 0, 0]);
+            packet.extend(chunk);
+            pad(&mut packet);
+
+            self.bulk_out(&packet, tag, timeout).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a transfer, aborting it on failure so the device is ready for the
+    /// next one
+    async fn bulk_out(&mut self, packet: &[u8], tag: u8, timeout: Duration) -> Result<()> {
+        let io = self.io()?;
+
+        let Err(e) = io.bulk_out(packet, timeout).await else {
+            return Ok(());
+        };
+        if !e.is_connection_lost()
+            && let Err(abort_err) = abort_bulk_out(io, tag).await
+        {
+            warn!("Failed to abort bulk-out transfer: {abort_err}");
+        }
+
+        Err(e)
+    }
+
+    /// Receive a message, ending at EOM, after length bytes or at termchar.
+    /// The end of the message may be missed if the device cannot end reads on
+    /// termchar and sends more after it.
+    async fn recv(
+        &mut self,
+        timeout: Duration,
+        length: Option<usize>,
+        termchar: Option<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut result = vec![];
+
+        debug!("recv({timeout:?}, {length:?}, {termchar:?})");
+
+        loop {
+            /* Device may return less than requested, only ask for the rest */
+            let size = length.map_or(TRANSFER_SIZE, |length| {
+                (length - result.len()).min(TRANSFER_SIZE)
+            });
+            let (mut data, eom) = self.recv_transfer(size, termchar, timeout).await?;
+
+            let ended_on_termchar = termchar.is_some() && data.last() == termchar.as_ref();
+            result.append(&mut data);
+            if eom || ended_on_termchar || length.is_some_and(|length| result.len() >= length) {
+                break;
+            }
+        }
+
+        debug!(
+            "recv: {}",
+            String::from_utf8_lossy(&result)
+                .replace('\n', "␤")
+                .replace('\r', "␊")
+        );
+
+        Ok(result)
+    }
+
+    /// Request and read a single transfer of up to size bytes, returning the
+    /// data and whether it ends the message
+    async fn recv_transfer(
+        &mut self,
+        size: usize,
+        termchar: Option<u8>,
+        timeout: Duration,
+    ) -> Result<(Vec<u8>, bool)> {
+        let termchar = termchar.filter(|_| self.capabilities.term_char);
+        let tag = self.next_tag();
+
+        let mut request = header(REQUEST_DEV_DEP_MSG_IN, tag);
+        request.extend((size as u32).to_le_bytes());
+        request.extend([(termchar.is_some() as u8) << 1, termchar.unwrap_or(0), 0, 0]);
+        self.bulk_out(&request, tag, timeout).await?;
+
+        let io = self.io()?;
+        match read_dev_dep_msg_in(io, tag, size, timeout).await {
+            Ok(res) => Ok(res),
+            Err(e) => {
+                if !e.is_connection_lost()
+                    && let Err(abort_err) = abort_bulk_in(io, tag).await
+                {
+                    warn!("Failed to abort bulk-in transfer: {abort_err}");
+                }
+                Err(e)
+            }
+        }
+    }
+}
+#[async_trait]
+impl Protocol for ScpiUsbtmcProtocol {
+    async fn connect(&mut self) -> Result<()> {
+        if self.connected {
+            return Err(Error::Unspecified("Already connected".into()));
+        }
+
+        if let Some(device) = &self.device {
+            self.io = Some(open_device(device)?);
+        }
+        let Some(io) = &mut self.io else {
+            return Err(Error::Unspecified("No device to connect to".into()));
+        };
+
+        match read_capabilities(io.as_mut()).await {
+            Ok(capabilities) => self.capabilities = capabilities,
+            Err(e) => {
+                if self.device.is_some() {
+                    self.io = None;
+                }
+                return Err(e);
+            }
+        }
+        self.connected = true;
+        debug!("USBTMC capabilities: {:?}", self.capabilities);
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.connected = false;
+        /* Releases the interface, unless the I/O was given */
+        if self.device.is_some() {
+            self.io = None;
+        }
+
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        (self as &mut dyn ScpiProtocol).idn_model().await
+    }
+}
+#[async_trait]
+impl ScpiProtocol for ScpiUsbtmcProtocol {
+    async fn int_send(&mut self, data: &[u8]) -> Result<()> {
+        self.write(data, self.settings.timeout).await
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        let termchar = self.settings.read_terminator.end_byte();
+        self.recv(self.settings.timeout, None, termchar).await
+    }
+
+    async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.int_send(data).await?;
+        self.int_recv().await
+    }
+
+    async fn recv_raw(
+        &mut self,
+        length: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let timeout = timeout.unwrap_or(self.settings.timeout);
+        self.recv(timeout, length, None).await
+    }
+
+    async fn recv_until(&mut self, byte: u8, timeout: Duration) -> Result<Vec<u8>> {
+        self.recv(timeout, None, Some(byte)).await
+    }
+
+    async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
+        match self.recv(timeout, None, None).await {
+            Ok(_) | Err(Error::Timeout(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        true
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        Some(self)
+    }
+}
+#[async_trait]
+impl Ieee488Control for ScpiUsbtmcProtocol {
+    async fn device_clear(&mut self) -> Result<()> {
+        let io = self.io()?;
+
+        let resp = io
+            .control_in(
+                UsbtmcRecipient::Interface,
+                INITIATE_CLEAR,
+                0,
+                1,
+                CONTROL_TIMEOUT,
+            )
+            .await?;
+        check_status(&resp, "INITIATE_CLEAR")?;
+
+        loop {
+            let resp = io
+                .control_in(
+                    UsbtmcRecipient::Interface,
+                    CHECK_CLEAR_STATUS,
+                    0,
+                    2,
+                    CONTROL_TIMEOUT,
+                )
+                .await?;
+            if resp.first() != Some(&STATUS_PENDING) {
+                check_status(&resp, "CHECK_CLEAR_STATUS")?;
+                break;
+            }
+
+            /* Device may be waiting for data it has queued to be read */
+            if resp.get(1).is_some_and(|clear| clear & 0x01 != 0) {
+                let size = io.max_packet_size();
+                io.bulk_in(size, CONTROL_TIMEOUT).await?;
+            } else {
+                tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+            }
+        }
+
+        io.clear_halt(UsbtmcEndpoint::BulkOut).await
+    }
+
+    async fn device_trigger(&mut self) -> Result<()> {
+        if !self.capabilities.trigger {
+            return self.write(b"*TRG\n", self.settings.timeout).await;
+        }
+
+        let tag = self.next_tag();
+        let mut packet = header(USB488_TRIGGER, tag);
+        packet.extend([0; 8]);
+        self.bulk_out(&packet, tag, self.settings.timeout).await
+    }
+
+    async fn read_stb(&mut self) -> Result<u8> {
+        self.last_stb_tag = if self.last_stb_tag >= 127 {
+            2
+        } else {
+            self.last_stb_tag + 1
+        };
+        let tag = self.last_stb_tag;
+        let timeout = self.settings.timeout;
+        let io = self.io()?;
+
+        let resp = io
+            .control_in(
+                UsbtmcRecipient::Interface,
+                READ_STATUS_BYTE,
+                tag as u16,
+                3,
+                CONTROL_TIMEOUT,
+            )
+            .await?;
+        check_status(&resp, "READ_STATUS_BYTE")?;
+
+        /* With an interrupt-in endpoint, the status byte is sent on it
+         * rather than in the response (USB488 4.3.1) */
+        if !io.has_interrupt_in() {
+            return resp
+                .get(2)
+                .copied()
+                .ok_or_else(|| Error::BadResponse("Status byte response too short".into()));
+        }
+
+        let notify = io.interrupt_in(2, timeout).await?;
+        match notify[..] {
+            [notify1, stb] if notify1 == 0x80 | tag => Ok(stb),
+            _ => Err(Error::BadResponse(format!(
+                "Unexpected status byte notification {notify:02x?}"
+            ))),
+        }
+    }
+
+    async fn device_local(&mut self) -> Result<()> {
+        if !self.capabilities.ren_control {
+            return Err(Error::NotSupported(
+                "Device cannot be returned to local control".into(),
+            ));
+        }
+
+        let io = self.io()?;
+        let resp = io
+            .control_in(
+                UsbtmcRecipient::Interface,
+                GO_TO_LOCAL,
+                0,
+                1,
+                CONTROL_TIMEOUT,
+            )
+            .await?;
+        check_status(&resp, "GO_TO_LOCAL")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_device(device: &UsbtmcDeviceId) -> Result<Box<dyn UsbtmcIo>> {
+    Ok(Box::new(UsbfsDevice::open(
+        device.vid,
+        device.pid,
+        device.serial.as_deref(),
+    )?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_device(_device: &UsbtmcDeviceId) -> Result<Box<dyn UsbtmcIo>> {
+    Err(Error::NotSupported(
+        "USBTMC is only supported on Linux".into(),
+    ))
+}
+
+async fn read_capabilities(io: &mut dyn UsbtmcIo) -> Result<UsbtmcCapabilities> {
+    let resp = io
+        .control_in(
+            UsbtmcRecipient::Interface,
+            GET_CAPABILITIES,
+            0,
+            0x18,
+            CONTROL_TIMEOUT,
+        )
+        .await?;
+    check_status(&resp, "GET_CAPABILITIES")?;
+
+    UsbtmcCapabilities::unpack(&resp)
+}
+
+/// Start of a bulk-out message, without its message-specific part
+fn header(msg_id: u8, tag: u8) -> Vec<u8> {
+    vec![msg_id, tag, !tag, 0]
+}
+
+/// Pad a bulk-out message to a multiple of 4 bytes
+fn pad(packet: &mut Vec<u8>) {
+    packet.resize(packet.len().next_multiple_of(4), 0);
+}
+
+fn check_status(resp: &[u8], request: &str) -> Result<()> {
+    match resp.first() {
+        Some(&STATUS_SUCCESS) => Ok(()),
+        Some(status) => Err(Error::Unspecified(format!(
+            "Device returned status {status:#04x} on {request}"
+        ))),
+        None => Err(Error::BadResponse(format!("Empty response to {request}"))),
+    }
+}
+
+/// Read a DEV_DEP_MSG_IN message, which may span several transfers,
+/// returning its data and whether EOM was set
+async fn read_dev_dep_msg_in(
+    io: &mut dyn UsbtmcIo,
+    tag: u8,
+    size: usize,
+    timeout: Duration,
+) -> Result<(Vec<u8>, bool)> {
+    /* Whole packets are read, as a device padding its last packet would
+     * otherwise overflow the transfer */
+    let max_packet = io.max_packet_size();
+    let read_len = (HEADER_SIZE + size + 3).next_multiple_of(max_packet);
+
+    let mut msg = io.bulk_in(read_len, timeout).await?;
+    if msg.len() < HEADER_SIZE {
+        return Err(Error::BadResponse(format!(
+            "Bulk-in message of {} bytes is too short",
+            msg.len()
+        )));
+    }
+    if msg[0] != DEV_DEP_MSG_IN || msg[1] != tag || msg[2] != !tag {
+        return Err(Error::BadResponse(format!(
+            "Unexpected bulk-in header {:02x?}, expected tag {tag}",
+            &msg[..4]
+        )));
+    }
+    let transfer_size = u32::from_le_bytes([msg[4], msg[5], msg[6], msg[7]]) as usize;
+    let eom = msg[8] & 0x01 != 0;
+    if transfer_size > size {
+        return Err(Error::BadResponse(format!(
+            "Device sent {transfer_size} bytes, more than the {size} requested"
+        )));
+    }
+
+    /* Rest of the message follows if it did not fit in a single transfer */
+    while msg.len() < HEADER_SIZE + transfer_size {
+        let mut more = io.bulk_in(read_len, timeout).await?;
+        if more.is_empty() {
+            return Err(Error::BadResponse("Bulk-in message truncated".into()));
+        }
+        msg.append(&mut more);
+    }
+
+    msg.truncate(HEADER_SIZE + transfer_size);
+    msg.drain(..HEADER_SIZE);
+
+    Ok((msg, eom))
+}
+
+/// Abort a bulk-out transfer (USBTMC 4.2.1.2)
+async fn abort_bulk_out(io: &mut dyn UsbtmcIo, tag: u8) -> Result<()> {
+    let endpoint = UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkOut);
+
+    let resp = io
+        .control_in(
+            endpoint,
+            INITIATE_ABORT_BULK_OUT,
+            tag as u16,
+            2,
+            CONTROL_TIMEOUT,
+        )
+        .await?;
+    match resp.first() {
+        Some(&STATUS_SUCCESS) => {}
+        /* Transfer already completed or never started */
+        Some(&STATUS_TRANSFER_NOT_IN_PROGRESS) => return Ok(()),
+        _ => return check_status(&resp, "INITIATE_ABORT_BULK_OUT"),
+    }
+
+    loop {
+        let resp = io
+            .control_in(endpoint, CHECK_ABORT_BULK_OUT_STATUS, 0, 8, CONTROL_TIMEOUT)
+            .await?;
+        if resp.first() != Some(&STATUS_PENDING) {
+            check_status(&resp, "CHECK_ABORT_BULK_OUT_STATUS")?;
+            break;
+        }
+        tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+    }
+
+    io.clear_halt(UsbtmcEndpoint::BulkOut).await
+}
+
+/// Abort a bulk-in transfer, discarding data the device has queued
+/// (USBTMC 4.2.1.4)
+async fn abort_bulk_in(io: &mut dyn UsbtmcIo, tag: u8) -> Result<()> {
+    let endpoint = UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkIn);
+
+    let resp = io
+        .control_in(
+            endpoint,
+            INITIATE_ABORT_BULK_IN,
+            tag as u16,
+            2,
+            CONTROL_TIMEOUT,
+        )
+        .await?;
+    match resp.first() {
+        Some(&STATUS_SUCCESS) => {}
+        Some(&STATUS_TRANSFER_NOT_IN_PROGRESS) => return Ok(()),
+        _ => return check_status(&resp, "INITIATE_ABORT_BULK_IN"),
+    }
+
+    /* Read until a short packet ends the aborted transfer, if anything was
+     * left to read */
+    let max_packet = io.max_packet_size();
+    loop {
+        match io.bulk_in(max_packet, ABORT_DRAIN_TIMEOUT).await {
+            Ok(data) if data.len() == max_packet => {}
+            Ok(_) | Err(Error::Timeout(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    loop {
+        let resp = io
+            .control_in(endpoint, CHECK_ABORT_BULK_IN_STATUS, 0, 8, CONTROL_TIMEOUT)
+            .await?;
+        if resp.first() != Some(&STATUS_PENDING) {
+            return check_status(&resp, "CHECK_ABORT_BULK_IN_STATUS");
+        }
+        tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const MAX_PACKET: usize = 64;
+
+    #[derive(Default)]
+    struct FakeState {
+        /// Transfers to return from the bulk-in endpoint, in order
+        bulk_in: VecDeque<Vec<u8>>,
+        /// Responses to control requests, in order for each request
+        control: HashMap<u8, VecDeque<Vec<u8>>>,
+        bulk_out: Vec<Vec<u8>>,
+        /// Control requests made, with their value
+        requests: Vec<(UsbtmcRecipient, u8, u16)>,
+        cleared: Vec<UsbtmcEndpoint>,
+    }
+
+    /// Fake interface, timing out once out of bulk-in transfers
+    struct FakeIo(Arc<Mutex<FakeState>>);
+    #[async_trait]
+    impl UsbtmcIo for FakeIo {
+        async fn bulk_out(&mut self, data: &[u8], _timeout: Duration) -> Result<()> {
+            self.0.lock().unwrap().bulk_out.push(data.to_vec());
+            Ok(())
+        }
+
+        async fn bulk_in(&mut self, len: usize, _timeout: Duration) -> Result<Vec<u8>> {
+            let Some(data) = self.0.lock().unwrap().bulk_in.pop_front() else {
+                return Err(Error::Timeout("No bulk-in data".into()));
+            };
+            assert!(
+                data.len() <= len,
+                "Transfer of {} bytes overflows",
+                data.len()
+            );
+            Ok(data)
+        }
+
+        async fn interrupt_in(&mut self, _len: usize, _timeout: Duration) -> Result<Vec<u8>> {
+            Err(Error::NotSupported("No interrupt-in endpoint".into()))
+        }
+
+        async fn control_in(
+            &mut self,
+            recipient: UsbtmcRecipient,
+            request: u8,
+            value: u16,
+            _len: usize,
+            _timeout: Duration,
+        ) -> Result<Vec<u8>> {
+            let mut state = self.0.lock().unwrap();
+            state.requests.push((recipient, request, value));
+            state
+                .control
+                .get_mut(&request)
+                .and_then(VecDeque::pop_front)
+                .ok_or_else(|| Error::Unspecified(format!("Unexpected request {request}")))
+        }
+
+        async fn clear_halt(&mut self, endpoint: UsbtmcEndpoint) -> Result<()> {
+            self.0.lock().unwrap().cleared.push(endpoint);
+            Ok(())
+        }
+
+        fn has_interrupt_in(&self) -> bool {
+            false
+        }
+
+        fn max_packet_size(&self) -> usize {
+            MAX_PACKET
+        }
+    }
+
+    fn fake() -> (FakeIo, Arc<Mutex<FakeState>>) {
+        let state = Arc::new(Mutex::new(FakeState::default()));
+        (FakeIo(state.clone()), state)
+    }
+
+    fn respond(state: &Mutex<FakeState>, request: u8, resp: &[u8]) {
+        let mut state = state.lock().unwrap();
+        state
+            .control
+            .entry(request)
+            .or_default()
+            .push_back(resp.to_vec());
+    }
+
+    fn dev_dep_msg_in(tag: u8, data: &[u8], eom: bool) -> Vec<u8> {
+        let mut msg = header(DEV_DEP_MSG_IN, tag);
+        msg.extend((data.len() as u32).to_le_bytes());
+        msg.extend([eom as u8, 0, 0, 0]);
+        msg.extend(data);
+        pad(&mut msg);
+        msg
+    }
+
+    #[tokio::test]
+    async fn reassembles_message_over_transfers() {
+        let (mut io, state) = fake();
+        let data: Vec<u8> = (0..100).collect();
+        let mut msg = dev_dep_msg_in(5, &data, true);
+        let rest = msg.split_off(MAX_PACKET);
+        state.lock().unwrap().bulk_in.extend([msg, rest]);
+
+        let (read, eom) = read_dev_dep_msg_in(&mut io, 5, 200, TIMEOUT).await.unwrap();
+        assert_eq!(read, data);
+        assert!(eom);
+        assert!(state.lock().unwrap().bulk_in.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_messages() {
+        let (mut io, state) = fake();
+        let mut wrong_inverse = dev_dep_msg_in(5, b"1\n", true);
+        wrong_inverse[2] = 0;
+        state.lock().unwrap().bulk_in.extend([
+            dev_dep_msg_in(4, b"1\n", true),
+            wrong_inverse,
+            dev_dep_msg_in(5, b"too long\n", true),
+            dev_dep_msg_in(5, b"1\n", true)[..HEADER_SIZE - 1].to_vec(),
+        ]);
+
+        for _ in 0..4 {
+            let res = read_dev_dep_msg_in(&mut io, 5, 4, TIMEOUT).await;
+            assert!(matches!(res, Err(Error::BadResponse(_))), "{res:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_bulk_in_drains_transfer() {
+        let (mut io, state) = fake();
+        respond(&state, INITIATE_ABORT_BULK_IN, &[STATUS_SUCCESS, 7]);
+        respond(&state, CHECK_ABORT_BULK_IN_STATUS, &[STATUS_PENDING, 0]);
+        respond(&state, CHECK_ABORT_BULK_IN_STATUS, &[STATUS_SUCCESS, 0]);
+        state.lock().unwrap().bulk_in.extend([
+            vec![0; MAX_PACKET],
+            vec![0; MAX_PACKET],
+            vec![0; 10],
+            b"next".to_vec(),
+        ]);
+
+        abort_bulk_in(&mut io, 7).await.unwrap();
+
+        let state = state.lock().unwrap();
+        let endpoint = UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkIn);
+        assert_eq!(
+            state.requests,
+            [
+                (endpoint, INITIATE_ABORT_BULK_IN, 7),
+                (endpoint, CHECK_ABORT_BULK_IN_STATUS, 0),
+                (endpoint, CHECK_ABORT_BULK_IN_STATUS, 0),
+            ]
+        );
+        /* Only the aborted transfer is read, up to its short packet */
+        assert_eq!(state.bulk_in, [b"next".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn abort_bulk_in_without_transfer() {
+        let (mut io, state) = fake();
+        respond(
+            &state,
+            INITIATE_ABORT_BULK_IN,
+            &[STATUS_TRANSFER_NOT_IN_PROGRESS, 0],
+        );
+        state.lock().unwrap().bulk_in.push_back(b"next".to_vec());
+
+        abort_bulk_in(&mut io, 7).await.unwrap();
+        assert_eq!(state.lock().unwrap().bulk_in.len(), 1);
+    }
+
+    async fn connected(state: &Mutex<FakeState>, io: FakeIo) -> Box<dyn ScpiProtocol> {
+        let mut capabilities = vec![0; 0x18];
+        capabilities[0] = STATUS_SUCCESS;
+        respond(state, GET_CAPABILITIES, &capabilities);
+
+        let mut proto: Box<dyn ScpiProtocol> = Box::new(ScpiUsbtmcProtocol::from_io(Box::new(io)));
+        proto.connect().await.unwrap();
+        proto
+    }
+
+    #[tokio::test]
+    async fn query() {
+        let (io, state) = fake();
+        let mut proto = connected(&state, io).await;
+        state
+            .lock()
+            .unwrap()
+            .bulk_in
+            .push_back(dev_dep_msg_in(2, b"FAKE,USBTMC,0,1.0\n", true));
+
+        assert_eq!(proto.query_str("*IDN?").await.unwrap(), "FAKE,USBTMC,0,1.0");
+
+        let state = state.lock().unwrap();
+        let mut write = dev_dep_msg_in(1, b"*IDN?\r\n", true);
+        write[0] = DEV_DEP_MSG_OUT;
+        write[2] = !1;
+        assert_eq!(state.bulk_out[0], write);
+        assert_eq!(
+            state.bulk_out[1],
+            [REQUEST_DEV_DEP_MSG_IN, 2, !2, 0, 0, 0, 1, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_empty_message() {
+        let (io, state) = fake();
+        let mut capabilities = vec![0; 0x18];
+        capabilities[0] = STATUS_SUCCESS;
+        respond(&state, GET_CAPABILITIES, &capabilities);
+        let mut usbtmc = ScpiUsbtmcProtocol::from_io(Box::new(io));
+        usbtmc.connect().await.unwrap();
+
+        usbtmc.write(&[], TIMEOUT).await.unwrap();
+
+        let mut write = dev_dep_msg_in(1, &[], true);
+        write[0] = DEV_DEP_MSG_OUT;
+        write[2] = !1;
+        assert_eq!(state.lock().unwrap().bulk_out, [write]);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_block() {
+        let (io, state) = fake();
+        let mut proto = connected(&state, io).await;
+        state.lock().unwrap().bulk_in.extend([
+            dev_dep_msg_in(2, b"#1", false),
+            dev_dep_msg_in(3, b"8", false),
+            dev_dep_msg_in(4, b"abc\n", true),
+        ]);
+
+        let res = proto.query_block(":DATA?").await;
+        assert!(matches!(res, Err(Error::BadResponse(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn aborts_read_of_unexpected_message() {
+        let (io, state) = fake();
+        let mut proto = connected(&state, io).await;
+        respond(
+            &state,
+            INITIATE_ABORT_BULK_IN,
+            &[STATUS_TRANSFER_NOT_IN_PROGRESS, 0],
+        );
+        state
+            .lock()
+            .unwrap()
+            .bulk_in
+            .push_back(dev_dep_msg_in(9, b"stale\n", true));
+
+        let res = proto.query("*IDN?").await;
+        assert!(matches!(res, Err(Error::BadResponse(_))), "{res:?}");
+        assert_eq!(
+            state.lock().unwrap().requests.last(),
+            Some(&(
+                UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkIn),
+                INITIATE_ABORT_BULK_IN,
+                2
+            ))
+        );
+    }
+}
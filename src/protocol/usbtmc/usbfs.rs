@@ -0,0 +1,408 @@
+//! USBTMC device access through Linux usbfs, found via sysfs
+
+use std::{
+    ffi::c_void,
+    fs::{self, File, OpenOptions},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::debug;
+
+use crate::error::{Error, Result};
+
+use super::{UsbtmcEndpoint, UsbtmcIo, UsbtmcRecipient};
+
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// Interface class and subclass of USBTMC
+const USBTMC_CLASS: u8 = 0xfe;
+const USBTMC_SUBCLASS: u8 = 0x03;
+
+/// Class request to an interface or endpoint, device to host
+const REQUEST_TYPE_INTERFACE: u8 = 0xa1;
+const REQUEST_TYPE_ENDPOINT: u8 = 0xa2;
+
+/// ioctl request numbers, from linux/usbdevice_fs.h
+const USBDEVFS_CONTROL: libc::Ioctl = ioc(3, 0, size_of::<CtrlTransfer>());
+const USBDEVFS_BULK: libc::Ioctl = ioc(3, 2, size_of::<BulkTransfer>());
+const USBDEVFS_RELEASEINTERFACE: libc::Ioctl = ioc(2, 16, size_of::<u32>());
+const USBDEVFS_IOCTL: libc::Ioctl = ioc(3, 18, size_of::<UsbdevfsIoctl>());
+const USBDEVFS_CLEAR_HALT: libc::Ioctl = ioc(2, 21, size_of::<u32>());
+const USBDEVFS_CONNECT: libc::Ioctl = ioc(0, 23, 0);
+const USBDEVFS_DISCONNECT_CLAIM: libc::Ioctl = ioc(2, 27, size_of::<DisconnectClaim>());
+
+/// Request number of the given direction, number and argument size, with the
+/// layout most architectures use
+const fn ioc(dir: u32, nr: u32, size: usize) -> libc::Ioctl {
+    ((dir << 30) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr) as libc::Ioctl
+}
+
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct BulkTransfer {
+    ep: u32,
+    len: u32,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct DisconnectClaim {
+    interface: u32,
+    flags: u32,
+    driver: [libc::c_char; 256],
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    interface: libc::c_int,
+    code: libc::c_int,
+    data: *mut c_void,
+}
+
+/// USBTMC interface of a device opened through usbfs, claimed from any
+/// kernel driver until dropped
+pub struct UsbfsDevice {
+    file: Arc<File>,
+    interface: u32,
+    bulk_in: u8,
+    bulk_out: u8,
+    interrupt_in: Option<u8>,
+    max_packet_size: usize,
+}
+impl UsbfsDevice {
+    /// Open the USBTMC interface of the device with the given vendor and
+    /// product IDs, and serial number if several are attached
+    pub fn open(vid: u16, pid: u16, serial: Option<&str>) -> Result<Self> {
+        let device = find_device(vid, pid, serial)?;
+        let (interface, dir) = find_interface(&device)?;
+
+        let (mut bulk_in, mut bulk_out, mut interrupt_in) = (None, None, None);
+        let mut max_packet_size = 64;
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if !entry.file_name().to_string_lossy().starts_with("ep_") {
+                continue;
+            }
+            let (Some(ep_type), Some(address)) = (
+                read_attr(&path, "type"),
+                read_hex(&path, "bEndpointAddress"),
+            ) else {
+                continue;
+            };
+
+            let is_in = address & 0x80 != 0;
+            match (ep_type.as_str(), is_in) {
+                ("Bulk", true) => {
+                    bulk_in = Some(address as u8);
+                    max_packet_size = read_hex(&path, "wMaxPacketSize")
+                        .map_or(max_packet_size, |size| size as usize & 0x7ff);
+                }
+                ("Bulk", false) => bulk_out = Some(address as u8),
+                ("Interrupt", true) => interrupt_in = Some(address as u8),
+                _ => {}
+            }
+        }
+        let (Some(bulk_in), Some(bulk_out)) = (bulk_in, bulk_out) else {
+            return Err(Error::Unspecified(format!(
+                "USBTMC interface {interface} of {vid:04x}:{pid:04x} lacks bulk endpoints"
+            )));
+        };
+
+        let (Some(busnum), Some(devnum)) = (
+            read_attr(&device, "busnum").and_then(|n| n.parse::<u32>().ok()),
+            read_attr(&device, "devnum").and_then(|n| n.parse::<u32>().ok()),
+        ) else {
+            return Err(Error::Unspecified(format!(
+                "Failed to find bus address of {vid:04x}:{pid:04x}"
+            )));
+        };
+        let node = format!("/dev/bus/usb/{busnum:03}/{devnum:03}");
+        debug!("USBTMC device {vid:04x}:{pid:04x} at {node}, interface {interface}");
+        let file = OpenOptions::new().read(true).write(true).open(&node)?;
+
+        /* Detaches the kernel usbtmc driver, if bound */
+        let mut claim = DisconnectClaim {
+            interface,
+            flags: 0,
+            driver: [0; 256],
+        };
+        ioctl(
+            &file,
+            USBDEVFS_DISCONNECT_CLAIM,
+            &mut claim as *mut _ as *mut c_void,
+        )?;
+
+        Ok(Self {
+            file: Arc::new(file),
+            interface,
+            bulk_in,
+            bulk_out,
+            interrupt_in,
+            max_packet_size,
+        })
+    }
+
+    /// Run a transfer on a blocking thread, with buf holding the data to send
+    /// or sized for the data to receive, returning it truncated to the length
+    /// transferred
+    async fn transfer(
+        &self,
+        transfer: Transfer,
+        mut buf: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let file = self.file.clone();
+        /* usbfs takes 0 to mean no timeout */
+        let timeout = u32::try_from(timeout.as_millis())
+            .unwrap_or(u32::MAX)
+            .max(1);
+
+        tokio::task::spawn_blocking(move || {
+            let data = buf.as_mut_ptr() as *mut c_void;
+            let len = match transfer {
+                Transfer::Control {
+                    request_type,
+                    request,
+                    value,
+                    index,
+                } => {
+                    let mut ctrl = CtrlTransfer {
+                        request_type,
+                        request,
+                        value,
+                        index,
+                        length: buf.len() as u16,
+                        timeout,
+                        data,
+                    };
+                    ioctl(&file, USBDEVFS_CONTROL, &mut ctrl as *mut _ as *mut c_void)?
+                }
+                Transfer::Bulk { ep } => {
+                    let mut bulk = BulkTransfer {
+                        ep: ep as u32,
+                        len: buf.len() as u32,
+                        timeout,
+                        data,
+                    };
+                    ioctl(&file, USBDEVFS_BULK, &mut bulk as *mut _ as *mut c_void)?
+                }
+            };
+            buf.truncate(len as usize);
+            Ok(buf)
+        })
+        .await
+        .map_err(|e| Error::Unhandled(e.into()))?
+    }
+}
+#[async_trait]
+impl UsbtmcIo for UsbfsDevice {
+    async fn bulk_out(&mut self, data: &[u8], timeout: Duration) -> Result<()> {
+        let sent = self
+            .transfer(Transfer::Bulk { ep: self.bulk_out }, data.to_vec(), timeout)
+            .await?;
+        if sent.len() < data.len() {
+            return Err(Error::Unspecified(format!(
+                "Only {} of {} bytes were sent",
+                sent.len(),
+                data.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn bulk_in(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        self.transfer(Transfer::Bulk { ep: self.bulk_in }, vec![0; len], timeout)
+            .await
+    }
+
+    async fn interrupt_in(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        let Some(ep) = self.interrupt_in else {
+            return Err(Error::NotSupported(
+                "Device has no interrupt-in endpoint".into(),
+            ));
+        };
+
+        /* Bulk transfers on an interrupt endpoint are done as interrupt
+         * transfers */
+        self.transfer(Transfer::Bulk { ep }, vec![0; len], timeout)
+            .await
+    }
+
+    async fn control_in(
+        &mut self,
+        recipient: UsbtmcRecipient,
+        request: u8,
+        value: u16,
+        len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let (request_type, index) = match recipient {
+            UsbtmcRecipient::Interface => (REQUEST_TYPE_INTERFACE, self.interface as u16),
+            UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkIn) => {
+                (REQUEST_TYPE_ENDPOINT, self.bulk_in as u16)
+            }
+            UsbtmcRecipient::Endpoint(UsbtmcEndpoint::BulkOut) => {
+                (REQUEST_TYPE_ENDPOINT, self.bulk_out as u16)
+            }
+        };
+
+        let transfer = Transfer::Control {
+            request_type,
+            request,
+            value,
+            index,
+        };
+        self.transfer(transfer, vec![0; len], timeout).await
+    }
+
+    async fn clear_halt(&mut self, endpoint: UsbtmcEndpoint) -> Result<()> {
+        let mut ep = match endpoint {
+            UsbtmcEndpoint::BulkIn => self.bulk_in,
+            UsbtmcEndpoint::BulkOut => self.bulk_out,
+        } as u32;
+
+        ioctl(
+            &self.file,
+            USBDEVFS_CLEAR_HALT,
+            &mut ep as *mut _ as *mut c_void,
+        )?;
+        Ok(())
+    }
+
+    fn has_interrupt_in(&self) -> bool {
+        self.interrupt_in.is_some()
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
+impl Drop for UsbfsDevice {
+    fn drop(&mut self) {
+        let mut interface = self.interface;
+        let _ = ioctl(
+            &self.file,
+            USBDEVFS_RELEASEINTERFACE,
+            &mut interface as *mut _ as *mut c_void,
+        );
+
+        /* Hands the interface back to the kernel driver */
+        let mut connect = UsbdevfsIoctl {
+            interface: self.interface as libc::c_int,
+            code: USBDEVFS_CONNECT as libc::c_int,
+            data: std::ptr::null_mut(),
+        };
+        let _ = ioctl(
+            &self.file,
+            USBDEVFS_IOCTL,
+            &mut connect as *mut _ as *mut c_void,
+        );
+    }
+}
+
+enum Transfer {
+    Control {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    Bulk {
+        ep: u8,
+    },
+}
+
+fn ioctl(file: &File, code: libc::Ioctl, arg: *mut c_void) -> Result<i32> {
+    /* SAFETY: arg points to the structure the request expects, along with
+     * any buffer it refers to, both outliving the call */
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), code, arg) };
+    if res >= 0 {
+        return Ok(res);
+    }
+
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ETIMEDOUT) => Err(Error::Timeout("USB transfer timed out".into())),
+        _ => Err(e.into()),
+    }
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+fn read_hex(dir: &Path, name: &str) -> Option<u32> {
+    u32::from_str_radix(&read_attr(dir, name)?, 16).ok()
+}
+
+/// Find the sysfs directory of a USB device
+fn find_device(vid: u16, pid: u16, serial: Option<&str>) -> Result<PathBuf> {
+    let mut matching = vec![];
+    let entries = fs::read_dir(SYSFS_USB_DEVICES)
+        .map_err(|e| Error::Unspecified(format!("Failed to list USB devices: {e}")))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if read_hex(&path, "idVendor") != Some(vid as u32)
+            || read_hex(&path, "idProduct") != Some(pid as u32)
+        {
+            continue;
+        }
+        if let Some(serial) = serial
+            && read_attr(&path, "serial").as_deref() != Some(serial)
+        {
+            continue;
+        }
+
+        matching.push(path);
+    }
+
+    match &matching[..] {
+        [] => Err(Error::Unspecified(format!(
+            "No USB device {vid:04x}:{pid:04x} found"
+        ))),
+        [device] => Ok(device.clone()),
+        _ => Err(Error::InvalidArgument(format!(
+            "{} USB devices {vid:04x}:{pid:04x} found, a serial number is needed",
+            matching.len()
+        ))),
+    }
+}
+
+/// Find the number and sysfs directory of the USBTMC interface of a device
+fn find_interface(device: &Path) -> Result<(u32, PathBuf)> {
+    for entry in fs::read_dir(device)?.flatten() {
+        let path = entry.path();
+        if read_hex(&path, "bInterfaceClass") != Some(USBTMC_CLASS as u32)
+            || read_hex(&path, "bInterfaceSubClass") != Some(USBTMC_SUBCLASS as u32)
+        {
+            continue;
+        }
+
+        if let Some(interface) = read_hex(&path, "bInterfaceNumber") {
+            return Ok((interface, path));
+        }
+    }
+
+    Err(Error::Unspecified(format!(
+        "Device at {} has no USBTMC interface",
+        device.display()
+    )))
+}
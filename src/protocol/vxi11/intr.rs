@@ -0,0 +1,110 @@
+//! VXI-11 interrupt channel server, receiving service requests from devices
+
+use std::net::{IpAddr, SocketAddr};
+
+use log::{debug, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::error::Result;
+
+use super::{
+    VXI_INTERRUPT_PROG, VXI_INTERRUPT_VERS,
+    onc::{self, AcceptedReplyBodyType},
+    rpc,
+};
+
+/// Number of service requests that may be queued for a slow receiver before
+/// older ones are dropped
+const SRQ_QUEUE_SIZE: usize = 16;
+
+/// RPC server for the interrupt channel. The device connects to this server
+/// and calls device_intr_srq whenever it asserts a service request.
+pub struct VxiInterruptServer {
+    addr: SocketAddr,
+    srq: broadcast::Sender<()>,
+    task: JoinHandle<()>,
+}
+impl VxiInterruptServer {
+    /// Start listening for interrupt channel connections on the given local
+    /// address
+    pub async fn start(ip: IpAddr) -> Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let addr = listener.local_addr()?;
+        let (srq, _) = broadcast::channel(SRQ_QUEUE_SIZE);
+
+        let task = tokio::spawn(Self::accept_loop(listener, srq.clone()));
+
+        debug!("Interrupt server listening on {addr}");
+
+        Ok(Self { addr, srq, task })
+    }
+
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get a new receiver that is notified on each service request
+    pub fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.srq.subscribe()
+    }
+
+    async fn accept_loop(listener: TcpListener, srq: broadcast::Sender<()>) {
+        /* Dropping the set when this task is aborted also aborts all
+         * connection tasks */
+        let mut connections = JoinSet::new();
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!("Interrupt channel connection from {peer}");
+                    let srq = srq.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = Self::serve_connection(stream, srq).await {
+                            debug!("Interrupt channel connection closed: {e}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept interrupt channel connection: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn serve_connection(mut stream: TcpStream, srq: broadcast::Sender<()>) -> Result<()> {
+        loop {
+            let (xid, call) = onc::read_call(&mut stream).await?;
+
+            let reply =
+                if let Some(reply) = call.check_program(VXI_INTERRUPT_PROG, VXI_INTERRUPT_VERS) {
+                    reply
+                } else if call.proc == rpc::RpcRequest::DeviceIntrSrq as u32 {
+                    let mut args = call.args;
+                    match rpc::RpcRequestDeviceSrq::unpack(&mut args) {
+                        Ok(req) => {
+                            debug!("Service request, handle {:02x?}", req.handle);
+                            /* Nobody listening is not an error */
+                            let _ = srq.send(());
+                            AcceptedReplyBodyType::success(())
+                        }
+                        Err(_) => AcceptedReplyBodyType::GarbageArgs(),
+                    }
+                } else {
+                    AcceptedReplyBodyType::ProcUnavail()
+                };
+
+            onc::write_reply(&mut stream, xid, reply).await?;
+        }
+    }
+}
+impl Drop for VxiInterruptServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
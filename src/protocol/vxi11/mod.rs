@@ -1,52 +1,157 @@
 //! VXI-11 protocol, referencing VXI-11 1.0 specification
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use log::{debug, error};
-use tokio::sync::Mutex;
+use log::{debug, error, warn};
+use tokio::sync::{Mutex, broadcast};
 
 use crate::{
     error::{Error, Result},
     model::ModelInfo,
 };
 
-use self::{onc::OncClient, xdr::XdrPack};
+use self::{
+    intr::VxiInterruptServer,
+    onc::OncClient,
+    portmap::{PortmapClient, RpcIpProto, RpcMapping},
+    xdr::XdrPack,
+};
 
-use super::{Protocol, ScpiProtocol};
+use super::{Ieee488Control, Protocol, ScpiProtocol, ScpiSettings, Terminator};
 
+mod intr;
 mod onc;
 pub mod portmap;
 mod rpc;
+mod server;
 mod xdr;
 
-const VXI_CORE_PROG: u32 = 395183;
-const VXI_CORE_VERS: u32 = 1;
+pub use server::VxiServer;
+
+pub(crate) const VXI_CORE_PROG: u32 = 395183;
+pub(crate) const VXI_CORE_VERS: u32 = 1;
 const VXI_ABORT_PROG: u32 = 395184;
 const VXI_ABORT_VERS: u32 = 1;
 const VXI_INTERRUPT_PROG: u32 = 395185;
 const VXI_INTERRUPT_VERS: u32 = 1;
 
+/// Device name to link to if none is given (VXI11.3 B.1.2)
+pub(crate) const VXI11_DEFAULT_DEVICE: &str = "inst0";
 /// Client ID to use, seems arbitrary?
 const CLIENT_ID: i32 = 1;
-/// Default IO timeout
-const IO_TIMEOUT: u32 = 10000;
-/// Device lock timeout
-const LOCK_TIMEOUT: u32 = 0;
+/// Time to wait for an RPC response beyond the I/O and lock timeouts given to
+/// the device
+const RPC_TIMEOUT_MARGIN: Duration = Duration::from_secs(2);
+/// Default device lock timeout
+const LOCK_TIMEOUT: Duration = Duration::ZERO;
 /// Max amount to read in a single transaction
 const READ_SIZE: u32 = 65536;
 
 pub struct ScpiVxiProtocol {
     vxi: VxiClient,
     link: Option<VxiClientLink>,
+    /// Interrupt channel server, created when service requests are enabled
+    intr: Option<VxiInterruptServer>,
+    /// Time operations wait for a lock held by another link, applied to each
+    /// link created
+    lock_timeout: Duration,
+    settings: ScpiSettings,
 }
 impl ScpiVxiProtocol {
     pub fn new(socket: SocketAddr) -> Self {
+        /* Messages are ended by the END indicator by default */
+        let mut settings = ScpiSettings::default();
+        settings.read_terminator = Terminator::Eoi;
+
         Self {
             vxi: VxiClient::new(socket),
             link: None,
+            intr: None,
+            lock_timeout: LOCK_TIMEOUT,
+            settings,
         }
     }
+
+    /// Enable service requests from the device, returning a receiver that is
+    /// notified each time the device asserts SRQ. The interrupt channel is
+    /// created on first use.
+    pub async fn enable_srq(&mut self) -> Result<broadcast::Receiver<()>> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        let intr = match &mut self.intr {
+            Some(intr) => intr,
+            None => {
+                /* The device must be able to reach us on the same address
+                 * used for the core channel */
+                let local = self.vxi.local_addr().await?;
+                let intr = VxiInterruptServer::start(local.ip()).await?;
+                /* Reported as unsupported, so callers may fall back to
+                 * polling, as many devices do not implement the channel */
+                self.vxi.create_intr_chan(intr.addr()).await.map_err(|e| {
+                    Error::NotSupported(format!("Could not create interrupt channel: {e}"))
+                })?;
+                self.intr.insert(intr)
+            }
+        };
+        let srq = intr.subscribe();
+
+        link.enable_srq(true).await?;
+
+        Ok(srq)
+    }
+
+    /// Disable service requests from the device. The interrupt channel is left
+    /// open, so they may be enabled again later.
+    pub async fn disable_srq(&mut self) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.enable_srq(false).await
+    }
+
+    /// Set the time operations on the link will wait for a lock held by
+    /// another link to be released, rather than failing immediately. May be
+    /// set before connecting, and is kept when the link is created again.
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+        if let Some(link) = &mut self.link {
+            link.lock_timeout = duration_ms(timeout);
+        }
+    }
+
+    /// Get a handle that may be used to abort an in-progress operation on the
+    /// link from another task
+    pub fn abort_handle(&self) -> Result<VxiAbortHandle> {
+        let Some(link) = &self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        Ok(link.abort.clone())
+    }
+
+    /// Set the name of the device to link to, e.g. `gpib0,5` on a LAN/GPIB
+    /// gateway. Takes effect on the next connect.
+    pub fn set_device_name(&mut self, device: impl Into<String>) {
+        self.vxi.device = device.into();
+    }
+
+    /// Abort the in-progress operation on the link via the abort channel. As
+    /// operations hold a mutable reference, use abort_handle() to abort from
+    /// another task.
+    pub async fn abort(&self) -> Result<()> {
+        self.abort_handle()?.abort().await
+    }
 }
 #[async_trait]
 impl Protocol for ScpiVxiProtocol {
@@ -57,14 +162,23 @@ impl Protocol for ScpiVxiProtocol {
 
         self.vxi.connect().await?;
 
-        self.link = Some(self.vxi.create_link().await?);
+        self.link = Some(self.vxi.create_link(self.lock_timeout).await?);
 
         Ok(())
     }
 
     async fn disconnect(&mut self) -> Result<()> {
-        /* TODO */
-        Ok(())
+        /* Continue tearing everything down on failure, so that a later
+         * connect() starts fresh, but report the first error */
+        let mut result = Ok(());
+
+        if let Some(link) = self.link.take() {
+            result = result.and(link.close().await);
+        }
+        if self.intr.take().is_some() {
+            result = result.and(self.vxi.destroy_intr_chan().await);
+        }
+        result.and(self.vxi.disconnect().await)
     }
 
     async fn model(&mut self) -> Result<ModelInfo> {
@@ -78,7 +192,7 @@ impl ScpiProtocol for ScpiVxiProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        link.write(data).await
+        link.write(data, self.settings.timeout).await
     }
 
     async fn int_recv(&mut self) -> Result<Vec<u8>> {
@@ -86,7 +200,8 @@ impl ScpiProtocol for ScpiVxiProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        link.recv(None, None, None).await
+        let termchr = self.settings.read_terminator.end_byte();
+        link.recv(self.settings.timeout, None, termchr).await
     }
 
     async fn int_query(&mut self, data: &[u8]) -> Result<Vec<u8>> {
@@ -103,6 +218,7 @@ impl ScpiProtocol for ScpiVxiProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
+        let timeout = timeout.unwrap_or(self.settings.timeout);
         link.recv(timeout, length.map(|l| l as _), None).await
     }
 
@@ -111,7 +227,7 @@ impl ScpiProtocol for ScpiVxiProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        link.recv(Some(timeout), None, Some(byte)).await
+        link.recv(timeout, None, Some(byte)).await
     }
 
     async fn flush_rx(&mut self, timeout: Duration) -> Result<()> {
@@ -119,30 +235,280 @@ impl ScpiProtocol for ScpiVxiProtocol {
             return Err(Error::Unspecified("Not connected".into()));
         };
 
-        match link.recv(Some(timeout), None, None).await {
+        match link.recv(timeout, None, None).await {
             Ok(_) | Err(Error::Timeout(_)) => Ok(()),
             Err(e) => Err(e),
         }
     }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+
+    fn supports_eoi(&self) -> bool {
+        true
+    }
+
+    fn ieee488(&mut self) -> Option<&mut dyn Ieee488Control> {
+        Some(self)
+    }
+}
+#[async_trait]
+impl Ieee488Control for ScpiVxiProtocol {
+    async fn device_clear(&mut self) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.generic(rpc::RpcRequest::DeviceClear, self.settings.timeout)
+            .await
+    }
+
+    async fn device_trigger(&mut self) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.generic(rpc::RpcRequest::DeviceTrigger, self.settings.timeout)
+            .await
+    }
+
+    async fn read_stb(&mut self) -> Result<u8> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.read_stb(self.settings.timeout).await
+    }
+
+    async fn device_local(&mut self) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.generic(rpc::RpcRequest::DeviceLocal, self.settings.timeout)
+            .await
+    }
+
+    async fn lock(&mut self, timeout: Duration) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.lock(timeout).await
+    }
+
+    async fn unlock(&mut self) -> Result<()> {
+        let Some(link) = &mut self.link else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        link.unlock().await
+    }
+
+    async fn subscribe_srq(&mut self) -> Result<broadcast::Receiver<()>> {
+        self.enable_srq().await
+    }
+}
+
+/// VXI-11 program registered with a device's portmapper
+#[derive(Debug, Clone)]
+pub struct VxiProgramInfo {
+    pub channel: VxiPortType,
+    pub prog: u32,
+    pub vers: u32,
+    pub prot: RpcIpProto,
+    pub port: u16,
+}
+
+/// List the VXI-11 programs and versions a device has registered with its
+/// portmapper, reached over TCP or UDP. Portmappers unable to list all their
+/// programs are asked for each known VXI-11 program instead.
+pub async fn vxi11_programs(portmapper: SocketAddr) -> Result<Vec<VxiProgramInfo>> {
+    let mut client = PortmapClient::connect_any(portmapper).await?;
+
+    let mappings = match client.dump().await {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            debug!("Failed to list programs: {e}, requesting each");
+            let mut mappings = vec![];
+            for ptype in [
+                VxiPortType::Core,
+                VxiPortType::Abort,
+                VxiPortType::Interrupt,
+            ] {
+                for prot in [RpcIpProto::Tcp, RpcIpProto::Udp] {
+                    let (prog, vers) = (ptype.get_prog(), ptype.get_vers());
+                    match client.get_port(prog, vers, prot).await {
+                        Ok(port) => mappings.push(RpcMapping {
+                            prog,
+                            vers,
+                            prot,
+                            port: port as u32,
+                        }),
+                        Err(Error::NotSupported(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            mappings
+        }
+    };
+    client.disconnect().await?;
+
+    Ok(mappings
+        .into_iter()
+        .filter_map(|mapping| {
+            Some(VxiProgramInfo {
+                channel: VxiPortType::from_prog(mapping.prog)?,
+                prog: mapping.prog,
+                vers: mapping.vers,
+                prot: mapping.prot,
+                port: mapping.port.try_into().ok()?,
+            })
+        })
+        .collect())
+}
+
+/// Handle for aborting in-progress operations on a link via the abort channel.
+/// The channel is connected on first use.
+#[derive(Clone)]
+pub struct VxiAbortHandle {
+    /// Address of the abort channel
+    socket: SocketAddr,
+    link_id: i32,
+    onc_client: Arc<Mutex<Option<OncClient>>>,
+    /// Set once the link has been destroyed
+    closed: Arc<AtomicBool>,
+}
+impl VxiAbortHandle {
+    fn new(socket: SocketAddr, link_id: i32) -> Self {
+        Self {
+            socket,
+            link_id,
+            onc_client: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Close the abort channel, further abort() calls will fail
+    async fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+
+        match self.onc_client.lock().await.take() {
+            Some(mut client) => client.disconnect().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Abort the in-progress operation on the link, if any. The aborted
+    /// operation will return an error.
+    pub async fn abort(&self) -> Result<()> {
+        debug!("abort()");
+
+        let mut onc_lock = self.onc_client.lock().await;
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(Error::Unspecified("Link has been closed".into()));
+        }
+        let client = match &mut *onc_lock {
+            Some(client) => client,
+            None => {
+                let mut client = OncClient::new(self.socket);
+                client.connect().await?;
+                onc_lock.insert(client)
+            }
+        };
+
+        let req = gen_call_packet(
+            client,
+            VxiPortType::Abort,
+            rpc::RpcRequest::DeviceAbort,
+            self.link_id,
+        );
+        let resp = match client.request(req).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                /* Reconnect on next use */
+                *onc_lock = None;
+                return Err(e);
+            }
+        };
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        if error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Device returned error on abort: {error:?}"
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 struct VxiClientLink {
     onc_client: Arc<Mutex<OncClient>>,
     link_id: i32,
     max_recv_size: u32,
+    /// Time to wait for a lock held by another link, in ms
+    lock_timeout: u32,
+    abort: VxiAbortHandle,
+    /// Set once the link has been destroyed on the device
+    destroyed: bool,
 }
 impl VxiClientLink {
-    fn new(onc_client: Arc<Mutex<OncClient>>, link_id: i32, max_recv_size: u32) -> Self {
+    fn new(
+        onc_client: Arc<Mutex<OncClient>>,
+        link_id: i32,
+        max_recv_size: u32,
+        lock_timeout: Duration,
+        abort: VxiAbortHandle,
+    ) -> Self {
         Self {
             onc_client,
             link_id,
             max_recv_size,
+            lock_timeout: duration_ms(lock_timeout),
+            abort,
+            destroyed: false,
+        }
+    }
+
+    /// Destroy the link on the device and close its abort channel
+    async fn close(mut self) -> Result<()> {
+        let result = self.destroy().await;
+        result.and(self.abort.close().await)
+    }
+
+    fn flags(&self, end: bool, termchr_set: bool) -> rpc::RpcOperationFlags {
+        rpc::RpcOperationFlags {
+            wait_lock: self.lock_timeout != 0,
+            end,
+            termchr_set,
         }
     }
 
+    /// Time to wait for the response to an operation with the given I/O
+    /// timeout, as the device may also wait for a lock
+    fn rpc_timeout(&self, io_timeout: Duration) -> Duration {
+        io_timeout
+            .saturating_add(Duration::from_millis(self.lock_timeout as _))
+            .saturating_add(RPC_TIMEOUT_MARGIN)
+    }
+
     /// Write a chunk via the LXI device link, data must not exceed the max
     /// reported write size
-    async fn write_packet(&mut self, data: &[u8], is_last: bool) -> Result<()> {
+    async fn write_packet(
+        &mut self,
+        data: &[u8],
+        is_last: bool,
+        io_timeout: Duration,
+    ) -> Result<()> {
         if data.len() > (self.max_recv_size as usize) {
             return Err(Error::Unspecified(format!(
                 "Request to write {} bytes, which is greater than reported max of {}",
@@ -153,13 +519,9 @@ impl VxiClientLink {
 
         let req = rpc::RpcRequestDeviceWrite {
             lid: self.link_id,
-            io_timeout: IO_TIMEOUT,
-            lock_timeout: LOCK_TIMEOUT,
-            flags: rpc::RpcOperationFlags {
-                wait_lock: false,
-                end: is_last,
-                termchr_set: false,
-            },
+            io_timeout: duration_ms(io_timeout),
+            lock_timeout: self.lock_timeout,
+            flags: self.flags(is_last, false),
             data: data.to_vec(),
         };
 
@@ -171,7 +533,9 @@ impl VxiClientLink {
             rpc::RpcRequest::DeviceWrite,
             req,
         );
-        let resp = client.request(req).await?;
+        let resp = client
+            .request_timeout(req, self.rpc_timeout(io_timeout))
+            .await?;
 
         let mut result = resp.get_success_result()?.to_vec();
         let result = rpc::RpcResponseDeviceWrite::unpack(&mut result)?;
@@ -188,7 +552,7 @@ impl VxiClientLink {
 
     /// Write data via the LXI device link, splitting it up into multiple
     /// writes if the size exceeds the maximum reported chunk size
-    async fn write(&mut self, data: &[u8]) -> Result<()> {
+    async fn write(&mut self, data: &[u8], io_timeout: Duration) -> Result<()> {
         debug!(
             "write(): {}",
             String::from_utf8_lossy(data)
@@ -199,7 +563,7 @@ impl VxiClientLink {
         let n_chunks = data.len().div_ceil(self.max_recv_size as usize);
         for (index, chunk) in data.chunks(self.max_recv_size as usize).enumerate() {
             let last = index == (n_chunks - 1);
-            self.write_packet(chunk, last).await?;
+            self.write_packet(chunk, last, io_timeout).await?;
         }
 
         Ok(())
@@ -210,30 +574,31 @@ impl VxiClientLink {
     /// set)
     async fn recv_packet(
         &mut self,
-        timeout: Option<Duration>,
+        timeout: Duration,
         size: Option<u32>,
         termchr: Option<u8>,
     ) -> Result<(Vec<u8>, bool)> {
+        /* Larger reads are split, keeping responses within the RPC record
+         * size limit */
+        let request_size = size.unwrap_or(READ_SIZE).min(READ_SIZE);
         let req = rpc::RpcRequestDeviceRead {
             lid: self.link_id,
-            request_size: size.unwrap_or(READ_SIZE),
+            request_size,
             /* NOTE: Siglent instruments do not appear to respect these fields -
              * most will always assume 10 seconds, and others will return nearly
              * immediately. */
-            io_timeout: timeout.map(|t| t.as_millis() as _).unwrap_or(IO_TIMEOUT),
-            lock_timeout: LOCK_TIMEOUT,
-            flags: rpc::RpcOperationFlags {
-                wait_lock: false,
-                end: false,
-                termchr_set: termchr.is_some(),
-            },
+            io_timeout: duration_ms(timeout),
+            lock_timeout: self.lock_timeout,
+            flags: self.flags(false, termchr.is_some()),
             termchr: termchr.unwrap_or(0),
         };
 
         let mut client = self.onc_client.lock().await;
 
         let req = gen_call_packet(&client, VxiPortType::Core, rpc::RpcRequest::DeviceRead, req);
-        let resp = client.request(req).await?;
+        let resp = client
+            .request_timeout(req, self.rpc_timeout(timeout))
+            .await?;
 
         let mut result = resp.get_success_result()?.to_vec();
         let result = rpc::RpcResponseDeviceRead::unpack(&mut result)?;
@@ -251,8 +616,8 @@ impl VxiClientLink {
         }
 
         let is_end = result.reason.end
-            || (size.is_some() && result.reason.reqcnt)
-            || (termchr.is_some() && result.reason.reqcnt);
+            || (size.is_some_and(|size| size <= request_size) && result.reason.reqcnt)
+            || (termchr.is_some() && result.reason.chr);
 
         Ok((result.data, is_end))
     }
@@ -261,7 +626,7 @@ impl VxiClientLink {
     /// indicates that we have reached the end.
     async fn recv(
         &mut self,
-        timeout: Option<Duration>,
+        timeout: Duration,
         size: Option<u32>,
         termchr: Option<u8>,
     ) -> Result<Vec<u8>> {
@@ -270,9 +635,22 @@ impl VxiClientLink {
         debug!("recv({timeout:?}, {size:?}, {termchr:?})");
 
         loop {
+            /* Device may return less than requested, only ask for the rest */
+            let remaining = size.map(|size| size - result.len() as u32);
             /* TODO: Decrease timeout by run time */
-            let (mut res, is_last) = self.recv_packet(timeout, size, termchr).await?;
+            let (mut res, is_last) = self.recv_packet(timeout, remaining, termchr).await?;
             result.append(&mut res);
+            if let Some(size) = size {
+                if result.len() > size as usize {
+                    return Err(Error::BadResponse(format!(
+                        "Device returned {} bytes, {size} requested",
+                        result.len()
+                    )));
+                }
+                if result.len() == size as usize {
+                    break;
+                }
+            }
             if is_last {
                 break;
             }
@@ -288,15 +666,113 @@ impl VxiClientLink {
         Ok(result)
     }
 
-    /// Destroy this link from the VXI-11 connection. Must only be called from
-    /// Drop.
-    async fn destroy(&mut self) -> Result<()> {
+    fn generic_parms(&self, io_timeout: Duration) -> rpc::RpcRequestDeviceGenericParms {
+        rpc::RpcRequestDeviceGenericParms {
+            lid: self.link_id,
+            flags: self.flags(false, false),
+            lock_timeout: self.lock_timeout,
+            io_timeout: duration_ms(io_timeout),
+        }
+    }
+
+    /// Perform an operation taking only the generic parameters, one of
+    /// device_trigger, device_clear or device_local
+    async fn generic(&mut self, proc: rpc::RpcRequest, io_timeout: Duration) -> Result<()> {
+        let req = self.generic_parms(io_timeout);
+
+        let mut client = self.onc_client.lock().await;
+
+        let req = gen_call_packet(&client, VxiPortType::Core, proc, req);
+        let resp = client
+            .request_timeout(req, self.rpc_timeout(io_timeout))
+            .await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        if error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Device returned error on generic operation: {error:?}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Read the device status byte
+    async fn read_stb(&mut self, io_timeout: Duration) -> Result<u8> {
+        let req = self.generic_parms(io_timeout);
+
         let mut client = self.onc_client.lock().await;
 
         let req = gen_call_packet(
             &client,
             VxiPortType::Core,
-            rpc::RpcRequest::DestroyLink,
+            rpc::RpcRequest::DeviceReadStb,
+            req,
+        );
+        let resp = client
+            .request_timeout(req, self.rpc_timeout(io_timeout))
+            .await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let result = rpc::RpcResponseDeviceReadStb::unpack(&mut result)?;
+
+        if result.error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Failed to read status byte: {:?}",
+                result.error
+            )));
+        }
+
+        Ok(result.stb)
+    }
+
+    /// Acquire an exclusive lock on the device for this link, waiting up to
+    /// timeout for another link to release it
+    async fn lock(&mut self, timeout: Duration) -> Result<()> {
+        let req = rpc::RpcRequestDeviceLock {
+            lid: self.link_id,
+            flags: rpc::RpcOperationFlags {
+                wait_lock: true,
+                end: false,
+                termchr_set: false,
+            },
+            lock_timeout: duration_ms(timeout),
+        };
+
+        debug!("lock({timeout:?})");
+
+        let mut client = self.onc_client.lock().await;
+
+        let req = gen_call_packet(&client, VxiPortType::Core, rpc::RpcRequest::DeviceLock, req);
+        let resp = client
+            .request_timeout(req, timeout.saturating_add(RPC_TIMEOUT_MARGIN))
+            .await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        match error {
+            rpc::RpcDeviceErrorCode::NoError => Ok(()),
+            rpc::RpcDeviceErrorCode::DeviceLockedByAnotherLink => Err(Error::Timeout(format!(
+                "Timed out waiting for lock for {} ms",
+                timeout.as_millis()
+            ))),
+            e => Err(Error::Unspecified(format!("Failed to lock device: {e:?}"))),
+        }
+    }
+
+    /// Release the lock held by this link
+    async fn unlock(&mut self) -> Result<()> {
+        debug!("unlock()");
+
+        let mut client = self.onc_client.lock().await;
+
+        let req = gen_call_packet(
+            &client,
+            VxiPortType::Core,
+            rpc::RpcRequest::DeviceUnlock,
             self.link_id,
         );
         let resp = client.request(req).await?;
@@ -306,21 +782,105 @@ impl VxiClientLink {
 
         if error != rpc::RpcDeviceErrorCode::NoError {
             return Err(Error::Unspecified(format!(
-                "Device returned error on destroy link: {error:?}"
+                "Failed to unlock device: {error:?}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Enable or disable service requests on this link. The link ID is used as
+    /// the handle passed back with each request.
+    async fn enable_srq(&mut self, enable: bool) -> Result<()> {
+        let req = rpc::RpcRequestDeviceEnableSrq {
+            lid: self.link_id,
+            enable,
+            handle: self.link_id.to_be_bytes().to_vec(),
+        };
+
+        let mut client = self.onc_client.lock().await;
+
+        let req = gen_call_packet(
+            &client,
+            VxiPortType::Core,
+            rpc::RpcRequest::DeviceEnableSrq,
+            req,
+        );
+        let resp = client.request(req).await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        if error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Failed to set SRQ enable state: {error:?}"
             )));
         }
 
         Ok(())
     }
+
+    /// Destroy this link from the VXI-11 connection. The link must not be used
+    /// afterwards.
+    async fn destroy(&mut self) -> Result<()> {
+        /* Only attempt once, the link ID may be reused by the device */
+        if self.destroyed {
+            return Ok(());
+        }
+        self.destroyed = true;
+
+        destroy_link(&self.onc_client, self.link_id).await
+    }
 }
 impl Drop for VxiClientLink {
     fn drop(&mut self) {
-        futures::executor::block_on(async {
-            if let Err(e) = self.destroy().await {
-                error!("Failed to destroy VXI-11 link: {e}")
+        if self.destroyed {
+            return;
+        }
+
+        /* Blocking on the RPC here could stall or deadlock the runtime, so
+         * leave it to a task. disconnect() closes the link deterministically. */
+        let onc_client = self.onc_client.clone();
+        let link_id = self.link_id;
+        let abort = self.abort.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = destroy_link(&onc_client, link_id).await {
+                        error!("Failed to destroy VXI-11 link: {e}");
+                    }
+                    if let Err(e) = abort.close().await {
+                        error!("Failed to close VXI-11 abort channel: {e}");
+                    }
+                });
             }
-        });
+            Err(_) => warn!("No runtime to destroy VXI-11 link {link_id}, leaving it open"),
+        }
+    }
+}
+
+/// Destroy a link on the device
+async fn destroy_link(onc_client: &Mutex<OncClient>, link_id: i32) -> Result<()> {
+    let mut client = onc_client.lock().await;
+
+    let req = gen_call_packet(
+        &client,
+        VxiPortType::Core,
+        rpc::RpcRequest::DestroyLink,
+        link_id,
+    );
+    let resp = client.request(req).await?;
+
+    let mut result = resp.get_success_result()?.to_vec();
+    let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+    if error != rpc::RpcDeviceErrorCode::NoError {
+        return Err(Error::Unspecified(format!(
+            "Device returned error on destroy link: {error:?}"
+        )));
     }
+
+    Ok(())
 }
 
 struct VxiClient {
@@ -328,12 +888,15 @@ struct VxiClient {
     pmap_socket: SocketAddr,
     /// ONC client for core VXI communication
     core_client: Option<Arc<Mutex<OncClient>>>,
+    /// Device name to link to
+    device: String,
 }
 impl VxiClient {
     pub fn new(socket: SocketAddr) -> Self {
         Self {
             pmap_socket: socket,
             core_client: None,
+            device: VXI11_DEFAULT_DEVICE.into(),
         }
     }
 
@@ -342,7 +905,7 @@ impl VxiClient {
             self.pmap_socket,
             ptype.get_prog(),
             ptype.get_vers(),
-            portmap::RpcIpProto::Tcp,
+            RpcIpProto::Tcp,
         )
         .await
     }
@@ -361,7 +924,92 @@ impl VxiClient {
         Ok(())
     }
 
-    async fn create_link(&mut self) -> Result<VxiClientLink> {
+    /// Local address of the core channel connection
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        let Some(onc_lock) = &self.core_client else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        onc_lock.lock().await.local_addr()
+    }
+
+    /// Close the connection to the device. Any links must be destroyed first.
+    async fn disconnect(&mut self) -> Result<()> {
+        let Some(onc_lock) = self.core_client.take() else {
+            return Ok(());
+        };
+
+        onc_lock.lock().await.disconnect().await
+    }
+
+    /// Ask the device to close its interrupt channel connection
+    async fn destroy_intr_chan(&self) -> Result<()> {
+        let Some(onc_lock) = &self.core_client else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+        let mut onc = onc_lock.lock().await;
+
+        let req = gen_call_packet(
+            &onc,
+            VxiPortType::Core,
+            rpc::RpcRequest::DestroyIntrChan,
+            (),
+        );
+        let resp = onc.request(req).await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        if error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Failed to destroy interrupt channel: {error:?}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Ask the device to connect to our interrupt server at addr
+    async fn create_intr_chan(&self, addr: SocketAddr) -> Result<()> {
+        let Some(onc_lock) = &self.core_client else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+        let IpAddr::V4(host_addr) = addr.ip() else {
+            return Err(Error::NotSupported(
+                "VXI-11 interrupt channel requires an IPv4 connection".into(),
+            ));
+        };
+        let mut onc = onc_lock.lock().await;
+
+        let req = rpc::RpcRequestCreateIntrChan {
+            host_addr: host_addr.into(),
+            host_port: addr.port(),
+            prog_num: VXI_INTERRUPT_PROG,
+            prog_vers: VXI_INTERRUPT_VERS,
+            prog_family: rpc::RpcAddrFamily::Tcp,
+        };
+
+        let req = gen_call_packet(
+            &onc,
+            VxiPortType::Core,
+            rpc::RpcRequest::CreateIntrChan,
+            req,
+        );
+        let resp = onc.request(req).await?;
+
+        let mut result = resp.get_success_result()?.to_vec();
+        let error = rpc::RpcDeviceErrorCode::unpack(&mut result)?;
+
+        if error != rpc::RpcDeviceErrorCode::NoError {
+            return Err(Error::Unspecified(format!(
+                "Failed to create interrupt channel: {error:?}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn create_link(&mut self, lock_timeout: Duration) -> Result<VxiClientLink> {
         let Some(onc_lock) = &self.core_client else {
             return Err(Error::Unspecified("Not connected".into()));
         };
@@ -370,9 +1018,8 @@ impl VxiClient {
         let req = rpc::RpcRequestCreateDeviceLink {
             client_id: CLIENT_ID,
             lock_device: false,
-            lock_timeout: LOCK_TIMEOUT,
-            /* VXI11.3 B.1.2 */
-            device: "inst0".into(),
+            lock_timeout: 0,
+            device: self.device.clone(),
         };
 
         let req = gen_call_packet(&onc, VxiPortType::Core, rpc::RpcRequest::CreateLink, req);
@@ -388,14 +1035,24 @@ impl VxiClient {
             )));
         }
 
+        let mut abort_socket = self.pmap_socket;
+        abort_socket.set_port(result.abort_port);
+
         Ok(VxiClientLink::new(
             onc_lock.clone(),
             result.lid,
             result.max_recv_size,
+            lock_timeout,
+            VxiAbortHandle::new(abort_socket, result.lid),
         ))
     }
 }
 
+/// Timeout in ms as sent to the device, saturating if out of range
+fn duration_ms(timeout: Duration) -> u32 {
+    u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX)
+}
+
 fn gen_call_packet(
     onc: &OncClient,
     ptype: VxiPortType,
@@ -405,14 +1062,23 @@ fn gen_call_packet(
     onc.gen_call_packet(ptype.get_prog(), ptype.get_vers(), proc as u32, req)
 }
 
-#[derive(Clone, Copy)]
-#[allow(unused)]
-enum VxiPortType {
+/// VXI-11 channel, each served by an RPC program of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VxiPortType {
     Core,
     Abort,
     Interrupt,
 }
 impl VxiPortType {
+    fn from_prog(prog: u32) -> Option<Self> {
+        match prog {
+            VXI_CORE_PROG => Some(Self::Core),
+            VXI_ABORT_PROG => Some(Self::Abort),
+            VXI_INTERRUPT_PROG => Some(Self::Interrupt),
+            _ => None,
+        }
+    }
+
     fn get_prog(&self) -> u32 {
         match self {
             Self::Core => VXI_CORE_PROG,
@@ -429,3 +1095,141 @@ impl VxiPortType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+    use crate::protocol::vxi11::{
+        onc::AcceptedReplyBodyType,
+        portmap::PortmapServer,
+        rpc::{RpcDeviceErrorCode, RpcDeviceReadReason, RpcResponseDeviceRead},
+    };
+
+    /// Serve a core channel whose reads return `extra` bytes more than
+    /// requested, never indicating why the read finished. Returns the
+    /// portmapper to connect to.
+    async fn serve_overlong(extra: usize) -> (PortmapServer, JoinHandle<()>) {
+        let core = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let portmap = PortmapServer::start(
+            (Ipv4Addr::LOCALHOST, 0).into(),
+            vec![RpcMapping {
+                prog: VXI_CORE_PROG,
+                vers: VXI_CORE_VERS,
+                prot: RpcIpProto::Tcp,
+                port: core.local_addr().unwrap().port() as u32,
+            }],
+        )
+        .await
+        .unwrap();
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = core.accept().await.unwrap();
+            while let Ok((xid, call)) = onc::read_call(&mut stream).await {
+                let mut args = call.args;
+                let reply = match rpc::RpcRequest::from_proc(call.proc) {
+                    Some(rpc::RpcRequest::CreateLink) => {
+                        AcceptedReplyBodyType::success(rpc::RpcResponseCreateDeviceLink {
+                            error: RpcDeviceErrorCode::NoError,
+                            lid: 1,
+                            abort_port: 0,
+                            max_recv_size: 1024,
+                        })
+                    }
+                    Some(rpc::RpcRequest::DeviceRead) => {
+                        let req = rpc::RpcRequestDeviceRead::unpack(&mut args).unwrap();
+                        AcceptedReplyBodyType::success(RpcResponseDeviceRead {
+                            error: RpcDeviceErrorCode::NoError,
+                            reason: RpcDeviceReadReason {
+                                reqcnt: false,
+                                chr: false,
+                                end: false,
+                            },
+                            data: vec![b'#'; req.request_size as usize + extra],
+                        })
+                    }
+                    _ => AcceptedReplyBodyType::success(RpcDeviceErrorCode::NoError),
+                };
+                onc::write_reply(&mut stream, xid, reply).await.unwrap();
+            }
+        });
+
+        (portmap, task)
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_read() {
+        let (portmap, _task) = serve_overlong(3).await;
+        let mut vxi11 = ScpiVxiProtocol::new(portmap.addr());
+        vxi11.connect().await.unwrap();
+
+        assert!(matches!(
+            vxi11.recv_raw(Some(16), None).await,
+            Err(Error::BadResponse(_))
+        ));
+
+        vxi11.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_reading_at_requested_size() {
+        let (portmap, _task) = serve_overlong(0).await;
+        let mut vxi11 = ScpiVxiProtocol::new(portmap.addr());
+        vxi11.connect().await.unwrap();
+
+        assert_eq!(vxi11.recv_raw(Some(16), None).await.unwrap(), [b'#'; 16]);
+
+        vxi11.disconnect().await.unwrap();
+    }
+
+    #[test]
+    fn timeouts_saturate() {
+        assert_eq!(duration_ms(Duration::from_millis(1500)), 1500);
+        /* Would otherwise wrap to 1 ms */
+        assert_eq!(
+            duration_ms(Duration::from_millis(u32::MAX as u64 + 2)),
+            u32::MAX
+        );
+        assert_eq!(duration_ms(Duration::MAX), u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn lists_vxi11_programs() {
+        let mapping = |prog, vers, port| RpcMapping {
+            prog,
+            vers,
+            prot: RpcIpProto::Tcp,
+            port,
+        };
+        let portmap = PortmapServer::start(
+            (Ipv4Addr::LOCALHOST, 0).into(),
+            vec![
+                mapping(VXI_CORE_PROG, VXI_CORE_VERS, 1024),
+                /* Not a VXI-11 program */
+                mapping(100003, 3, 2049),
+                mapping(VXI_ABORT_PROG, VXI_ABORT_VERS, 1025),
+                mapping(VXI_INTERRUPT_PROG, VXI_INTERRUPT_VERS, 1026),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let programs: Vec<_> = vxi11_programs(portmap.addr())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.channel, info.port))
+            .collect();
+        assert_eq!(
+            programs,
+            [
+                (VxiPortType::Core, 1024),
+                (VxiPortType::Abort, 1025),
+                (VxiPortType::Interrupt, 1026),
+            ]
+        );
+    }
+}
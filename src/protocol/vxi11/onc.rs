@@ -1,12 +1,13 @@
 //! Open Network Computing (ONC) RPC protocol, as defined by RFC5531
 
-use std::{mem, net::SocketAddr, sync::Arc};
+use std::{mem, net::SocketAddr, sync::Arc, time::Duration};
 
 use log::warn;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpSocket, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream, UdpSocket},
     sync::Mutex,
+    time::Instant,
 };
 
 use crate::error::{Error, Result};
@@ -17,81 +18,255 @@ pub const RPC_VERSION: u32 = 2;
 
 pub const LAST_MESSAGE_MARKER: u32 = 0x80000000;
 
+/// Default time to wait for a response to a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to wait for a response over UDP before sending the request again
+pub const UDP_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+/// Largest datagram expected over UDP
+const UDP_MAX_SIZE: usize = 65536;
+/// Largest record accepted over TCP, guarding against corrupt or hostile
+/// record marks
+pub const MAX_RECORD_SIZE: usize = 4 << 20;
+
 pub struct OncClient {
     socket: SocketAddr,
     stream: Option<Arc<Mutex<TcpStream>>>,
+    /// Local address of the connection, as seen by the server
+    local_addr: Option<SocketAddr>,
     last_xid: u32,
+    /// Data received but not yet unpacked into a complete record
+    rx_buf: Vec<u8>,
+    /// Set when the connection was lost, until connected again
+    lost: bool,
 }
 impl OncClient {
     pub fn new(socket: SocketAddr) -> Self {
         Self {
             socket,
             stream: None,
+            local_addr: None,
             last_xid: 0,
+            rx_buf: vec![],
+            lost: false,
         }
     }
 
+    /// Local address of the connection to the server
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr
+            .ok_or_else(|| Error::Unspecified("Not connected".into()))
+    }
+
     pub async fn connect(&mut self) -> Result<()> {
         if self.stream.is_some() {
             return Err(Error::Unspecified("Already connected!".into()));
         }
 
-        /* TODO: Support IPv6 */
-        let socket = TcpSocket::new_v4().map_err(|e| Error::Unhandled(e.into()))?;
-        self.stream = Some(Arc::new(Mutex::new(
-            socket
-                .connect(self.socket)
-                .await
-                .map_err(|e| Error::Unhandled(e.into()))?,
-        )));
+        let socket = if self.socket.is_ipv6() {
+            TcpSocket::new_v6()
+        } else {
+            TcpSocket::new_v4()
+        }
+        .map_err(|e| Error::Unhandled(e.into()))?;
+        let stream = match tokio::time::timeout(REQUEST_TIMEOUT, socket.connect(self.socket)).await
+        {
+            Ok(res) => res.map_err(|e| Error::Unhandled(e.into()))?,
+            Err(_) => {
+                return Err(Error::Timeout(format!(
+                    "Timed out connecting to {} for {} ms",
+                    self.socket,
+                    REQUEST_TIMEOUT.as_millis()
+                )));
+            }
+        };
+        self.rx_buf.clear();
+        self.lost = false;
+        self.local_addr = Some(stream.local_addr()?);
+        self.stream = Some(Arc::new(Mutex::new(stream)));
+
+        Ok(())
+    }
+
+    /// Shut down the connection to the server, a later connect() may be used
+    /// to reconnect
+    pub async fn disconnect(&mut self) -> Result<()> {
+        self.local_addr = None;
+        self.lost = false;
+        let Some(stream) = self.stream.take() else {
+            return Ok(());
+        };
+
+        stream.lock().await.shutdown().await?;
 
         Ok(())
     }
 
+    /// Perform a request, waiting up to REQUEST_TIMEOUT for the response
     pub async fn request(&mut self, req: impl XdrPack) -> Result<RpcMessage> {
+        self.request_timeout(req, REQUEST_TIMEOUT).await
+    }
+
+    /// Perform a request, waiting up to timeout for the response
+    pub async fn request_timeout(
+        &mut self,
+        req: impl XdrPack,
+        timeout: Duration,
+    ) -> Result<RpcMessage> {
         let Some(stream) = &self.stream else {
+            if self.lost {
+                return Err(Error::connection_closed());
+            }
             return Err(Error::Unspecified("Not connected".into()));
         };
+        let stream = stream.clone();
 
         let mut packed = vec![];
         req.pack_xdr(&mut packed);
 
-        let header = packed.len() as u32 | LAST_MESSAGE_MARKER;
-        let mut packet = vec![];
-        packet.extend_from_slice(&header.to_be_bytes());
-        packet.append(&mut packed);
-
         let mut stream = stream.lock().await;
-        stream.write_all(&packet).await?;
-
-        /* TODO: Timeout */
-        let resp = self.read_response(&mut stream).await;
+        let resp = match write_record(&mut *stream, packed).await {
+            /* Reading is cancel-safe, a late response will be discarded as its
+             * xid no longer matches */
+            Ok(()) => match tokio::time::timeout(timeout, self.read_response(&mut stream)).await {
+                Ok(resp) => resp,
+                Err(_) => Err(Error::Timeout(format!(
+                    "Timed out waiting for RPC response for {} ms",
+                    timeout.as_millis()
+                ))),
+            },
+            Err(e) => Err(e),
+        };
+        drop(stream);
 
         self.last_xid += 1;
 
+        /* Fail further requests straight away rather than each waiting on a
+         * dead connection, until reconnected */
+        if let Err(e) = &resp
+            && e.is_connection_lost()
+        {
+            self.stream = None;
+            self.local_addr = None;
+            self.lost = true;
+        }
+
         resp
     }
 
-    async fn read_response(&self, stream: &mut TcpStream) -> Result<RpcMessage> {
-        let mut full_packet = vec![];
+    async fn read_response(&mut self, stream: &mut TcpStream) -> Result<RpcMessage> {
+        loop {
+            let mut record = self.read_buffered_record(stream).await?;
+            let unpacked = RpcMessage::unpack(&mut record)?;
+            if unpacked.xid == self.last_xid {
+                return Ok(unpacked);
+            } else {
+                warn!("Received non-matching xid: {}", unpacked.xid);
+            }
+        }
+    }
 
+    /// Read a complete record into rx_buf, cancel-safe unlike read_record()
+    async fn read_buffered_record(&mut self, stream: &mut TcpStream) -> Result<Vec<u8>> {
         loop {
-            let header = stream.read_u32().await?;
-            let size = (header & !LAST_MESSAGE_MARKER) as usize;
-
-            let mut packet = vec![0; size];
-            stream.read_exact(&mut packet).await?;
-            full_packet.append(&mut packet);
-
-            if (header & LAST_MESSAGE_MARKER) != 0 {
-                let unpacked = RpcMessage::unpack(&mut full_packet)?;
-                if unpacked.xid == self.last_xid {
-                    return Ok(unpacked);
-                } else {
-                    warn!("Received non-matching xid: {}", unpacked.xid);
+            if let Some(record) = take_record(&mut self.rx_buf)? {
+                return Ok(record);
+            }
+
+            if stream.read_buf(&mut self.rx_buf).await? == 0 {
+                return Err(Error::connection_closed());
+            }
+        }
+    }
+
+    pub fn gen_call_packet(
+        &self,
+        prog: u32,
+        vers: u32,
+        proc: u32,
+        req: impl XdrPack,
+    ) -> RpcMessage {
+        RpcMessage::new_call(self.last_xid, prog, vers, proc, req)
+    }
+}
+
+/// ONC client over UDP, where each message is a single datagram. Requests are
+/// sent again until a response arrives, as datagrams may be lost.
+pub struct OncUdpClient {
+    socket: SocketAddr,
+    udp: Option<UdpSocket>,
+    last_xid: u32,
+}
+impl OncUdpClient {
+    pub fn new(socket: SocketAddr) -> Self {
+        Self {
+            socket,
+            udp: None,
+            last_xid: 0,
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<()> {
+        if self.udp.is_some() {
+            return Err(Error::Unspecified("Already connected!".into()));
+        }
+
+        let local = if self.socket.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let udp = UdpSocket::bind(local).await?;
+        /* Only datagrams from the server are received once connected */
+        udp.connect(self.socket).await?;
+        self.udp = Some(udp);
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) {
+        self.udp = None;
+    }
+
+    /// Perform a request, waiting up to REQUEST_TIMEOUT for the response
+    pub async fn request(&mut self, req: impl XdrPack) -> Result<RpcMessage> {
+        self.request_timeout(req, REQUEST_TIMEOUT).await
+    }
+
+    /// Perform a request, waiting up to timeout for the response
+    pub async fn request_timeout(
+        &mut self,
+        req: impl XdrPack,
+        timeout: Duration,
+    ) -> Result<RpcMessage> {
+        let Some(udp) = &self.udp else {
+            return Err(Error::Unspecified("Not connected".into()));
+        };
+
+        let mut packed = vec![];
+        req.pack_xdr(&mut packed);
+        let xid = self.last_xid;
+        self.last_xid += 1;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; UDP_MAX_SIZE];
+        while Instant::now() < deadline {
+            udp.send(&packed).await?;
+
+            let retransmit = (Instant::now() + UDP_RETRANSMIT_INTERVAL).min(deadline);
+            while let Ok(len) = tokio::time::timeout_at(retransmit, udp.recv(&mut buf)).await {
+                let mut datagram = buf[..len?].to_vec();
+                match RpcMessage::unpack(&mut datagram) {
+                    Ok(msg) if msg.xid == xid => return Ok(msg),
+                    Ok(msg) => warn!("Received non-matching xid: {}", msg.xid),
+                    Err(e) => warn!("Received invalid datagram: {e}"),
                 }
             }
         }
+
+        Err(Error::Timeout(format!(
+            "Timed out waiting for RPC response for {} ms",
+            timeout.as_millis()
+        )))
     }
 
     pub fn gen_call_packet(
@@ -101,25 +276,113 @@ impl OncClient {
         proc: u32,
         req: impl XdrPack,
     ) -> RpcMessage {
-        let mut args = vec![];
-        req.pack_xdr(&mut args);
+        RpcMessage::new_call(self.last_xid, prog, vers, proc, req)
+    }
+}
 
-        RpcMessage {
-            xid: self.last_xid,
-            body: MessageBody::Call(CallBody {
-                rpc_version: RPC_VERSION,
-                prog,
-                vers,
-                proc,
-                cred: OpaqueAuth::new_null(),
-                verf: OpaqueAuth::new_null(),
-                args,
-            }),
+/// Remove a complete record from the start of buf, reassembling record
+/// fragments, if one has been fully received
+fn take_record(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    let mut fragments = vec![];
+    let mut offset = 0;
+
+    /* Find all fragments before taking any, the record may be incomplete */
+    loop {
+        let Some(header) = buf.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        let header = u32::from_be_bytes(header.try_into().unwrap());
+        let len = (header & !LAST_MESSAGE_MARKER) as usize;
+        check_record_size(offset + 4 + len)?;
+
+        fragments.push(offset + 4..offset + 4 + len);
+        offset += 4 + len;
+        if buf.len() < offset {
+            return Ok(None);
+        }
+        if header & LAST_MESSAGE_MARKER != 0 {
+            break;
         }
     }
+
+    let record = fragments
+        .into_iter()
+        .flat_map(|range| buf[range].iter().copied())
+        .collect();
+    buf.drain(..offset);
+
+    Ok(Some(record))
 }
 
-#[derive(Debug)]
+/// Read a complete record from a stream, reassembling record fragments (RFC5531
+/// section 11)
+pub async fn read_record(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut record = vec![];
+    /* Including fragment headers, so empty fragments count towards the limit */
+    let mut received = 0;
+
+    loop {
+        let header = stream.read_u32().await?;
+        let size = (header & !LAST_MESSAGE_MARKER) as usize;
+        received += 4 + size;
+        check_record_size(received)?;
+
+        let mut fragment = vec![0; size];
+        stream.read_exact(&mut fragment).await?;
+        record.append(&mut fragment);
+
+        if (header & LAST_MESSAGE_MARKER) != 0 {
+            return Ok(record);
+        }
+    }
+}
+
+fn check_record_size(size: usize) -> Result<()> {
+    if size > MAX_RECORD_SIZE {
+        return Err(Error::BadResponse(format!(
+            "RPC record size {size} exceeds maximum of {MAX_RECORD_SIZE}"
+        )));
+    }
+    Ok(())
+}
+
+/// Write a record to a stream as a single record fragment
+pub async fn write_record(stream: &mut (impl AsyncWrite + Unpin), mut data: Vec<u8>) -> Result<()> {
+    let header = data.len() as u32 | LAST_MESSAGE_MARKER;
+    let mut packet = vec![];
+    packet.extend_from_slice(&header.to_be_bytes());
+    packet.append(&mut data);
+
+    stream.write_all(&packet).await?;
+
+    Ok(())
+}
+
+/// Read records from a stream until a call is received, returning its xid
+/// and body
+pub async fn read_call(stream: &mut (impl AsyncRead + Unpin)) -> Result<(u32, CallBody)> {
+    loop {
+        let mut record = read_record(stream).await?;
+        let msg = RpcMessage::unpack(&mut record)?;
+        match msg.body {
+            MessageBody::Call(call) => return Ok((msg.xid, call)),
+            MessageBody::Reply(_) => warn!("Received non-call message, xid {}", msg.xid),
+        }
+    }
+}
+
+/// Write an accepted reply to the call with the given xid
+pub async fn write_reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    xid: u32,
+    body: AcceptedReplyBodyType,
+) -> Result<()> {
+    let mut packed = vec![];
+    RpcMessage::new_reply(xid, body).pack_xdr(&mut packed);
+    write_record(stream, packed).await
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum AuthStat {
     AuthOk = 0,
     AuthBadCred = 1,
@@ -159,6 +422,11 @@ impl AuthStat {
         }
     }
 }
+impl XdrPack for AuthStat {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        (self as u32).pack_xdr(out);
+    }
+}
 
 #[derive(Debug)]
 pub enum MessageBody {
@@ -195,6 +463,36 @@ pub struct RpcMessage {
     pub body: MessageBody,
 }
 impl RpcMessage {
+    /// Generate a call with null authentication
+    pub fn new_call(xid: u32, prog: u32, vers: u32, proc: u32, req: impl XdrPack) -> Self {
+        let mut args = vec![];
+        req.pack_xdr(&mut args);
+
+        Self {
+            xid,
+            body: MessageBody::Call(CallBody {
+                rpc_version: RPC_VERSION,
+                prog,
+                vers,
+                proc,
+                cred: OpaqueAuth::new_null(),
+                verf: OpaqueAuth::new_null(),
+                args,
+            }),
+        }
+    }
+
+    /// Generate an accepted reply to a call
+    pub fn new_reply(xid: u32, body: AcceptedReplyBodyType) -> Self {
+        Self {
+            xid,
+            body: MessageBody::Reply(ReplyBody::Accepted(AcceptedReplyBody {
+                verf: OpaqueAuth::new_null(),
+                body,
+            })),
+        }
+    }
+
     pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
         Ok(Self {
             xid: xdr::unpack_u32(src)?,
@@ -255,9 +553,24 @@ impl CallBody {
             proc: xdr::unpack_u32(src)?,
             cred: OpaqueAuth::unpack(src)?,
             verf: OpaqueAuth::unpack(src)?,
-            args: xdr::unpack_opaque(src)?,
+            /* Arguments are the remainder of the message */
+            args: mem::take(src),
         })
     }
+
+    /// Reply to give if the call is not for the given program and version
+    pub fn check_program(&self, prog: u32, vers: u32) -> Option<AcceptedReplyBodyType> {
+        if self.prog != prog {
+            Some(AcceptedReplyBodyType::ProgUnavail())
+        } else if self.vers != vers {
+            Some(AcceptedReplyBodyType::ProgMismatch(ProgMismatchBody {
+                low: vers,
+                high: vers,
+            }))
+        } else {
+            None
+        }
+    }
 }
 impl XdrPack for CallBody {
     fn pack_xdr(self, out: &mut Vec<u8>) {
@@ -288,13 +601,13 @@ impl ReplyBody {
 impl XdrPack for ReplyBody {
     fn pack_xdr(self, out: &mut Vec<u8>) {
         match self {
-            Self::Accepted(_accepted) => {
+            Self::Accepted(accepted) => {
                 0u32.pack_xdr(out);
-                unimplemented!()
+                accepted.pack_xdr(out);
             }
-            Self::Rejected(_rejected) => {
+            Self::Rejected(rejected) => {
                 1u32.pack_xdr(out);
-                unimplemented!()
+                rejected.pack_xdr(out);
             }
         }
     }
@@ -314,6 +627,12 @@ impl AcceptedReplyBody {
         })
     }
 }
+impl XdrPack for AcceptedReplyBody {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.verf.pack_xdr(out);
+        self.body.pack_xdr(out);
+    }
+}
 
 #[allow(unused)]
 #[derive(Debug)]
@@ -326,6 +645,13 @@ pub enum AcceptedReplyBodyType {
     SystemErr(),
 }
 impl AcceptedReplyBodyType {
+    /// Successful reply with the given results
+    pub fn success(results: impl XdrPack) -> Self {
+        let mut packed = vec![];
+        results.pack_xdr(&mut packed);
+        Self::Success(SuccessAcceptedReplyBody { results: packed })
+    }
+
     fn unpack(src: &mut Vec<u8>) -> Result<Self> {
         match xdr::unpack_u32(src)? {
             0 => Ok(Self::Success(SuccessAcceptedReplyBody::unpack(src)?)),
@@ -340,6 +666,25 @@ impl AcceptedReplyBodyType {
         }
     }
 }
+impl XdrPack for AcceptedReplyBodyType {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        match self {
+            Self::Success(success) => {
+                0u32.pack_xdr(out);
+                /* Results are already XDR-encoded */
+                out.extend(success.results);
+            }
+            Self::ProgUnavail() => 1u32.pack_xdr(out),
+            Self::ProgMismatch(mismatch) => {
+                2u32.pack_xdr(out);
+                mismatch.pack_xdr(out);
+            }
+            Self::ProcUnavail() => 3u32.pack_xdr(out),
+            Self::GarbageArgs() => 4u32.pack_xdr(out),
+            Self::SystemErr() => 5u32.pack_xdr(out),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct SuccessAcceptedReplyBody {
@@ -367,6 +712,12 @@ impl ProgMismatchBody {
         })
     }
 }
+impl XdrPack for ProgMismatchBody {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.low.pack_xdr(out);
+        self.high.pack_xdr(out);
+    }
+}
 
 #[allow(unused)]
 #[derive(Debug)]
@@ -385,6 +736,20 @@ impl RejectedReplyBody {
         }
     }
 }
+impl XdrPack for RejectedReplyBody {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        match self {
+            Self::Mismatch(mismatch) => {
+                0u32.pack_xdr(out);
+                mismatch.pack_xdr(out);
+            }
+            Self::AuthError(stat) => {
+                1u32.pack_xdr(out);
+                stat.pack_xdr(out);
+            }
+        }
+    }
+}
 
 #[repr(u32)]
 #[derive(Clone, Copy, Debug)]
@@ -429,3 +794,100 @@ impl XdrPack for OpaqueAuth {
         self.body.pack_xdr(out);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(data: &[u8], last: bool) -> Vec<u8> {
+        let mut header = data.len() as u32;
+        if last {
+            header |= LAST_MESSAGE_MARKER;
+        }
+        let mut out = header.to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn take_record_reassembles_fragments() {
+        let mut buf = fragment(b"abc", false);
+        buf.extend(fragment(b"def", true));
+        buf.extend(fragment(b"next", false));
+
+        /* Incomplete records are left in place */
+        let mut partial = buf[..9].to_vec();
+        assert_eq!(take_record(&mut partial).unwrap(), None);
+        assert_eq!(partial.len(), 9);
+
+        assert_eq!(take_record(&mut buf).unwrap().unwrap(), b"abcdef");
+        assert_eq!(buf, fragment(b"next", false));
+        assert_eq!(take_record(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn take_record_rejects_oversized_record() {
+        let mut buf = (0x7fff_ffffu32 | LAST_MESSAGE_MARKER)
+            .to_be_bytes()
+            .to_vec();
+        assert!(matches!(take_record(&mut buf), Err(Error::BadResponse(_))));
+
+        /* Unterminated runs of fragments are also limited */
+        let mut buf = vec![];
+        while buf.len() <= MAX_RECORD_SIZE {
+            buf.extend(fragment(&[0; 1024], false));
+        }
+        assert!(matches!(take_record(&mut buf), Err(Error::BadResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn read_record_limits_size() {
+        let mut stream = &fragment(b"abc", true)[..];
+        assert_eq!(read_record(&mut stream).await.unwrap(), b"abc");
+
+        let header = (0x7fff_ffffu32 | LAST_MESSAGE_MARKER).to_be_bytes();
+        let mut stream = &header[..];
+        assert!(matches!(
+            read_record(&mut stream).await,
+            Err(Error::BadResponse(_))
+        ));
+
+        let empty = fragment(&[], false);
+        let stream = empty.repeat(MAX_RECORD_SIZE / 4 + 1);
+        assert!(matches!(
+            read_record(&mut &stream[..]).await,
+            Err(Error::BadResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn udp_request_is_sent_again() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = OncUdpClient::new(server.local_addr().unwrap());
+        client.connect().await.unwrap();
+
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0; UDP_MAX_SIZE];
+            /* First request is lost */
+            server.recv_from(&mut buf).await.unwrap();
+
+            let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+            let xid = RpcMessage::unpack(&mut buf[..len].to_vec()).unwrap().xid;
+            /* Replies to other calls are ignored */
+            for (xid, result) in [(xid + 1, 1u32), (xid, 42)] {
+                let mut packed = vec![];
+                RpcMessage::new_reply(xid, AcceptedReplyBodyType::success(result))
+                    .pack_xdr(&mut packed);
+                server.send_to(&packed, peer).await.unwrap();
+            }
+        });
+
+        let req = client.gen_call_packet(1, 1, 0, ());
+        let resp = client
+            .request_timeout(req, UDP_RETRANSMIT_INTERVAL * 4)
+            .await
+            .unwrap();
+        assert_eq!(resp.get_success_result().unwrap(), 42u32.to_be_bytes());
+        task.await.unwrap();
+    }
+}
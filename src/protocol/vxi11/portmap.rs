@@ -1,11 +1,24 @@
-//! Portmap client, RFC1833
+//! Portmap client and server, RFC1833
 
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::{JoinHandle, JoinSet},
+    time::Instant,
+};
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 use super::{
-    onc::OncClient,
+    onc::{
+        self, AcceptedReplyBodyType, CallBody, MessageBody, OncClient, OncUdpClient, RpcMessage,
+    },
     xdr::{self, XdrPack},
 };
 
@@ -14,45 +27,337 @@ const PORTMAP_VERS: u32 = 2;
 
 pub const PORTMAP_PORT: u16 = 111;
 
-/// Connect to an ONC server, and request a port for a program
+/// Connect to a portmapper, and request a port for a program
 pub async fn connect_and_request_port(
     socket: SocketAddr,
     prog: u32,
     vers: u32,
     prot: RpcIpProto,
 ) -> Result<u16> {
-    let mut client = OncClient::new(socket);
-    client.connect().await?;
+    let mut client = PortmapClient::connect_any(socket).await?;
+    let port = client.get_port(prog, vers, prot).await;
+    client.disconnect().await?;
+
+    port
+}
 
-    request_port(&mut client, prog, vers, prot).await
+/// Portmap client, over either TCP or UDP
+pub struct PortmapClient {
+    onc: PortmapTransport,
 }
+enum PortmapTransport {
+    Tcp(OncClient),
+    Udp(OncUdpClient),
+}
+impl PortmapClient {
+    /// Connect to the portmapper at the given address over TCP or UDP
+    pub async fn connect(socket: SocketAddr, prot: RpcIpProto) -> Result<Self> {
+        let onc = match prot {
+            RpcIpProto::Tcp => {
+                let mut client = OncClient::new(socket);
+                client.connect().await?;
+                PortmapTransport::Tcp(client)
+            }
+            RpcIpProto::Udp => {
+                let mut client = OncUdpClient::new(socket);
+                client.connect().await?;
+                PortmapTransport::Udp(client)
+            }
+        };
+
+        Ok(Self { onc })
+    }
+
+    /// Connect to the portmapper over TCP, or over UDP if it does not accept
+    /// TCP connections, as is the case on some embedded devices
+    pub async fn connect_any(socket: SocketAddr) -> Result<Self> {
+        match Self::connect(socket, RpcIpProto::Tcp).await {
+            Ok(client) => Ok(client),
+            Err(e) => {
+                debug!("Portmap over TCP failed: {e}, using UDP");
+                Self::connect(socket, RpcIpProto::Udp).await
+            }
+        }
+    }
+
+    /// Protocol the client is connected over
+    pub fn protocol(&self) -> RpcIpProto {
+        match self.onc {
+            PortmapTransport::Tcp(_) => RpcIpProto::Tcp,
+            PortmapTransport::Udp(_) => RpcIpProto::Udp,
+        }
+    }
+
+    pub async fn disconnect(&mut self) -> Result<()> {
+        match &mut self.onc {
+            PortmapTransport::Tcp(client) => client.disconnect().await,
+            PortmapTransport::Udp(client) => {
+                client.disconnect();
+                Ok(())
+            }
+        }
+    }
+
+    /// Call a procedure, returning its results
+    async fn call(&mut self, proc: RpcRequest, args: impl XdrPack) -> Result<Vec<u8>> {
+        let resp = match &mut self.onc {
+            PortmapTransport::Tcp(client) => {
+                let packet = client.gen_call_packet(PORTMAP_PROG, PORTMAP_VERS, proc as u32, args);
+                client.request(packet).await?
+            }
+            PortmapTransport::Udp(client) => {
+                let packet = client.gen_call_packet(PORTMAP_PROG, PORTMAP_VERS, proc as u32, args);
+                client.request(packet).await?
+            }
+        };
+
+        Ok(resp.get_success_result()?.to_vec())
+    }
+
+    /// Call the procedure that does nothing, to check the portmapper is alive
+    pub async fn null(&mut self) -> Result<()> {
+        self.call(RpcRequest::Null, ()).await?;
+
+        Ok(())
+    }
 
-/// Request a port for a program, using an existing ONC client
-pub async fn request_port(
-    client: &mut OncClient,
+    /// Request the port a program is served on, failing if it is not
+    /// registered
+    pub async fn get_port(&mut self, prog: u32, vers: u32, prot: RpcIpProto) -> Result<u16> {
+        let mapping = RpcMapping {
+            prog,
+            vers,
+            prot,
+            port: 0,
+        };
+
+        let mut results = self.call(RpcRequest::GetPort, mapping).await?;
+        match xdr::unpack_u16(&mut results)? {
+            0 => Err(Error::NotSupported(format!(
+                "Program {prog} version {vers} over {prot:?} is not registered"
+            ))),
+            port => Ok(port),
+        }
+    }
+
+    /// List all registered programs
+    pub async fn dump(&mut self) -> Result<Vec<RpcMapping>> {
+        let mut results = self.call(RpcRequest::Dump, ()).await?;
+
+        /* Linked list, each entry preceded by whether there is one */
+        let mut mappings = vec![];
+        while xdr::unpack_u32(&mut results)? != 0 {
+            mappings.push(RpcMapping::unpack(&mut results)?);
+        }
+
+        Ok(mappings)
+    }
+}
+
+/// Request a port for a program over UDP from each of the given portmappers,
+/// which may be broadcast addresses. Returns the address of each portmapper
+/// that replied within timeout, with the port it has the program on.
+pub async fn broadcast_request_port(
+    targets: &[SocketAddr],
     prog: u32,
     vers: u32,
     prot: RpcIpProto,
-) -> Result<u16> {
+    timeout: Duration,
+) -> Result<Vec<(SocketAddr, u16)>> {
+    let local = if targets.iter().all(SocketAddr::is_ipv6) {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.set_broadcast(true)?;
+
+    /* Arbitrary xid, replies to other calls are ignored */
+    let xid = 0x7465_7100;
     let mapping = RpcMapping {
         prog,
         vers,
         prot,
         port: 0,
     };
-
-    let packet = client.gen_call_packet(
+    let mut packed = vec![];
+    RpcMessage::new_call(
+        xid,
         PORTMAP_PROG,
         PORTMAP_VERS,
         RpcRequest::GetPort as u32,
         mapping,
-    );
+    )
+    .pack_xdr(&mut packed);
+
+    for target in targets {
+        /* Unreachable targets should not prevent others from being queried */
+        if let Err(e) = socket.send_to(&packed, target).await {
+            warn!("Failed to send portmap request to {target}: {e}");
+        }
+    }
+
+    let mut found = vec![];
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 1500];
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, peer) = res?;
+
+        let mut reply = buf[..len].to_vec();
+        let port = RpcMessage::unpack(&mut reply)
+            .ok()
+            .filter(|msg| msg.xid == xid)
+            .and_then(|msg| xdr::unpack_u16(&mut msg.get_success_result().ok()?.to_vec()).ok());
+        match port {
+            /* Port 0 means the program is not registered */
+            Some(0) => debug!("Portmapper {peer} does not have program {prog}"),
+            Some(port) if !found.contains(&(peer, port)) => found.push((peer, port)),
+            Some(_) => {}
+            None => debug!("Ignoring invalid portmap reply from {peer}"),
+        }
+    }
+
+    Ok(found)
+}
 
-    let resp = client.request(packet).await?;
+/// Portmapper answering queries for a fixed set of mappings, for serving ONC
+/// programs on a host with no system portmapper running
+pub struct PortmapServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+    udp_task: JoinHandle<()>,
+}
+impl PortmapServer {
+    /// Start listening on the given address over both TCP and UDP, port 0
+    /// picking any free port
+    pub async fn start(socket: SocketAddr, mappings: Vec<RpcMapping>) -> Result<Self> {
+        let listener = TcpListener::bind(socket).await?;
+        let addr = listener.local_addr()?;
+        /* Same port on UDP, so that a free TCP port is also used for UDP */
+        let udp = UdpSocket::bind(addr).await?;
+
+        let mappings = Arc::new(mappings);
+        let task = tokio::spawn(Self::accept_loop(listener, mappings.clone()));
+        let udp_task = tokio::spawn(Self::serve_udp(udp, mappings));
+
+        debug!("Portmapper listening on {addr}");
+
+        Ok(Self {
+            addr,
+            task,
+            udp_task,
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    async fn accept_loop(listener: TcpListener, mappings: Arc<Vec<RpcMapping>>) {
+        /* Dropping the set when this task is aborted also aborts all
+         * connection tasks */
+        let mut connections = JoinSet::new();
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!("Portmap connection from {peer}");
+                    let mappings = mappings.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = Self::serve_connection(stream, &mappings).await {
+                            debug!("Portmap connection closed: {e}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept portmap connection: {e}");
+                    return;
+                }
+            }
+        }
+    }
 
-    let mut results = resp.get_success_result()?.to_vec();
+    async fn serve_connection(mut stream: TcpStream, mappings: &[RpcMapping]) -> Result<()> {
+        loop {
+            let (xid, call) = onc::read_call(&mut stream).await?;
+            let reply = Self::handle_call(call, mappings);
+            onc::write_reply(&mut stream, xid, reply).await?;
+        }
+    }
+
+    /// Reply to each call datagram, which needs no record marking unlike TCP
+    async fn serve_udp(socket: UdpSocket, mappings: Arc<Vec<RpcMapping>>) {
+        let mut buf = vec![0; 1500];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("Failed to receive portmap datagram: {e}");
+                    return;
+                }
+            };
+
+            let mut msg = buf[..len].to_vec();
+            let Ok(RpcMessage {
+                xid,
+                body: MessageBody::Call(call),
+            }) = RpcMessage::unpack(&mut msg)
+            else {
+                debug!("Ignoring invalid portmap datagram from {peer}");
+                continue;
+            };
+
+            let mut packed = vec![];
+            RpcMessage::new_reply(xid, Self::handle_call(call, &mappings)).pack_xdr(&mut packed);
+            if let Err(e) = socket.send_to(&packed, peer).await {
+                debug!("Failed to send portmap reply to {peer}: {e}");
+            }
+        }
+    }
 
-    xdr::unpack_u16(&mut results)
+    fn handle_call(call: CallBody, mappings: &[RpcMapping]) -> AcceptedReplyBodyType {
+        if let Some(reply) = call.check_program(PORTMAP_PROG, PORTMAP_VERS) {
+            reply
+        } else if call.proc == RpcRequest::Null as u32 {
+            AcceptedReplyBodyType::success(())
+        } else if call.proc == RpcRequest::GetPort as u32 {
+            let mut args = call.args;
+            match RpcMapping::unpack(&mut args) {
+                Ok(req) => {
+                    /* Port 0 tells the client the program is not registered */
+                    let port = mappings
+                        .iter()
+                        .find(|m| m.prog == req.prog && m.vers == req.vers && m.prot == req.prot)
+                        .map(|m| m.port)
+                        .unwrap_or(0);
+                    debug!("GETPORT {} {} {:?}: {port}", req.prog, req.vers, req.prot);
+                    AcceptedReplyBodyType::success(port)
+                }
+                Err(_) => AcceptedReplyBodyType::GarbageArgs(),
+            }
+        } else if call.proc == RpcRequest::Dump as u32 {
+            let mut packed = vec![];
+            for mapping in mappings {
+                true.pack_xdr(&mut packed);
+                mapping.clone().pack_xdr(&mut packed);
+            }
+            false.pack_xdr(&mut packed);
+            AcceptedReplyBodyType::Success(onc::SuccessAcceptedReplyBody { results: packed })
+        } else if call.proc == RpcRequest::Set as u32 || call.proc == RpcRequest::Unset as u32 {
+            /* Mappings are fixed, so registration always fails */
+            AcceptedReplyBodyType::success(false)
+        } else {
+            AcceptedReplyBodyType::ProcUnavail()
+        }
+    }
+}
+impl Drop for PortmapServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.udp_task.abort();
+    }
 }
 
 #[allow(unused)]
@@ -62,19 +367,31 @@ enum RpcRequest {
     Set = 1,
     Unset = 2,
     GetPort = 3,
-    CallIt = 4,
+    Dump = 4,
+    CallIt = 5,
 }
 
-#[derive(Debug)]
-struct RpcMapping {
+/// Mapping of a program version to the port it is served on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcMapping {
     /// Program number
-    prog: u32,
+    pub prog: u32,
     /// Version number
-    vers: u32,
+    pub vers: u32,
     /// Protocol number
-    prot: RpcIpProto,
+    pub prot: RpcIpProto,
     /// Port
-    port: u32,
+    pub port: u32,
+}
+impl RpcMapping {
+    fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            prog: xdr::unpack_u32(src)?,
+            vers: xdr::unpack_u32(src)?,
+            prot: RpcIpProto::unpack(src)?,
+            port: xdr::unpack_u32(src)?,
+        })
+    }
 }
 impl XdrPack for RpcMapping {
     fn pack_xdr(self, out: &mut Vec<u8>) {
@@ -85,10 +402,77 @@ impl XdrPack for RpcMapping {
     }
 }
 
-#[allow(unused)]
-#[derive(Clone, Copy, Debug)]
+/// Protocol a program is served over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u32)]
 pub enum RpcIpProto {
     Tcp = 6,
     Udp = 17,
 }
+impl RpcIpProto {
+    fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        match xdr::unpack_u32(src)? {
+            6 => Ok(Self::Tcp),
+            17 => Ok(Self::Udp),
+            i => Err(Error::BadResponse(format!("Unknown protocol {i}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings() -> Vec<RpcMapping> {
+        vec![
+            RpcMapping {
+                prog: 395183,
+                vers: 1,
+                prot: RpcIpProto::Tcp,
+                port: 1024,
+            },
+            RpcMapping {
+                prog: 395184,
+                vers: 1,
+                prot: RpcIpProto::Udp,
+                port: 1025,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn serves_tcp_and_udp() {
+        let server = PortmapServer::start((Ipv4Addr::LOCALHOST, 0).into(), mappings())
+            .await
+            .unwrap();
+
+        for prot in [RpcIpProto::Tcp, RpcIpProto::Udp] {
+            let mut client = PortmapClient::connect(server.addr(), prot).await.unwrap();
+            assert_eq!(client.protocol(), prot);
+
+            client.null().await.unwrap();
+            assert_eq!(client.dump().await.unwrap(), mappings());
+            assert_eq!(
+                client.get_port(395184, 1, RpcIpProto::Udp).await.unwrap(),
+                1025
+            );
+            /* Registered over the other protocol only */
+            assert!(matches!(
+                client.get_port(395184, 1, RpcIpProto::Tcp).await,
+                Err(Error::NotSupported(_))
+            ));
+
+            client.disconnect().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dumps_empty_list() {
+        let server = PortmapServer::start((Ipv4Addr::LOCALHOST, 0).into(), vec![])
+            .await
+            .unwrap();
+
+        let mut client = PortmapClient::connect_any(server.addr()).await.unwrap();
+        assert!(client.dump().await.unwrap().is_empty());
+    }
+}
@@ -2,7 +2,7 @@
 
 use super::xdr::{self, XdrPack};
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 #[allow(unused)]
 #[repr(u8)]
@@ -26,6 +26,32 @@ pub enum RpcRequest {
     DeviceIntrSrq = 30,
 }
 
+impl RpcRequest {
+    /// Request for a procedure number, if it is one defined by VXI-11
+    pub fn from_proc(proc: u32) -> Option<Self> {
+        Some(match proc {
+            1 => Self::DeviceAbort,
+            10 => Self::CreateLink,
+            11 => Self::DeviceWrite,
+            12 => Self::DeviceRead,
+            13 => Self::DeviceReadStb,
+            14 => Self::DeviceTrigger,
+            15 => Self::DeviceClear,
+            16 => Self::DeviceError,
+            17 => Self::DeviceLocal,
+            18 => Self::DeviceLock,
+            19 => Self::DeviceUnlock,
+            20 => Self::DeviceEnableSrq,
+            22 => Self::DeviceDoCmd,
+            23 => Self::DestroyLink,
+            25 => Self::CreateIntrChan,
+            26 => Self::DestroyIntrChan,
+            30 => Self::DeviceIntrSrq,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RpcDeviceErrorCode {
     NoError,
@@ -67,6 +93,29 @@ impl RpcDeviceErrorCode {
         })
     }
 }
+impl XdrPack for RpcDeviceErrorCode {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        let code: u32 = match self {
+            Self::NoError => 0,
+            Self::SyntaxError => 1,
+            Self::DeviceNotAccessible => 3,
+            Self::InvalidLinkIdentifier => 4,
+            Self::ParameterError => 5,
+            Self::ChannelNotEstablished => 6,
+            Self::OperationNotSupported => 8,
+            Self::OutOfResources => 9,
+            Self::DeviceLockedByAnotherLink => 11,
+            Self::NoLockHeldByThisLink => 12,
+            Self::IoTimeout => 15,
+            Self::IoError => 17,
+            Self::InvalidAddress => 21,
+            Self::Abort => 23,
+            Self::ChannelAlreadyEstablished => 29,
+            Self::Unknown(i) => i,
+        };
+        code.pack_xdr(out);
+    }
+}
 
 #[derive(Debug)]
 pub struct RpcOperationFlags {
@@ -92,6 +141,16 @@ impl XdrPack for RpcOperationFlags {
         flags.pack_xdr(out);
     }
 }
+impl RpcOperationFlags {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        let flags = xdr::unpack_u32(src)?;
+        Ok(Self {
+            wait_lock: (flags & (1 << 0)) != 0,
+            end: (flags & (1 << 3)) != 0,
+            termchr_set: (flags & (1 << 7)) != 0,
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct RpcRequestCreateDeviceLink {
@@ -112,8 +171,17 @@ impl XdrPack for RpcRequestCreateDeviceLink {
         self.device.pack_xdr(out);
     }
 }
+impl RpcRequestCreateDeviceLink {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            client_id: xdr::unpack_i32(src)?,
+            lock_device: xdr::unpack_u32(src)? != 0,
+            lock_timeout: xdr::unpack_u32(src)?,
+            device: String::from_utf8_lossy(&xdr::unpack_opaque(src)?).into_owned(),
+        })
+    }
+}
 
-#[allow(unused)]
 #[derive(Debug)]
 pub struct RpcResponseCreateDeviceLink {
     /// Error code
@@ -135,6 +203,14 @@ impl RpcResponseCreateDeviceLink {
         })
     }
 }
+impl XdrPack for RpcResponseCreateDeviceLink {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.error.pack_xdr(out);
+        self.lid.pack_xdr(out);
+        (self.abort_port as u32).pack_xdr(out);
+        self.max_recv_size.pack_xdr(out);
+    }
+}
 
 #[derive(Debug)]
 pub struct RpcRequestDeviceWrite {
@@ -158,6 +234,17 @@ impl XdrPack for RpcRequestDeviceWrite {
         self.data.pack_xdr(out);
     }
 }
+impl RpcRequestDeviceWrite {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            lid: xdr::unpack_i32(src)?,
+            io_timeout: xdr::unpack_u32(src)?,
+            lock_timeout: xdr::unpack_u32(src)?,
+            flags: RpcOperationFlags::unpack(src)?,
+            data: xdr::unpack_opaque(src)?,
+        })
+    }
+}
 
 #[allow(unused)]
 #[derive(Debug)]
@@ -175,6 +262,92 @@ impl RpcResponseDeviceWrite {
         })
     }
 }
+impl XdrPack for RpcResponseDeviceWrite {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.error.pack_xdr(out);
+        self.size.pack_xdr(out);
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcRequestDeviceGenericParms {
+    /// Link ID
+    pub lid: i32,
+    /// Flags
+    pub flags: RpcOperationFlags,
+    /// Time to wait for lock
+    pub lock_timeout: u32,
+    /// Time to wait for I/O
+    pub io_timeout: u32,
+}
+impl XdrPack for RpcRequestDeviceGenericParms {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.lid.pack_xdr(out);
+        self.flags.pack_xdr(out);
+        self.lock_timeout.pack_xdr(out);
+        self.io_timeout.pack_xdr(out);
+    }
+}
+impl RpcRequestDeviceGenericParms {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            lid: xdr::unpack_i32(src)?,
+            flags: RpcOperationFlags::unpack(src)?,
+            lock_timeout: xdr::unpack_u32(src)?,
+            io_timeout: xdr::unpack_u32(src)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcRequestDeviceLock {
+    /// Link ID
+    pub lid: i32,
+    /// Flags
+    pub flags: RpcOperationFlags,
+    /// Time to wait for lock
+    pub lock_timeout: u32,
+}
+impl XdrPack for RpcRequestDeviceLock {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.lid.pack_xdr(out);
+        self.flags.pack_xdr(out);
+        self.lock_timeout.pack_xdr(out);
+    }
+}
+impl RpcRequestDeviceLock {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            lid: xdr::unpack_i32(src)?,
+            flags: RpcOperationFlags::unpack(src)?,
+            lock_timeout: xdr::unpack_u32(src)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcResponseDeviceReadStb {
+    /// Error code
+    pub error: RpcDeviceErrorCode,
+    /// Status byte
+    pub stb: u8,
+}
+impl RpcResponseDeviceReadStb {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            error: RpcDeviceErrorCode::unpack(src)?,
+            /* Status byte is transferred as an unsigned char, which XDR
+             * packs into a full word */
+            stb: xdr::unpack_u32(src)? as u8,
+        })
+    }
+}
+impl XdrPack for RpcResponseDeviceReadStb {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.error.pack_xdr(out);
+        (self.stb as u32).pack_xdr(out);
+    }
+}
 
 #[derive(Debug)]
 pub struct RpcRequestDeviceRead {
@@ -201,8 +374,19 @@ impl XdrPack for RpcRequestDeviceRead {
         (self.termchr as u32).pack_xdr(out);
     }
 }
+impl RpcRequestDeviceRead {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            lid: xdr::unpack_i32(src)?,
+            request_size: xdr::unpack_u32(src)?,
+            io_timeout: xdr::unpack_u32(src)?,
+            lock_timeout: xdr::unpack_u32(src)?,
+            flags: RpcOperationFlags::unpack(src)?,
+            termchr: xdr::unpack_u32(src)? as u8,
+        })
+    }
+}
 
-#[allow(unused)]
 #[derive(Debug)]
 pub struct RpcDeviceReadReason {
     /// request_size bytes have been transferred
@@ -222,6 +406,21 @@ impl RpcDeviceReadReason {
         })
     }
 }
+impl XdrPack for RpcDeviceReadReason {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        let mut flags = 0u32;
+        if self.reqcnt {
+            flags |= 1 << 0;
+        }
+        if self.chr {
+            flags |= 1 << 1;
+        }
+        if self.end {
+            flags |= 1 << 2;
+        }
+        flags.pack_xdr(out);
+    }
+}
 
 #[derive(Debug)]
 pub struct RpcResponseDeviceRead {
@@ -241,3 +440,101 @@ impl RpcResponseDeviceRead {
         })
     }
 }
+impl XdrPack for RpcResponseDeviceRead {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.error.pack_xdr(out);
+        self.reason.pack_xdr(out);
+        self.data.pack_xdr(out);
+    }
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+#[repr(u32)]
+pub enum RpcAddrFamily {
+    Tcp = 0,
+    Udp = 1,
+}
+
+#[derive(Debug)]
+pub struct RpcRequestCreateIntrChan {
+    /// IPv4 address of the interrupt server
+    pub host_addr: u32,
+    /// Port of the interrupt server
+    pub host_port: u16,
+    /// Program number of the interrupt server
+    pub prog_num: u32,
+    /// Program version of the interrupt server
+    pub prog_vers: u32,
+    /// Transport used by the interrupt server
+    pub prog_family: RpcAddrFamily,
+}
+impl XdrPack for RpcRequestCreateIntrChan {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.host_addr.pack_xdr(out);
+        (self.host_port as u32).pack_xdr(out);
+        self.prog_num.pack_xdr(out);
+        self.prog_vers.pack_xdr(out);
+        (self.prog_family as u32).pack_xdr(out);
+    }
+}
+impl RpcRequestCreateIntrChan {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            host_addr: xdr::unpack_u32(src)?,
+            host_port: xdr::unpack_u16(src)?,
+            prog_num: xdr::unpack_u32(src)?,
+            prog_vers: xdr::unpack_u32(src)?,
+            prog_family: match xdr::unpack_u32(src)? {
+                0 => RpcAddrFamily::Tcp,
+                1 => RpcAddrFamily::Udp,
+                i => return Err(Error::BadResponse(format!("Unknown address family {i}"))),
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcRequestDeviceEnableSrq {
+    /// Link ID
+    pub lid: i32,
+    /// Whether to enable or disable service requests
+    pub enable: bool,
+    /// Opaque handle returned by the device with each service request, max
+    /// 40 bytes
+    pub handle: Vec<u8>,
+}
+impl XdrPack for RpcRequestDeviceEnableSrq {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.lid.pack_xdr(out);
+        self.enable.pack_xdr(out);
+        self.handle.pack_xdr(out);
+    }
+}
+impl RpcRequestDeviceEnableSrq {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            lid: xdr::unpack_i32(src)?,
+            enable: xdr::unpack_u32(src)? != 0,
+            handle: xdr::unpack_opaque(src)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcRequestDeviceSrq {
+    /// Handle given when enabling service requests
+    pub handle: Vec<u8>,
+}
+impl RpcRequestDeviceSrq {
+    pub fn unpack(src: &mut Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            handle: xdr::unpack_opaque(src)?,
+        })
+    }
+}
+impl XdrPack for RpcRequestDeviceSrq {
+    fn pack_xdr(self, out: &mut Vec<u8>) {
+        self.handle.pack_xdr(out);
+    }
+}
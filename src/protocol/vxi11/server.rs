@@ -0,0 +1,742 @@
+//! VXI-11 server, making simulated instruments available to any VXI-11 client
+//! with a portmapper of its own
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+    task::{JoinHandle, JoinSet},
+    time::Instant,
+};
+
+use crate::{error::Result, sim::SimDevice};
+
+use super::{
+    VXI_ABORT_PROG, VXI_ABORT_VERS, VXI_CORE_PROG, VXI_CORE_VERS,
+    onc::{self, AcceptedReplyBodyType, CallBody, OncClient, SuccessAcceptedReplyBody},
+    portmap::{PortmapServer, RpcIpProto, RpcMapping},
+    rpc::{self, RpcDeviceErrorCode, RpcRequest},
+    xdr::{self, XdrPack},
+};
+
+/// Largest write accepted in a single device_write call
+const MAX_RECV_SIZE: u32 = 65536;
+/// Largest program message accepted, over any number of device_write calls
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+/// Message available bit of the status byte
+const STB_MAV: u8 = 1 << 4;
+/// Master summary status bit of the status byte
+const STB_MSS: u8 = 1 << 6;
+
+/// VXI-11 server for simulated instruments. Each instrument may be linked to
+/// as `inst<n>`, in the order given, or by its simulated name.
+///
+/// Operations complete immediately, so a read with no response pending fails
+/// with an I/O timeout straight away. Service requests are sent over the
+/// interrupt channel of the link whose write set MSS in the status byte.
+pub struct VxiServer {
+    portmap: PortmapServer,
+    tasks: Vec<JoinHandle<()>>,
+}
+impl VxiServer {
+    /// Start serving on the given address, with the portmapper listening on
+    /// portmap_port, 0 picking any free port. The core and abort channels
+    /// always use any free port.
+    pub async fn start(
+        ip: IpAddr,
+        portmap_port: u16,
+        devices: Vec<Arc<Mutex<SimDevice>>>,
+    ) -> Result<Self> {
+        let core = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let abort = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let core_port = core.local_addr()?.port();
+        let abort_port = abort.local_addr()?.port();
+
+        let portmap = PortmapServer::start(
+            SocketAddr::new(ip, portmap_port),
+            vec![
+                RpcMapping {
+                    prog: VXI_CORE_PROG,
+                    vers: VXI_CORE_VERS,
+                    prot: RpcIpProto::Tcp,
+                    port: core_port as u32,
+                },
+                RpcMapping {
+                    prog: VXI_ABORT_PROG,
+                    vers: VXI_ABORT_VERS,
+                    prot: RpcIpProto::Tcp,
+                    port: abort_port as u32,
+                },
+            ],
+        )
+        .await?;
+
+        let shared = Arc::new(VxiServerShared::new(devices));
+        let core_shared = shared.clone();
+        let tasks = vec![
+            tokio::spawn(accept_loop(core, "core", move |stream| {
+                VxiCoreConnection::new(core_shared.clone(), abort_port).serve(stream)
+            })),
+            tokio::spawn(accept_loop(abort, "abort", move |stream| {
+                serve_abort(shared.clone(), stream)
+            })),
+        ];
+
+        debug!("VXI-11 core channel on port {core_port}, abort channel on port {abort_port}");
+
+        Ok(Self { portmap, tasks })
+    }
+
+    /// Address of the portmapper, as given in a `vxi11://` URI
+    pub fn addr(&self) -> SocketAddr {
+        self.portmap.addr()
+    }
+}
+impl Drop for VxiServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Accept connections until the listener fails, serving each with serve
+async fn accept_loop<F, Fut>(listener: TcpListener, channel: &'static str, serve: F)
+where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    /* Dropping the set when this task is aborted also aborts all connection
+     * tasks */
+    let mut connections = JoinSet::new();
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("VXI-11 {channel} channel connection from {peer}");
+                let conn = serve(stream);
+                connections.spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!("VXI-11 {channel} channel connection closed: {e}");
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Failed to accept VXI-11 {channel} channel connection: {e}");
+                return;
+            }
+        }
+    }
+}
+
+async fn serve_abort(shared: Arc<VxiServerShared>, mut stream: TcpStream) -> Result<()> {
+    loop {
+        let (xid, call) = onc::read_call(&mut stream).await?;
+
+        let reply = if let Some(reply) = call.check_program(VXI_ABORT_PROG, VXI_ABORT_VERS) {
+            reply
+        } else if call.proc == RpcRequest::DeviceAbort as u32 {
+            let mut args = call.args;
+            match xdr::unpack_i32(&mut args) {
+                Ok(lid) => AcceptedReplyBodyType::success(shared.abort(lid)),
+                Err(_) => AcceptedReplyBodyType::GarbageArgs(),
+            }
+        } else {
+            AcceptedReplyBodyType::ProcUnavail()
+        };
+
+        onc::write_reply(&mut stream, xid, reply).await?;
+    }
+}
+
+struct VxiServerDevice {
+    device: Arc<Mutex<SimDevice>>,
+    /// Link holding the exclusive lock, if any
+    lock: Option<i32>,
+}
+
+struct VxiServerLink {
+    /// Index of the linked device
+    device: usize,
+    /// Data written so far of a message not yet ended
+    message: Vec<u8>,
+    /// Responses not yet read, the first possibly partially read
+    responses: VecDeque<Vec<u8>>,
+    /// Handle to pass back with service requests, if enabled
+    srq_handle: Option<Vec<u8>>,
+    /// Whether MSS was set when last checked, as service requests are only
+    /// sent when it becomes set
+    mss: bool,
+    /// Set by device_abort to end an operation waiting for the lock
+    aborted: bool,
+}
+
+struct VxiServerState {
+    devices: Vec<VxiServerDevice>,
+    links: HashMap<i32, VxiServerLink>,
+    last_lid: i32,
+}
+
+/// State shared by all connections to the server
+struct VxiServerShared {
+    state: Mutex<VxiServerState>,
+    /// Notified when a lock is released or an operation aborted
+    released: Notify,
+}
+impl VxiServerShared {
+    fn new(devices: Vec<Arc<Mutex<SimDevice>>>) -> Self {
+        Self {
+            state: Mutex::new(VxiServerState {
+                devices: devices
+                    .into_iter()
+                    .map(|device| VxiServerDevice { device, lock: None })
+                    .collect(),
+                links: HashMap::new(),
+                last_lid: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Index of a device by its name, `inst<n>` or the simulated name
+    fn find_device(&self, name: &str) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+
+        if let Some(num) = name.to_ascii_lowercase().strip_prefix("inst")
+            && let Ok(idx) = num.parse::<usize>()
+        {
+            return (idx < state.devices.len()).then_some(idx);
+        }
+
+        state
+            .devices
+            .iter()
+            .position(|dev| dev.device.lock().unwrap().name().eq_ignore_ascii_case(name))
+    }
+
+    fn add_link(&self, device: usize) -> i32 {
+        let mut state = self.state.lock().unwrap();
+        state.last_lid += 1;
+        let lid = state.last_lid;
+        state.links.insert(
+            lid,
+            VxiServerLink {
+                device,
+                message: vec![],
+                responses: VecDeque::new(),
+                srq_handle: None,
+                mss: false,
+                aborted: false,
+            },
+        );
+
+        lid
+    }
+
+    fn destroy_link(&self, lid: i32) -> RpcDeviceErrorCode {
+        let mut state = self.state.lock().unwrap();
+        let Some(link) = state.links.remove(&lid) else {
+            return RpcDeviceErrorCode::InvalidLinkIdentifier;
+        };
+
+        let device = &mut state.devices[link.device];
+        if device.lock == Some(lid) {
+            device.lock = None;
+            self.released.notify_waiters();
+        }
+
+        RpcDeviceErrorCode::NoError
+    }
+
+    /// Run op on a link and its device once no other link holds the lock,
+    /// waiting up to lock_timeout ms for it to be released if wait_lock is set
+    async fn with_access<T>(
+        &self,
+        lid: i32,
+        wait_lock: bool,
+        lock_timeout: u32,
+        op: impl FnOnce(&mut VxiServerLink, &mut VxiServerDevice) -> T,
+    ) -> std::result::Result<T, RpcDeviceErrorCode> {
+        let deadline = Instant::now() + Duration::from_millis(lock_timeout as _);
+
+        /* Only aborts made while waiting apply */
+        if let Some(link) = self.state.lock().unwrap().links.get_mut(&lid) {
+            link.aborted = false;
+        }
+
+        loop {
+            /* Register for notification before checking, so that a release
+             * in between is not missed */
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                let state = &mut *state;
+                let Some(link) = state.links.get_mut(&lid) else {
+                    return Err(RpcDeviceErrorCode::InvalidLinkIdentifier);
+                };
+                if link.aborted {
+                    link.aborted = false;
+                    return Err(RpcDeviceErrorCode::Abort);
+                }
+
+                let device = &mut state.devices[link.device];
+                if device.lock.is_none_or(|holder| holder == lid) {
+                    return Ok(op(link, device));
+                }
+            }
+
+            if !wait_lock || tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(RpcDeviceErrorCode::DeviceLockedByAnotherLink);
+            }
+        }
+    }
+
+    async fn write(&self, req: rpc::RpcRequestDeviceWrite) -> rpc::RpcResponseDeviceWrite {
+        let size = req.data.len() as u32;
+        let end = req.flags.end;
+
+        let res = self
+            .with_access(
+                req.lid,
+                req.flags.wait_lock,
+                req.lock_timeout,
+                |link, device| {
+                    if link.message.len() + req.data.len() > MAX_MESSAGE_SIZE {
+                        link.message.clear();
+                        return Err(RpcDeviceErrorCode::OutOfResources);
+                    }
+                    link.message.extend(req.data);
+                    if !end {
+                        return Ok(());
+                    }
+
+                    let message = mem::take(&mut link.message);
+                    if let Some(mut resp) = device.device.lock().unwrap().write(&message) {
+                        resp.push(b'\n');
+                        link.responses.push_back(resp);
+                    }
+                    Ok(())
+                },
+            )
+            .await
+            .and_then(|res| res);
+
+        match res {
+            Ok(()) => rpc::RpcResponseDeviceWrite {
+                error: RpcDeviceErrorCode::NoError,
+                size,
+            },
+            Err(error) => rpc::RpcResponseDeviceWrite { error, size: 0 },
+        }
+    }
+
+    async fn read(&self, req: rpc::RpcRequestDeviceRead) -> rpc::RpcResponseDeviceRead {
+        let request_size = req.request_size as usize;
+        let termchr = req.flags.termchr_set.then_some(req.termchr);
+
+        let res = self
+            .with_access(req.lid, req.flags.wait_lock, req.lock_timeout, |link, _| {
+                let Some(resp) = link.responses.front_mut() else {
+                    return Err(RpcDeviceErrorCode::IoTimeout);
+                };
+
+                let mut len = resp.len().min(request_size);
+                let chr_pos = termchr.and_then(|chr| resp[..len].iter().position(|&c| c == chr));
+                if let Some(pos) = chr_pos {
+                    len = pos + 1;
+                }
+                let data: Vec<u8> = resp.drain(..len).collect();
+                let end = resp.is_empty();
+                if end {
+                    link.responses.pop_front();
+                }
+
+                let reason = rpc::RpcDeviceReadReason {
+                    reqcnt: len == request_size,
+                    chr: chr_pos.is_some(),
+                    end,
+                };
+                Ok((reason, data))
+            })
+            .await
+            .and_then(|res| res);
+
+        match res {
+            Ok((reason, data)) => rpc::RpcResponseDeviceRead {
+                error: RpcDeviceErrorCode::NoError,
+                reason,
+                data,
+            },
+            Err(error) => rpc::RpcResponseDeviceRead {
+                error,
+                reason: rpc::RpcDeviceReadReason {
+                    reqcnt: false,
+                    chr: false,
+                    end: false,
+                },
+                data: vec![],
+            },
+        }
+    }
+
+    async fn read_stb(
+        &self,
+        req: rpc::RpcRequestDeviceGenericParms,
+    ) -> rpc::RpcResponseDeviceReadStb {
+        let res = self
+            .with_access(
+                req.lid,
+                req.flags.wait_lock,
+                req.lock_timeout,
+                |link, device| {
+                    let mut stb = device.device.lock().unwrap().status_byte();
+                    if !link.responses.is_empty() {
+                        stb |= STB_MAV;
+                    }
+                    stb
+                },
+            )
+            .await;
+
+        match res {
+            Ok(stb) => rpc::RpcResponseDeviceReadStb {
+                error: RpcDeviceErrorCode::NoError,
+                stb,
+            },
+            Err(error) => rpc::RpcResponseDeviceReadStb { error, stb: 0 },
+        }
+    }
+
+    /// Perform an operation taking only the generic parameters
+    async fn generic(
+        &self,
+        proc: RpcRequest,
+        req: rpc::RpcRequestDeviceGenericParms,
+    ) -> RpcDeviceErrorCode {
+        let res = self
+            .with_access(
+                req.lid,
+                req.flags.wait_lock,
+                req.lock_timeout,
+                |link, device| match proc {
+                    RpcRequest::DeviceTrigger => {
+                        device.device.lock().unwrap().instrument().trigger();
+                    }
+                    RpcRequest::DeviceClear => {
+                        link.message.clear();
+                        link.responses.clear();
+                    }
+                    /* Local and remote control make no difference */
+                    _ => {}
+                },
+            )
+            .await;
+
+        res.err().unwrap_or(RpcDeviceErrorCode::NoError)
+    }
+
+    async fn lock(&self, req: rpc::RpcRequestDeviceLock) -> RpcDeviceErrorCode {
+        let res = self
+            .with_access(
+                req.lid,
+                req.flags.wait_lock,
+                req.lock_timeout,
+                |_, device| device.lock = Some(req.lid),
+            )
+            .await;
+
+        res.err().unwrap_or(RpcDeviceErrorCode::NoError)
+    }
+
+    fn unlock(&self, lid: i32) -> RpcDeviceErrorCode {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(link) = state.links.get(&lid) else {
+            return RpcDeviceErrorCode::InvalidLinkIdentifier;
+        };
+
+        let device = &mut state.devices[link.device];
+        if device.lock != Some(lid) {
+            return RpcDeviceErrorCode::NoLockHeldByThisLink;
+        }
+        device.lock = None;
+        self.released.notify_waiters();
+
+        RpcDeviceErrorCode::NoError
+    }
+
+    fn abort(&self, lid: i32) -> RpcDeviceErrorCode {
+        let mut state = self.state.lock().unwrap();
+        let Some(link) = state.links.get_mut(&lid) else {
+            return RpcDeviceErrorCode::InvalidLinkIdentifier;
+        };
+
+        link.aborted = true;
+        self.released.notify_waiters();
+
+        RpcDeviceErrorCode::NoError
+    }
+
+    fn enable_srq(&self, req: rpc::RpcRequestDeviceEnableSrq) -> RpcDeviceErrorCode {
+        let mut state = self.state.lock().unwrap();
+        let Some(link) = state.links.get_mut(&req.lid) else {
+            return RpcDeviceErrorCode::InvalidLinkIdentifier;
+        };
+
+        link.srq_handle = req.enable.then_some(req.handle);
+
+        RpcDeviceErrorCode::NoError
+    }
+
+    /// Handle to send a service request with, if service requests are enabled
+    /// on the link and MSS has become set since last checked
+    fn srq_pending(&self, lid: i32) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let link = state.links.get_mut(&lid)?;
+
+        let stb = state.devices[link.device]
+            .device
+            .lock()
+            .unwrap()
+            .status_byte();
+        let mss = stb & STB_MSS != 0;
+        let rising = mss && !link.mss;
+        link.mss = mss;
+
+        if rising {
+            link.srq_handle.clone()
+        } else {
+            None
+        }
+    }
+}
+
+/// Client's interrupt channel server, for sending service requests
+struct VxiInterruptClient {
+    onc: OncClient,
+    prog: u32,
+    vers: u32,
+}
+
+/// A connection to the core channel
+struct VxiCoreConnection {
+    shared: Arc<VxiServerShared>,
+    abort_port: u16,
+    /// Links created on this connection, destroyed when it closes
+    links: Vec<i32>,
+    intr: Option<VxiInterruptClient>,
+}
+impl VxiCoreConnection {
+    fn new(shared: Arc<VxiServerShared>, abort_port: u16) -> Self {
+        Self {
+            shared,
+            abort_port,
+            links: vec![],
+            intr: None,
+        }
+    }
+
+    async fn serve(mut self, mut stream: TcpStream) -> Result<()> {
+        let result = self.serve_calls(&mut stream).await;
+
+        for lid in self.links.drain(..) {
+            self.shared.destroy_link(lid);
+        }
+        if let Some(mut intr) = self.intr.take() {
+            let _ = intr.onc.disconnect().await;
+        }
+
+        result
+    }
+
+    async fn serve_calls(&mut self, stream: &mut TcpStream) -> Result<()> {
+        loop {
+            let (xid, call) = onc::read_call(stream).await?;
+
+            let reply = match call.check_program(VXI_CORE_PROG, VXI_CORE_VERS) {
+                Some(reply) => reply,
+                None => self
+                    .call(call)
+                    .await
+                    .unwrap_or(AcceptedReplyBodyType::GarbageArgs()),
+            };
+
+            onc::write_reply(stream, xid, reply).await?;
+        }
+    }
+
+    async fn call(&mut self, call: CallBody) -> Result<AcceptedReplyBodyType> {
+        let mut args = call.args;
+        let shared = self.shared.clone();
+
+        let reply = match RpcRequest::from_proc(call.proc) {
+            Some(RpcRequest::CreateLink) => {
+                let req = rpc::RpcRequestCreateDeviceLink::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(self.create_link(req).await)
+            }
+            Some(RpcRequest::DeviceWrite) => {
+                let req = rpc::RpcRequestDeviceWrite::unpack(&mut args)?;
+                let lid = req.lid;
+                let resp = shared.write(req).await;
+                self.send_srq(lid).await;
+                AcceptedReplyBodyType::success(resp)
+            }
+            Some(RpcRequest::DeviceRead) => {
+                let req = rpc::RpcRequestDeviceRead::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(shared.read(req).await)
+            }
+            Some(RpcRequest::DeviceReadStb) => {
+                let req = rpc::RpcRequestDeviceGenericParms::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(shared.read_stb(req).await)
+            }
+            /* Procedure 16 is device_remote */
+            Some(
+                proc @ (RpcRequest::DeviceTrigger
+                | RpcRequest::DeviceClear
+                | RpcRequest::DeviceError
+                | RpcRequest::DeviceLocal),
+            ) => {
+                let req = rpc::RpcRequestDeviceGenericParms::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(shared.generic(proc, req).await)
+            }
+            Some(RpcRequest::DeviceLock) => {
+                let req = rpc::RpcRequestDeviceLock::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(shared.lock(req).await)
+            }
+            Some(RpcRequest::DeviceUnlock) => {
+                let lid = xdr::unpack_i32(&mut args)?;
+                AcceptedReplyBodyType::success(shared.unlock(lid))
+            }
+            Some(RpcRequest::DeviceEnableSrq) => {
+                let req = rpc::RpcRequestDeviceEnableSrq::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(shared.enable_srq(req))
+            }
+            Some(RpcRequest::DeviceDoCmd) => {
+                /* Error followed by empty output data */
+                let mut results = vec![];
+                RpcDeviceErrorCode::OperationNotSupported.pack_xdr(&mut results);
+                Vec::new().pack_xdr(&mut results);
+                AcceptedReplyBodyType::Success(SuccessAcceptedReplyBody { results })
+            }
+            Some(RpcRequest::DestroyLink) => {
+                let lid = xdr::unpack_i32(&mut args)?;
+                self.links.retain(|&l| l != lid);
+                AcceptedReplyBodyType::success(shared.destroy_link(lid))
+            }
+            Some(RpcRequest::CreateIntrChan) => {
+                let req = rpc::RpcRequestCreateIntrChan::unpack(&mut args)?;
+                AcceptedReplyBodyType::success(self.create_intr_chan(req).await)
+            }
+            Some(RpcRequest::DestroyIntrChan) => {
+                let error = match self.intr.take() {
+                    Some(mut intr) => {
+                        let _ = intr.onc.disconnect().await;
+                        RpcDeviceErrorCode::NoError
+                    }
+                    None => RpcDeviceErrorCode::ChannelNotEstablished,
+                };
+                AcceptedReplyBodyType::success(error)
+            }
+            _ => AcceptedReplyBodyType::ProcUnavail(),
+        };
+
+        Ok(reply)
+    }
+
+    async fn create_link(
+        &mut self,
+        req: rpc::RpcRequestCreateDeviceLink,
+    ) -> rpc::RpcResponseCreateDeviceLink {
+        let abort_port = self.abort_port;
+        let resp = |error, lid| rpc::RpcResponseCreateDeviceLink {
+            error,
+            lid,
+            abort_port,
+            max_recv_size: MAX_RECV_SIZE,
+        };
+
+        let Some(device) = self.shared.find_device(&req.device) else {
+            debug!("create_link({}): no such device", req.device);
+            return resp(RpcDeviceErrorCode::DeviceNotAccessible, 0);
+        };
+        let lid = self.shared.add_link(device);
+
+        if req.lock_device {
+            let res = self
+                .shared
+                .with_access(lid, true, req.lock_timeout, |_, device| {
+                    device.lock = Some(lid)
+                })
+                .await;
+            if let Err(error) = res {
+                self.shared.destroy_link(lid);
+                return resp(error, 0);
+            }
+        }
+
+        debug!("create_link({}): link {lid}", req.device);
+        self.links.push(lid);
+
+        resp(RpcDeviceErrorCode::NoError, lid)
+    }
+
+    async fn create_intr_chan(&mut self, req: rpc::RpcRequestCreateIntrChan) -> RpcDeviceErrorCode {
+        if self.intr.is_some() {
+            return RpcDeviceErrorCode::ChannelAlreadyEstablished;
+        }
+        if !matches!(req.prog_family, rpc::RpcAddrFamily::Tcp) {
+            return RpcDeviceErrorCode::OperationNotSupported;
+        }
+
+        let addr = SocketAddr::new(Ipv4Addr::from(req.host_addr).into(), req.host_port);
+        let mut onc = OncClient::new(addr);
+        if let Err(e) = onc.connect().await {
+            warn!("Failed to connect to interrupt channel at {addr}: {e}");
+            return RpcDeviceErrorCode::ChannelNotEstablished;
+        }
+
+        debug!("Interrupt channel connected to {addr}");
+        self.intr = Some(VxiInterruptClient {
+            onc,
+            prog: req.prog_num,
+            vers: req.prog_vers,
+        });
+
+        RpcDeviceErrorCode::NoError
+    }
+
+    /// Send a service request for a link if its write caused one
+    async fn send_srq(&mut self, lid: i32) {
+        let Some(handle) = self.shared.srq_pending(lid) else {
+            return;
+        };
+        let Some(intr) = &mut self.intr else {
+            return;
+        };
+
+        debug!("Sending service request for link {lid}");
+
+        let req = intr.onc.gen_call_packet(
+            intr.prog,
+            intr.vers,
+            RpcRequest::DeviceIntrSrq as u32,
+            rpc::RpcRequestDeviceSrq { handle },
+        );
+        if let Err(e) = intr.onc.request(req).await {
+            warn!("Failed to send service request: {e}");
+        }
+    }
+}
@@ -7,6 +7,11 @@ pub trait XdrPack {
     fn pack_xdr(self, out: &mut Vec<u8>);
 }
 
+impl XdrPack for () {
+    /// Void, has no representation
+    fn pack_xdr(self, _out: &mut Vec<u8>) {}
+}
+
 impl XdrPack for u32 {
     fn pack_xdr(self, out: &mut Vec<u8>) {
         out.extend(self.to_be_bytes());
@@ -48,7 +53,7 @@ impl XdrPack for String {
 
 pub fn unpack_u32(src: &mut Vec<u8>) -> Result<u32> {
     let bytes = src
-        .drain(0..4)
+        .drain(0..src.len().min(4))
         .collect::<Vec<_>>()
         .try_into()
         .map_err(|_| Error::BadResponse("Not enough bytes to read u32".to_string()))?;
@@ -58,7 +63,7 @@ pub fn unpack_u32(src: &mut Vec<u8>) -> Result<u32> {
 
 pub fn unpack_i32(src: &mut Vec<u8>) -> Result<i32> {
     let bytes = src
-        .drain(0..4)
+        .drain(0..src.len().min(4))
         .collect::<Vec<_>>()
         .try_into()
         .map_err(|_| Error::BadResponse("Not enough bytes to read i32".to_string()))?;
@@ -68,7 +73,7 @@ pub fn unpack_i32(src: &mut Vec<u8>) -> Result<i32> {
 
 pub fn unpack_u16(src: &mut Vec<u8>) -> Result<u16> {
     let bytes = src
-        .drain(0..4)
+        .drain(0..src.len().min(4))
         .collect::<Vec<_>>()
         .try_into()
         .map_err(|_| Error::BadResponse("Not enough bytes to read u32".to_string()))?;
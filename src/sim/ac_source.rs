@@ -0,0 +1,246 @@
+//! Simulated HP/Agilent/Keysight 6800 series AC source, driving a series
+//! resistive and inductive load
+
+use std::f64::consts::{SQRT_2, TAU};
+
+use crate::error::{Error, Result};
+
+use super::{SimCommand, SimError, SimInstrument, SimNet, SimNoise, SimResult, respond};
+
+/// Load until set otherwise, as series resistance in Ω and inductance in H
+const DEFAULT_LOAD: (f64, f64) = (50.0, 50e-3);
+const MAX_VOLTAGE: f64 = 300.0;
+const MIN_FREQUENCY: f64 = 45.0;
+const MAX_FREQUENCY: f64 = 1000.0;
+
+/// Readings of the output, computed from the settings and load
+struct SimAcReadings {
+    dc_voltage: f64,
+    ac_voltage: f64,
+    dc_current: f64,
+    ac_current: f64,
+    /// AC power, in W
+    real: f64,
+    /// AC power, in VA
+    apparent: f64,
+    /// AC power, in VAR
+    reactive: f64,
+}
+
+pub struct SimAcSource {
+    idn: String,
+    /// RMS voltage
+    voltage: f64,
+    /// DC offset voltage
+    offset: f64,
+    frequency: f64,
+    enabled: bool,
+    resistance: f64,
+    inductance: f64,
+    noise: SimNoise,
+}
+impl SimAcSource {
+    pub fn new(model: &str) -> Result<Self> {
+        if !model.starts_with("68") {
+            return Err(Error::NotSupported(format!(
+                "No simulated AC source {model}"
+            )));
+        }
+
+        let (resistance, inductance) = DEFAULT_LOAD;
+        let mut source = Self {
+            idn: format!("HEWLETT-PACKARD,{model},SIM00001,A.00.00"),
+            voltage: 0.0,
+            offset: 0.0,
+            frequency: 0.0,
+            enabled: false,
+            resistance,
+            inductance,
+            noise: SimNoise::new(0x4143_0000),
+        };
+        source.reset();
+
+        Ok(source)
+    }
+
+    fn readings(&self) -> SimAcReadings {
+        if !self.enabled {
+            return SimAcReadings {
+                dc_voltage: 0.0,
+                ac_voltage: 0.0,
+                dc_current: 0.0,
+                ac_current: 0.0,
+                real: 0.0,
+                apparent: 0.0,
+                reactive: 0.0,
+            };
+        }
+
+        let reactance = TAU * self.frequency * self.inductance;
+        let impedance = self.resistance.hypot(reactance);
+        let ac_current = self.voltage / impedance;
+
+        SimAcReadings {
+            dc_voltage: self.offset,
+            ac_voltage: self.voltage,
+            dc_current: self.offset / self.resistance,
+            ac_current,
+            real: ac_current.powi(2) * self.resistance,
+            apparent: self.voltage * ac_current,
+            reactive: ac_current.powi(2) * reactance,
+        }
+    }
+
+    /// Add measurement noise, relative to the full scale value given
+    fn measured(&mut self, value: f64, full_scale: f64) -> f64 {
+        if !self.enabled {
+            return 0.0;
+        }
+
+        value + self.noise.gaussian() * full_scale * 1e-4
+    }
+
+    /// Value of a measurement query, either fetched or measured anew, which
+    /// are the same as measurements are always up to date
+    fn measurement(&mut self, cmd: &SimCommand) -> Option<f64> {
+        let matches = |pattern: &str| {
+            ["FETCh", "MEASure"]
+                .iter()
+                .any(|root| cmd.matches(&format!("{root}[:SCALar]{pattern}")).is_some())
+        };
+        let r = self.readings();
+
+        let value = if matches(":VOLTage[:DC]?") {
+            self.measured(r.dc_voltage, MAX_VOLTAGE)
+        } else if matches(":VOLTage:ACDC?") {
+            let rms = r.dc_voltage.hypot(r.ac_voltage);
+            self.measured(rms, MAX_VOLTAGE)
+        } else if matches(":VOLTage:AC?") {
+            self.measured(r.ac_voltage, MAX_VOLTAGE)
+        } else if matches(":CURRent[:DC]?") {
+            self.measured(r.dc_current, 1.0)
+        } else if matches(":CURRent:AC?") {
+            self.measured(r.ac_current, 1.0)
+        } else if matches(":CURRent:AMPLitude:MAXimum?") {
+            let peak = r.dc_current.abs() + r.ac_current * SQRT_2;
+            self.measured(peak, 1.0)
+        } else if matches(":POWer[:DC]?") {
+            self.measured(r.real + r.dc_voltage * r.dc_current, 100.0)
+        } else if matches(":POWer:AC[:REAL]?") {
+            self.measured(r.real, 100.0)
+        } else if matches(":POWer:AC:APParent?") {
+            self.measured(r.apparent, 100.0)
+        } else if matches(":POWer:AC:REACtive?") {
+            self.measured(r.reactive, 100.0)
+        } else if matches(":POWer:AC:PFACtor?") {
+            if r.apparent > 0.0 {
+                r.real / r.apparent
+            } else {
+                1.0
+            }
+        } else if matches(":FREQuency?") {
+            if self.enabled { self.frequency } else { 0.0 }
+        } else {
+            return None;
+        };
+
+        Some(value)
+    }
+}
+impl SimInstrument for SimAcSource {
+    fn idn(&self) -> String {
+        self.idn.clone()
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        if let Some(value) = self.measurement(cmd) {
+            return respond(format!("{value:+.5E}"));
+        }
+
+        if cmd
+            .matches("[SOURce]:VOLTage[:LEVel][:IMMediate][:AMPLitude]")
+            .is_some()
+        {
+            let voltage = cmd.f64_param(0)?;
+            if !(0.0..=MAX_VOLTAGE).contains(&voltage) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.voltage = voltage;
+            Ok(None)
+        } else if cmd
+            .matches("[SOURce]:VOLTage[:LEVel][:IMMediate][:AMPLitude]?")
+            .is_some()
+        {
+            respond(format!("{:+.5E}", self.voltage))
+        } else if cmd.matches("[SOURce]:VOLTage:OFFSet[:IMMediate]").is_some() {
+            let offset = cmd.f64_param(0)?;
+            if offset.abs() > MAX_VOLTAGE * SQRT_2 {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.offset = offset;
+            Ok(None)
+        } else if cmd
+            .matches("[SOURce]:VOLTage:OFFSet[:IMMediate]?")
+            .is_some()
+        {
+            respond(format!("{:+.5E}", self.offset))
+        } else if cmd.matches("[SOURce]:FREQuency[:CW][:IMMediate]").is_some() {
+            let frequency = cmd.f64_param(0)?;
+            if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.frequency = frequency;
+            Ok(None)
+        } else if cmd
+            .matches("[SOURce]:FREQuency[:CW][:IMMediate]?")
+            .is_some()
+        {
+            respond(format!("{:+.5E}", self.frequency))
+        } else if cmd.matches("OUTPut[:STATe]").is_some() {
+            self.enabled = cmd.bool_param(0)?;
+            Ok(None)
+        } else if cmd.matches("OUTPut[:STATe]?").is_some() {
+            respond(u8::from(self.enabled))
+        } else if cmd.matches("INITiate[:IMMediate]:SEQuence#").is_some()
+            || cmd.matches("TRIGger:SEQuence#:SOURce").is_some()
+        {
+            /* Measurements are always up to date */
+            Ok(None)
+        } else {
+            Err(SimError::UNDEFINED_HEADER)
+        }
+    }
+
+    /// Power-on state, with the output enabled
+    fn reset(&mut self) {
+        self.voltage = 120.0;
+        self.offset = 0.0;
+        self.frequency = 60.0;
+        self.enabled = true;
+    }
+
+    fn net(&self, net: &str) -> Option<SimNet> {
+        if net != "OUT" {
+            return None;
+        }
+        let r = self.readings();
+
+        Some(SimNet {
+            voltage: r.dc_voltage,
+            ac_voltage: r.ac_voltage,
+            frequency: if self.enabled { self.frequency } else { 0.0 },
+            current: r.dc_current,
+            ac_current: r.ac_current,
+            load: Some(self.resistance),
+        })
+    }
+
+    fn set_load(&mut self, net: &str, ohms: f64) -> Result<()> {
+        if net != "OUT" {
+            return Err(Error::InvalidArgument(format!("No output `{net}`")));
+        }
+
+        self.resistance = ohms;
+        Ok(())
+    }
+}
@@ -0,0 +1,665 @@
+//! Simulated instruments, answering the same SCPI commands as the real
+//! instruments supported by our drivers
+//!
+//! Simulated instruments are shared by name, so several connections to the
+//! same name reach the same instrument. Instruments drive and probe named
+//! nets, given as `<instrument>:<net>` (e.g. `psu:CH1`), so a simulated
+//! multimeter can measure the output of a simulated power supply.
+//!
+//! [`server::SimServer`] makes them available to other programs over raw TCP
+//! and VXI-11, as does the `testeq-sim` binary.
+
+pub mod ac_source;
+pub mod multimeter;
+pub mod oscilloscope;
+pub mod psu;
+pub mod server;
+pub mod spectrum_analyzer;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, LazyLock, Mutex, Weak},
+};
+
+use log::debug;
+use strum_macros::{AsRefStr, EnumIter, EnumString};
+
+use crate::error::{Error, Result};
+
+use self::{
+    ac_source::SimAcSource, multimeter::SimMultimeter, oscilloscope::SimOscilloscope, psu::SimPsu,
+    spectrum_analyzer::SimSpectrumAnalyzer,
+};
+
+/// Maximum number of errors held in the error queue
+const ERROR_QUEUE_LEN: usize = 16;
+
+/* Event status register bits */
+const ESR_OPC: u8 = 0x01;
+const ESR_QYE: u8 = 0x04;
+const ESR_DDE: u8 = 0x08;
+const ESR_EXE: u8 = 0x10;
+const ESR_CME: u8 = 0x20;
+
+/* Status byte bits */
+const STB_EAV: u8 = 0x04;
+const STB_ESB: u8 = 0x20;
+const STB_MSS: u8 = 0x40;
+
+/// Instruments currently simulated, by name
+static BENCH: LazyLock<Mutex<HashMap<String, Weak<Mutex<SimDevice>>>>> =
+    LazyLock::new(Default::default);
+
+/// Class of simulated instrument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsRefStr, EnumString, EnumIter)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum SimClass {
+    /// AC power source
+    Ac,
+    /// Multimeter
+    Dmm,
+    /// Power supply
+    Psu,
+    /// Oscilloscope
+    Scope,
+    /// Spectrum analyzer
+    Sa,
+}
+impl SimClass {
+    /// Model simulated if none is given
+    pub fn default_model(&self) -> &'static str {
+        match self {
+            Self::Ac => "6811B",
+            Self::Dmm => "SDM4065A",
+            Self::Psu => "DP832",
+            Self::Scope => "SDS3104X HD",
+            Self::Sa => "SSA3075X Plus",
+        }
+    }
+}
+
+/// SCPI error, as reported through the error queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimError {
+    pub code: i32,
+    pub message: &'static str,
+}
+impl SimError {
+    pub const DATA_TYPE: Self = Self::new(-104, "Data type error");
+    pub const PARAMETER_NOT_ALLOWED: Self = Self::new(-108, "Parameter not allowed");
+    pub const MISSING_PARAMETER: Self = Self::new(-109, "Missing parameter");
+    pub const UNDEFINED_HEADER: Self = Self::new(-113, "Undefined header");
+    pub const SETTINGS_CONFLICT: Self = Self::new(-221, "Settings conflict");
+    pub const OUT_OF_RANGE: Self = Self::new(-222, "Data out of range");
+    pub const ILLEGAL_VALUE: Self = Self::new(-224, "Illegal parameter value");
+    pub const QUEUE_OVERFLOW: Self = Self::new(-350, "Queue overflow");
+
+    pub const fn new(code: i32, message: &'static str) -> Self {
+        Self { code, message }
+    }
+
+    /// Event status register bit set when the error occurs
+    fn esr_bit(&self) -> u8 {
+        match self.code {
+            -199..=-100 => ESR_CME,
+            -299..=-200 => ESR_EXE,
+            -399..=-300 => ESR_DDE,
+            -499..=-400 => ESR_QYE,
+            _ => ESR_DDE,
+        }
+    }
+}
+
+/// Result of a simulated command, holding the response to a query
+pub type SimResult = std::result::Result<Option<Vec<u8>>, SimError>;
+
+/// Respond to a query with text
+pub fn respond(text: impl ToString) -> SimResult {
+    Ok(Some(text.to_string().into_bytes()))
+}
+
+/// Wrap binary data in a definite length block, as sent by Siglent
+/// instruments
+pub(crate) fn block(data: &[u8]) -> Vec<u8> {
+    let mut block = format!("#9{:09}", data.len()).into_bytes();
+    block.extend_from_slice(data);
+    block
+}
+
+/// Format a value in NR3 form, e.g. `+1.23456789E+00`
+pub fn format_nr3(value: f64) -> String {
+    let text = format!("{value:+.8E}");
+    let Some((mantissa, exp)) = text.split_once('E') else {
+        return text;
+    };
+    let exp: i32 = exp.parse().unwrap_or(0);
+
+    format!("{mantissa}E{exp:+03}")
+}
+
+/// Single command or query of a program message, e.g. `:SOUR1:VOLT 5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimCommand {
+    /// Header nodes, without colons or query mark
+    nodes: Vec<String>,
+    pub query: bool,
+    pub params: Vec<String>,
+}
+impl SimCommand {
+    pub fn parse(text: &str) -> std::result::Result<Self, SimError> {
+        let text = text.trim();
+        let (header, params) = match text.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some((header, params)) => (header, params.trim()),
+            None => (text, ""),
+        };
+
+        let (header, query) = match header.strip_suffix('?') {
+            Some(header) => (header, true),
+            None => (header, false),
+        };
+        let header = header.strip_prefix(':').unwrap_or(header);
+        if header.is_empty() {
+            return Err(SimError::UNDEFINED_HEADER);
+        }
+
+        let nodes = header.split(':').map(String::from).collect::<Vec<_>>();
+        if nodes.iter().any(|node| node.is_empty()) {
+            return Err(SimError::UNDEFINED_HEADER);
+        }
+
+        let params = if params.is_empty() {
+            vec![]
+        } else {
+            params
+                .split(',')
+                .map(|param| param.trim().trim_matches('"').to_string())
+                .collect()
+        };
+
+        Ok(Self {
+            nodes,
+            query,
+            params,
+        })
+    }
+
+    /// Whether this is a common command, e.g. `*IDN?`
+    pub fn is_common(&self) -> bool {
+        self.nodes.len() == 1 && self.nodes[0].starts_with('*')
+    }
+
+    /// Match against a header in the usual SCPI notation, with mnemonics in
+    /// long form and their short form in upper case, optional nodes in
+    /// brackets and `#` for a numeric suffix, e.g. `SOURce#:VOLTage[:LEVel]?`.
+    /// Returns the numeric suffixes matched, with 1 for any omitted.
+    pub fn matches(&self, pattern: &str) -> Option<Vec<u32>> {
+        let (pattern, query) = match pattern.strip_suffix('?') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        if query != self.query {
+            return None;
+        }
+
+        let pattern = pattern.replace("[:", ":[");
+        let pattern: Vec<_> = pattern
+            .trim_start_matches(':')
+            .split(':')
+            .map(|node| match node.strip_prefix('[') {
+                Some(node) => (node.trim_end_matches(']'), true),
+                None => (node, false),
+            })
+            .collect();
+
+        let mut suffixes = vec![];
+        match_nodes(&self.nodes, &pattern, &mut suffixes).then_some(suffixes)
+    }
+
+    /// Check the number of parameters given, allowing no more than max
+    pub fn check_params(&self, min: usize, max: usize) -> std::result::Result<(), SimError> {
+        if self.params.len() < min {
+            Err(SimError::MISSING_PARAMETER)
+        } else if self.params.len() > max {
+            Err(SimError::PARAMETER_NOT_ALLOWED)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn param(&self, idx: usize) -> std::result::Result<&str, SimError> {
+        self.params
+            .get(idx)
+            .map(String::as_str)
+            .ok_or(SimError::MISSING_PARAMETER)
+    }
+
+    /// Numeric parameter, allowing an engineering suffix (e.g. `10k`)
+    pub fn f64_param(&self, idx: usize) -> std::result::Result<f64, SimError> {
+        parse_number(self.param(idx)?).ok_or(SimError::DATA_TYPE)
+    }
+
+    /// Boolean parameter, `ON`/`OFF` or `1`/`0`
+    pub fn bool_param(&self, idx: usize) -> std::result::Result<bool, SimError> {
+        let param = self.param(idx)?;
+        if param.eq_ignore_ascii_case("ON") || param == "1" {
+            Ok(true)
+        } else if param.eq_ignore_ascii_case("OFF") || param == "0" {
+            Ok(false)
+        } else {
+            Err(SimError::ILLEGAL_VALUE)
+        }
+    }
+
+    /// Channel parameter in the form `CH<n>`, or its number alone
+    pub fn channel_param(&self, idx: usize) -> std::result::Result<u32, SimError> {
+        let param = self.param(idx)?;
+        let num = strip_prefix_ignore_case(param, "CH").unwrap_or(param);
+        num.parse().map_err(|_| SimError::ILLEGAL_VALUE)
+    }
+}
+
+fn match_nodes(nodes: &[String], pattern: &[(&str, bool)], suffixes: &mut Vec<u32>) -> bool {
+    let Some((&(mnemonic, optional), pattern_rest)) = pattern.split_first() else {
+        return nodes.is_empty();
+    };
+
+    let len = suffixes.len();
+    if let Some((node, nodes_rest)) = nodes.split_first()
+        && let Some(suffix) = match_mnemonic(node, mnemonic)
+    {
+        suffixes.extend(suffix);
+        if match_nodes(nodes_rest, pattern_rest, suffixes) {
+            return true;
+        }
+        suffixes.truncate(len);
+    }
+
+    if optional {
+        if mnemonic.ends_with('#') {
+            suffixes.push(1);
+        }
+        if match_nodes(nodes, pattern_rest, suffixes) {
+            return true;
+        }
+        suffixes.truncate(len);
+    }
+
+    false
+}
+
+/// Match a header node against a mnemonic, returning the numeric suffix if
+/// the mnemonic takes one
+fn match_mnemonic(node: &str, mnemonic: &str) -> Option<Option<u32>> {
+    let (mnemonic, has_suffix) = match mnemonic.strip_suffix('#') {
+        Some(mnemonic) => (mnemonic, true),
+        None => (mnemonic, false),
+    };
+
+    let name = node.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &node[name.len()..];
+    if !digits.is_empty() && !has_suffix {
+        return None;
+    }
+
+    let short: String = mnemonic.chars().filter(|c| !c.is_lowercase()).collect();
+    if !name.eq_ignore_ascii_case(&short) && !name.eq_ignore_ascii_case(mnemonic) {
+        return None;
+    }
+
+    if !has_suffix {
+        Some(None)
+    } else if digits.is_empty() {
+        Some(Some(1))
+    } else {
+        digits.parse().ok().map(Some)
+    }
+}
+
+/// Parse a number, allowing an engineering suffix and unit (e.g. `1.5GHz`)
+pub fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let end = text
+        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(text.len());
+    /* An exponent marker may instead start a unit, e.g. 5E */
+    let (num, suffix) = text.split_at(end);
+    let value: f64 = num.parse().ok()?;
+
+    let mult = match suffix.chars().next() {
+        None => 1.0,
+        Some('n') => 1e-9,
+        Some('u') => 1e-6,
+        Some('m') => 1e-3,
+        Some('k' | 'K') => 1e3,
+        Some('M') => 1e6,
+        Some('G') => 1e9,
+        Some(_) => 1.0,
+    };
+
+    Some(value * mult)
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    match text.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&text[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Electrical state of a net driven by a simulated instrument
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimNet {
+    /// DC voltage in V
+    pub voltage: f64,
+    /// RMS voltage of the AC component in V
+    pub ac_voltage: f64,
+    /// Frequency of the AC component in Hz
+    pub frequency: f64,
+    /// DC current through the load in A
+    pub current: f64,
+    /// RMS current of the AC component in A
+    pub ac_current: f64,
+    /// Resistance of the load on the net in Ω, if any
+    pub load: Option<f64>,
+}
+
+/// Simulated instrument, handling the commands specific to its class. Common
+/// commands and the error queue are handled by [`SimDevice`].
+/* Don't warn about unused arguments for default implementations */
+#[allow(unused_variables)]
+pub trait SimInstrument: Send {
+    /// Response to *IDN?
+    fn idn(&self) -> String;
+
+    /// Handle a command or query, returning the response to a query
+    fn command(&mut self, cmd: &SimCommand) -> SimResult;
+
+    /// Return to the power-on state, for *RST
+    fn reset(&mut self);
+
+    /// Handle a trigger, from *TRG
+    fn trigger(&mut self) {}
+
+    /// State of a net driven by the instrument
+    fn net(&self, net: &str) -> Option<SimNet> {
+        None
+    }
+
+    /// Set the resistance of the load on a net driven by the instrument
+    fn set_load(&mut self, net: &str, ohms: f64) -> Result<()> {
+        Err(Error::NotSupported(format!(
+            "Instrument drives no net `{net}`"
+        )))
+    }
+
+    /// Connect the instrument's inputs to a net of another instrument, given
+    /// as `<instrument>:<net>`
+    fn set_probe(&mut self, net: &str) -> Result<()> {
+        Err(Error::NotSupported("Instrument has no probe inputs".into()))
+    }
+}
+
+/// Simulated instrument with the state common to all IEEE 488.2 devices,
+/// processing whole program messages
+pub struct SimDevice {
+    name: String,
+    class: SimClass,
+    model: String,
+    instrument: Box<dyn SimInstrument>,
+    errors: VecDeque<SimError>,
+    /// Event status register
+    esr: u8,
+    /// Event status enable register
+    ese: u8,
+    /// Service request enable register
+    sre: u8,
+}
+impl SimDevice {
+    pub fn new(
+        name: impl Into<String>,
+        class: SimClass,
+        model: impl Into<String>,
+        instrument: Box<dyn SimInstrument>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            class,
+            model: model.into(),
+            instrument,
+            errors: VecDeque::new(),
+            esr: 0,
+            ese: 0,
+            sre: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn class(&self) -> SimClass {
+        self.class
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn instrument(&mut self) -> &mut dyn SimInstrument {
+        self.instrument.as_mut()
+    }
+
+    /// Process a program message, without its terminator, returning the
+    /// responses to any queries it contains
+    pub fn write(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        let message = String::from_utf8_lossy(message);
+        debug!("{}: {}", self.name, message.trim_end());
+
+        let mut responses: Vec<Vec<u8>> = vec![];
+        for unit in message
+            .split(['\n', ';'])
+            .filter(|unit| !unit.trim().is_empty())
+        {
+            let res = SimCommand::parse(unit).and_then(|cmd| self.command(&cmd));
+            match res {
+                Ok(Some(resp)) => responses.push(resp),
+                Ok(None) => {}
+                Err(e) => self.push_error(e),
+            }
+        }
+
+        if responses.is_empty() {
+            None
+        } else {
+            Some(responses.join(&b';'))
+        }
+    }
+
+    /// Status byte, as read by *STB? or a serial poll
+    pub fn status_byte(&self) -> u8 {
+        let mut stb = 0;
+        if !self.errors.is_empty() {
+            stb |= STB_EAV;
+        }
+        if self.esr & self.ese != 0 {
+            stb |= STB_ESB;
+        }
+        if stb & self.sre != 0 {
+            stb |= STB_MSS;
+        }
+        stb
+    }
+
+    /// Clear status, as by *CLS or a device clear
+    pub fn clear_status(&mut self) {
+        self.errors.clear();
+        self.esr = 0;
+    }
+
+    fn push_error(&mut self, error: SimError) {
+        debug!("{}: error {} \"{}\"", self.name, error.code, error.message);
+
+        self.esr |= error.esr_bit();
+        if self.errors.len() >= ERROR_QUEUE_LEN {
+            *self.errors.back_mut().unwrap() = SimError::QUEUE_OVERFLOW;
+        } else {
+            self.errors.push_back(error);
+        }
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        if cmd.matches("SYSTem:ERRor[:NEXT]?").is_some() {
+            return match self.errors.pop_front() {
+                Some(e) => respond(format!("{},\"{}\"", e.code, e.message)),
+                None => respond("0,\"No error\""),
+            };
+        }
+        if !cmd.is_common() {
+            return self.instrument.command(cmd);
+        }
+
+        let header = cmd.nodes[0].to_ascii_uppercase();
+        match (header.as_str(), cmd.query) {
+            ("*IDN", true) => respond(self.instrument.idn()),
+            ("*RST", false) => {
+                self.instrument.reset();
+                Ok(None)
+            }
+            ("*CLS", false) => {
+                self.clear_status();
+                Ok(None)
+            }
+            ("*ESE", false) => {
+                self.ese = cmd.f64_param(0)? as u8;
+                Ok(None)
+            }
+            ("*ESE", true) => respond(self.ese),
+            ("*ESR", true) => {
+                let esr = self.esr;
+                self.esr = 0;
+                respond(esr)
+            }
+            ("*SRE", false) => {
+                self.sre = cmd.f64_param(0)? as u8;
+                Ok(None)
+            }
+            ("*SRE", true) => respond(self.sre),
+            ("*STB", true) => respond(self.status_byte()),
+            /* Operations complete immediately */
+            ("*OPC", false) => {
+                self.esr |= ESR_OPC;
+                Ok(None)
+            }
+            ("*OPC", true) => respond(1),
+            ("*WAI", false) => Ok(None),
+            ("*TRG", false) => {
+                self.instrument.trigger();
+                Ok(None)
+            }
+            ("*TST", true) => respond(0),
+            _ => Err(SimError::UNDEFINED_HEADER),
+        }
+    }
+}
+
+/// Open a simulated instrument, or the existing one of the same name. The
+/// name defaults to that of the class, and the model to the class default.
+pub fn open(
+    class: SimClass,
+    model: Option<&str>,
+    name: Option<&str>,
+) -> Result<Arc<Mutex<SimDevice>>> {
+    let name = name.unwrap_or(class.as_ref());
+
+    let mut bench = BENCH.lock().unwrap();
+    if let Some(device) = bench.get(name).and_then(Weak::upgrade) {
+        /* Instruments look up others on the bench while locked, so release
+         * the bench before locking one */
+        drop(bench);
+        {
+            let device = device.lock().unwrap();
+            if device.class != class || model.is_some_and(|model| model != device.model) {
+                return Err(Error::InvalidArgument(format!(
+                    "Simulated instrument `{name}` is a {} {}",
+                    device.class.as_ref(),
+                    device.model
+                )));
+            }
+        }
+        return Ok(device);
+    }
+
+    let model = model.unwrap_or(class.default_model());
+    let instrument: Box<dyn SimInstrument> = match class {
+        SimClass::Ac => Box::new(SimAcSource::new(model)?),
+        SimClass::Dmm => Box::new(SimMultimeter::new(model, name)?),
+        SimClass::Psu => Box::new(SimPsu::new(model)?),
+        SimClass::Scope => Box::new(SimOscilloscope::new(model)?),
+        SimClass::Sa => Box::new(SimSpectrumAnalyzer::new(model)?),
+    };
+
+    let device = Arc::new(Mutex::new(SimDevice::new(name, class, model, instrument)));
+    bench.insert(name.to_string(), Arc::downgrade(&device));
+
+    Ok(device)
+}
+
+/// Get a simulated instrument by name, if it exists
+pub fn instrument(name: &str) -> Option<Arc<Mutex<SimDevice>>> {
+    BENCH.lock().unwrap().get(name).and_then(Weak::upgrade)
+}
+
+/// Get the state of a net, given as `<instrument>:<net>`
+pub fn net(net: &str) -> Option<SimNet> {
+    let (name, net) = net.split_once(':')?;
+    let device = instrument(name)?;
+    let mut device = device.lock().unwrap();
+
+    device.instrument().net(net)
+}
+
+/// Set the resistance of the load on a net, given as `<instrument>:<net>`
+pub fn set_load(net: &str, ohms: f64) -> Result<()> {
+    let Some((name, net)) = net.split_once(':') else {
+        return Err(Error::InvalidArgument(format!(
+            "Net `{net}` is not of the form <instrument>:<net>"
+        )));
+    };
+    if ohms.is_nan() || ohms <= 0.0 {
+        return Err(Error::InvalidArgument(format!("Invalid load of {ohms} Ω")));
+    }
+    let Some(device) = instrument(name) else {
+        return Err(Error::InvalidArgument(format!(
+            "No simulated instrument `{name}`"
+        )));
+    };
+
+    device.lock().unwrap().instrument().set_load(net, ohms)
+}
+
+/// Source of repeatable pseudo-random noise
+pub(crate) struct SimNoise {
+    state: u64,
+}
+impl SimNoise {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { state: seed | 1 }
+    }
+
+    /// Uniformly distributed value in (0, 1]
+    pub(crate) fn uniform(&mut self) -> f64 {
+        /* xorshift64* */
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let value = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+
+        ((value >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Normally distributed value with a standard deviation of 1
+    pub(crate) fn gaussian(&mut self) -> f64 {
+        let (u1, u2) = (self.uniform(), self.uniform());
+        (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+}
@@ -0,0 +1,206 @@
+//! Simulated Siglent multimeter, measuring a net driven by another simulated
+//! instrument
+
+use crate::error::{Error, Result};
+
+use super::{SimCommand, SimError, SimInstrument, SimNoise, SimResult, format_nr3, respond};
+
+/// Reading returned on overload
+const OVERLOAD: f64 = 9.9e37;
+/// Room temperature in °C, for temperature readings
+const AMBIENT_TEMP: f64 = 23.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SimFunction {
+    DcVoltage,
+    AcVoltage,
+    DcCurrent,
+    AcCurrent,
+    Resistance,
+    Resistance4W,
+    Continuity,
+    Diode,
+    Frequency,
+    Period,
+    Temperature,
+    Capacitance,
+}
+impl SimFunction {
+    /// Functions with the header selecting them, after CONF or MEAS
+    const HEADERS: [(Self, &'static str); 12] = [
+        (Self::DcVoltage, "VOLTage[:DC]"),
+        (Self::AcVoltage, "VOLTage:AC"),
+        (Self::DcCurrent, "CURRent[:DC]"),
+        (Self::AcCurrent, "CURRent:AC"),
+        (Self::Resistance, "RESistance"),
+        (Self::Resistance4W, "FRESistance"),
+        (Self::Continuity, "CONTinuity"),
+        (Self::Diode, "DIODe"),
+        (Self::Frequency, "FREQuency"),
+        (Self::Period, "PERiod"),
+        (Self::Temperature, "TEMPerature"),
+        (Self::Capacitance, "CAPacitance"),
+    ];
+
+    /// Function and range as reported by CONF?
+    fn conf(&self) -> (&'static str, Option<f64>) {
+        match self {
+            Self::DcVoltage => ("VOLT", Some(20.0)),
+            Self::AcVoltage => ("VOLT:AC", Some(20.0)),
+            Self::DcCurrent => ("CURR", Some(2.0)),
+            Self::AcCurrent => ("CURR:AC", Some(2.0)),
+            Self::Resistance => ("RES", Some(10e3)),
+            Self::Resistance4W => ("FRES", Some(10e3)),
+            Self::Continuity => ("CONT", None),
+            Self::Diode => ("DIOD", None),
+            Self::Frequency => ("FREQ", None),
+            Self::Period => ("PER", None),
+            Self::Temperature => ("TEMP", None),
+            Self::Capacitance => ("CAP", Some(1e-6)),
+        }
+    }
+}
+
+pub struct SimMultimeter {
+    idn: String,
+    /// Name of the multimeter itself, which it cannot probe
+    name: String,
+    function: SimFunction,
+    trigger_source: &'static str,
+    /// Net the inputs are connected to, if any
+    probe: Option<String>,
+    noise: SimNoise,
+}
+impl SimMultimeter {
+    pub fn new(model: &str, name: &str) -> Result<Self> {
+        if !model.starts_with("SDM4") {
+            return Err(Error::NotSupported(format!(
+                "No simulated multimeter {model}"
+            )));
+        }
+
+        Ok(Self {
+            idn: format!("Siglent Technologies,{model},SIM00001,1.0"),
+            name: name.to_string(),
+            function: SimFunction::DcVoltage,
+            trigger_source: "IMM",
+            probe: None,
+            noise: SimNoise::new(0x444d_4d00),
+        })
+    }
+
+    fn reading(&mut self) -> f64 {
+        let net = self.probe.as_deref().and_then(super::net);
+        let voltage = net.map(|net| net.voltage).unwrap_or(0.0);
+        let ac_voltage = net.map(|net| net.ac_voltage).unwrap_or(0.0);
+        let frequency = net.map(|net| net.frequency).unwrap_or(0.0);
+        let load = net.and_then(|net| net.load);
+
+        let noise = self.noise.gaussian();
+        match self.function {
+            SimFunction::DcVoltage => voltage + noise * (5e-6 + voltage.abs() * 5e-6),
+            SimFunction::AcVoltage => ac_voltage + noise.abs() * (2e-5 + ac_voltage * 5e-5),
+            /* The meter is taken to be in series with the load */
+            SimFunction::DcCurrent => {
+                let current = net.map(|net| net.current).unwrap_or(0.0);
+                current + noise * (1e-7 + current.abs() * 5e-5)
+            }
+            SimFunction::AcCurrent => {
+                let current = net.map(|net| net.ac_current).unwrap_or(0.0);
+                current + noise.abs() * (1e-6 + current * 1e-4)
+            }
+            SimFunction::Resistance | SimFunction::Resistance4W => match load {
+                Some(load) => load * (1.0 + noise * 1e-5),
+                None => OVERLOAD,
+            },
+            SimFunction::Continuity => match load {
+                Some(load) if load < 1e3 => load * (1.0 + noise * 1e-5),
+                _ => OVERLOAD,
+            },
+            SimFunction::Diode | SimFunction::Capacitance => OVERLOAD,
+            SimFunction::Frequency => {
+                if frequency > 0.0 && ac_voltage > 0.0 {
+                    frequency * (1.0 + noise * 1e-6)
+                } else {
+                    0.0
+                }
+            }
+            SimFunction::Period => {
+                if frequency > 0.0 && ac_voltage > 0.0 {
+                    (1.0 + noise * 1e-6) / frequency
+                } else {
+                    OVERLOAD
+                }
+            }
+            SimFunction::Temperature => AMBIENT_TEMP + noise * 0.05,
+        }
+    }
+}
+impl SimInstrument for SimMultimeter {
+    fn idn(&self) -> String {
+        self.idn.clone()
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        for (function, header) in SimFunction::HEADERS {
+            if cmd.matches(&format!("CONFigure:{header}")).is_some() {
+                self.function = function;
+                return Ok(None);
+            }
+            if cmd.matches(&format!("MEASure:{header}?")).is_some() {
+                self.function = function;
+                return respond(format_nr3(self.reading()));
+            }
+        }
+
+        if cmd.matches("CONFigure?").is_some() {
+            let (function, range) = self.function.conf();
+            match range {
+                Some(range) => respond(format!(
+                    "\"{function} {},{}\"",
+                    format_nr3(range),
+                    format_nr3(range * 1e-6)
+                )),
+                None => respond(format!("\"{function}\"")),
+            }
+        } else if cmd.matches("FETCh?").is_some() || cmd.matches("READ?").is_some() {
+            respond(format_nr3(self.reading()))
+        } else if cmd.matches("INITiate[:IMMediate]").is_some() {
+            Ok(None)
+        } else if cmd.matches("TRIGger:SOURce").is_some() {
+            let source = cmd.param(0)?.to_ascii_uppercase();
+            self.trigger_source = match source.as_str() {
+                "IMM" | "IMMEDIATE" => "IMM",
+                "BUS" => "BUS",
+                "EXT" | "EXTERNAL" => "EXT",
+                _ => return Err(SimError::ILLEGAL_VALUE),
+            };
+            Ok(None)
+        } else if cmd.matches("TRIGger:SOURce?").is_some() {
+            respond(self.trigger_source)
+        } else {
+            Err(SimError::UNDEFINED_HEADER)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.function = SimFunction::DcVoltage;
+        self.trigger_source = "IMM";
+    }
+
+    fn set_probe(&mut self, net: &str) -> Result<()> {
+        let Some((name, _)) = net.split_once(':') else {
+            return Err(Error::InvalidArgument(format!(
+                "Net `{net}` is not of the form <instrument>:<net>"
+            )));
+        };
+        if name == self.name {
+            return Err(Error::InvalidArgument(
+                "Multimeter cannot probe itself".into(),
+            ));
+        }
+
+        self.probe = Some(net.to_string());
+        Ok(())
+    }
+}
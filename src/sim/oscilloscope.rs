@@ -0,0 +1,418 @@
+//! Simulated Siglent SDS3000X HD oscilloscope, with a signal generator on
+//! each channel
+
+use std::{f64::consts::TAU, time::Instant};
+
+use crate::error::{Error, Result};
+
+use super::{SimCommand, SimError, SimInstrument, SimNoise, SimResult, block, respond};
+
+const CHANNELS: usize = 4;
+/// Horizontal divisions on screen
+const HORIZ_DIVS: f64 = 10.0;
+/// Codes per vertical division, for byte wide samples
+const CODE_PER_DIV: f64 = 30.0;
+/// Length of the waveform descriptor block
+const WAVEDESC_LEN: usize = 346;
+
+/// Memory depths selectable with one channel enabled, and with more
+const MEMORY_DEPTHS: [&[u64]; 2] = [
+    &[
+        2_000,
+        10_000,
+        20_000,
+        100_000,
+        200_000,
+        1_000_000,
+        2_000_000,
+        10_000_000,
+        20_000_000,
+        100_000_000,
+        200_000_000,
+        400_000_000,
+    ],
+    &[
+        1_000,
+        5_000,
+        10_000,
+        50_000,
+        100_000,
+        500_000,
+        1_000_000,
+        5_000_000,
+        10_000_000,
+        50_000_000,
+        100_000_000,
+    ],
+];
+
+#[derive(Debug, Clone, Copy)]
+enum SimWaveShape {
+    Sine,
+    Square,
+}
+
+/// Signal fed into a channel
+#[derive(Debug, Clone, Copy)]
+struct SimSignal {
+    shape: SimWaveShape,
+    frequency: f64,
+    /// Peak amplitude about the offset
+    amplitude: f64,
+    offset: f64,
+}
+impl SimSignal {
+    /// Signal value at time t in seconds
+    fn value(&self, t: f64) -> f64 {
+        let phase = (t * self.frequency).rem_euclid(1.0);
+        let wave = match self.shape {
+            SimWaveShape::Sine => (phase * TAU).sin(),
+            SimWaveShape::Square if phase < 0.5 => 1.0,
+            SimWaveShape::Square => -1.0,
+        };
+
+        self.offset + self.amplitude * wave
+    }
+}
+
+struct SimScopeChannel {
+    signal: SimSignal,
+    enabled: bool,
+    /// Vertical scale in V/div
+    scale: f64,
+    /// Vertical offset in V
+    offset: f64,
+}
+impl SimScopeChannel {
+    /// Power-on state of each channel: a 1 kHz sine, a 1 kHz 3.3 V logic
+    /// signal, a 10 kHz sine and a 100 kHz 1.8 V logic signal
+    fn defaults() -> [Self; CHANNELS] {
+        let signals = [
+            (SimWaveShape::Sine, 1e3, 1.0, 0.0, 0.5),
+            (SimWaveShape::Square, 1e3, 1.65, 1.65, 1.0),
+            (SimWaveShape::Sine, 10e3, 0.5, 0.0, 0.2),
+            (SimWaveShape::Square, 100e3, 0.9, 0.9, 0.5),
+        ];
+
+        signals.map(|(shape, frequency, amplitude, offset, scale)| Self {
+            signal: SimSignal {
+                shape,
+                frequency,
+                amplitude,
+                offset,
+            },
+            enabled: false,
+            scale,
+            offset: 0.0,
+        })
+    }
+}
+
+pub struct SimOscilloscope {
+    idn: String,
+    channels: [SimScopeChannel; CHANNELS],
+    memory_depth: u64,
+    /// Horizontal scale in s/div
+    timebase: f64,
+    trigger_mode: &'static str,
+    /// Time of the last capture, relative to start
+    capture_time: f64,
+    start: Instant,
+    /* Waveform readout settings */
+    wav_source: usize,
+    wav_start: u64,
+    wav_points: u64,
+    wav_interval: u64,
+    wav_word: bool,
+}
+impl SimOscilloscope {
+    pub fn new(model: &str) -> Result<Self> {
+        if !model.starts_with("SDS3") {
+            return Err(Error::NotSupported(format!(
+                "No simulated oscilloscope {model}"
+            )));
+        }
+
+        let mut scope = Self {
+            idn: format!("Siglent Technologies,{model},SIM00001,1.0"),
+            channels: SimScopeChannel::defaults(),
+            memory_depth: 0,
+            timebase: 0.0,
+            trigger_mode: "",
+            capture_time: 0.0,
+            start: Instant::now(),
+            wav_source: 0,
+            wav_start: 0,
+            wav_points: 0,
+            wav_interval: 1,
+            wav_word: false,
+        };
+        scope.reset();
+
+        Ok(scope)
+    }
+
+    fn enabled_channels(&self) -> usize {
+        self.channels.iter().filter(|chan| chan.enabled).count()
+    }
+
+    fn memory_depths(&self) -> &'static [u64] {
+        MEMORY_DEPTHS[if self.enabled_channels() <= 1 { 0 } else { 1 }]
+    }
+
+    fn channel(&mut self, num: u32) -> std::result::Result<&mut SimScopeChannel, SimError> {
+        num.checked_sub(1)
+            .and_then(|idx| self.channels.get_mut(idx as usize))
+            .ok_or(SimError::UNDEFINED_HEADER)
+    }
+
+    /// Take a new capture, unless stopped
+    fn capture(&mut self, forced: bool) {
+        if self.trigger_mode == "STOP" && !forced {
+            return;
+        }
+        self.capture_time = self.start.elapsed().as_secs_f64();
+        if self.trigger_mode == "SINGle" {
+            self.trigger_mode = "STOP";
+        }
+    }
+
+    fn code_per_div(&self) -> f64 {
+        if self.wav_word {
+            CODE_PER_DIV * 256.0
+        } else {
+            CODE_PER_DIV
+        }
+    }
+
+    /// Sample interval in seconds
+    fn sample_interval(&self) -> f64 {
+        self.timebase * HORIZ_DIVS / self.memory_depth as f64
+    }
+
+    fn wavedesc(&self) -> Vec<u8> {
+        let chan = &self.channels[self.wav_source];
+        let mut desc = vec![0u8; WAVEDESC_LEN];
+        let mut put = |offset: usize, data: &[u8]| {
+            desc[offset..offset + data.len()].copy_from_slice(data);
+        };
+
+        put(0, b"WAVEDESC");
+        put(16, b"WAVEACE");
+        put(32, &u16::from(self.wav_word).to_le_bytes());
+        /* Little-endian */
+        put(34, &1u16.to_le_bytes());
+        put(36, &(WAVEDESC_LEN as u32).to_le_bytes());
+        let sample_bytes = if self.wav_word { 2 } else { 1 };
+        put(
+            60,
+            &((self.memory_depth * sample_bytes) as u32).to_le_bytes(),
+        );
+        put(76, b"Siglent SDS");
+        put(116, &(self.memory_depth as u32).to_le_bytes());
+        put(132, &(self.wav_start as u32).to_le_bytes());
+        put(136, &(self.wav_interval as u32).to_le_bytes());
+        put(144, &1u32.to_le_bytes());
+        put(148, &1u32.to_le_bytes());
+        put(156, &(chan.scale as f32).to_le_bytes());
+        put(160, &(chan.offset as f32).to_le_bytes());
+        put(164, &(self.code_per_div() as f32).to_le_bytes());
+        put(172, &8u16.to_le_bytes());
+        put(176, &(self.sample_interval() as f32).to_le_bytes());
+        put(180, &(-self.timebase * HORIZ_DIVS / 2.0).to_le_bytes());
+        /* 1 MΩ DC coupling */
+        put(326, &0u16.to_le_bytes());
+        put(328, &1f32.to_le_bytes());
+        put(344, &(self.wav_source as u16).to_le_bytes());
+
+        desc
+    }
+
+    /// Samples of the source channel for the readout settings, as codes
+    fn samples(&self) -> Vec<u8> {
+        let chan = &self.channels[self.wav_source];
+        let interval = self.sample_interval();
+        let horiz_offset = -self.timebase * HORIZ_DIVS / 2.0;
+        let code_per_div = self.code_per_div();
+        let (min, max) = if self.wav_word {
+            (i16::MIN as f64, i16::MAX as f64)
+        } else {
+            (i8::MIN as f64, i8::MAX as f64)
+        };
+
+        /* Triggered on the rising edge of the first channel, other channels
+         * run freely */
+        let t0 = if self.wav_source == 0 {
+            0.0
+        } else {
+            self.capture_time
+        };
+        let mut noise = SimNoise::new(self.capture_time.to_bits() ^ self.wav_source as u64);
+
+        let available =
+            (self.memory_depth.saturating_sub(self.wav_start)).div_ceil(self.wav_interval);
+        let count = match self.wav_points {
+            0 => available,
+            points => available.min(points),
+        };
+
+        let mut data = Vec::with_capacity(count as usize * 2);
+        for idx in 0..count {
+            let point = self.wav_start + idx * self.wav_interval;
+            let t = horiz_offset + point as f64 * interval;
+            let volts = chan.signal.value(t0 + t) + noise.gaussian() * chan.scale * 0.02;
+            let code = ((volts + chan.offset) / chan.scale * code_per_div)
+                .round()
+                .clamp(min, max);
+
+            if self.wav_word {
+                data.extend_from_slice(&(code as i16).to_le_bytes());
+            } else {
+                data.push(code as i8 as u8);
+            }
+        }
+
+        data
+    }
+}
+impl SimInstrument for SimOscilloscope {
+    fn idn(&self) -> String {
+        self.idn.clone()
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        if let Some(s) = cmd.matches("CHANnel#:SWITch") {
+            let enabled = cmd.bool_param(0)?;
+            self.channel(s[0])?.enabled = enabled;
+            /* Keep the memory depth valid for the number of channels */
+            if !self.memory_depths().contains(&self.memory_depth) {
+                self.memory_depth = 10_000;
+            }
+            Ok(None)
+        } else if let Some(s) = cmd.matches("CHANnel#:SWITch?") {
+            respond(if self.channel(s[0])?.enabled {
+                "ON"
+            } else {
+                "OFF"
+            })
+        } else if let Some(s) = cmd.matches("CHANnel#:SCALe") {
+            let scale = cmd.f64_param(0)?;
+            if !(1e-3..=10.0).contains(&scale) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.channel(s[0])?.scale = scale;
+            Ok(None)
+        } else if let Some(s) = cmd.matches("CHANnel#:SCALe?") {
+            respond(format!("{:.2E}", self.channel(s[0])?.scale))
+        } else if let Some(s) = cmd.matches("CHANnel#:OFFSet") {
+            let offset = cmd.f64_param(0)?;
+            self.channel(s[0])?.offset = offset;
+            Ok(None)
+        } else if let Some(s) = cmd.matches("CHANnel#:OFFSet?") {
+            respond(format!("{:.2E}", self.channel(s[0])?.offset))
+        } else if cmd.matches("TIMebase[:SCALe]").is_some() {
+            let timebase = cmd.f64_param(0)?;
+            if !(200e-12..=1e3).contains(&timebase) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.timebase = timebase;
+            Ok(None)
+        } else if cmd.matches("TIMebase[:SCALe]?").is_some() {
+            respond(format!("{:.2E}", self.timebase))
+        } else if cmd.matches("ACQuire:MDEPth").is_some() {
+            let depth = cmd.f64_param(0)? as u64;
+            if !self.memory_depths().contains(&depth) {
+                return Err(SimError::ILLEGAL_VALUE);
+            }
+            self.memory_depth = depth;
+            Ok(None)
+        } else if cmd.matches("ACQuire:MDEPth?").is_some() {
+            respond(format_depth(self.memory_depth))
+        } else if cmd.matches("TRIGger:MODE").is_some() {
+            let mode = cmd.param(0)?.to_ascii_uppercase();
+            match mode.as_str() {
+                "AUTO" => self.trigger_mode = "AUTO",
+                "NORM" | "NORMAL" => self.trigger_mode = "NORMal",
+                "SING" | "SINGLE" => self.trigger_mode = "SINGle",
+                "STOP" => self.trigger_mode = "STOP",
+                "FTRIG" => self.capture(true),
+                _ => return Err(SimError::ILLEGAL_VALUE),
+            }
+            Ok(None)
+        } else if cmd.matches("TRIGger:MODE?").is_some() {
+            respond(self.trigger_mode)
+        } else if cmd.matches("WAVeform:SOURce").is_some() {
+            let source = cmd.param(0)?;
+            let num: usize = source
+                .strip_prefix(['C', 'c'])
+                .and_then(|num| num.parse().ok())
+                .ok_or(SimError::ILLEGAL_VALUE)?;
+            if !(1..=CHANNELS).contains(&num) {
+                return Err(SimError::ILLEGAL_VALUE);
+            }
+            self.wav_source = num - 1;
+            Ok(None)
+        } else if cmd.matches("WAVeform:SOURce?").is_some() {
+            respond(format!("C{}", self.wav_source + 1))
+        } else if cmd.matches("WAVeform:STARt").is_some() {
+            self.wav_start = cmd.f64_param(0)? as u64;
+            Ok(None)
+        } else if cmd.matches("WAVeform:POINt").is_some() {
+            self.wav_points = cmd.f64_param(0)? as u64;
+            Ok(None)
+        } else if cmd.matches("WAVeform:INTerval").is_some() {
+            let interval = cmd.f64_param(0)? as u64;
+            if interval == 0 {
+                return Err(SimError::ILLEGAL_VALUE);
+            }
+            self.wav_interval = interval;
+            Ok(None)
+        } else if cmd.matches("WAVeform:WIDTh").is_some() {
+            let width = cmd.param(0)?.to_ascii_uppercase();
+            self.wav_word = match width.as_str() {
+                "BYTE" => false,
+                "WORD" => true,
+                _ => return Err(SimError::ILLEGAL_VALUE),
+            };
+            Ok(None)
+        } else if cmd.matches("WAVeform:PREamble?").is_some() {
+            self.capture(false);
+            Ok(Some(block(&self.wavedesc())))
+        } else if cmd.matches("WAVeform:DATA?").is_some() {
+            if !self.channels[self.wav_source].enabled {
+                return Err(SimError::SETTINGS_CONFLICT);
+            }
+            Ok(Some(block(&self.samples())))
+        } else {
+            Err(SimError::UNDEFINED_HEADER)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.channels = SimScopeChannel::defaults();
+        self.channels[0].enabled = true;
+        self.channels[1].enabled = true;
+        self.memory_depth = 10_000;
+        self.timebase = 1e-3;
+        self.trigger_mode = "AUTO";
+        self.wav_source = 0;
+        self.wav_start = 0;
+        self.wav_points = 0;
+        self.wav_interval = 1;
+        self.wav_word = false;
+    }
+
+    fn trigger(&mut self) {
+        self.capture(true);
+    }
+}
+
+fn format_depth(depth: u64) -> String {
+    if depth >= 1_000_000 && depth.is_multiple_of(1_000_000) {
+        format!("{}M", depth / 1_000_000)
+    } else if depth >= 1_000 && depth.is_multiple_of(1_000) {
+        format!("{}k", depth / 1_000)
+    } else {
+        depth.to_string()
+    }
+}
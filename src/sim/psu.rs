@@ -0,0 +1,202 @@
+//! Simulated Rigol and Siglent power supplies, with each output driving a
+//! resistive load
+
+use crate::{
+    equipment::{drivers::psu_scpi::ScpiPsuModel, psu::PowerSupplyChannelDetails},
+    error::{Error, Result},
+    model::ModelInfo,
+};
+
+use super::{SimCommand, SimError, SimInstrument, SimNet, SimNoise, SimResult, respond};
+
+/// Load on each output until set otherwise
+const DEFAULT_LOAD: f64 = 100.0;
+
+struct SimPsuChannel {
+    details: PowerSupplyChannelDetails,
+    voltage: f64,
+    current: f64,
+    enabled: bool,
+    /// Load resistance in Ω
+    load: f64,
+}
+impl SimPsuChannel {
+    fn new(details: PowerSupplyChannelDetails) -> Self {
+        Self {
+            voltage: 0.0,
+            current: details.max_current as f64,
+            enabled: false,
+            load: DEFAULT_LOAD,
+            details,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.voltage = 0.0;
+        self.current = self.details.max_current as f64;
+        self.enabled = false;
+    }
+
+    /// Output voltage and current, limited to the current set in constant
+    /// current mode
+    fn output(&self) -> (f64, f64) {
+        if !self.enabled {
+            return (0.0, 0.0);
+        }
+
+        let current = self.voltage.abs() / self.load;
+        if current <= self.current {
+            (self.voltage, current)
+        } else {
+            (
+                self.voltage.signum() * self.current * self.load,
+                self.current,
+            )
+        }
+    }
+}
+
+pub struct SimPsu {
+    idn: String,
+    channels: Vec<SimPsuChannel>,
+    noise: SimNoise,
+}
+impl SimPsu {
+    pub fn new(model: &str) -> Result<Self> {
+        let manufacturer = if model.starts_with("DP") {
+            "RIGOL TECHNOLOGIES"
+        } else if model.starts_with("SPD") {
+            "Siglent Technologies"
+        } else {
+            return Err(Error::NotSupported(format!(
+                "No simulated power supply {model}"
+            )));
+        };
+        let idn = format!("{manufacturer},{model},SIM00001,1.0");
+
+        let psu_model = ScpiPsuModel::from_model(&ModelInfo::from_idn(&idn)?)?;
+
+        Ok(Self {
+            idn,
+            channels: psu_model
+                .channel_details()
+                .into_iter()
+                .map(SimPsuChannel::new)
+                .collect(),
+            noise: SimNoise::new(0x5053_5500),
+        })
+    }
+
+    /// Channel addressed by either a `CH<n>` parameter before the value
+    /// (Siglent) or a header suffix (Rigol)
+    fn channel(
+        &mut self,
+        cmd: &SimCommand,
+        suffix: u32,
+        values: usize,
+    ) -> std::result::Result<&mut SimPsuChannel, SimError> {
+        cmd.check_params(values, values + 1)?;
+        let num = if cmd.params.len() > values {
+            cmd.channel_param(0)?
+        } else {
+            suffix
+        };
+
+        num.checked_sub(1)
+            .and_then(|idx| self.channels.get_mut(idx as usize))
+            .ok_or(SimError::ILLEGAL_VALUE)
+    }
+
+    fn measure(
+        &mut self,
+        cmd: &SimCommand,
+        suffix: u32,
+    ) -> std::result::Result<(f64, f64), SimError> {
+        let (voltage, current) = self.channel(cmd, suffix, 0)?.output();
+        if voltage == 0.0 && current == 0.0 {
+            return Ok((0.0, 0.0));
+        }
+
+        Ok((
+            voltage + self.noise.gaussian() * 2e-4,
+            current + self.noise.gaussian() * 5e-5,
+        ))
+    }
+}
+impl SimInstrument for SimPsu {
+    fn idn(&self) -> String {
+        self.idn.clone()
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        if let Some(s) = cmd.matches("[SOURce#]:VOLTage[:LEVel][:IMMediate][:AMPLitude]") {
+            let value = cmd.f64_param(cmd.params.len().saturating_sub(1))?;
+            let chan = self.channel(cmd, s[0], 1)?;
+            let (min, max) = (chan.details.min_voltage, chan.details.max_voltage);
+            if !(min as f64..=max as f64).contains(&value) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            chan.voltage = value;
+            Ok(None)
+        } else if let Some(s) = cmd.matches("[SOURce#]:VOLTage[:LEVel][:IMMediate][:AMPLitude]?") {
+            respond(format!("{:.3}", self.channel(cmd, s[0], 0)?.voltage))
+        } else if let Some(s) = cmd.matches("[SOURce#]:CURRent[:LEVel][:IMMediate][:AMPLitude]") {
+            let value = cmd.f64_param(cmd.params.len().saturating_sub(1))?;
+            let chan = self.channel(cmd, s[0], 1)?;
+            if !(0.0..=chan.details.max_current as f64).contains(&value) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            chan.current = value;
+            Ok(None)
+        } else if let Some(s) = cmd.matches("[SOURce#]:CURRent[:LEVel][:IMMediate][:AMPLitude]?") {
+            respond(format!("{:.3}", self.channel(cmd, s[0], 0)?.current))
+        } else if cmd.matches("OUTPut[:STATe]").is_some() {
+            let enabled = cmd.bool_param(cmd.params.len().saturating_sub(1))?;
+            self.channel(cmd, 1, 1)?.enabled = enabled;
+            Ok(None)
+        } else if cmd.matches("OUTPut[:STATe]?").is_some() {
+            let enabled = self.channel(cmd, 1, 0)?.enabled;
+            respond(if enabled { "ON" } else { "OFF" })
+        } else if cmd.matches("MEASure[:SCALar]:VOLTage[:DC]?").is_some() {
+            respond(format!("{:.4}", self.measure(cmd, 1)?.0))
+        } else if cmd.matches("MEASure[:SCALar]:CURRent[:DC]?").is_some() {
+            respond(format!("{:.4}", self.measure(cmd, 1)?.1))
+        } else if cmd.matches("MEASure[:SCALar]:POWEr[:DC]?").is_some() {
+            let (voltage, current) = self.measure(cmd, 1)?;
+            respond(format!("{:.3}", voltage * current))
+        } else {
+            Err(SimError::UNDEFINED_HEADER)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.channels.iter_mut().for_each(SimPsuChannel::reset);
+    }
+
+    fn net(&self, net: &str) -> Option<SimNet> {
+        let chan = self.channels.get(channel_index(net)?)?;
+        let (voltage, current) = chan.output();
+
+        Some(SimNet {
+            voltage,
+            current,
+            load: Some(chan.load),
+            ..Default::default()
+        })
+    }
+
+    fn set_load(&mut self, net: &str, ohms: f64) -> Result<()> {
+        let Some(chan) = channel_index(net).and_then(|idx| self.channels.get_mut(idx)) else {
+            return Err(Error::InvalidArgument(format!("No output `{net}`")));
+        };
+
+        chan.load = ohms;
+        Ok(())
+    }
+}
+
+/// Index of an output net, named `CH<n>`
+fn channel_index(net: &str) -> Option<usize> {
+    let num: usize = net.strip_prefix("CH")?.parse().ok()?;
+    num.checked_sub(1)
+}
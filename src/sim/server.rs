@@ -0,0 +1,130 @@
+//! Server making simulated instruments available to other programs on real
+//! transports, raw TCP and VXI-11
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    error::{Error, Result},
+    protocol::VxiServer,
+};
+
+use super::SimDevice;
+
+/// Serves simulated instruments, each on a raw TCP port of its own and all of
+/// them via a single VXI-11 server
+pub struct SimServer {
+    raw_addrs: Vec<SocketAddr>,
+    raw_tasks: Vec<JoinHandle<()>>,
+    vxi11: VxiServer,
+}
+impl SimServer {
+    /// Start serving on the given address. Raw TCP ports are consecutive from
+    /// raw_port, and the VXI-11 portmapper listens on portmap_port. Either
+    /// being 0 picks free ports instead.
+    pub async fn start(
+        ip: IpAddr,
+        raw_port: u16,
+        portmap_port: u16,
+        devices: Vec<Arc<Mutex<SimDevice>>>,
+    ) -> Result<Self> {
+        let mut raw_addrs = vec![];
+        let mut raw_tasks = vec![];
+        for (idx, device) in devices.iter().enumerate() {
+            let port = if raw_port == 0 {
+                0
+            } else {
+                u16::try_from(idx)
+                    .ok()
+                    .and_then(|idx| raw_port.checked_add(idx))
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!(
+                            "Too many instruments for raw TCP ports from {raw_port}"
+                        ))
+                    })?
+            };
+            let listener = TcpListener::bind(SocketAddr::new(ip, port)).await?;
+            raw_addrs.push(listener.local_addr()?);
+            raw_tasks.push(tokio::spawn(Self::accept_loop(listener, device.clone())));
+        }
+
+        let vxi11 = VxiServer::start(ip, portmap_port, devices).await?;
+
+        Ok(Self {
+            raw_addrs,
+            raw_tasks,
+            vxi11,
+        })
+    }
+
+    /// Raw TCP addresses, in the order the instruments were given
+    pub fn raw_addrs(&self) -> &[SocketAddr] {
+        &self.raw_addrs
+    }
+
+    /// Address of the VXI-11 portmapper
+    pub fn vxi11_addr(&self) -> SocketAddr {
+        self.vxi11.addr()
+    }
+
+    async fn accept_loop(listener: TcpListener, device: Arc<Mutex<SimDevice>>) {
+        /* Dropping the set when this task is aborted also aborts all
+         * connection tasks */
+        let mut connections = JoinSet::new();
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!("Raw TCP connection from {peer}");
+                    let device = device.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = Self::serve_connection(stream, device).await {
+                            debug!("Raw TCP connection closed: {e}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept raw TCP connection: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Process each line received as a program message, responses being
+    /// terminated by a newline
+    async fn serve_connection(stream: TcpStream, device: Arc<Mutex<SimDevice>>) -> Result<()> {
+        let (rx, mut tx) = stream.into_split();
+        let mut rx = BufReader::new(rx);
+        let mut line = vec![];
+
+        loop {
+            line.clear();
+            if rx.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(());
+            }
+
+            let resp = device.lock().unwrap().write(line.trim_ascii_end());
+            if let Some(mut resp) = resp {
+                resp.push(b'\n');
+                tx.write_all(&resp).await?;
+            }
+        }
+    }
+}
+impl Drop for SimServer {
+    fn drop(&mut self) {
+        for task in &self.raw_tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Simulated Siglent SSA3000X spectrum analyzer, sweeping a synthetic
+//! spectrum of carriers over the noise floor
+
+use crate::error::{Error, Result};
+
+use super::{SimCommand, SimError, SimInstrument, SimNoise, SimResult, block, respond};
+
+/// Points in each sweep
+const SWEEP_POINTS: usize = 751;
+/// Displayed average noise level, in dBm/Hz
+const NOISE_DENSITY: f64 = -161.0;
+const MIN_RBW: f64 = 10.0;
+const MAX_RBW: f64 = 1e6;
+
+/// Carriers in the synthetic spectrum, as frequency and power in dBm
+const CARRIERS: [(f64, f64); 8] = [
+    (10e6, -30.0),
+    (100e6, -20.0),
+    (200e6, -55.0),
+    (300e6, -62.0),
+    (433.92e6, -45.0),
+    (915e6, -50.0),
+    (2.44e9, -35.0),
+    (5.8e9, -48.0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SimTraceFormat {
+    Ascii,
+    Real32,
+    Real64,
+}
+
+pub struct SimSpectrumAnalyzer {
+    idn: String,
+    /// Upper frequency limit in Hz
+    max_freq: f64,
+    center: f64,
+    span: f64,
+    rbw: f64,
+    unit: &'static str,
+    format: SimTraceFormat,
+    noise: SimNoise,
+}
+impl SimSpectrumAnalyzer {
+    pub fn new(model: &str) -> Result<Self> {
+        /* Upper frequency limit is given by the model number, e.g. 7.5 GHz
+         * for the SSA3075X */
+        let max_freq = model
+            .strip_prefix("SSA3")
+            .and_then(|num| num.get(..3))
+            .and_then(|num| num.parse::<u32>().ok())
+            .map(|num| (num % 100) as f64 * 0.1e9);
+        let Some(max_freq) = max_freq.filter(|freq| *freq > 0.0) else {
+            return Err(Error::NotSupported(format!(
+                "No simulated spectrum analyzer {model}"
+            )));
+        };
+
+        let mut sa = Self {
+            idn: format!("Siglent Technologies,{model},SIM00001,1.0"),
+            max_freq,
+            center: 0.0,
+            span: 0.0,
+            rbw: 0.0,
+            unit: "",
+            format: SimTraceFormat::Ascii,
+            noise: SimNoise::new(0x5341_0000),
+        };
+        sa.reset();
+
+        Ok(sa)
+    }
+
+    fn start(&self) -> f64 {
+        self.center - self.span / 2.0
+    }
+
+    /// Set the sweep to start and stop frequencies, limited to the range of
+    /// the analyzer
+    fn set_range(&mut self, start: f64, stop: f64) -> std::result::Result<(), SimError> {
+        if start > stop {
+            return Err(SimError::SETTINGS_CONFLICT);
+        }
+        let start = start.clamp(0.0, self.max_freq);
+        let stop = stop.clamp(0.0, self.max_freq);
+
+        self.center = (start + stop) / 2.0;
+        self.span = stop - start;
+        Ok(())
+    }
+
+    /// Set the sweep to a center frequency and span, keeping the center and
+    /// narrowing the span to fit the range of the analyzer
+    fn set_center_span(&mut self, center: f64, span: f64) {
+        self.center = center.clamp(0.0, self.max_freq);
+        self.span = span
+            .max(0.0)
+            .min(2.0 * self.center)
+            .min(2.0 * (self.max_freq - self.center));
+    }
+
+    /// Sweep the spectrum, in dBm, with a peak detector over each point
+    fn sweep(&mut self) -> Vec<f64> {
+        let step = self.span / (SWEEP_POINTS - 1) as f64;
+        let noise_floor = NOISE_DENSITY + 10.0 * self.rbw.log10();
+
+        (0..SWEEP_POINTS)
+            .map(|idx| {
+                let freq = self.start() + step * idx as f64;
+
+                /* Noise power is exponentially distributed */
+                let mut power = 10f64.powf(noise_floor / 10.0) * -self.noise.uniform().ln();
+                for (carrier, level) in CARRIERS {
+                    /* Distance from the carrier to the nearest frequency in
+                     * this point's bin */
+                    let offset = ((carrier - freq).abs() - step / 2.0).max(0.0);
+                    /* Gaussian filter shape, 3 dB down at RBW/2 */
+                    let atten = 3.01 * (offset / (self.rbw / 2.0)).powi(2);
+                    if atten < 200.0 {
+                        power += 10f64.powf((level - atten) / 10.0);
+                    }
+                }
+
+                10.0 * power.log10()
+            })
+            .collect()
+    }
+
+    /// Convert a power in dBm to the current unit, for a 50 Ω input
+    fn convert(&self, dbm: f64) -> f64 {
+        match self.unit {
+            "DBMV" => dbm + 46.99,
+            "DBUV" => dbm + 106.99,
+            "DBUA" => dbm + 73.01,
+            "V" => (10f64.powf(dbm / 10.0) * 1e-3 * 50.0).sqrt(),
+            "W" => 10f64.powf(dbm / 10.0) * 1e-3,
+            _ => dbm,
+        }
+    }
+}
+impl SimInstrument for SimSpectrumAnalyzer {
+    fn idn(&self) -> String {
+        self.idn.clone()
+    }
+
+    fn command(&mut self, cmd: &SimCommand) -> SimResult {
+        if cmd.matches("[SENSe]:FREQuency:CENTer").is_some() {
+            self.set_center_span(cmd.f64_param(0)?, self.span);
+            Ok(None)
+        } else if cmd.matches("[SENSe]:FREQuency:CENTer?").is_some() {
+            respond(self.center)
+        } else if cmd.matches("[SENSe]:FREQuency:SPAN").is_some() {
+            self.set_center_span(self.center, cmd.f64_param(0)?);
+            Ok(None)
+        } else if cmd.matches("[SENSe]:FREQuency:SPAN?").is_some() {
+            respond(self.span)
+        } else if cmd.matches("[SENSe]:FREQuency:STARt").is_some() {
+            let start = cmd.f64_param(0)?;
+            self.set_range(start, (self.center + self.span / 2.0).max(start))?;
+            Ok(None)
+        } else if cmd.matches("[SENSe]:FREQuency:STARt?").is_some() {
+            respond(self.start())
+        } else if cmd.matches("[SENSe]:FREQuency:STOP").is_some() {
+            let stop = cmd.f64_param(0)?;
+            self.set_range(self.start().min(stop), stop)?;
+            Ok(None)
+        } else if cmd.matches("[SENSe]:FREQuency:STOP?").is_some() {
+            respond(self.center + self.span / 2.0)
+        } else if cmd.matches("[SENSe]:BWIDth[:RESolution]").is_some() {
+            let rbw = cmd.f64_param(0)?;
+            if !(MIN_RBW..=MAX_RBW).contains(&rbw) {
+                return Err(SimError::OUT_OF_RANGE);
+            }
+            self.rbw = rbw;
+            Ok(None)
+        } else if cmd.matches("[SENSe]:BWIDth[:RESolution]?").is_some() {
+            respond(self.rbw)
+        } else if cmd.matches("UNIT:POWer").is_some() {
+            let unit = cmd.param(0)?.to_ascii_uppercase();
+            self.unit = match unit.as_str() {
+                "DBM" => "DBM",
+                "DBMV" => "DBMV",
+                "DBUV" => "DBUV",
+                "DBUA" => "DBUA",
+                "V" => "V",
+                "W" => "W",
+                _ => return Err(SimError::ILLEGAL_VALUE),
+            };
+            Ok(None)
+        } else if cmd.matches("UNIT:POWer?").is_some() {
+            respond(self.unit)
+        } else if cmd.matches("FORMat[:DATA]").is_some() {
+            let format = cmd.param(0)?.to_ascii_uppercase();
+            self.format = match (format.as_str(), cmd.params.get(1).map(String::as_str)) {
+                ("ASC" | "ASCII", _) => SimTraceFormat::Ascii,
+                ("REAL", Some("32")) => SimTraceFormat::Real32,
+                ("REAL", None | Some("64")) => SimTraceFormat::Real64,
+                _ => return Err(SimError::ILLEGAL_VALUE),
+            };
+            Ok(None)
+        } else if cmd.matches("FORMat[:DATA]?").is_some() {
+            respond(match self.format {
+                SimTraceFormat::Ascii => "ASCii",
+                SimTraceFormat::Real32 => "REAL,32",
+                SimTraceFormat::Real64 => "REAL,64",
+            })
+        } else if cmd.matches("[SENSe]:SWEep:POINts?").is_some() {
+            respond(SWEEP_POINTS)
+        } else if cmd.matches("TRACe[:DATA]?").is_some() {
+            let trace = cmd.f64_param(0)?;
+            if !(1.0..=4.0).contains(&trace) {
+                return Err(SimError::ILLEGAL_VALUE);
+            }
+
+            let values: Vec<_> = self
+                .sweep()
+                .into_iter()
+                .map(|dbm| self.convert(dbm))
+                .collect();
+            /* Binary data is sent as a definite length block */
+            Ok(Some(match self.format {
+                SimTraceFormat::Ascii => values
+                    .iter()
+                    .map(|value| format!("{value:.3}"))
+                    .collect::<Vec<_>>()
+                    .join(",")
+                    .into_bytes(),
+                SimTraceFormat::Real32 => block(
+                    &values
+                        .iter()
+                        .flat_map(|value| (*value as f32).to_le_bytes())
+                        .collect::<Vec<_>>(),
+                ),
+                SimTraceFormat::Real64 => block(
+                    &values
+                        .iter()
+                        .flat_map(|value| value.to_le_bytes())
+                        .collect::<Vec<_>>(),
+                ),
+            }))
+        } else {
+            Err(SimError::UNDEFINED_HEADER)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.center = self.max_freq / 2.0;
+        self.span = self.max_freq;
+        self.rbw = MAX_RBW;
+        self.unit = "DBM";
+        self.format = SimTraceFormat::Ascii;
+    }
+}
@@ -0,0 +1,36 @@
+//! Discovery of simulated instruments served on the loopback interface
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use testeq_rs::{
+    protocol::{DiscoveryOptions, discover_with},
+    sim::{self, SimClass, server::SimServer},
+};
+
+#[tokio::test]
+async fn discovers_vxi11_portmapper() {
+    let psu = sim::open(SimClass::Psu, None, Some("discovery-psu")).unwrap();
+    let server = SimServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 0, vec![psu])
+        .await
+        .unwrap();
+    let portmapper = server.vxi11_addr();
+
+    let found = discover_with(&DiscoveryOptions {
+        timeout: Duration::from_millis(200),
+        portmap_targets: vec![portmapper],
+        mdns_targets: vec![],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].model.model, "DP832");
+    assert_eq!(
+        found[0].uri(),
+        format!("vxi11://127.0.0.1:{}", portmapper.port())
+    );
+}
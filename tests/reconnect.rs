@@ -0,0 +1,264 @@
+//! Reconnection to simulated instruments whose server restarts, and to
+//! transports that keep failing
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use testeq_rs::{
+    error::{Error, Result},
+    model::ModelInfo,
+    protocol::{
+        Protocol, ReconnectEvent, ReconnectPolicy, ReconnectingProtocol, ScpiProtocol,
+        ScpiSettings, ScpiTcpProtocol, ScpiVxiProtocol,
+    },
+    sim::{self, SimClass, SimDevice, server::SimServer},
+};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+#[derive(Default)]
+struct Events {
+    reconnects: u32,
+    mismatches: u32,
+}
+
+/// Connect through the given transport, counting reconnection events
+async fn connect(
+    inner: Box<dyn ScpiProtocol>,
+    retry_commands: bool,
+) -> (Box<dyn ScpiProtocol>, Arc<Mutex<Events>>) {
+    let policy = ReconnectPolicy {
+        retries: 10,
+        backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(100),
+        retry_commands,
+        ..Default::default()
+    };
+    let mut proto = ReconnectingProtocol::new(inner, policy);
+    let events = Arc::new(Mutex::new(Events::default()));
+    let counter = events.clone();
+    proto.set_callback(move |event| match event {
+        ReconnectEvent::Reconnected { .. } => counter.lock().unwrap().reconnects += 1,
+        ReconnectEvent::IdentityMismatch { .. } => counter.lock().unwrap().mismatches += 1,
+        _ => {}
+    });
+    let mut proto: Box<dyn ScpiProtocol> = Box::new(proto);
+    proto.connect().await.unwrap();
+
+    (proto, events)
+}
+
+fn tcp(server: &SimServer) -> Box<dyn ScpiProtocol> {
+    Box::new(ScpiTcpProtocol::new(server.raw_addrs()[0]).unwrap())
+}
+
+/// Restart the server on the same ports with the given device, dropping its
+/// connections
+async fn restart(server: SimServer, device: &Arc<Mutex<SimDevice>>) -> SimServer {
+    let raw_port = server.raw_addrs()[0].port();
+    let portmap_port = server.vxi11_addr().port();
+    drop(server);
+
+    /* Listeners are only closed once their aborted tasks are dropped */
+    for _ in 0..100 {
+        if let Ok(server) =
+            SimServer::start(LOCALHOST, raw_port, portmap_port, vec![device.clone()]).await
+        {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Ports {raw_port} and {portmap_port} not released");
+}
+
+#[tokio::test]
+async fn fails_lost_query_then_reconnects() {
+    let device = sim::open(SimClass::Psu, None, Some("reconnect-psu")).unwrap();
+    let server = SimServer::start(LOCALHOST, 0, 0, vec![device.clone()])
+        .await
+        .unwrap();
+    let (mut proto, events) = connect(tcp(&server), false).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+
+    let _server = restart(server, &device).await;
+    assert!(proto.query_str("*IDN?").await.is_err());
+    assert_eq!(events.lock().unwrap().reconnects, 1);
+
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+}
+
+#[tokio::test]
+async fn retries_lost_query_if_enabled() {
+    let device = sim::open(SimClass::Psu, None, Some("retry-psu")).unwrap();
+    let server = SimServer::start(LOCALHOST, 0, 0, vec![device.clone()])
+        .await
+        .unwrap();
+    let (mut proto, events) = connect(tcp(&server), true).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+
+    let _server = restart(server, &device).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+    assert_eq!(events.lock().unwrap().reconnects, 1);
+}
+
+#[tokio::test]
+async fn recreates_vxi11_link() {
+    let device = sim::open(SimClass::Psu, None, Some("reconnect-vxi11-psu")).unwrap();
+    let server = SimServer::start(LOCALHOST, 0, 0, vec![device.clone()])
+        .await
+        .unwrap();
+    let vxi11 = Box::new(ScpiVxiProtocol::new(server.vxi11_addr()));
+    let (mut proto, events) = connect(vxi11, false).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+
+    let _server = restart(server, &device).await;
+    /* Tearing down the dead link must not wait for RPCs to time out */
+    let start = Instant::now();
+    assert!(proto.query_str("*IDN?").await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(events.lock().unwrap().reconnects, 1);
+
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+}
+
+#[tokio::test]
+async fn keeps_reconnecting_after_identity_mismatch() {
+    let psu = sim::open(SimClass::Psu, None, Some("mismatch-psu")).unwrap();
+    let dmm = sim::open(SimClass::Dmm, None, Some("mismatch-dmm")).unwrap();
+    let server = SimServer::start(LOCALHOST, 0, 0, vec![psu.clone()])
+        .await
+        .unwrap();
+    let (mut proto, events) = connect(tcp(&server), false).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+
+    /* Different device behind the same address is rejected every time */
+    let server = restart(server, &dmm).await;
+    for mismatches in 1..=2 {
+        let err = proto.query_str("*IDN?").await.unwrap_err();
+        assert!(err.to_string().contains("different device"), "{err}");
+        assert_eq!(events.lock().unwrap().mismatches, mismatches);
+    }
+
+    let _server = restart(server, &psu).await;
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+    assert_eq!(events.lock().unwrap().reconnects, 1);
+}
+
+/// Transport whose operations fail with the given kind of I/O error, and that
+/// cannot be connected again once disconnected
+struct Failing {
+    kind: std::io::ErrorKind,
+    connects: Arc<Mutex<u32>>,
+    settings: ScpiSettings,
+}
+impl Failing {
+    fn error(&self) -> Error {
+        std::io::Error::from(self.kind).into()
+    }
+}
+#[async_trait]
+impl Protocol for Failing {
+    async fn connect(&mut self) -> Result<()> {
+        let mut connects = self.connects.lock().unwrap();
+        *connects += 1;
+        if *connects > 1 {
+            return Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into());
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn model(&mut self) -> Result<ModelInfo> {
+        Err(self.error())
+    }
+}
+#[async_trait]
+impl ScpiProtocol for Failing {
+    async fn int_send(&mut self, _data: &[u8]) -> Result<()> {
+        Err(self.error())
+    }
+
+    async fn int_recv(&mut self) -> Result<Vec<u8>> {
+        Err(self.error())
+    }
+
+    async fn int_query(&mut self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(self.error())
+    }
+
+    async fn recv_raw(
+        &mut self,
+        _length: Option<usize>,
+        _timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        Err(self.error())
+    }
+
+    async fn recv_until(&mut self, _byte: u8, _timeout: Duration) -> Result<Vec<u8>> {
+        Err(self.error())
+    }
+
+    async fn flush_rx(&mut self, _timeout: Duration) -> Result<()> {
+        Err(self.error())
+    }
+
+    fn settings(&self) -> &ScpiSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut ScpiSettings {
+        &mut self.settings
+    }
+}
+
+/// Connect to a failing transport with the given backoff, returning the
+/// count of connection attempts
+async fn failing(
+    kind: std::io::ErrorKind,
+    backoff: Duration,
+) -> (ReconnectingProtocol, Arc<Mutex<u32>>) {
+    let connects = Arc::new(Mutex::new(0));
+    let inner = Failing {
+        kind,
+        connects: connects.clone(),
+        settings: ScpiSettings::default(),
+    };
+    let policy = ReconnectPolicy {
+        retries: 3,
+        backoff,
+        max_backoff: Duration::MAX,
+        verify_identity: false,
+        ..Default::default()
+    };
+    let mut proto = ReconnectingProtocol::new(Box::new(inner), policy);
+    proto.connect().await.unwrap();
+
+    (proto, connects)
+}
+
+#[tokio::test(start_paused = true)]
+async fn saturates_backoff() {
+    let (mut proto, connects) = failing(std::io::ErrorKind::BrokenPipe, Duration::MAX).await;
+
+    let proto: &mut dyn ScpiProtocol = &mut proto;
+    assert!(proto.send("*RST").await.is_err());
+    assert_eq!(*connects.lock().unwrap(), 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn timeout_does_not_reconnect() {
+    let (mut proto, connects) =
+        failing(std::io::ErrorKind::TimedOut, Duration::from_millis(10)).await;
+
+    let proto: &mut dyn ScpiProtocol = &mut proto;
+    assert!(proto.send("*RST").await.is_err());
+    assert_eq!(*connects.lock().unwrap(), 1);
+}
@@ -0,0 +1,240 @@
+//! Drivers run against scripted device sessions
+
+use testeq_rs::{
+    data::Unit,
+    equipment::{Equipment, equipment_from_scpi, multimeter::MultimeterMode},
+    protocol::{ReplayProtocol, ScpiProtocol},
+};
+
+async fn equipment(script: &str) -> Equipment {
+    let mut proto: Box<dyn ScpiProtocol> = Box::new(ReplayProtocol::from_script(script).unwrap());
+    proto.connect().await.unwrap();
+    equipment_from_scpi(proto).await.unwrap()
+}
+
+#[tokio::test]
+async fn generic_scpi_psu() {
+    let Equipment::PowerSupply(mut psu) = equipment(
+        r"
+        > *IDN?
+        < RIGOL TECHNOLOGIES,DP832,DP8A0000000001,00.01.16
+        > *IDN?
+        < RIGOL TECHNOLOGIES,DP832,DP8A0000000001,00.01.16
+        > :SOUR2:VOLT 12
+        > :SOUR2:CURR 0.25
+        > :OUTP CH2,ON
+        > :OUTP? CH2
+        < ON
+        > :MEAS:VOLT? CH2
+        < 11.9987
+        > :MEAS:CURR? CH2
+        < 0.1234
+        ",
+    )
+    .await
+    else {
+        panic!("Not detected as a power supply");
+    };
+
+    psu.connect().await.unwrap();
+    let channel = psu.get_channel(1).await.unwrap();
+    let mut channel = channel.lock().await;
+    channel.set_voltage(12.0).await.unwrap();
+    channel.set_current(0.25).await.unwrap();
+    channel.set_enabled(true).await.unwrap();
+    assert!(channel.get_enabled().await.unwrap());
+    assert_eq!(channel.read_voltage().await.unwrap(), 11.9987);
+    assert_eq!(channel.read_current().await.unwrap(), 0.1234);
+}
+
+#[tokio::test]
+async fn siglent_multimeter() {
+    let Equipment::Multimeter(mut dmm) = equipment(
+        r#"
+        > *IDN?
+        < Siglent Technologies,SDM4065A,SDM4HBAQ000000,1.01.01.06
+        > CONF:CURR:AC
+        > CONF?
+        < "CURR:AC +2.00000000E-01,+2.00000000E-07"
+        > FETC?
+        < +1.23450000E-02
+        "#,
+    )
+    .await
+    else {
+        panic!("Not detected as a multimeter");
+    };
+
+    dmm.connect().await.unwrap();
+    let channel = dmm.get_channel(0).await.unwrap();
+    let mut channel = channel.lock().await;
+    channel
+        .set_mode(MultimeterMode::AcCurrent, None)
+        .await
+        .unwrap();
+    assert_eq!(channel.get_mode().await.unwrap(), MultimeterMode::AcCurrent);
+    assert_eq!(channel.get_reading().await.unwrap().value, 0.012345);
+}
+
+/// WAVEDESC block of 16-bit samples at 1 V/div, 30 codes/div and 1 µs apart
+fn wavedesc(points: u32) -> Vec<u8> {
+    let mut desc = vec![0u8; 346];
+    let mut put = |offset: usize, data: &[u8]| {
+        desc[offset..offset + data.len()].copy_from_slice(data);
+    };
+
+    put(0, b"WAVEDESC");
+    put(16, b"WAVEACE");
+    put(32, &1u16.to_le_bytes());
+    put(34, &1u16.to_le_bytes());
+    put(36, &346u32.to_le_bytes());
+    put(60, &(points * 2).to_le_bytes());
+    put(116, &points.to_le_bytes());
+    put(156, &1f32.to_le_bytes());
+    put(164, &30f32.to_le_bytes());
+    put(176, &1e-6f32.to_le_bytes());
+    put(328, &1f32.to_le_bytes());
+
+    desc
+}
+
+#[tokio::test]
+async fn siglent_oscilloscope() {
+    let samples: Vec<u8> = [0i16, 30, -30, 60]
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    let script = format!(
+        r"
+        > *IDN?
+        < Siglent Technologies,SDS3104X HD,SDS3HBAQ000000,1.1.3.4
+        > *IDN?
+        < Siglent Technologies,SDS3104X HD,SDS3HBAQ000000,1.1.3.4
+        > :WAV:SOUR C2
+        > :WAV:STAR 0
+        > :WAV:POIN 20000
+        > :WAV:INT 1
+        > :WAV:WIDT WORD
+        > :WAV:PRE?
+        < #9000000346{}
+        > :WAV:DATA?
+        < #18{}
+        ",
+        wavedesc(4).escape_ascii(),
+        samples.escape_ascii()
+    );
+    let Equipment::Oscilloscope(mut scope) = equipment(&script).await else {
+        panic!("Not detected as an oscilloscope");
+    };
+
+    scope.connect().await.unwrap();
+    let channel = scope.get_channel(1).await.unwrap();
+    let waveform = channel.lock().await.read_waveform().await.unwrap();
+    assert_eq!(waveform.time_per_pt, 1e-6f32 as f64);
+    assert_eq!(waveform.readings.values, [0.0, 1.0, -1.0, 2.0]);
+}
+
+#[tokio::test]
+async fn siglent_spectrum_analyzer() {
+    /* Trace in dBµV, read back as a block of 64-bit values */
+    let trace: Vec<u8> = [40.0f64, 46.5, 100.0, 33.25]
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    let script = format!(
+        r"
+        > *IDN?
+        < Siglent Technologies,SSA3075X Plus,SSA3PCDQ000000,3.2.2.5.1R1.r1
+        > *IDN?
+        < Siglent Technologies,SSA3075X Plus,SSA3PCDQ000000,3.2.2.5.1R1.r1
+        > :FREQ:CENT?
+        < 1.000000000E+08
+        > :FREQ:SPAN?
+        < 1.000000000E+06
+        > :BWID?
+        < 1.000000000E+04
+        > :UNIT:POW?
+        < DBUV
+        > :FORM REAL,64
+        > :TRAC? 1
+        < #232{}
+        ",
+        trace.escape_ascii()
+    );
+    let Equipment::SpectrumAnalyzer(mut sa) = equipment(&script).await else {
+        panic!("Not detected as a spectrum analyzer");
+    };
+
+    sa.connect().await.unwrap();
+    let channel = sa.get_channel(0).await.unwrap();
+    let trace = channel.lock().await.read_trace(0).await.unwrap();
+    assert_eq!(trace.span.center(), 100e6);
+    assert_eq!(trace.span.span(), 1e6);
+    assert_eq!(trace.freq_step, 250e3);
+    assert_eq!(trace.readings.unit, Unit::LogVoltage);
+    assert_eq!(trace.readings.values, [-20.0, -13.5, 40.0, -26.75]);
+}
+
+#[tokio::test]
+async fn keysight_ac_source() {
+    let Equipment::AcSource(mut ac) = equipment(
+        r"
+        > *IDN?
+        < HEWLETT-PACKARD,6811B,0,A.01.02
+        > INIT:IMM:SEQuence3
+        > TRIG:SEQuence3:SOUR BUS
+        > *TRG
+        > :FETC:VOLT?
+        < +1.2500E-01
+        > :FETC:VOLT:AC?
+        < +2.3000E+02
+        > :FETC:CURR?
+        < -2.5000E-03
+        > :FETC:CURR:AC?
+        < +4.3500E-01
+        > :FETC:CURR:AMPL:MAX?
+        < +6.2500E-01
+        > :FETC:POW?
+        < +0.0000E+00
+        > :FETC:POW:AC?
+        < +9.8500E+01
+        > :FETC:POW:AC:APP?
+        < +1.0000E+02
+        > :FETC:POW:AC:REAC?
+        < +1.7250E+01
+        > :FETC:POW:AC:PFAC?
+        < +9.8500E-01
+        > :FETC:FREQ?
+        < +5.0000E+01
+        ",
+    )
+    .await
+    else {
+        panic!("Not detected as an AC source");
+    };
+
+    ac.connect().await.unwrap();
+    ac.trigger_now().await.unwrap();
+    let channel = ac.get_channel(0).await.unwrap();
+    let channel = channel.lock().await;
+
+    let voltage = channel.read_voltage().await.unwrap();
+    assert_eq!((voltage.dc, voltage.ac_rms), (0.125, 230.0));
+    let current = channel.read_current().await.unwrap();
+    assert_eq!(
+        (current.dc, current.ac_rms, current.max),
+        (-0.0025, 0.435, 0.625)
+    );
+    let power = channel.read_power().await.unwrap();
+    assert_eq!(
+        (
+            power.dc,
+            power.real,
+            power.apparent,
+            power.reactive,
+            power.factor
+        ),
+        (0.0, 98.5, 100.0, 17.25, 0.985)
+    );
+    assert_eq!(channel.read_frequency().await.unwrap(), 50.0);
+}
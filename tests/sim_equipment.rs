@@ -0,0 +1,176 @@
+//! Drivers run against simulated instruments
+
+use testeq_rs::{
+    equipment::{
+        Equipment, equipment_from_uri,
+        multimeter::MultimeterMode,
+        spectrum_analyzer::{SpectrumAnalyzerFreqConfig, SpectrumAnalyzerSpan},
+    },
+    sim,
+};
+
+fn assert_near(value: f64, expected: f64, tolerance: f64) {
+    assert!(
+        (value - expected).abs() <= tolerance,
+        "{value} is not within {tolerance} of {expected}"
+    );
+}
+
+#[tokio::test]
+async fn multimeter_follows_psu_load() {
+    let Equipment::PowerSupply(mut psu) = equipment_from_uri("sim://psu/DP832/load-psu")
+        .await
+        .unwrap()
+    else {
+        panic!("Not detected as a power supply");
+    };
+    let Equipment::Multimeter(mut dmm) =
+        equipment_from_uri("sim://dmm/SDM4065A/load-dmm?net=load-psu:CH1")
+            .await
+            .unwrap()
+    else {
+        panic!("Not detected as a multimeter");
+    };
+
+    psu.connect().await.unwrap();
+    dmm.connect().await.unwrap();
+    let output = psu.get_channel(0).await.unwrap();
+    let mut output = output.lock().await;
+    let meter = dmm.get_channel(0).await.unwrap();
+    let mut meter = meter.lock().await;
+
+    /* Nothing on the net until the output is enabled */
+    meter
+        .set_mode(MultimeterMode::DcVoltage, None)
+        .await
+        .unwrap();
+    output.set_voltage(5.0).await.unwrap();
+    output.set_current(1.0).await.unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 0.0, 1e-3);
+
+    /* Constant voltage into the default 100 Ω load */
+    output.set_enabled(true).await.unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 5.0, 1e-3);
+    assert_near(output.read_current().await.unwrap() as f64, 0.05, 1e-3);
+    meter
+        .set_mode(MultimeterMode::DcCurrent, None)
+        .await
+        .unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 0.05, 1e-4);
+    meter
+        .set_mode(MultimeterMode::Resistance, None)
+        .await
+        .unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 100.0, 0.01);
+
+    sim::set_load("load-psu:CH1", 10.0).unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 10.0, 1e-3);
+    meter
+        .set_mode(MultimeterMode::DcCurrent, None)
+        .await
+        .unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 0.5, 1e-3);
+
+    /* Heavier load than the current limit allows, so constant current */
+    sim::set_load("load-psu:CH1", 2.0).unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 1.0, 1e-3);
+    assert_near(output.read_voltage().await.unwrap() as f64, 2.0, 1e-2);
+    meter
+        .set_mode(MultimeterMode::DcVoltage, None)
+        .await
+        .unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 2.0, 1e-3);
+
+    output.set_enabled(false).await.unwrap();
+    assert_near(meter.get_reading().await.unwrap().value, 0.0, 1e-3);
+}
+
+#[tokio::test]
+async fn oscilloscope_generates_sine_and_square() {
+    let Equipment::Oscilloscope(mut scope) = equipment_from_uri("sim://scope").await.unwrap()
+    else {
+        panic!("Not detected as an oscilloscope");
+    };
+    scope.connect().await.unwrap();
+
+    /* 1 kHz sine of 1 V peak over 10 ms */
+    let channel = scope.get_channel(0).await.unwrap();
+    let sine = channel.lock().await.read_waveform().await.unwrap();
+    assert_near(sine.time_per_pt, 1e-6, 1e-9);
+    let values = &sine.readings.values;
+    let max = values.iter().copied().fold(f64::MIN, f64::max);
+    let min = values.iter().copied().fold(f64::MAX, f64::min);
+    assert_near(max, 1.0, 0.05);
+    assert_near(min, -1.0, 0.05);
+    /* Rising edges, with hysteresis against the noise */
+    let mut rising = 0;
+    let mut high = true;
+    for value in values {
+        if high && *value < -0.5 {
+            high = false;
+        } else if !high && *value > 0.5 {
+            high = true;
+            rising += 1;
+        }
+    }
+    assert!((9..=11).contains(&rising), "{rising} rising edges");
+    /* Unlike a square wave, a sine spends a third of the time within half
+     * its amplitude */
+    let middle = values.iter().filter(|value| value.abs() < 0.5).count();
+    assert_near(middle as f64 / values.len() as f64, 1.0 / 3.0, 0.05);
+
+    /* 1 kHz logic signal between 0 and 3.3 V */
+    let channel = scope.get_channel(1).await.unwrap();
+    let square = channel.lock().await.read_waveform().await.unwrap();
+    let values = &square.readings.values;
+    let high = values
+        .iter()
+        .filter(|value| (**value - 3.3).abs() < 0.1)
+        .count();
+    let low = values.iter().filter(|value| value.abs() < 0.1).count();
+    assert_eq!(high + low, values.len());
+    assert_near(high as f64 / values.len() as f64, 0.5, 0.05);
+}
+
+#[tokio::test]
+async fn spectrum_analyzer_shows_carrier() {
+    let Equipment::SpectrumAnalyzer(mut sa) = equipment_from_uri("sim://sa").await.unwrap() else {
+        panic!("Not detected as a spectrum analyzer");
+    };
+    sa.connect().await.unwrap();
+    let channel = sa.get_channel(0).await.unwrap();
+    let channel = channel.lock().await;
+
+    /* 100 MHz carrier at -20 dBm */
+    channel
+        .set_frequency_conf(SpectrumAnalyzerFreqConfig {
+            span: SpectrumAnalyzerSpan::CenterSpan {
+                center: 100e6,
+                span: 10e6,
+            },
+            resolution: 100e3,
+        })
+        .await
+        .unwrap();
+    let trace = channel.read_trace(0).await.unwrap();
+    let values = &trace.readings.values;
+    assert_eq!(values.len(), 751);
+    assert_near(trace.span.start() as f64, 95e6, 1.0);
+    assert_near(trace.freq_step as f64, 10e6 / 751.0, 1.0);
+
+    let (peak, level) = values
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+    let peak_freq = trace.span.start() as f64 + peak as f64 * trace.freq_step as f64;
+    assert_near(peak_freq, 100e6, trace.freq_step as f64);
+    assert_near(level, -20.0, 0.5);
+
+    /* Noise floor of -161 dBm/Hz in a 100 kHz bandwidth, away from the
+     * carrier */
+    let mut floor = values[..100].to_vec();
+    floor.sort_by(f64::total_cmp);
+    assert_near(floor[50], -111.0, 3.0);
+}
@@ -0,0 +1,73 @@
+//! Simulated instruments served over real transports
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use testeq_rs::{
+    protocol::scpi_from_uri,
+    sim::{self, SimClass, server::SimServer},
+};
+
+#[tokio::test]
+async fn serves_raw_tcp_and_vxi11() {
+    let psu = sim::open(SimClass::Psu, None, Some("server-psu")).unwrap();
+    let dmm = sim::open(SimClass::Dmm, None, Some("server-dmm")).unwrap();
+    let server = SimServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 0, vec![psu, dmm])
+        .await
+        .unwrap();
+    let [psu_addr, dmm_addr] = server.raw_addrs() else {
+        panic!("Expected a raw TCP address per instrument");
+    };
+    let vxi11_port = server.vxi11_addr().port();
+
+    let mut tcp = scpi_from_uri(format!("tcp://127.0.0.1:{}", psu_addr.port()))
+        .await
+        .unwrap();
+    assert!(tcp.query_str("*IDN?").await.unwrap().contains("DP832"));
+    tcp.send(":SOUR2:VOLT 7.5").await.unwrap();
+
+    /* Same instrument by index and by name, sharing its state */
+    for device in ["inst0", "server-psu"] {
+        let mut vxi11 = scpi_from_uri(format!("vxi11://127.0.0.1:{vxi11_port}/{device}"))
+            .await
+            .unwrap();
+        assert!(vxi11.query_str("*IDN?").await.unwrap().contains("DP832"));
+        assert_eq!(vxi11.query_f32(":SOUR2:VOLT?").await.unwrap(), 7.5);
+        vxi11.disconnect().await.unwrap();
+    }
+
+    let mut dmm = scpi_from_uri(format!("tcp://127.0.0.1:{}", dmm_addr.port()))
+        .await
+        .unwrap();
+    assert!(dmm.query_str("*IDN?").await.unwrap().contains("SDM4065A"));
+    let mut vxi11 = scpi_from_uri(format!("vxi11://127.0.0.1:{vxi11_port}/inst1"))
+        .await
+        .unwrap();
+    assert!(vxi11.query_str("*IDN?").await.unwrap().contains("SDM4065A"));
+}
+
+#[tokio::test]
+async fn serves_ipv6_loopback() {
+    let psu = sim::open(SimClass::Psu, None, Some("ipv6-psu")).unwrap();
+    /* Not every host has IPv6 enabled */
+    let Ok(server) = SimServer::start(IpAddr::V6(Ipv6Addr::LOCALHOST), 0, 0, vec![psu]).await
+    else {
+        return;
+    };
+    let port = server.raw_addrs()[0].port();
+
+    let mut tcp = scpi_from_uri(format!("tcp://[::1]:{port}")).await.unwrap();
+    assert!(tcp.query_str("*IDN?").await.unwrap().contains("DP832"));
+    let mut tcp = scpi_from_uri(format!("TCPIP0::[::1]::{port}::SOCKET"))
+        .await
+        .unwrap();
+    assert!(tcp.query_str("*IDN?").await.unwrap().contains("DP832"));
+}
+
+#[tokio::test]
+async fn rejects_raw_ports_past_the_last() {
+    let psu = sim::open(SimClass::Psu, None, Some("overflow-psu")).unwrap();
+    let dmm = sim::open(SimClass::Dmm, None, Some("overflow-dmm")).unwrap();
+
+    let res = SimServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), u16::MAX, 0, vec![psu, dmm]).await;
+    assert!(res.is_err());
+}
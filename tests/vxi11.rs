@@ -0,0 +1,295 @@
+//! VXI-11 client operations against the simulated instrument server
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use testeq_rs::{
+    error::Error,
+    protocol::{
+        Ieee488Control, Protocol, RecordCall, RecordEntry, RecordingProtocol, ScpiProtocol,
+        ScpiSession, ScpiVxiProtocol,
+    },
+    sim::{self, SimClass, server::SimServer},
+};
+use tokio::io::{AsyncReadExt, DuplexStream};
+
+async fn serve(name: &str) -> SimServer {
+    let psu = sim::open(SimClass::Psu, None, Some(name)).unwrap();
+    SimServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 0, vec![psu])
+        .await
+        .unwrap()
+}
+
+async fn link(server: &SimServer) -> ScpiVxiProtocol {
+    let mut vxi11 = ScpiVxiProtocol::new(server.vxi11_addr());
+    vxi11.connect().await.unwrap();
+    vxi11
+}
+
+/// Commands sent, as recorded by a recording protocol writing to reader
+async fn sent_commands(mut reader: DuplexStream) -> Vec<String> {
+    let mut lines = String::new();
+    reader.read_to_string(&mut lines).await.unwrap();
+    RecordEntry::parse_lines(&lines)
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.call == RecordCall::IntSend)
+        .map(|entry| String::from_utf8(entry.data.unwrap()).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn device_control() {
+    let server = serve("vxi11-control-psu").await;
+    let mut vxi11 = link(&server).await;
+    let proto: &mut dyn ScpiProtocol = &mut vxi11;
+
+    /* Event status bit set by an enabled operation complete event */
+    proto.send("*ESE 1").await.unwrap();
+    proto.send("*OPC").await.unwrap();
+    assert_eq!(proto.read_stb().await.unwrap() & 0x20, 0x20);
+    proto.query_str("*ESR?").await.unwrap();
+    assert_eq!(proto.read_stb().await.unwrap() & 0x20, 0);
+
+    /* Clear discards the pending response */
+    proto.send("*IDN?").await.unwrap();
+    proto.device_clear().await.unwrap();
+    assert!(matches!(proto.recv().await, Err(Error::Timeout(_))));
+    assert!(proto.query_str("*IDN?").await.unwrap().contains("DP832"));
+
+    proto.device_trigger().await.unwrap();
+    proto.device_local().await.unwrap();
+
+    vxi11.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn links_contend_for_lock() {
+    let server = serve("vxi11-lock-psu").await;
+    let mut holder = link(&server).await;
+    let mut other = link(&server).await;
+
+    holder.lock(Duration::from_secs(1)).await.unwrap();
+    assert!(matches!(
+        other.lock(Duration::from_millis(50)).await,
+        Err(Error::Timeout(_))
+    ));
+    /* Operations fail straight away without a lock timeout */
+    assert!(
+        (&mut other as &mut dyn ScpiProtocol)
+            .send("*RST")
+            .await
+            .is_err()
+    );
+    (&mut holder as &mut dyn ScpiProtocol)
+        .send("*RST")
+        .await
+        .unwrap();
+
+    /* Waiting operations go ahead once the lock is released */
+    other.set_lock_timeout(Duration::from_secs(5));
+    let waiting = tokio::spawn(async move {
+        (&mut other as &mut dyn ScpiProtocol)
+            .query_str("*IDN?")
+            .await
+            .map(|_| other)
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    holder.unlock().await.unwrap();
+    let mut other = waiting.await.unwrap().unwrap();
+
+    other.lock(Duration::from_secs(1)).await.unwrap();
+    other.unlock().await.unwrap();
+    assert!(other.unlock().await.is_err());
+
+    holder.disconnect().await.unwrap();
+    other.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn lock_timeout_kept_across_links() {
+    let server = serve("vxi11-lock-timeout-psu").await;
+    let mut holder = link(&server).await;
+    let mut other = ScpiVxiProtocol::new(server.vxi11_addr());
+    other.set_lock_timeout(Duration::from_secs(5));
+
+    for _ in 0..2 {
+        other.connect().await.unwrap();
+        holder.lock(Duration::from_secs(1)).await.unwrap();
+
+        let waiting = tokio::spawn(async move {
+            (&mut other as &mut dyn ScpiProtocol)
+                .query_str("*IDN?")
+                .await
+                .map(|_| other)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        holder.unlock().await.unwrap();
+        other = waiting.await.unwrap().unwrap();
+
+        other.disconnect().await.unwrap();
+    }
+
+    holder.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn unbounded_lock_timeout() {
+    let server = serve("vxi11-lock-max-psu").await;
+    let mut vxi11 = link(&server).await;
+    vxi11.set_lock_timeout(Duration::MAX);
+
+    vxi11.lock(Duration::MAX).await.unwrap();
+    assert!(
+        (&mut vxi11 as &mut dyn ScpiProtocol)
+            .query_str("*IDN?")
+            .await
+            .unwrap()
+            .contains("DP832")
+    );
+    vxi11.unlock().await.unwrap();
+
+    vxi11.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn abort_ends_wait_for_lock() {
+    let server = serve("vxi11-abort-psu").await;
+    let mut holder = link(&server).await;
+    let mut other = link(&server).await;
+    holder.lock(Duration::from_secs(1)).await.unwrap();
+
+    let abort = other.abort_handle().unwrap();
+    let start = Instant::now();
+    let waiting = tokio::spawn(async move {
+        let res = other.lock(Duration::from_secs(10)).await;
+        (other, res)
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    abort.abort().await.unwrap();
+
+    let (mut other, res) = waiting.await.unwrap();
+    assert!(res.is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    /* Abort only applies to the operation in progress */
+    holder.unlock().await.unwrap();
+    other.lock(Duration::from_secs(1)).await.unwrap();
+
+    holder.disconnect().await.unwrap();
+    other.disconnect().await.unwrap();
+    assert!(abort.abort().await.is_err());
+}
+
+#[tokio::test]
+async fn disconnect_releases_lock() {
+    let server = serve("vxi11-disconnect-psu").await;
+    let mut holder = link(&server).await;
+    let mut other = link(&server).await;
+
+    holder.lock(Duration::from_secs(1)).await.unwrap();
+    holder.disconnect().await.unwrap();
+    assert!(holder.lock(Duration::from_secs(1)).await.is_err());
+
+    other.lock(Duration::from_millis(50)).await.unwrap();
+    other.disconnect().await.unwrap();
+
+    /* Link may be created again after disconnecting */
+    holder.connect().await.unwrap();
+    holder.lock(Duration::from_millis(50)).await.unwrap();
+    holder.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn drop_destroys_link_without_blocking() {
+    let server = serve("vxi11-drop-psu").await;
+    let mut holder = link(&server).await;
+    let mut other = link(&server).await;
+
+    /* Link is destroyed in the background, releasing its lock */
+    holder.lock(Duration::from_secs(1)).await.unwrap();
+    drop(holder);
+    other.lock(Duration::from_secs(1)).await.unwrap();
+
+    other.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn session_waits_for_service_request() {
+    let server = serve("vxi11-session-srq-psu").await;
+    let (writer, reader) = tokio::io::duplex(1 << 16);
+    let recording = RecordingProtocol::new(Box::new(link(&server).await), writer);
+    let session = ScpiSession::new(Box::new(recording));
+
+    session.send("*RST").await.unwrap();
+    let start = Instant::now();
+    session.wait_complete(Duration::from_secs(5)).await.unwrap();
+    /* Notified rather than waiting out the timeout */
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(session);
+
+    let sent = sent_commands(reader).await;
+    assert!(
+        sent.iter().any(|cmd| cmd.starts_with("*SRE 32")),
+        "{sent:?}"
+    );
+}
+
+#[tokio::test]
+async fn delivers_service_requests() {
+    let server = serve("vxi11-srq-psu").await;
+    let mut vxi11 = link(&server).await;
+    let mut srq = vxi11.enable_srq().await.unwrap();
+
+    let proto: &mut dyn ScpiProtocol = &mut vxi11;
+    proto.query_str("*ESR?").await.unwrap();
+    proto.send("*ESE 1").await.unwrap();
+    proto.send("*SRE 32").await.unwrap();
+    assert!(srq.try_recv().is_err());
+
+    /* Operation complete event raises MSS, requesting service */
+    proto.send("*OPC").await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), srq.recv())
+        .await
+        .expect("Service request not delivered")
+        .unwrap();
+    assert_eq!(proto.read_stb().await.unwrap() & 0x40, 0x40);
+
+    /* No longer delivered once disabled */
+    proto.query_str("*ESR?").await.unwrap();
+    vxi11.disable_srq().await.unwrap();
+    (&mut vxi11 as &mut dyn ScpiProtocol)
+        .send("*OPC")
+        .await
+        .unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), srq.recv())
+            .await
+            .is_err()
+    );
+
+    vxi11.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn wait_complete_uses_service_request() {
+    let server = serve("vxi11-wait-srq-psu").await;
+    let (writer, reader) = tokio::io::duplex(1 << 16);
+    let mut recording = RecordingProtocol::new(Box::new(link(&server).await), writer);
+
+    let proto: &mut dyn ScpiProtocol = &mut recording;
+    proto.send("*RST").await.unwrap();
+    let start = Instant::now();
+    proto.wait_complete(Duration::from_secs(5)).await.unwrap();
+    /* Notified rather than waiting out the timeout */
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(recording);
+
+    let sent = sent_commands(reader).await;
+    assert!(
+        sent.iter().any(|cmd| cmd.starts_with("*SRE 32")),
+        "{sent:?}"
+    );
+}